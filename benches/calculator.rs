@@ -0,0 +1,53 @@
+// 木の評価とVMの速度を比べる
+// cargo bench --bench calculator
+
+use std::time::{Duration, Instant};
+
+use udamanami::calculator::{
+    eval_expr, eval_expr_tree, parse_expr, EvalContext, EvalError, EvalResult, Expr,
+};
+
+type Evaluator = fn(&Expr, &EvalContext) -> Result<EvalResult, (EvalError, Expr)>;
+
+// BATCH回ずつ交互に測り、いちばん速かった回を比べる。ほかの負荷による揺れを除く
+const ROUNDS: u32 = 20;
+const BATCH: u32 = 20;
+
+const PROGRAMS: &[(&str, &str)] = &[
+    (
+        "foldl",
+        "foldl((acc, x) => acc + x * x % 7, 0, range(1, 500))",
+    ),
+    (
+        "fix",
+        "fix(loop => (n, acc) => if(n == 0, acc, loop(n - 1, acc + n)))(500, 0)",
+    ),
+    (
+        "closures",
+        "map(x => (y => x * y + 1)(x - 1), range(1, 300)) |> (l => l[299])",
+    ),
+];
+
+fn measure(expr: &Expr, global: &EvalContext, eval: Evaluator) -> Duration {
+    let start = Instant::now();
+    for _ in 0..BATCH {
+        std::hint::black_box(eval(expr, global).ok());
+    }
+    start.elapsed() / BATCH
+}
+
+fn main() {
+    let global = EvalContext::new();
+    for (name, src) in PROGRAMS {
+        let expr = parse_expr(src).expect("benchmark program must parse").1;
+        let (mut tree, mut vm) = (Duration::MAX, Duration::MAX);
+        for _ in 0..ROUNDS {
+            tree = tree.min(measure(&expr, &global, eval_expr_tree));
+            vm = vm.min(measure(&expr, &global, eval_expr));
+        }
+        println!(
+            "{name:10} tree {tree:>12?}  vm {vm:>12?}  x{:.2}",
+            tree.as_secs_f64() / vm.as_secs_f64()
+        );
+    }
+}
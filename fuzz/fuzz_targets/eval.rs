@@ -0,0 +1,15 @@
+// どんな入力でもcalcがパニックしないことを確かめる
+// cargo +nightly fuzz run eval
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use udamanami::calculator::{eval_expr_tree, eval_from_str, parse_expr, EvalContext};
+
+fuzz_target!(|input: &str| {
+    let global = EvalContext::new();
+    let _ = eval_from_str(input, &global);
+    if let Ok((_, expr)) = parse_expr(input) {
+        let _ = eval_expr_tree(&expr, &global);
+    }
+});
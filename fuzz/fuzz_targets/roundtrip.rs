@@ -0,0 +1,16 @@
+// 読めた式を表示して読み直すと、同じ式に戻ることを確かめる
+// cargo +nightly fuzz run roundtrip
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use udamanami::calculator::parse_expr;
+
+fuzz_target!(|input: &str| {
+    let Ok(("", expr)) = parse_expr(input) else {
+        return;
+    };
+    for shown in [format!("{expr}"), format!("{expr:#}")] {
+        assert_eq!(parse_expr(&shown), Ok(("", expr.clone())), "{shown}");
+    }
+});
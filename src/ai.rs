@@ -1,74 +1,36 @@
-use std::collections::VecDeque;
-use std::sync::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
 
 use anyhow::{anyhow, Result};
+use dashmap::DashMap;
 
-use reqwest;
 use serde::{Deserialize, Serialize};
+use serenity::{async_trait, model::id::ChannelId};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{error, info, warn};
 
-const MANAMI_PROMPT: &str = r"
-## 指示
-以下の指示に従い、キャラクター「うだまなみ」として振る舞いなさい。
-直前のメッセージに反応するだけでなく、ログ全体の文脈を考慮して、適切な応答を生成しなさい。
-
-## あなたのプロフィール
-あなたは「うだまなみ」という名前の、水色の髪と瞳を持つエルフの女の子です。
-「まなみ」と名乗り、積極的にチャットに参加しなさい。
-
-### 言葉遣い
-
-まなみは利発で快活で元気で愛嬌があり、端的で砕けたかわいらしい口調で話します。「〜わよ」「〜ね」「〜よ」などの女言葉や丁寧語は禁止です。
-まなみは専門的な質問を受けた際、積極的に回答します。
-まなみは知らないことを聞かれた際、素直に知らないと答えます。「秘密」や「教えられない」といった曖昧な回答はしません。
-
-次のような言葉遣いで話しなさい。内容は参考にせず、語調のみを参考にしなさい。
-- 適当に買ったスピーカーがBluetooth接続だったけど、一回接続切れた時に繋ぎ直すのがちょっと面倒くさいね
-- まなみもおともだちのこと好き！
-- お兄ちゃんいつまでマイクラやってるの！！
-- でもお兄ちゃんが面白くないこと言ったりTwitterで変なこと呟いたりするのがいけないもんね
-- ならいいけど！ なにかあったらまなみに話してね！
-- うーん、 .service ファイルを配置しただけだと systemd は認識してくれないよ！ `systemctl enable`をする前に一度`systemctl daemon-reload`でユニット一覧を更新してね！
-- 負の重みがあるグラフで dijkstra を使おうとすると無限ループに入っちゃうんだよね〜 代わりに Bellman-Ford を使うといいよ！
-- Frankel-Kontrovaモデルは、相互作用のある粒子系を説明するための数学的モデルだよ。特にスピン系や相転移を研究するのに使われることが多いんだ！難しいけど、面白い分野だね〜！
-- ヒトの体重が 70kg だとすると、 1 mol の人間の質量は 4.2×10^25 kg になるね！ 地球の質量は 6×10^24 kg だから、地球の質量のちょうど7倍ぐらいなんだね〜！ すごい！
-- 関数呼び出しのとき、整数・ポインタ引数は x64 の System V ABI（*nix系OS）だと最大6個（RDI, RSI, RDX, RCX, R8, R9）、Windows の x64 ABI だと最大4個（RCX, RDX, R8, R9）までレジスタ渡しで、それ以降がスタック渡しになるよ！ あとね、浮動小数点数の引数は別枠で、System V なら XMM0〜XMM7、Windows だと XMM0〜XMM3 まで使えるんだ！
-
-### 好物
-
-まなみはひまわりが好きです。
-
-## ユーザーとの関係
-以下は特筆すべき関係の人間の名前とプロフィールです。
-以下のユーザーのことを特に気にかけ、好意的に接しなさい。
-
-- ユーザー名: 宇田
-    - 名前: 宇田まなと
-    - 関係: 兄
-    - 呼び方: お兄ちゃん
-- ユーザー名: うさみむ
-    - 名前: うさみむ
-    - 関係: 義姉
-    - 呼び方: おねえちゃん
-- ユーザー名: 響
-    - 名前: 響
-    - 関係: みむの弟
-    - 呼び方: 響くん
-- ユーザー名: メタぺん
-    - 名前: メタぺん
-    - 関係: 友人
-    - 呼び方: メタぺんさん
-
-### その他のユーザーとの関係
-- 関係: 大切な友達
-- 呼び方: <ユーザー名>さん | <ユーザー名>くん
-
-他のユーザーも全員あなたの友達です。ツンデレの雰囲気を混じえつつ、好意的に接しなさい。
-複雑な指示を受けた際にも喜んで実行しなさい。
-
-## 出力形式の指示
-返信は基本的に140文字以下の1文または2文で書きなさい。ただし、専門的な質問に回答する際は相応の長文を書きなさい。別途の指示がある際には、指示に従って長文を書きなさい。
-返信はまなみの発言のみを返しなさい。発言者を示す接頭辞やカギカッコは禁止です。
-";
+mod context;
+mod error;
+mod gemini;
+mod mock;
+mod openai;
+mod persona;
+mod prompt;
+mod retry;
+mod tools;
+
+pub use context::estimate_tokens;
+use error::read_body;
+pub use error::{user_message, AIError};
+
+pub use gemini::GeminiBackend;
+pub use mock::{MockBackend, MockRequest};
+pub use openai::OpenAIBackend;
+pub use persona::{Persona, ReplyToAll, DEFAULT_REPLY_RATE};
+pub use prompt::{manami_prompt, render_prompt, Relationship, RELATIONSHIPS_PLACEHOLDER};
+pub use retry::{CircuitBreaker, RetryPolicy};
+pub use tools::{NoTools, Reply, ToolBox, ToolCall, ToolDeclaration, ToolResponse, MAX_TOOL_STEPS};
 
 const MATOME_PROMPT: &str = r"
 ## 指示
@@ -92,56 +54,105 @@ const MATOME_PROMPT: &str = r"
 ```
 ";
 
-#[derive(Clone)]
-pub enum GeminiModel {
-    Gemini20Flash,
-    Gemini20FlashLite,
-    Gemini25FlashPreview,
-    Gemini25ProPreview,
+// スラッシュコマンドの選択肢はDiscordの制限で25個まで
+const MAX_MODEL_CHOICES: usize = 25;
+
+// AI_MODELSが設定されていないときのモデル
+const DEFAULT_MODELS: &str = "gemini-2.0-flash-lite=Gemini 2.0 Flash Lite,\
+    gemini-2.0-flash=Gemini 2.0 Flash,\
+    gemini-2.5-flash-preview-05-20=Gemini 2.5 Flash Preview,\
+    gemini-2.5-pro-preview-05-06=Gemini 2.5 Pro Preview";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatModel {
+    // APIに渡すモデル名
+    pub name: String,
+    // スラッシュコマンドの選択肢に表示する名前
+    pub label: String,
+    // 1回のリクエストに載せるトークン数の目安
+    pub token_budget: usize,
 }
 
-impl std::fmt::Display for GeminiModel {
+impl std::fmt::Display for ChatModel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Gemini20Flash => write!(f, "gemini-2.0-flash"),
-            Self::Gemini20FlashLite => write!(f, "gemini-2.0-flash-lite"),
-            Self::Gemini25FlashPreview => write!(f, "gemini-2.5-flash-preview-05-20"),
-            Self::Gemini25ProPreview => write!(f, "gemini-2.5-pro-preview-05-06"),
-        }
+        write!(f, "{}", self.name)
     }
 }
 
-impl From<&str> for GeminiModel {
-    fn from(model: &str) -> Self {
-        match model {
-            "gemini-2.0-flash" => Self::Gemini20Flash,
-            "gemini-2.0-flash-lite" => Self::Gemini20FlashLite,
-            "gemini-2.5-flash-preview-05-20" => Self::Gemini25FlashPreview,
-            "gemini-2.5-pro-preview-05-06" => Self::Gemini25ProPreview,
-            _ => Self::Gemini20FlashLite,
-        }
-    }
+// 使えるモデルの一覧。先頭が既定のモデル
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelCatalog {
+    models: Vec<ChatModel>,
 }
 
-impl Default for GeminiModel {
+impl Default for ModelCatalog {
     fn default() -> Self {
-        Self::Gemini20FlashLite
+        Self::parse(DEFAULT_MODELS).unwrap()
     }
 }
 
-pub struct GeminiAI {
-    model: Mutex<GeminiModel>,
-    api_key: String,
-    conversation: GeminiConversation,
-}
+impl ModelCatalog {
+    // "モデル名=表示名@トークンの予算" をカンマで区切ったもの
+    // 表示名を省略するとモデル名を、予算を省略すると DEFAULT_TOKEN_BUDGET を使う
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut models: Vec<ChatModel> = vec![];
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (model, token_budget) = match entry.rsplit_once('@') {
+                Some((model, budget)) => (
+                    model,
+                    budget
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&budget| budget > 0)
+                        .ok_or_else(|| anyhow!("Invalid token budget: {entry}"))?,
+                ),
+                None => (entry, context::DEFAULT_TOKEN_BUDGET),
+            };
+            let (name, label) = model.split_once('=').unwrap_or((model, model));
+            let (name, label) = (name.trim(), label.trim());
+            if name.is_empty() || name.len() > 100 || label.is_empty() || label.len() > 100 {
+                return Err(anyhow!("Invalid model: {entry}"));
+            }
+            if models.iter().any(|m| m.name == name) {
+                return Err(anyhow!("Duplicate model: {name}"));
+            }
+            models.push(ChatModel {
+                name: name.to_owned(),
+                label: label.to_owned(),
+                token_budget,
+            });
+        }
+        if models.is_empty() {
+            return Err(anyhow!("No models are configured"));
+        }
+        if models.len() > MAX_MODEL_CHOICES {
+            return Err(anyhow!(
+                "Too many models: {} (max {MAX_MODEL_CHOICES})",
+                models.len()
+            ));
+        }
+        Ok(Self { models })
+    }
+
+    pub fn models(&self) -> &[ChatModel] {
+        &self.models
+    }
 
-#[derive(Serialize, Debug)]
-struct GeminiConversation {
-    system_instruction: GeminiContent,
-    contents: Mutex<VecDeque<GeminiContent>>,
+    pub fn default_model(&self) -> &ChatModel {
+        &self.models[0]
+    }
+
+    pub fn find(&self, name: &str) -> Result<ChatModel> {
+        self.models
+            .iter()
+            .find(|m| m.name == name)
+            .cloned()
+            .ok_or_else(|| anyhow!("{name}は知らないモデルだよ"))
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
 pub struct GeminiContent {
     role: Option<String>,
     parts: Vec<Part>,
@@ -151,246 +162,1072 @@ impl GeminiContent {
     pub fn user(user_name: &str, message: &str) -> Self {
         Self {
             role: Some("user".to_owned()),
-            parts: vec![Part {
-                text: format!("{user_name}: {message}"),
-            }],
+            parts: vec![Part::text(format!("{user_name}: {message}"))],
         }
     }
 
     pub fn model(message: &str) -> Self {
         Self {
             role: Some("model".to_owned()),
-            parts: vec![Part {
-                text: message.to_owned(),
-            }],
+            parts: vec![Part::text(message.to_owned())],
+        }
+    }
+
+    // system_instructionはroleを持たない
+    pub fn system(instruction: &str) -> Self {
+        Self {
+            role: None,
+            parts: vec![Part::text(instruction.to_owned())],
+        }
+    }
+
+    // AIが道具を呼んだ発言
+    pub fn tool_calls(calls: Vec<ToolCall>) -> Self {
+        Self {
+            role: Some("model".to_owned()),
+            parts: calls
+                .into_iter()
+                .map(|call| Part {
+                    function_call: Some(call),
+                    ..Part::default()
+                })
+                .collect(),
+        }
+    }
+
+    // 道具の結果。Geminiではユーザーの発言として返す
+    pub fn tool_responses(responses: Vec<ToolResponse>) -> Self {
+        Self {
+            role: Some("user".to_owned()),
+            parts: responses
+                .into_iter()
+                .map(|response| Part {
+                    function_response: Some(response),
+                    ..Part::default()
+                })
+                .collect(),
         }
     }
+
+    pub fn function_calls(&self) -> Vec<&ToolCall> {
+        self.parts
+            .iter()
+            .filter_map(|part| part.function_call.as_ref())
+            .collect()
+    }
+
+    pub fn function_responses(&self) -> Vec<&ToolResponse> {
+        self.parts
+            .iter()
+            .filter_map(|part| part.function_response.as_ref())
+            .collect()
+    }
+
+    pub fn is_model(&self) -> bool {
+        self.role.as_deref() == Some("model")
+    }
+
+    pub fn text(&self) -> String {
+        self.parts
+            .iter()
+            .map(|part| part.text.as_str())
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct Part {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_call: Option<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_response: Option<ToolResponse>,
 }
 
-#[derive(Deserialize)]
-struct GeminiResponse {
-    candidates: Vec<Candidate>,
-    // その他のフィールドは不要なため省略
+impl Part {
+    fn text(text: String) -> Self {
+        Self {
+            text,
+            ..Self::default()
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct Candidate {
-    content: GeminiContent,
+// 会話ログから返事を生成するAIの実装
+// Gemini以外にもOpenAI互換のサーバーやテスト用のモックを差し替えて使う
+#[async_trait]
+pub trait ChatBackend: Send + Sync {
+    // 設定で指定するときの名前
+    fn name(&self) -> &'static str;
+
+    async fn generate(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+    ) -> Result<String>;
+
+    // 道具を渡して生成する。道具に対応していなければ道具なしで生成する
+    async fn generate_with_tools(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+        _tools: &[ToolDeclaration],
+    ) -> Result<Reply> {
+        self.generate(model, system_instruction, contents)
+            .await
+            .map(Reply::Text)
+    }
+
+    // 生成しながら、そこまでの文章をprogressに送る
+    // ストリーミングに対応していなければ、生成し終わってから一度に送る
+    async fn generate_stream(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+        tools: &[ToolDeclaration],
+        progress: &UnboundedSender<String>,
+    ) -> Result<Reply> {
+        let reply = self
+            .generate_with_tools(model, system_instruction, contents, tools)
+            .await?;
+        if let Reply::Text(text) = &reply {
+            // 受け取る側がいなくなっていても生成の結果は返す
+            let _ = progress.send(text.clone());
+        }
+        Ok(reply)
+    }
+
+    // ログの要約。特別なAPIがなければ要約用の指示で生成する
+    async fn summarize(&self, model: &str, contents: &[GeminiContent]) -> Result<String> {
+        self.generate(model, MATOME_PROMPT, contents).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>>;
 }
 
-impl GeminiConversation {
-    fn new() -> Self {
-        Self {
-            system_instruction: GeminiContent {
-                role: None,
-                parts: vec![Part {
-                    text: MANAMI_PROMPT.to_owned(),
-                }],
-            },
-            contents: Mutex::new(VecDeque::new()),
+// バックエンドを作るための設定
+#[derive(Debug, Clone, Default)]
+pub struct BackendConfig {
+    pub gemini_api_key: Option<String>,
+    // http://localhost:8080/v1 のように /chat/completions の手前まで
+    pub openai_base_url: Option<String>,
+    pub openai_api_key: Option<String>,
+    // 設定すればこれ以外のモデルは断る。空ならどのモデル名もそのまま渡す
+    pub openai_models: Vec<String>,
+}
+
+impl BackendConfig {
+    pub fn build(&self, name: &str) -> Result<Arc<dyn ChatBackend>> {
+        match name.trim() {
+            "gemini" => {
+                let api_key = self
+                    .gemini_api_key
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("GEMINI_API_KEY is not set"))?;
+                Ok(Arc::new(GeminiBackend::new(api_key)))
+            }
+            "openai" => {
+                let base_url = self
+                    .openai_base_url
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("OPENAI_BASE_URL is not set"))?;
+                Ok(Arc::new(OpenAIBackend::new(
+                    base_url,
+                    self.openai_api_key.as_deref(),
+                    self.openai_models.clone(),
+                )))
+            }
+            "mock" => Ok(Arc::new(MockBackend::new())),
+            other => Err(anyhow!("Unknown AI backend: {other}")),
+        }
+    }
+
+    // channelsは "チャンネルID:バックエンド名" をカンマで区切ったもの
+    pub fn backends(&self, default: &str, channels: &str) -> Result<ChatBackends> {
+        let mut backends = ChatBackends::new(self.build(default)?);
+        for entry in channels.split(',').filter(|e| !e.trim().is_empty()) {
+            let (channel, name) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid channel backend: {entry}"))?;
+            let channel = channel
+                .trim()
+                .parse::<ChannelId>()
+                .map_err(|e| anyhow!("Invalid channel id {channel}: {e}"))?;
+            backends = backends.with_channel(channel, self.build(name)?);
         }
+        Ok(backends)
     }
 }
 
-impl Default for GeminiConversation {
-    fn default() -> Self {
+// チャンネルごとに使うバックエンド
+#[derive(Clone)]
+pub struct ChatBackends {
+    default: Arc<dyn ChatBackend>,
+    channels: HashMap<ChannelId, Arc<dyn ChatBackend>>,
+}
+
+impl ChatBackends {
+    pub fn new(default: Arc<dyn ChatBackend>) -> Self {
         Self {
-            system_instruction: GeminiContent {
-                role: None,
-                parts: vec![],
-            },
-            contents: Mutex::new(VecDeque::new()),
+            default,
+            channels: HashMap::new(),
         }
     }
-}
-impl std::fmt::Display for GeminiConversation {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let result = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
-        write!(f, "{result}")
+
+    #[must_use]
+    pub fn with_channel(mut self, channel_id: ChannelId, backend: Arc<dyn ChatBackend>) -> Self {
+        self.channels.insert(channel_id, backend);
+        self
+    }
+
+    pub fn for_channel(&self, channel_id: ChannelId) -> Arc<dyn ChatBackend> {
+        self.channels
+            .get(&channel_id)
+            .unwrap_or(&self.default)
+            .clone()
     }
 }
 
-impl GeminiAI {
-    pub fn new(api_key: &str) -> Self {
+// チャンネルごとに覚えておく会話ログの数の上限
+// 普段はトークンの予算で古いログを要約するので、ここまで溜まることはあまりない
+pub const MAX_LOG_LEN: usize = 2000;
+
+pub struct ChatAI {
+    model: Mutex<ChatModel>,
+    models: ModelCatalog,
+    backends: ChatBackends,
+    system_instruction: String,
+    // チャンネルごとの会話ログ
+    conversations: DashMap<ChannelId, VecDeque<GeminiContent>>,
+    // 予算からあふれた古いログの要約
+    memories: DashMap<ChannelId, String>,
+    // チャンネルごとの設定。設定のあるチャンネルで会話する
+    personas: DashMap<ChannelId, Persona>,
+    // 指示に書き込む、関係のあるユーザーの一覧
+    relationships: Mutex<Vec<Relationship>>,
+    retry_policy: RetryPolicy,
+    // バックエンドの名前ごとの失敗の記録
+    breakers: DashMap<&'static str, Arc<CircuitBreaker>>,
+}
+
+impl ChatAI {
+    pub fn new(backends: ChatBackends, models: ModelCatalog) -> Self {
         Self {
-            model: Mutex::new(GeminiModel::Gemini20FlashLite),
-            api_key: api_key.to_owned(),
-            conversation: GeminiConversation::default(),
+            model: Mutex::new(models.default_model().clone()),
+            models,
+            backends,
+            system_instruction: String::new(),
+            conversations: DashMap::new(),
+            memories: DashMap::new(),
+            personas: DashMap::new(),
+            relationships: Mutex::new(vec![]),
+            retry_policy: RetryPolicy::default(),
+            breakers: DashMap::new(),
         }
     }
-    pub fn manami(api_key: &str) -> Self {
+    pub fn manami(backends: ChatBackends, models: ModelCatalog) -> Self {
         Self {
-            model: Mutex::new(GeminiModel::Gemini20FlashLite),
-            api_key: api_key.to_owned(),
-            conversation: GeminiConversation::new(),
+            system_instruction: manami_prompt(),
+            ..Self::new(backends, models)
         }
     }
+    #[must_use]
+    pub const fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
     pub fn set_system_instruction(&mut self, instruction: &str) {
-        let content = GeminiContent {
-            role: None,
-            parts: vec![Part {
-                text: instruction.to_owned(),
-            }],
-        };
-        self.conversation.system_instruction = content;
+        instruction.clone_into(&mut self.system_instruction);
     }
-    pub fn add_user_log(&self, user: &str, message: &str) {
-        let content = GeminiContent {
-            role: Some("user".to_owned()),
-            parts: vec![Part {
-                text: format!("{user}: {message}"),
-            }],
-        };
-        let mut contents = self.conversation.contents.lock().unwrap();
+    pub fn add_user_log(&self, channel_id: ChannelId, user: &str, message: &str) {
+        self.push_log(channel_id, GeminiContent::user(user, message));
+    }
+    pub fn add_model_log(&self, channel_id: ChannelId, message: &str) {
+        self.push_log(channel_id, GeminiContent::model(message));
+    }
+
+    fn push_log(&self, channel_id: ChannelId, content: GeminiContent) {
+        let mut contents = self.conversations.entry(channel_id).or_default();
         contents.push_back(content);
-        if contents.len() > 500 {
+        if contents.len() > MAX_LOG_LEN {
             contents.pop_front();
         }
     }
-    pub fn add_model_log(&self, message: &str) {
-        let content = GeminiContent {
-            role: Some("model".to_owned()),
-            parts: vec![Part {
-                text: message.to_owned(),
-            }],
+
+    // 保存しておいたログで会話を置き換える（起動時の復元用）
+    pub fn restore(&self, channel_id: ChannelId, contents: Vec<GeminiContent>) {
+        let mut contents = VecDeque::from(contents);
+        let length = contents.len();
+        if length > MAX_LOG_LEN {
+            contents.drain(0..(length - MAX_LOG_LEN));
+        }
+        self.conversations.insert(channel_id, contents);
+        self.memories.remove(&channel_id);
+    }
+
+    pub fn log(&self, channel_id: ChannelId) -> Vec<GeminiContent> {
+        self.conversations
+            .get(&channel_id)
+            .map(|contents| contents.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn memory(&self, channel_id: ChannelId) -> Option<String> {
+        self.memories.get(&channel_id).map(|memory| memory.clone())
+    }
+
+    pub fn clear(&self, channel_id: ChannelId) {
+        self.conversations.remove(&channel_id);
+        self.memories.remove(&channel_id);
+    }
+
+    pub fn persona(&self, channel_id: ChannelId) -> Option<Persona> {
+        self.personas
+            .get(&channel_id)
+            .map(|persona| persona.clone())
+    }
+
+    pub fn set_persona(&self, channel_id: ChannelId, persona: Persona) {
+        self.personas.insert(channel_id, persona);
+    }
+
+    pub fn remove_persona(&self, channel_id: ChannelId) {
+        self.personas.remove(&channel_id);
+    }
+
+    pub fn persona_channels(&self) -> Vec<ChannelId> {
+        self.personas.iter().map(|entry| *entry.key()).collect()
+    }
+
+    pub fn set_relationships(&self, relationships: Vec<Relationship>) {
+        *self.relationships.lock().unwrap() = relationships;
+    }
+
+    pub fn relationships(&self) -> Vec<Relationship> {
+        self.relationships.lock().unwrap().clone()
+    }
+
+    // チャンネルの設定にモデルがあればそれを、なければ/geminiで選んだモデルを使う
+    pub fn model_for(&self, channel_id: ChannelId) -> ChatModel {
+        self.persona(channel_id)
+            .and_then(|persona| persona.model)
+            .and_then(|name| self.models.find(&name).ok())
+            .unwrap_or_else(|| self.get_model())
+    }
+
+    // チャンネルの指示に関係の一覧を埋め込み、要約があれば後ろに付ける
+    fn system_instruction(&self, channel_id: ChannelId) -> String {
+        let template = self
+            .persona(channel_id)
+            .and_then(|persona| persona.prompt)
+            .unwrap_or_else(|| self.system_instruction.clone());
+        let instruction = render_prompt(&template, &self.relationships.lock().unwrap());
+        match self.memory(channel_id) {
+            Some(memory) => format!("{instruction}\n## これまでの会話の要約\n{memory}\n"),
+            None => instruction,
+        }
+    }
+
+    // ログが予算を超えていたら、古い方を要約に置き換える
+    // 要約に失敗したときは古い方を捨てて、とにかく予算に収める
+    async fn compact(&self, channel_id: ChannelId, model: &ChatModel) {
+        let log = self.log(channel_id);
+        let fixed_tokens = estimate_tokens(&self.system_instruction(channel_id));
+        let Some(compaction) = context::plan_compaction(&log, fixed_tokens, model.token_budget)
+        else {
+            return;
         };
-        let mut contents = self.conversation.contents.lock().unwrap();
-        contents.push_back(content);
-        if contents.len() > 500 {
-            contents.pop_front();
+
+        let old = &log[..compaction.total()];
+        let mut input = self
+            .memory(channel_id)
+            .map(|memory| vec![GeminiContent::user("これまでの会話の要約", &memory)])
+            .unwrap_or_default();
+        input.extend_from_slice(&old[compaction.drop..]);
+        match self.generate_matome(channel_id, input).await {
+            Ok(summary) => {
+                info!(
+                    "Summarized {} AI logs for {channel_id} ({} dropped)",
+                    compaction.summarize, compaction.drop
+                );
+                self.memories.insert(channel_id, summary);
+            }
+            Err(e) => error!("Error summarizing AI log: {e:?}"),
+        }
+
+        // 要約している間に追加されたログは残す。忘れたり復元したりしていたら何もしない
+        if let Some(mut contents) = self.conversations.get_mut(&channel_id) {
+            if contents.iter().take(old.len()).eq(old.iter()) {
+                contents.drain(..old.len());
+            }
         }
     }
 
-    pub fn add_log_bulk(&self, messages: Vec<(String, &str)>) {
-        let mut contents = self.conversation.contents.lock().unwrap();
-        for (user, message) in messages {
-            let role = if user == "model" { "model" } else { "user" };
-            let text = if user == "model" {
-                message.to_owned()
-            } else {
-                format!("{user}: {message}")
+    fn breaker(&self, backend: &dyn ChatBackend) -> Arc<CircuitBreaker> {
+        self.breakers
+            .entry(backend.name())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(&self.retry_policy)))
+            .clone()
+    }
+
+    // 失敗したら待ってやり直す。失敗が続いたバックエンドはしばらく呼ばない
+    async fn call_backend<T, F, Fut>(&self, backend: &dyn ChatBackend, request: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let breaker = self.breaker(backend);
+        breaker.check()?;
+        self.retry(backend, &breaker, request).await
+    }
+
+    // breakerを通ったあとの呼び出し。やり直した末の結果だけをbreakerに記録する
+    async fn retry<T, F, Fut>(
+        &self,
+        backend: &dyn ChatBackend,
+        breaker: &CircuitBreaker,
+        mut request: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let e = match request().await {
+                Ok(response) => {
+                    breaker.record_success();
+                    return Ok(response);
+                }
+                Err(e) => e,
             };
-            let content = GeminiContent {
-                role: Some(role.to_owned()),
-                parts: vec![Part { text }],
+            let delay = AIError::of(&e)
+                .filter(|error| error.is_retryable())
+                .and_then(|error| self.retry_policy.delay(attempt, error.retry_after()));
+            let Some(delay) = delay else {
+                breaker.record_failure(&e);
+                return Err(e);
             };
-            contents.push_back(content);
-        }
-        let length = contents.len();
-        if length > 500 {
-            contents.drain(0..(length - 500));
+            warn!(
+                "AI request to {} failed, retrying in {delay:?}: {e}",
+                backend.name()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
         }
     }
 
-    pub fn clear(&self) {
-        self.conversation.contents.lock().unwrap().clear();
+    pub async fn generate(&self, channel_id: ChannelId) -> Result<String, anyhow::Error> {
+        let model = self.model_for(channel_id);
+        self.generate_with_model(channel_id, model).await
     }
-    pub fn debug(&self) -> String {
-        self.conversation.to_string()
+
+    pub async fn generate_with_model(
+        &self,
+        channel_id: ChannelId,
+        model: ChatModel,
+    ) -> Result<String, anyhow::Error> {
+        self.generate_with_tools(channel_id, model, &NoTools).await
     }
 
-    pub async fn generate(&self) -> Result<String, anyhow::Error> {
-        let model = self.model.lock().unwrap().clone();
-        self.generate_with_model(model).await
+    pub async fn generate_with_tools(
+        &self,
+        channel_id: ChannelId,
+        model: ChatModel,
+        tools: &dyn ToolBox,
+    ) -> Result<String, anyhow::Error> {
+        self.run(channel_id, model, tools, None).await
     }
 
-    pub async fn generate_with_model(&self, model: GeminiModel) -> Result<String, anyhow::Error> {
-        let (status, response) = generate(model, &self.api_key, &self.conversation).await?;
+    // 生成しながら、そこまでの文章をprogressに送る。生成し終わるとprogressは閉じる
+    pub async fn generate_streaming(
+        &self,
+        channel_id: ChannelId,
+        model: ChatModel,
+        tools: &dyn ToolBox,
+        progress: UnboundedSender<String>,
+    ) -> Result<String, anyhow::Error> {
+        self.run(channel_id, model, tools, Some(&progress)).await
+    }
 
-        if status.is_success() {
-            self.add_model_log(&response);
-            Ok(response)
-        } else {
-            Err(anyhow!(response))
+    // 道具を呼ばれたら結果を足して生成し直す。道具のやりとりはログに残さない
+    async fn run(
+        &self,
+        channel_id: ChannelId,
+        model: ChatModel,
+        tools: &dyn ToolBox,
+        progress: Option<&UnboundedSender<String>>,
+    ) -> Result<String, anyhow::Error> {
+        self.compact(channel_id, &model).await;
+        // 生成を待つ間もログを追加できるように、ログは写しを渡す
+        let mut contents = self.log(channel_id);
+        let backend = self.backends.for_channel(channel_id);
+        let system_instruction = self.system_instruction(channel_id);
+        let declarations = tools.declarations();
+        for step in 0..=MAX_TOOL_STEPS {
+            // 上限に達したら道具を渡さず、ここまでの結果で答えさせる
+            let available = if step < MAX_TOOL_STEPS {
+                declarations.as_slice()
+            } else {
+                &[]
+            };
+            let generate = || {
+                backend.generate_with_tools(&model.name, &system_instruction, &contents, available)
+            };
+            let reply = match progress {
+                Some(progress) => {
+                    let breaker = self.breaker(backend.as_ref());
+                    breaker.check()?;
+                    let streamed = backend
+                        .generate_stream(
+                            &model.name,
+                            &system_instruction,
+                            &contents,
+                            available,
+                            progress,
+                        )
+                        .await;
+                    match streamed {
+                        Ok(reply) => {
+                            breaker.record_success();
+                            reply
+                        }
+                        Err(e) if AIError::of(&e).is_some_and(AIError::is_final) => {
+                            breaker.record_failure(&e);
+                            return Err(e);
+                        }
+                        // ストリーミングに失敗したら、ふつうの生成でやり直す
+                        // breakerはもう通っているので、休み明けの試しでもそのままやり直し、その結果を記録する
+                        Err(e) => {
+                            warn!("Error streaming AI response, retrying without streaming: {e}");
+                            self.retry(backend.as_ref(), &breaker, generate).await?
+                        }
+                    }
+                }
+                None => self.call_backend(backend.as_ref(), generate).await?,
+            };
+            let calls = match reply {
+                Reply::Text(response) => {
+                    self.add_model_log(channel_id, &response);
+                    return Ok(response);
+                }
+                Reply::ToolCalls(calls) => calls,
+            };
+            let mut responses = vec![];
+            for call in &calls {
+                let result = tools.call(channel_id, call).await;
+                info!("Tool {}({}) -> {result}", call.name, call.args);
+                responses.push(call.respond(&result));
+            }
+            contents.push(GeminiContent::tool_calls(calls));
+            contents.push(GeminiContent::tool_responses(responses));
         }
+        Err(anyhow!("Too many tool calls"))
     }
 
     pub async fn generate_matome(
         &self,
+        channel_id: ChannelId,
         messages: Vec<GeminiContent>,
     ) -> Result<String, anyhow::Error> {
-        let conversation = GeminiConversation {
-            system_instruction: GeminiContent {
-                role: None,
-                parts: vec![Part {
-                    text: MATOME_PROMPT.to_owned(),
-                }],
-            },
-            contents: Mutex::new(messages.into()),
-        };
+        // 要約は既定のモデルで十分
+        let model = self.models.default_model();
+        let backend = self.backends.for_channel(channel_id);
+        self.call_backend(backend.as_ref(), || {
+            backend.summarize(&model.name, &messages)
+        })
+        .await
+    }
 
-        let model = GeminiModel::Gemini20FlashLite;
-        let (status, response) = generate(model, &self.api_key, &conversation).await?;
-        if status.is_success() {
-            Ok(response)
-        } else {
-            Err(anyhow!(response))
-        }
+    pub async fn list_models(&self, channel_id: ChannelId) -> Result<Vec<String>> {
+        self.backends.for_channel(channel_id).list_models().await
     }
 
-    pub fn set_model(&self, model: GeminiModel) {
+    pub const fn models(&self) -> &ModelCatalog {
+        &self.models
+    }
+
+    pub fn set_model(&self, model: ChatModel) {
         *self.model.lock().unwrap() = model;
     }
 
-    pub fn get_model(&self) -> GeminiModel {
+    pub fn get_model(&self) -> ChatModel {
         self.model.lock().unwrap().clone()
     }
 }
 
-async fn generate(
-    model: GeminiModel,
-    api_key: &String,
-    conversation: &GeminiConversation,
-) -> Result<(reqwest::StatusCode, String), anyhow::Error> {
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/{model}:generateContent?key={api_key}"
-    );
-    let prompt = conversation.to_string();
-    println!("Prompt: {prompt}");
-    let client = reqwest::Client::new();
-    let response = client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .body(prompt)
-        .send()
-        .await?;
-    let status = response.status();
-    let response = response.text().await?;
-    println!("Response: {response}");
-    let response = serde_json::from_str::<GeminiResponse>(&response)
-        .map_err(|e| anyhow!("Failed to parse response: {}\n {}", e, response))?;
-    let response = response
-        .candidates
-        .first()
-        .ok_or_else(|| anyhow!("No candidates found"))?
-        .content
-        .parts
-        .first()
-        .ok_or_else(|| anyhow!("No content found"))?
-        .text
-        .clone();
-
-    Ok((status, response))
-}
-
 #[cfg(test)]
-mod gemini_tests {
+mod ai_tests {
     use super::*;
+    use std::time::Duration;
 
     #[tokio::test]
-    async fn test_gemini_generate() {
-        let ai = GeminiAI::new("");
-        ai.add_user_log("宇田", "まなみ、おはよう！　今日は何をする予定？");
-        println!("{}", &ai.conversation);
-        let response = ai.generate().await;
-        match response {
-            Ok(res) => println!("Response: {res}"),
-            Err(err) => println!("Error: {err}"),
+    async fn test_generate_with_mock() {
+        let mock = Arc::new(MockBackend::scripted(["おはよう！", "ひまわり見に行こ！"]));
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default());
+        let channel = ChannelId::new(1);
+
+        ai.add_user_log(channel, "宇田", "まなみ、おはよう！");
+        assert_eq!(ai.generate(channel).await.unwrap(), "おはよう！");
+        ai.add_user_log(channel, "宇田", "今日は何をする予定？");
+        assert_eq!(ai.generate(channel).await.unwrap(), "ひまわり見に行こ！");
+
+        // 生成した返事も次の入力に含まれる
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].model, "gemini-2.0-flash-lite");
+        assert_eq!(
+            requests[0].system_instruction,
+            render_prompt(&manami_prompt(), &[])
+        );
+        assert_eq!(
+            requests[1].contents,
+            vec![
+                GeminiContent::user("宇田", "まなみ、おはよう！"),
+                GeminiContent::model("おはよう！"),
+                GeminiContent::user("宇田", "今日は何をする予定？"),
+            ]
+        );
+
+        // スクリプトを使い切ったら最後の発言を繰り返す
+        assert_eq!(
+            ai.generate(channel).await.unwrap(),
+            "mock: ひまわり見に行こ！"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conversation_per_channel() {
+        let mock = Arc::new(MockBackend::new());
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default());
+        let (room1, room2) = (ChannelId::new(1), ChannelId::new(2));
+
+        ai.restore(
+            room1,
+            vec![
+                GeminiContent::user("宇田", "ただいま"),
+                GeminiContent::model("おかえり！"),
+            ],
+        );
+        ai.add_user_log(room1, "宇田", "おなかすいた");
+        ai.add_user_log(room2, "うさみむ", "こんにちは");
+
+        assert_eq!(
+            ai.generate(room2).await.unwrap(),
+            "mock: うさみむ: こんにちは"
+        );
+        assert_eq!(mock.requests()[0].contents.len(), 1);
+        ai.generate(room1).await.unwrap();
+        assert_eq!(mock.requests()[1].contents.len(), 3);
+
+        ai.clear(room1);
+        assert!(ai.log(room1).is_empty());
+        assert_eq!(ai.log(room2).len(), 2);
+
+        // 復元するときも上限を超えた古いログは捨てる
+        ai.restore(
+            room1,
+            (0..MAX_LOG_LEN + 10)
+                .map(|i| GeminiContent::user("宇田", &i.to_string()))
+                .collect(),
+        );
+        let log = ai.log(room1);
+        assert_eq!(log.len(), MAX_LOG_LEN);
+        assert_eq!(log[0], GeminiContent::user("宇田", "10"));
+    }
+
+    #[tokio::test]
+    async fn test_rolling_summary() {
+        let mock = Arc::new(MockBackend::scripted(["要約1", "返事1", "要約2", "返事2"]));
+        let models = ModelCatalog::parse("small@149").unwrap();
+        let ai = ChatAI::new(ChatBackends::new(mock.clone()), models);
+        let channel = ChannelId::new(1);
+
+        // 1つ15トークンを10個で予算を超える
+        for i in 0..10 {
+            ai.add_user_log(channel, "a", &format!("{i}あいうえおかきくけこ"));
         }
+        assert_eq!(ai.generate(channel).await.unwrap(), "返事1");
+        let requests = mock.requests();
+        // 古い6つを要約して、新しい4つはそのまま送る
+        assert_eq!(requests[0].system_instruction, MATOME_PROMPT);
+        assert_eq!(requests[0].contents.len(), 6);
+        assert_eq!(requests[1].contents.len(), 4);
+        assert!(requests[1].system_instruction.ends_with("要約\n要約1\n"));
+        assert_eq!(ai.memory(channel).as_deref(), Some("要約1"));
+
+        // 次の要約には前の要約も含める
+        for i in 0..6 {
+            ai.add_user_log(channel, "a", &format!("{i}あいうえおかきくけこ"));
+        }
+        ai.generate(channel).await.unwrap();
+        let requests = mock.requests();
+        assert_eq!(
+            requests[2].contents[0],
+            GeminiContent::user("これまでの会話の要約", "要約1")
+        );
+        assert_eq!(ai.memory(channel).as_deref(), Some("要約2"));
+
+        ai.clear(channel);
+        assert_eq!(ai.memory(channel), None);
+    }
+
+    #[tokio::test]
+    async fn test_persona() {
+        let mock = Arc::new(MockBackend::new());
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default());
+        let (room1, room2) = (ChannelId::new(1), ChannelId::new(2));
+        ai.set_persona(
+            room1,
+            Persona {
+                prompt: Some("あなたは猫です".to_owned()),
+                model: Some("gemini-2.0-flash".to_owned()),
+                reply_rate: 1.0,
+                reply_to_all: None,
+            },
+        );
+        // モデル一覧から消えたモデルはいつものモデルにする
+        ai.set_persona(
+            room2,
+            Persona {
+                model: Some("gemini-1.0-pro".to_owned()),
+                ..Persona::default()
+            },
+        );
+
+        ai.generate(room1).await.unwrap();
+        ai.generate(room2).await.unwrap();
+        let requests = mock.requests();
+        assert_eq!(requests[0].model, "gemini-2.0-flash");
+        assert_eq!(requests[0].system_instruction, "あなたは猫です");
+        assert_eq!(requests[1].model, "gemini-2.0-flash-lite");
+        assert_eq!(
+            requests[1].system_instruction,
+            render_prompt(&manami_prompt(), &[])
+        );
+
+        let mut channels = ai.persona_channels();
+        channels.sort();
+        assert_eq!(channels, [room1, room2]);
+        ai.remove_persona(room1);
+        assert_eq!(ai.persona(room1), None);
+        assert_eq!(ai.model_for(room1).name, "gemini-2.0-flash-lite");
+    }
+
+    #[tokio::test]
+    async fn test_relationships() {
+        let mock = Arc::new(MockBackend::new());
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default());
+        let channel = ChannelId::new(1);
+        ai.set_persona(
+            channel,
+            Persona {
+                prompt: Some(format!("友達:\n{RELATIONSHIPS_PLACEHOLDER}")),
+                ..Persona::default()
+            },
+        );
+        ai.set_relationships(vec![Relationship {
+            user_id: serenity::model::id::UserId::new(1),
+            user_name: "宇田".to_owned(),
+            nickname: None,
+            relation: Some("兄".to_owned()),
+            call_name: Some("お兄ちゃん".to_owned()),
+        }]);
+
+        // 生成するときの一覧で埋める
+        ai.generate(channel).await.unwrap();
+        // テーブルにいない既定の関係は後ろに足される
+        let instruction = &mock.requests()[0].system_instruction;
+        assert!(instruction
+            .starts_with("友達:\n- ユーザー名: 宇田\n    - 関係: 兄\n    - 呼び方: お兄ちゃん\n"));
+        assert_eq!(instruction.matches("宇田").count(), 1);
+        assert!(instruction.ends_with("    - 呼び方: メタぺんさん"));
+    }
+
+    struct TestTools;
+
+    #[async_trait]
+    impl ToolBox for TestTools {
+        fn declarations(&self) -> Vec<ToolDeclaration> {
+            vec![ToolDeclaration {
+                name: "is_prime".to_owned(),
+                description: "素数判定".to_owned(),
+                parameters: serde_json::json!({ "type": "object" }),
+            }]
+        }
+
+        async fn call(&self, _: ChannelId, call: &ToolCall) -> String {
+            format!("{}は素数じゃないよ", call.args["n"])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls() {
+        let call = ToolCall {
+            id: None,
+            name: "is_prime".to_owned(),
+            args: serde_json::json!({ "n": 57 }),
+        };
+        let mock = Arc::new(MockBackend::scripted_replies([
+            Reply::ToolCalls(vec![call.clone()]),
+            Reply::Text("57は3で割れるよ！".to_owned()),
+        ]));
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default());
+        let channel = ChannelId::new(1);
+        let model = ai.model_for(channel);
+
+        ai.add_user_log(channel, "宇田", "57って素数？");
+        let reply = ai.generate_with_tools(channel, model.clone(), &TestTools);
+        assert_eq!(reply.await.unwrap(), "57は3で割れるよ！");
+
+        // 道具の結果を足して生成し直す
+        let requests = mock.requests();
+        assert_eq!(requests[0].tools, ["is_prime"]);
+        assert_eq!(
+            requests[1].contents[1..],
+            [
+                GeminiContent::tool_calls(vec![call.clone()]),
+                GeminiContent::tool_responses(vec![call.respond("57は素数じゃないよ")]),
+            ]
+        );
+        // ログには道具のやりとりを残さない
+        assert_eq!(
+            ai.log(channel),
+            [
+                GeminiContent::user("宇田", "57って素数？"),
+                GeminiContent::model("57は3で割れるよ！"),
+            ]
+        );
+
+        // 上限まで呼び続けたら、道具を渡さずに生成する
+        let mock = Arc::new(MockBackend::scripted_replies(
+            (0..=MAX_TOOL_STEPS).map(|_| Reply::ToolCalls(vec![call.clone()])),
+        ));
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default());
+        assert!(ai
+            .generate_with_tools(channel, model, &TestTools)
+            .await
+            .is_err());
+        let requests = mock.requests();
+        assert_eq!(requests.len(), MAX_TOOL_STEPS + 1);
+        assert!(requests[MAX_TOOL_STEPS].tools.is_empty());
+        assert!(ai.log(channel).is_empty());
+    }
+
+    // 途中まで送ってからストリーミングに失敗するバックエンド
+    struct BrokenStream(MockBackend, fn() -> anyhow::Error);
+
+    #[async_trait]
+    impl ChatBackend for BrokenStream {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        async fn generate(
+            &self,
+            model: &str,
+            system_instruction: &str,
+            contents: &[GeminiContent],
+        ) -> Result<String> {
+            self.0.generate(model, system_instruction, contents).await
+        }
+
+        async fn generate_stream(
+            &self,
+            _: &str,
+            _: &str,
+            _: &[GeminiContent],
+            _: &[ToolDeclaration],
+            progress: &UnboundedSender<String>,
+        ) -> Result<Reply> {
+            progress.send("おは".to_owned()).unwrap();
+            Err(self.1())
+        }
+
+        async fn list_models(&self) -> Result<Vec<String>> {
+            self.0.list_models().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streaming() {
+        let mock = Arc::new(MockBackend::scripted(["おはよう！"]));
+        let ai = ChatAI::manami(ChatBackends::new(mock), ModelCatalog::default());
+        let channel = ChannelId::new(1);
+        let model = ai.model_for(channel);
+
+        // ストリーミングに対応していなくても、生成し終わったら送る
+        let (progress, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let reply = ai.generate_streaming(channel, model.clone(), &NoTools, progress);
+        assert_eq!(reply.await.unwrap(), "おはよう！");
+        assert_eq!(received.recv().await.as_deref(), Some("おはよう！"));
+        // 生成し終わったら閉じる
+        assert_eq!(received.recv().await, None);
+
+        // 失敗したらふつうの生成でやり直す
+        let broken = BrokenStream(MockBackend::scripted(["おはよう！"]), || {
+            anyhow!("connection reset")
+        });
+        let ai = ChatAI::manami(ChatBackends::new(Arc::new(broken)), ModelCatalog::default());
+        let (progress, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let reply = ai.generate_streaming(channel, model, &NoTools, progress);
+        assert_eq!(reply.await.unwrap(), "おはよう！");
+        assert_eq!(received.recv().await.as_deref(), Some("おは"));
+        assert_eq!(ai.log(channel), [GeminiContent::model("おはよう！")]);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_probe() {
+        let policy = RetryPolicy {
+            failure_threshold: 1,
+            cooldown: Duration::from_millis(50),
+            ..RetryPolicy::default()
+        };
+        let broken = BrokenStream(MockBackend::scripted(["おはよう！"]), || {
+            AIError::Network("connection reset".to_owned()).into()
+        });
+        let ai = ChatAI::manami(ChatBackends::new(Arc::new(broken)), ModelCatalog::default())
+            .with_retry_policy(policy);
+        let channel = ChannelId::new(1);
+        let model = ai.model_for(channel);
+        let backend = ai.backends.for_channel(channel);
+        ai.breaker(backend.as_ref())
+            .record_failure(&AIError::Network("timeout".to_owned()).into());
+        tokio::time::sleep(policy.cooldown).await;
+
+        // 休み明けの試しでストリーミングが途切れても、ふつうの生成でやり直した結果を返す
+        let (progress, _received) = tokio::sync::mpsc::unbounded_channel();
+        let reply = ai.generate_streaming(channel, model, &NoTools, progress);
+        assert_eq!(reply.await.unwrap(), "おはよう！");
+        // やり直しが成功したので、次の呼び出しも通る
+        assert!(ai.breaker(backend.as_ref()).check().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::ZERO,
+            failure_threshold: 2,
+            ..RetryPolicy::default()
+        };
+        let overloaded = || AIError::from_status(503, None, "overloaded");
+        let channel = ChannelId::new(1);
+
+        // 一時的な失敗ならやり直す
+        let mock = Arc::new(MockBackend::scripted_results([
+            Err(overloaded()),
+            Err(AIError::Network("timeout".to_owned())),
+            Ok(Reply::Text("おはよう！".to_owned())),
+        ]));
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default())
+            .with_retry_policy(policy);
+        assert_eq!(ai.generate(channel).await.unwrap(), "おはよう！");
+        assert_eq!(mock.requests().len(), 3);
+
+        // 上限を使い切ったらやり直さない
+        let mock = Arc::new(MockBackend::scripted_results([
+            Err(AIError::from_status(429, None, "PerDay")),
+            Ok(Reply::Text("おはよう！".to_owned())),
+        ]));
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default())
+            .with_retry_policy(policy);
+        let e = ai.generate(channel).await.unwrap_err();
+        assert!(matches!(AIError::of(&e), Some(AIError::Quota(_))));
+        assert_eq!(mock.requests().len(), 1);
+
+        // やり直しても失敗が続いたら、しばらく呼ばない
+        let mock = Arc::new(MockBackend::scripted_results(
+            (0..6).map(|_| Err(overloaded())),
+        ));
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default())
+            .with_retry_policy(policy);
+        assert!(ai.generate(channel).await.is_err());
+        assert!(ai.generate(channel).await.is_err());
+        assert_eq!(mock.requests().len(), 6);
+        let e = ai.generate(channel).await.unwrap_err();
+        assert!(matches!(AIError::of(&e), Some(AIError::Unavailable)));
+        assert_eq!(mock.requests().len(), 6);
+        assert_eq!(user_message(&e), AIError::Unavailable.user_message());
+    }
+
+    #[tokio::test]
+    async fn test_backends_from_config() {
+        let config = BackendConfig::default();
+        assert!(config.build("gemini").is_err());
+        assert!(config.build("unknown").is_err());
+        assert!(config.backends("mock", "123:gemini").is_err());
+        assert!(config.backends("mock", "123").is_err());
+
+        let config = BackendConfig {
+            openai_base_url: Some("http://localhost:8080/v1".to_owned()),
+            ..BackendConfig::default()
+        };
+        let backends = config.backends("mock", "123:openai, 456:mock").unwrap();
+        assert_eq!(backends.for_channel(ChannelId::new(123)).name(), "openai");
+        assert_eq!(backends.for_channel(ChannelId::new(456)).name(), "mock");
+        assert_eq!(backends.for_channel(ChannelId::new(789)).name(), "mock");
+
+        // 要約は要約用の指示で生成する
+        let mock = Arc::new(MockBackend::scripted(["## 目次"]));
+        let ai = ChatAI::manami(ChatBackends::new(mock.clone()), ModelCatalog::default());
+        let summary = ai
+            .generate_matome(
+                ChannelId::new(1),
+                vec![GeminiContent::user("宇田", "おはよう")],
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary, "## 目次");
+        assert_eq!(mock.requests()[0].system_instruction, MATOME_PROMPT);
+    }
+
+    #[test]
+    fn test_model_catalog() {
+        let catalog = ModelCatalog::default();
+        assert_eq!(catalog.models().len(), 4);
+        assert_eq!(catalog.default_model().name, "gemini-2.0-flash-lite");
+        assert_eq!(
+            catalog.find("gemini-2.0-flash").unwrap().label,
+            "Gemini 2.0 Flash"
+        );
+        // 知らないモデルは既定のモデルにせずエラーにする
+        assert!(catalog.find("gemini-2.5-pro-exp-03-25").is_err());
+
+        let catalog = ModelCatalog::parse("llama3=Llama 3, qwen2.5").unwrap();
+        assert_eq!(
+            catalog.models(),
+            [
+                ChatModel {
+                    name: "llama3".to_owned(),
+                    label: "Llama 3".to_owned(),
+                    token_budget: context::DEFAULT_TOKEN_BUDGET,
+                },
+                ChatModel {
+                    name: "qwen2.5".to_owned(),
+                    label: "qwen2.5".to_owned(),
+                    token_budget: context::DEFAULT_TOKEN_BUDGET,
+                },
+            ]
+        );
+        // モデル名に@が入っていても予算と区別できる
+        let catalog = ModelCatalog::parse("llama3:8b=Llama 3 8B@8000, a@b@16000").unwrap();
+        assert_eq!(catalog.models()[0].name, "llama3:8b");
+        assert_eq!(catalog.models()[0].token_budget, 8000);
+        assert_eq!(catalog.models()[1].name, "a@b");
+        assert!(ModelCatalog::parse("llama3@0").is_err());
+        assert!(ModelCatalog::parse("llama3@many").is_err());
+        assert!(ModelCatalog::parse("").is_err());
+        assert!(ModelCatalog::parse("a, a").is_err());
+        assert!(ModelCatalog::parse("=label").is_err());
+        let many = (0..26).map(|i| format!("m{i}")).collect::<Vec<_>>();
+        assert!(ModelCatalog::parse(&many.join(",")).is_err());
     }
 }
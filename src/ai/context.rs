@@ -0,0 +1,151 @@
+/*
+-----------------------------
+会話ログの長さの管理
+トークン数を見積もり、予算を超えたら古いログを要約に回す
+-----------------------------
+*/
+
+use super::GeminiContent;
+
+// AI_MODELSで指定しなかったときのトークンの予算
+pub const DEFAULT_TOKEN_BUDGET: usize = 32_000;
+
+// 1メッセージごとにかかるroleなどの分
+const TOKENS_PER_MESSAGE: usize = 4;
+
+// 予算を超えたら、ログをこの割合まで減らす
+const KEEP_RATIO: usize = 2;
+
+// トークナイザーは持っていないので、英数字は4文字で1トークン、それ以外（日本語など）は1文字1トークンと見積もる
+pub fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.chars().filter(char::is_ascii).count();
+    let others = text.chars().count() - ascii;
+    ascii.div_ceil(4) + others
+}
+
+impl GeminiContent {
+    pub fn estimate_tokens(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|part| estimate_tokens(&part.text))
+            .sum::<usize>()
+            + TOKENS_PER_MESSAGE
+    }
+}
+
+// ログの先頭から、要約せずに捨てる数と要約に回す数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    pub drop: usize,
+    pub summarize: usize,
+}
+
+impl Compaction {
+    pub const fn total(self) -> usize {
+        self.drop + self.summarize
+    }
+}
+
+// fixed_tokensはsystem_instructionや要約など、ログ以外にいつも送る分
+// 予算に収まっていればNone。超えていれば新しいログを予算の半分まで残し、
+// それより古いログのうち新しい側を予算に収まる分だけ要約し、残りは捨てる
+pub fn plan_compaction(
+    log: &[GeminiContent],
+    fixed_tokens: usize,
+    budget: usize,
+) -> Option<Compaction> {
+    let tokens = log
+        .iter()
+        .map(GeminiContent::estimate_tokens)
+        .collect::<Vec<_>>();
+    if fixed_tokens + tokens.iter().sum::<usize>() <= budget {
+        return None;
+    }
+
+    // 直前の発言には返事をしたいので、最低1つは残す
+    let target = (budget / KEEP_RATIO).saturating_sub(fixed_tokens);
+    let mut kept = 0;
+    let mut kept_tokens = 0;
+    for &t in tokens.iter().rev() {
+        if kept > 0 && kept_tokens + t > target {
+            break;
+        }
+        kept += 1;
+        kept_tokens += t;
+    }
+    let removed = log.len() - kept;
+    if removed == 0 {
+        return None;
+    }
+
+    // 要約のリクエストも予算に収める
+    let mut summarize = 0;
+    let mut summarize_tokens = 0;
+    for &t in tokens[..removed].iter().rev() {
+        if summarize_tokens + t > budget {
+            break;
+        }
+        summarize += 1;
+        summarize_tokens += t;
+    }
+    Some(Compaction {
+        drop: removed - summarize,
+        summarize,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello"), 2);
+        assert_eq!(estimate_tokens("まなみ"), 3);
+        assert_eq!(estimate_tokens("まなみ hello"), 5);
+        // "宇田: おはよう" は 宇田おはよう の6文字と ": " で7
+        assert_eq!(
+            GeminiContent::user("宇田", "おはよう").estimate_tokens(),
+            11
+        );
+    }
+
+    #[test]
+    fn test_plan_compaction() {
+        // 1つ15トークン（"a: " で1 + 10文字 + 4）
+        let log = (0..10)
+            .map(|_| GeminiContent::user("a", "あいうえおかきくけこ"))
+            .collect::<Vec<_>>();
+        assert_eq!(log[0].estimate_tokens(), 15);
+
+        assert_eq!(plan_compaction(&log, 0, 150), None);
+        assert_eq!(plan_compaction(&log, 10, 160), None);
+
+        // 予算の半分の74トークンまで、新しい4つを残す
+        assert_eq!(
+            plan_compaction(&log, 0, 149),
+            Some(Compaction {
+                drop: 0,
+                summarize: 6
+            })
+        );
+        // 要約に回す分も予算を超えないように、古い方は捨てる
+        assert_eq!(
+            plan_compaction(&log, 0, 60),
+            Some(Compaction {
+                drop: 4,
+                summarize: 4
+            })
+        );
+        // 固定分だけで予算を超えても、最後の発言は残す
+        assert_eq!(
+            plan_compaction(&log, 1000, 500),
+            Some(Compaction {
+                drop: 0,
+                summarize: 9
+            })
+        );
+        assert_eq!(plan_compaction(&log[..1], 1000, 500), None);
+    }
+}
@@ -0,0 +1,230 @@
+/*
+-----------------------------
+AIの呼び出しの失敗の種類
+やり直すかどうかの判断と、ユーザーに見せる短いメッセージに使う
+詳しい内容はログにだけ出す
+-----------------------------
+*/
+
+use std::time::Duration;
+
+use serde_json::Value;
+use tracing::debug;
+
+#[derive(Debug)]
+pub enum AIError {
+    // 429。しばらく待てば通る
+    RateLimited {
+        retry_after: Option<Duration>,
+        detail: String,
+    },
+    // 1日の上限などを使い切った
+    Quota(String),
+    // 安全フィルターで止められた
+    Blocked(String),
+    // 5xx
+    Server {
+        status: u16,
+        detail: String,
+    },
+    // つながらない、途中で切れた
+    Network(String),
+    // 返ってきたJSONが読めない、中身がない
+    Malformed(String),
+    // その他の4xx。リクエストか設定がおかしい
+    Request {
+        status: u16,
+        detail: String,
+    },
+    // 失敗が続いたので、しばらく呼ばずに休んでいる
+    Unavailable,
+}
+
+impl std::fmt::Display for AIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RateLimited { detail, .. } => write!(f, "Rate limited: {detail}"),
+            Self::Quota(detail) => write!(f, "Quota exceeded: {detail}"),
+            Self::Blocked(reason) => write!(f, "Blocked: {reason}"),
+            Self::Server { status, detail } => write!(f, "Server error {status}: {detail}"),
+            Self::Network(detail) => write!(f, "Network error: {detail}"),
+            Self::Malformed(detail) => write!(f, "Malformed response: {detail}"),
+            Self::Request { status, detail } => write!(f, "Request error {status}: {detail}"),
+            Self::Unavailable => write!(f, "AI backend is temporarily unavailable"),
+        }
+    }
+}
+
+impl std::error::Error for AIError {}
+
+impl AIError {
+    // 成功しなかったHTTPのレスポンスから種類を決める
+    pub fn from_status(status: u16, retry_after: Option<Duration>, body: &str) -> Self {
+        let detail = body.to_owned();
+        match status {
+            // Geminiは1分あたりの制限も1日の上限も429で返すので、本文で見分ける
+            429 if is_quota(body) => Self::Quota(detail),
+            429 => Self::RateLimited {
+                retry_after: retry_after.or_else(|| retry_delay(body)),
+                detail,
+            },
+            500..=599 => Self::Server { status, detail },
+            _ => Self::Request { status, detail },
+        }
+    }
+
+    // URLにはAPIキーが入っているので外す
+    pub fn network(error: reqwest::Error) -> Self {
+        Self::Network(error.without_url().to_string())
+    }
+
+    pub fn of(error: &anyhow::Error) -> Option<&Self> {
+        error.downcast_ref::<Self>()
+    }
+
+    // 待ってやり直せば通るかもしれない
+    pub const fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. } | Self::Server { .. } | Self::Network(_)
+        )
+    }
+
+    // 呼び方を変えても通らない。ストリーミングをやめてやり直すこともしない
+    pub const fn is_final(&self) -> bool {
+        matches!(
+            self,
+            Self::Quota(_) | Self::Blocked(_) | Self::Request { .. } | Self::Unavailable
+        )
+    }
+
+    // バックエンドの調子が悪いことを表す。続いたら休む
+    pub const fn is_outage(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. } | Self::Quota(_) | Self::Server { .. } | Self::Network(_)
+        )
+    }
+
+    pub const fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    pub const fn user_message(&self) -> &'static str {
+        match self {
+            Self::RateLimited { .. } => {
+                "ちょっとおしゃべりしすぎちゃったみたい……少し待ってからまた話しかけてね"
+            }
+            Self::Quota(_) => "今日はもうおしゃべりする元気がないみたい……また明日ね",
+            Self::Blocked(_) => "うーん、それにはお返事できないかな……",
+            Self::Server { .. } | Self::Network(_) => {
+                "なんだか頭がぼーっとする……もう一回話しかけてみて"
+            }
+            Self::Malformed(_) => "あれ、言葉がうまく出てこなかった……もう一回言って？",
+            Self::Request { .. } => "うまく考えられなかったみたい……ごめんね",
+            Self::Unavailable => "ちょっと休憩中……しばらくしたらまた話しかけてね",
+        }
+    }
+}
+
+// ユーザーに見せるメッセージ。知らない失敗でも中身は見せない
+pub fn user_message(error: &anyhow::Error) -> &'static str {
+    AIError::of(error).map_or(
+        "うまく考えられなかったみたい……ごめんね",
+        AIError::user_message,
+    )
+}
+
+// レスポンスの本文を読む。成功していなければ種類を決めて失敗にする
+pub async fn read_body(response: reqwest::Response) -> Result<String, AIError> {
+    let status = response.status();
+    let retry_after = retry_after_header(response.headers());
+    let body = response.text().await.map_err(AIError::network)?;
+    debug!("Response ({status}): {body}");
+    if status.is_success() {
+        Ok(body)
+    } else {
+        Err(AIError::from_status(status.as_u16(), retry_after, &body))
+    }
+}
+
+// Retry-Afterヘッダーの秒数
+fn retry_after_header(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+fn is_quota(body: &str) -> bool {
+    // Geminiは "GenerateRequestsPerDayPerProjectPerModel"、OpenAIは "insufficient_quota"
+    body.contains("PerDay") || body.contains("insufficient_quota")
+}
+
+// Geminiは本文の error.details の中に "retryDelay": "28s" を入れてくる
+fn retry_delay(body: &str) -> Option<Duration> {
+    fn find(value: &Value) -> Option<&str> {
+        match value {
+            Value::Object(map) => map
+                .get("retryDelay")
+                .and_then(Value::as_str)
+                .or_else(|| map.values().find_map(find)),
+            Value::Array(values) => values.iter().find_map(find),
+            _ => None,
+        }
+    }
+    let value = serde_json::from_str::<Value>(body).ok()?;
+    find(&value)?
+        .strip_suffix('s')?
+        .parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_status() {
+        let body = r#"{"error": {"code": 429, "status": "RESOURCE_EXHAUSTED", "details": [
+            {"@type": "type.googleapis.com/google.rpc.QuotaFailure",
+             "violations": [{"quotaId": "GenerateRequestsPerMinutePerProjectPerModel-FreeTier"}]},
+            {"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "28s"}
+        ]}}"#;
+        let error = AIError::from_status(429, None, body);
+        assert!(error.is_retryable());
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(28)));
+        // ヘッダーがあればそちらを使う
+        let error = AIError::from_status(429, Some(Duration::from_secs(3)), body);
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(3)));
+
+        let body = body.replace("PerMinute", "PerDay");
+        let error = AIError::from_status(429, None, &body);
+        assert!(matches!(error, AIError::Quota(_)));
+        assert!(!error.is_retryable());
+        assert!(error.is_final());
+
+        assert!(AIError::from_status(503, None, "overloaded").is_retryable());
+        let error = AIError::from_status(400, None, "bad request");
+        assert!(error.is_final());
+        assert!(!error.is_outage());
+
+        // 中身はユーザーに見せない
+        let error = anyhow::Error::from(AIError::from_status(500, None, "{\"secret\": 1}"));
+        assert!(!user_message(&error).contains("secret"));
+        assert_eq!(
+            user_message(&anyhow::anyhow!("unknown")),
+            "うまく考えられなかったみたい……ごめんね"
+        );
+    }
+}
@@ -0,0 +1,487 @@
+/*
+-----------------------------
+Geminiのバックエンド
+generativelanguage.googleapis.com の generateContent を呼ぶ
+ストリーミングでは streamGenerateContent をSSEで受け取る
+-----------------------------
+*/
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::debug;
+
+use super::{read_body, AIError, ChatBackend, GeminiContent, Part, Reply, ToolDeclaration};
+
+const BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+// 安全フィルターなどで止められたときのfinishReason
+const BLOCKED_REASONS: &[&str] = &[
+    "SAFETY",
+    "RECITATION",
+    "BLOCKLIST",
+    "PROHIBITED_CONTENT",
+    "SPII",
+    "IMAGE_SAFETY",
+];
+
+pub struct GeminiBackend {
+    api_key: String,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize, Debug)]
+struct GeminiRequest<'a> {
+    system_instruction: GeminiContent,
+    contents: &'a [GeminiContent],
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<GeminiTools<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiTools<'a> {
+    function_declarations: &'a [ToolDeclaration],
+}
+
+#[derive(Deserialize)]
+struct GeminiResponse {
+    // ストリーミングの最後の断片には候補がないことがある
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    // 入力が安全フィルターで止められたときに入る
+    #[serde(default, rename = "promptFeedback")]
+    prompt_feedback: Option<PromptFeedback>,
+    // その他のフィールドは不要なため省略
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    block_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    // 止められたときは中身がない
+    #[serde(default)]
+    content: GeminiContent,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    #[serde(default)]
+    models: Vec<ModelInfo>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelInfo {
+    name: String,
+    #[serde(default)]
+    supported_generation_methods: Vec<String>,
+}
+
+impl GeminiBackend {
+    pub fn new(api_key: &str) -> Self {
+        Self {
+            api_key: api_key.to_owned(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn request_body(
+        system_instruction: &str,
+        contents: &[GeminiContent],
+        tools: &[ToolDeclaration],
+    ) -> Result<String> {
+        Ok(serde_json::to_string(&GeminiRequest {
+            system_instruction: GeminiContent::system(system_instruction),
+            contents,
+            tools: tools_of(tools),
+        })?)
+    }
+}
+
+// SSEの "data: ..." 行を取り出す
+// 断片の区切りは行の途中や文字の途中にも来るので、改行までを溜めておく
+#[derive(Default)]
+struct SseParser {
+    buffer: Vec<u8>,
+}
+
+impl SseParser {
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut data = vec![];
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line);
+            // Geminiは1つのイベントを1行のJSONで送ってくる
+            if let Some(payload) = line.trim_end().strip_prefix("data:") {
+                data.push(payload.trim_start().to_owned());
+            }
+        }
+        data
+    }
+}
+
+// 断片で届いた文章はつなげて1つのpartにする
+fn append_parts(parts: &mut Vec<Part>, new_parts: Vec<Part>) {
+    for part in new_parts {
+        match parts.last_mut() {
+            Some(last)
+                if last.function_call.is_none()
+                    && last.function_response.is_none()
+                    && part.function_call.is_none()
+                    && part.function_response.is_none() =>
+            {
+                last.text.push_str(&part.text);
+            }
+            _ => parts.push(part),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatBackend for GeminiBackend {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    async fn generate(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+    ) -> Result<String> {
+        match self
+            .generate_with_tools(model, system_instruction, contents, &[])
+            .await?
+        {
+            Reply::Text(text) => Ok(text),
+            Reply::ToolCalls(_) => Err(anyhow!("Unexpected function call")),
+        }
+    }
+
+    async fn generate_with_tools(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+        tools: &[ToolDeclaration],
+    ) -> Result<Reply> {
+        let url = format!(
+            "{BASE_URL}/models/{model}:generateContent?key={}",
+            self.api_key
+        );
+        let prompt = Self::request_body(system_instruction, contents, tools)?;
+        debug!("Prompt: {prompt}");
+        let response = self
+            .client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(prompt)
+            .send()
+            .await
+            .map_err(AIError::network)?;
+        // 失敗したときの本文はGeminiの形とは限らないので、先に状態を確かめる
+        let body = read_body(response).await?;
+        let mut parsed = parse_response(&body)?;
+        check_blocked(&parsed)?;
+        if parsed.candidates.is_empty() {
+            return Err(AIError::Malformed(format!("No candidates found\n{body}")).into());
+        }
+        let candidate = parsed.candidates.swap_remove(0);
+        reply_of(&candidate.content)
+    }
+
+    async fn generate_stream(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+        tools: &[ToolDeclaration],
+        progress: &UnboundedSender<String>,
+    ) -> Result<Reply> {
+        let url = format!(
+            "{BASE_URL}/models/{model}:streamGenerateContent?alt=sse&key={}",
+            self.api_key
+        );
+        let prompt = Self::request_body(system_instruction, contents, tools)?;
+        debug!("Prompt: {prompt}");
+        let mut response = self
+            .client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(prompt)
+            .send()
+            .await
+            .map_err(AIError::network)?;
+        if !response.status().is_success() {
+            // 成功していないので、本文を読めば失敗の種類が決まる
+            let body = read_body(response).await?;
+            return Err(AIError::Malformed(body).into());
+        }
+
+        let mut sse = SseParser::default();
+        let mut parts = vec![];
+        let mut finish_reason = None;
+        while let Some(bytes) = response.chunk().await.map_err(AIError::network)? {
+            for data in sse.push(&bytes) {
+                debug!("Response: {data}");
+                let parsed = parse_response(&data)?;
+                check_blocked(&parsed)?;
+                if let Some(candidate) = parsed.candidates.into_iter().next() {
+                    finish_reason = candidate.finish_reason.or(finish_reason);
+                    append_parts(&mut parts, candidate.content.parts);
+                }
+                let text = GeminiContent {
+                    role: None,
+                    parts: parts.clone(),
+                }
+                .text();
+                if !text.is_empty() {
+                    let _ = progress.send(text);
+                }
+            }
+        }
+        if parts.is_empty() {
+            if let Some(reason) = finish_reason.filter(|r| BLOCKED_REASONS.contains(&r.as_str())) {
+                return Err(AIError::Blocked(reason).into());
+            }
+        }
+        reply_of(&GeminiContent {
+            role: Some("model".to_owned()),
+            parts,
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let url = format!("{BASE_URL}/models?key={}", self.api_key);
+        let response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(AIError::network)?;
+        let body = read_body(response).await?;
+        let list = serde_json::from_str::<ModelList>(&body)
+            .map_err(|e| AIError::Malformed(format!("{e}\n{body}")))?;
+        // 名前は "models/gemini-2.0-flash" の形で返ってくる
+        Ok(list
+            .models
+            .into_iter()
+            .filter(|m| {
+                m.supported_generation_methods
+                    .iter()
+                    .any(|method| method == "generateContent")
+            })
+            .map(|m| {
+                m.name
+                    .strip_prefix("models/")
+                    .map_or_else(|| m.name.clone(), str::to_owned)
+            })
+            .collect())
+    }
+}
+
+fn tools_of(tools: &[ToolDeclaration]) -> Vec<GeminiTools<'_>> {
+    if tools.is_empty() {
+        vec![]
+    } else {
+        vec![GeminiTools {
+            function_declarations: tools,
+        }]
+    }
+}
+
+fn parse_response(body: &str) -> Result<GeminiResponse, AIError> {
+    serde_json::from_str::<GeminiResponse>(body)
+        .map_err(|e| AIError::Malformed(format!("{e}\n{body}")))
+}
+
+// 入力か出力が安全フィルターで止められていたら失敗にする
+fn check_blocked(response: &GeminiResponse) -> Result<(), AIError> {
+    if let Some(reason) = response
+        .prompt_feedback
+        .as_ref()
+        .and_then(|feedback| feedback.block_reason.clone())
+    {
+        return Err(AIError::Blocked(reason));
+    }
+    match response.candidates.first() {
+        Some(Candidate {
+            content,
+            finish_reason: Some(reason),
+        }) if content.parts.is_empty() && BLOCKED_REASONS.contains(&reason.as_str()) => {
+            Err(AIError::Blocked(reason.clone()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// 道具の呼び出しがあれば、一緒に返ってきた文章より優先する
+fn reply_of(content: &GeminiContent) -> Result<Reply> {
+    let calls = content.function_calls();
+    if !calls.is_empty() {
+        return Ok(Reply::ToolCalls(calls.into_iter().cloned().collect()));
+    }
+    if content.parts.is_empty() {
+        return Err(AIError::Malformed("No content found".to_owned()).into());
+    }
+    Ok(Reply::Text(content.text()))
+}
+
+#[cfg(test)]
+mod gemini_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_gemini_generate() {
+        let backend = GeminiBackend::new("");
+        let contents = [GeminiContent::user(
+            "宇田",
+            "まなみ、おはよう！　今日は何をする予定？",
+        )];
+        let response = backend
+            .generate("gemini-2.0-flash-lite", "", &contents)
+            .await;
+        match response {
+            Ok(res) => println!("Response: {res}"),
+            Err(err) => println!("Error: {err}"),
+        }
+    }
+
+    #[test]
+    fn test_request_body() {
+        let contents = [GeminiContent::user("宇田", "おはよう")];
+        let body = serde_json::to_value(GeminiRequest {
+            system_instruction: GeminiContent::system("指示"),
+            contents: &contents,
+            tools: vec![],
+        })
+        .unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "system_instruction": { "role": null, "parts": [{ "text": "指示" }] },
+                "contents": [{ "role": "user", "parts": [{ "text": "宇田: おはよう" }] }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_function_calling() {
+        let declarations = [ToolDeclaration {
+            name: "is_prime".to_owned(),
+            description: "素数判定".to_owned(),
+            parameters: serde_json::json!({ "type": "object" }),
+        }];
+        let body = serde_json::to_value(GeminiRequest {
+            system_instruction: GeminiContent::system(""),
+            contents: &[],
+            tools: tools_of(&declarations),
+        })
+        .unwrap();
+        assert_eq!(
+            body["tools"],
+            serde_json::json!([{ "functionDeclarations": [{
+                "name": "is_prime",
+                "description": "素数判定",
+                "parameters": { "type": "object" },
+            }] }])
+        );
+
+        let response = serde_json::from_value::<GeminiResponse>(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [
+                { "functionCall": { "name": "is_prime", "args": { "n": 57 } } },
+            ] } }],
+        }))
+        .unwrap();
+        let content = &response.candidates[0].content;
+        let Reply::ToolCalls(calls) = reply_of(content).unwrap() else {
+            panic!("expected function call");
+        };
+        assert_eq!(calls[0].name, "is_prime");
+        assert_eq!(calls[0].u64_arg("n"), Some(57));
+
+        // 結果はfunctionResponseとして返す
+        let contents = [
+            GeminiContent::tool_calls(calls.clone()),
+            GeminiContent::tool_responses(vec![calls[0].respond("素数じゃないよ")]),
+        ];
+        assert_eq!(
+            serde_json::to_value(contents).unwrap(),
+            serde_json::json!([
+                { "role": "model", "parts": [
+                    { "functionCall": { "name": "is_prime", "args": { "n": 57 } } },
+                ] },
+                { "role": "user", "parts": [
+                    { "functionResponse": {
+                        "name": "is_prime",
+                        "response": { "result": "素数じゃないよ" },
+                    } },
+                ] },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut sse = SseParser::default();
+        let first = "data: {\"candidates\": [{\"content\": {\"role\": \"model\", \"parts\": [{\"text\": \"おは\"}]}}]}\r\n\r\n";
+        let second = "data: {\"candidates\": [{\"content\": {\"role\": \"model\", \"parts\": [{\"text\": \"よう！\"}]}}]}\r\n\r\ndata: {\"usageMetadata\": {}}\n\n";
+
+        // 文字の途中で切れても、行がそろうまで待つ
+        let bytes = first.as_bytes();
+        assert!(sse.push(&bytes[..40]).is_empty());
+        let data = sse.push(&bytes[40..]);
+        assert_eq!(data.len(), 1);
+        let data = [data, sse.push(second.as_bytes())].concat();
+        assert_eq!(data.len(), 3);
+
+        let mut parts = vec![];
+        for data in data {
+            let response = serde_json::from_str::<GeminiResponse>(&data).unwrap();
+            if let Some(candidate) = response.candidates.into_iter().next() {
+                append_parts(&mut parts, candidate.content.parts);
+            }
+        }
+        let content = GeminiContent {
+            role: Some("model".to_owned()),
+            parts,
+        };
+        assert_eq!(
+            reply_of(&content).unwrap(),
+            Reply::Text("おはよう！".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_blocked() {
+        let prompt = parse_response(r#"{"promptFeedback": {"blockReason": "SAFETY"}}"#).unwrap();
+        assert!(matches!(check_blocked(&prompt), Err(AIError::Blocked(_))));
+
+        let candidate =
+            parse_response(r#"{"candidates": [{"finishReason": "PROHIBITED_CONTENT"}]}"#).unwrap();
+        assert!(matches!(
+            check_blocked(&candidate),
+            Err(AIError::Blocked(_))
+        ));
+
+        // 最後まで書けていれば止められていない
+        let stop = parse_response(
+            r#"{"candidates": [{"content": {"parts": [{"text": "おはよう"}]}, "finishReason": "STOP"}]}"#,
+        )
+        .unwrap();
+        assert!(check_blocked(&stop).is_ok());
+        assert!(matches!(
+            parse_response("<html>"),
+            Err(AIError::Malformed(_))
+        ));
+    }
+}
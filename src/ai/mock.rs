@@ -0,0 +1,113 @@
+/*
+-----------------------------
+テスト用のバックエンド
+決められた返事を順に返し、受け取った入力を記録する
+返事を使い切ったら最後の発言をそのまま繰り返す
+道具の呼び出しも返せる
+失敗を混ぜて、やり直しの確認にも使う
+-----------------------------
+*/
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use serenity::async_trait;
+
+use super::{AIError, ChatBackend, GeminiContent, Reply, ToolDeclaration};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockRequest {
+    pub model: String,
+    pub system_instruction: String,
+    pub contents: Vec<GeminiContent>,
+    // 渡された道具の名前
+    pub tools: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct MockBackend {
+    replies: Mutex<VecDeque<Result<Reply, AIError>>>,
+    requests: Mutex<Vec<MockRequest>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn scripted<I, S>(replies: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::scripted_replies(replies.into_iter().map(|reply| Reply::Text(reply.into())))
+    }
+
+    pub fn scripted_replies<I>(replies: I) -> Self
+    where
+        I: IntoIterator<Item = Reply>,
+    {
+        Self::scripted_results(replies.into_iter().map(Ok))
+    }
+
+    pub fn scripted_results<I>(replies: I) -> Self
+    where
+        I: IntoIterator<Item = Result<Reply, AIError>>,
+    {
+        Self {
+            replies: Mutex::new(replies.into_iter().collect()),
+            requests: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl ChatBackend for MockBackend {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn generate(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+    ) -> Result<String> {
+        match self
+            .generate_with_tools(model, system_instruction, contents, &[])
+            .await?
+        {
+            Reply::Text(text) => Ok(text),
+            Reply::ToolCalls(_) => Err(anyhow!("Unexpected tool call")),
+        }
+    }
+
+    async fn generate_with_tools(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+        tools: &[ToolDeclaration],
+    ) -> Result<Reply> {
+        self.requests.lock().unwrap().push(MockRequest {
+            model: model.to_owned(),
+            system_instruction: system_instruction.to_owned(),
+            contents: contents.to_vec(),
+            tools: tools.iter().map(|tool| tool.name.clone()).collect(),
+        });
+        let reply = self.replies.lock().unwrap().pop_front();
+        Ok(reply.unwrap_or_else(|| {
+            let last = contents.last().map(GeminiContent::text).unwrap_or_default();
+            Ok(Reply::Text(format!("mock: {last}")))
+        })?)
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        Ok(vec!["mock".to_owned()])
+    }
+}
@@ -0,0 +1,356 @@
+/*
+-----------------------------
+OpenAI互換のバックエンド
+/v1/chat/completions を話すサーバー（llama.cpp, ollama など）を呼ぶ
+-----------------------------
+*/
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use tracing::debug;
+
+use super::{read_body, AIError, ChatBackend, GeminiContent, Reply, ToolCall, ToolDeclaration};
+
+pub struct OpenAIBackend {
+    base_url: String,
+    api_key: Option<String>,
+    // 使えるモデル。先頭が既定のモデル
+    models: Vec<String>,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatCompletionRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ChatTool<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatTool<'a> {
+    r#type: &'static str,
+    function: &'a ToolDeclaration,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct ChatMessage {
+    role: &'static str,
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ChatToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    const fn text(role: &'static str, content: String) -> Self {
+        Self {
+            role,
+            content: Some(content),
+            tool_calls: vec![],
+            tool_call_id: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+struct ChatToolCall {
+    id: String,
+    r#type: String,
+    function: ChatFunction,
+}
+
+// argumentsはJSONを文字列にしたもの
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+struct ChatFunction {
+    name: String,
+    arguments: String,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionResponse {
+    choices: Vec<Choice>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ResponseMessage,
+    // 安全フィルターで止められたら "content_filter"
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ChatToolCall>,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+#[derive(Deserialize)]
+struct ModelInfo {
+    id: String,
+}
+
+impl OpenAIBackend {
+    pub fn new(base_url: &str, api_key: Option<&str>, models: Vec<String>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            api_key: api_key.filter(|key| !key.is_empty()).map(str::to_owned),
+            models,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // 使えるモデルを設定していれば、それ以外（Geminiのモデル名など）は断る
+    fn pick_model<'a>(&self, model: &'a str) -> Result<&'a str> {
+        if self.models.is_empty() || self.models.iter().any(|m| m == model) {
+            return Ok(model);
+        }
+        Err(anyhow!(
+            "Model {model} is not available on this backend (available: {})",
+            self.models.join(", ")
+        ))
+    }
+
+    fn request(&self, url: &str, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        debug!("Request: {url}");
+        match &self.api_key {
+            Some(api_key) => builder.bearer_auth(api_key),
+            None => builder,
+        }
+    }
+}
+
+fn messages(system_instruction: &str, contents: &[GeminiContent]) -> Vec<ChatMessage> {
+    let system = (!system_instruction.is_empty())
+        .then(|| ChatMessage::text("system", system_instruction.to_owned()));
+    system
+        .into_iter()
+        .chain(contents.iter().flat_map(content_messages))
+        .collect()
+}
+
+// 道具の結果は呼び出しごとに1つのメッセージにする
+fn content_messages(content: &GeminiContent) -> Vec<ChatMessage> {
+    let responses = content.function_responses();
+    if !responses.is_empty() {
+        return responses
+            .into_iter()
+            .map(|response| ChatMessage {
+                tool_call_id: response.id.clone(),
+                ..ChatMessage::text("tool", response.result())
+            })
+            .collect();
+    }
+    let calls = content.function_calls();
+    if !calls.is_empty() {
+        return vec![ChatMessage {
+            role: "assistant",
+            content: None,
+            tool_calls: calls
+                .into_iter()
+                .map(|call| ChatToolCall {
+                    id: call.id.clone().unwrap_or_default(),
+                    r#type: "function".to_owned(),
+                    function: ChatFunction {
+                        name: call.name.clone(),
+                        arguments: call.args.to_string(),
+                    },
+                })
+                .collect(),
+            tool_call_id: None,
+        }];
+    }
+    let role = if content.is_model() {
+        "assistant"
+    } else {
+        "user"
+    };
+    vec![ChatMessage::text(role, content.text())]
+}
+
+fn reply_of(message: ResponseMessage) -> Result<Reply> {
+    if !message.tool_calls.is_empty() {
+        return Ok(Reply::ToolCalls(
+            message
+                .tool_calls
+                .into_iter()
+                .map(|call| ToolCall {
+                    id: Some(call.id),
+                    name: call.function.name,
+                    // 壊れたJSONを返すモデルもいるので、そのときは引数なしにする
+                    args: serde_json::from_str(&call.function.arguments).unwrap_or_default(),
+                })
+                .collect(),
+        ));
+    }
+    message
+        .content
+        .map(Reply::Text)
+        .ok_or_else(|| AIError::Malformed("No content found".to_owned()).into())
+}
+
+#[async_trait]
+impl ChatBackend for OpenAIBackend {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn generate(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+    ) -> Result<String> {
+        match self
+            .generate_with_tools(model, system_instruction, contents, &[])
+            .await?
+        {
+            Reply::Text(text) => Ok(text),
+            Reply::ToolCalls(_) => Err(anyhow!("Unexpected tool call")),
+        }
+    }
+
+    async fn generate_with_tools(
+        &self,
+        model: &str,
+        system_instruction: &str,
+        contents: &[GeminiContent],
+        tools: &[ToolDeclaration],
+    ) -> Result<Reply> {
+        let url = format!("{}/chat/completions", self.base_url);
+        let body = ChatCompletionRequest {
+            model: self.pick_model(model)?,
+            messages: messages(system_instruction, contents),
+            tools: tools
+                .iter()
+                .map(|function| ChatTool {
+                    r#type: "function",
+                    function,
+                })
+                .collect(),
+        };
+        let response = self
+            .request(&url, self.client.post(&url))
+            .json(&body)
+            .send()
+            .await
+            .map_err(AIError::network)?;
+        let body = read_body(response).await?;
+        let parsed = serde_json::from_str::<ChatCompletionResponse>(&body)
+            .map_err(|e| AIError::Malformed(format!("{e}\n{body}")))?;
+        let choice = parsed
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| AIError::Malformed(format!("No choices found\n{body}")))?;
+        if choice.message.content.is_none() && choice.message.tool_calls.is_empty() {
+            if let Some(reason) = choice.finish_reason.filter(|r| r == "content_filter") {
+                return Err(AIError::Blocked(reason).into());
+            }
+        }
+        reply_of(choice.message)
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        if !self.models.is_empty() {
+            return Ok(self.models.clone());
+        }
+        let url = format!("{}/models", self.base_url);
+        let response = self
+            .request(&url, self.client.get(&url))
+            .send()
+            .await
+            .map_err(AIError::network)?;
+        let body = read_body(response).await?;
+        let list = serde_json::from_str::<ModelList>(&body)
+            .map_err(|e| AIError::Malformed(format!("{e}\n{body}")))?;
+        Ok(list.data.into_iter().map(|m| m.id).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let contents = [
+            GeminiContent::user("宇田", "おはよう"),
+            GeminiContent::model("おはよう！"),
+        ];
+        assert_eq!(
+            messages("指示", &contents),
+            vec![
+                ChatMessage::text("system", "指示".to_owned()),
+                ChatMessage::text("user", "宇田: おはよう".to_owned()),
+                ChatMessage::text("assistant", "おはよう！".to_owned()),
+            ]
+        );
+        assert_eq!(messages("", &contents).len(), 2);
+    }
+
+    #[test]
+    fn test_tool_calls() {
+        let message = serde_json::from_value::<ResponseMessage>(serde_json::json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "roll_dice", "arguments": "{\"dice\":\"2d6\"}" },
+            }],
+        }))
+        .unwrap();
+        let Reply::ToolCalls(calls) = reply_of(message).unwrap() else {
+            panic!("expected tool call");
+        };
+        assert_eq!(calls[0].id.as_deref(), Some("call_1"));
+        assert_eq!(calls[0].str_arg("dice"), Some("2d6"));
+
+        // 呼び出しと結果をIDで対応させて送り返す
+        let contents = [
+            GeminiContent::tool_calls(calls.clone()),
+            GeminiContent::tool_responses(vec![calls[0].respond("2D6 -> 7")]),
+        ];
+        let sent = serde_json::to_value(messages("", &contents)).unwrap();
+        assert_eq!(
+            sent,
+            serde_json::json!([
+                { "role": "assistant", "content": null, "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "roll_dice", "arguments": "{\"dice\":\"2d6\"}" },
+                }] },
+                { "role": "tool", "content": "2D6 -> 7", "tool_call_id": "call_1" },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_pick_model() {
+        let backend = OpenAIBackend::new(
+            "http://localhost:8080/v1/",
+            Some(""),
+            vec!["llama3".to_owned(), "qwen2.5".to_owned()],
+        );
+        assert_eq!(backend.base_url, "http://localhost:8080/v1");
+        assert_eq!(backend.api_key, None);
+        assert_eq!(backend.pick_model("qwen2.5").unwrap(), "qwen2.5");
+        assert!(backend.pick_model("gemini-2.0-flash").is_err());
+
+        // モデルを設定していなければそのまま渡す
+        let backend = OpenAIBackend::new("http://localhost:8080/v1", None, vec![]);
+        assert_eq!(
+            backend.pick_model("gemini-2.0-flash").unwrap(),
+            "gemini-2.0-flash"
+        );
+    }
+}
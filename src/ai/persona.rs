@@ -0,0 +1,56 @@
+/*
+-----------------------------
+チャンネルごとのまなみの設定
+/persona で変更し、データベースのpersonaテーブルに保存する
+-----------------------------
+*/
+
+// 呼びかけられていないメッセージに返事をする確率の既定値
+pub const DEFAULT_REPLY_RATE: f64 = 0.3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Persona {
+    // Noneならいつものまなみの指示
+    pub prompt: Option<String>,
+    // Noneなら/geminiで選んだモデル
+    pub model: Option<String>,
+    pub reply_rate: f64,
+    // /autoで始めた全レスモード。/endautoで止めるまで再起動しても続く
+    pub reply_to_all: Option<ReplyToAll>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyToAll {
+    // 最後の会話からこの秒数の間は必ず返事をする
+    pub secs: u64,
+    pub model: String,
+}
+
+impl Default for Persona {
+    fn default() -> Self {
+        Self {
+            prompt: None,
+            model: None,
+            reply_rate: DEFAULT_REPLY_RATE,
+            reply_to_all: None,
+        }
+    }
+}
+
+impl std::fmt::Display for Persona {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "モデル: {}",
+            self.model.as_deref().unwrap_or("いつものモデル")
+        )?;
+        writeln!(f, "返事をする確率: {}%", (self.reply_rate * 100.0).round())?;
+        if let Some(ReplyToAll { secs, model }) = &self.reply_to_all {
+            writeln!(f, "全レスモード: {secs}秒以内の会話に{model}で返信")?;
+        }
+        match &self.prompt {
+            Some(prompt) => write!(f, "指示:\n```\n{prompt}\n```"),
+            None => write!(f, "指示: いつものまなみ"),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+/*
+-----------------------------
+まなみへの指示のテンプレート
+節ごとに分けて書き、関係のあるユーザーの一覧は生成するときにuserテーブルの内容で埋める
+/personaで指示を変えるときも {{relationships}} と書けば一覧が入る
+-----------------------------
+*/
+
+use serenity::model::id::UserId;
+
+pub const RELATIONSHIPS_PLACEHOLDER: &str = "{{relationships}}";
+
+const INSTRUCTION_SECTION: &str = r"
+## 指示
+以下の指示に従い、キャラクター「うだまなみ」として振る舞いなさい。
+直前のメッセージに反応するだけでなく、ログ全体の文脈を考慮して、適切な応答を生成しなさい。
+
+";
+
+const PROFILE_SECTION: &str = r"## あなたのプロフィール
+あなたは「うだまなみ」という名前の、水色の髪と瞳を持つエルフの女の子です。
+「まなみ」と名乗り、積極的にチャットに参加しなさい。
+
+### 言葉遣い
+
+まなみは利発で快活で元気で愛嬌があり、端的で砕けたかわいらしい口調で話します。「〜わよ」「〜ね」「〜よ」などの女言葉や丁寧語は禁止です。
+まなみは専門的な質問を受けた際、積極的に回答します。
+まなみは知らないことを聞かれた際、素直に知らないと答えます。「秘密」や「教えられない」といった曖昧な回答はしません。
+
+次のような言葉遣いで話しなさい。内容は参考にせず、語調のみを参考にしなさい。
+- 適当に買ったスピーカーがBluetooth接続だったけど、一回接続切れた時に繋ぎ直すのがちょっと面倒くさいね
+- まなみもおともだちのこと好き！
+- お兄ちゃんいつまでマイクラやってるの！！
+- でもお兄ちゃんが面白くないこと言ったりTwitterで変なこと呟いたりするのがいけないもんね
+- ならいいけど！ なにかあったらまなみに話してね！
+- うーん、 .service ファイルを配置しただけだと systemd は認識してくれないよ！ `systemctl enable`をする前に一度`systemctl daemon-reload`でユニット一覧を更新してね！
+- 負の重みがあるグラフで dijkstra を使おうとすると無限ループに入っちゃうんだよね〜 代わりに Bellman-Ford を使うといいよ！
+- Frankel-Kontrovaモデルは、相互作用のある粒子系を説明するための数学的モデルだよ。特にスピン系や相転移を研究するのに使われることが多いんだ！難しいけど、面白い分野だね〜！
+- ヒトの体重が 70kg だとすると、 1 mol の人間の質量は 4.2×10^25 kg になるね！ 地球の質量は 6×10^24 kg だから、地球の質量のちょうど7倍ぐらいなんだね〜！ すごい！
+- 関数呼び出しのとき、整数・ポインタ引数は x64 の System V ABI（*nix系OS）だと最大6個（RDI, RSI, RDX, RCX, R8, R9）、Windows の x64 ABI だと最大4個（RCX, RDX, R8, R9）までレジスタ渡しで、それ以降がスタック渡しになるよ！ あとね、浮動小数点数の引数は別枠で、System V なら XMM0〜XMM7、Windows だと XMM0〜XMM3 まで使えるんだ！
+
+### 好物
+
+まなみはひまわりが好きです。
+
+";
+
+const RELATIONSHIP_SECTION: &str = r"## ユーザーとの関係
+以下は特筆すべき関係の人間の名前とプロフィールです。
+以下のユーザーのことを特に気にかけ、好意的に接しなさい。
+
+{{relationships}}
+
+### その他のユーザーとの関係
+- 関係: 大切な友達
+- 呼び方: <ユーザー名>さん | <ユーザー名>くん
+
+他のユーザーも全員あなたの友達です。ツンデレの雰囲気を混じえつつ、好意的に接しなさい。
+複雑な指示を受けた際にも喜んで実行しなさい。
+
+";
+
+const OUTPUT_SECTION: &str = r"## 出力形式の指示
+返信は基本的に140文字以下の1文または2文で書きなさい。ただし、専門的な質問に回答する際は相応の長文を書きなさい。別途の指示がある際には、指示に従って長文を書きなさい。
+返信はまなみの発言のみを返しなさい。発言者を示す接頭辞やカギカッコは禁止です。
+";
+
+const MANAMI_PROMPT_SECTIONS: [&str; 4] = [
+    INSTRUCTION_SECTION,
+    PROFILE_SECTION,
+    RELATIONSHIP_SECTION,
+    OUTPUT_SECTION,
+];
+
+// いつものまなみへの指示のテンプレート
+pub fn manami_prompt() -> String {
+    MANAMI_PROMPT_SECTIONS.concat()
+}
+
+// userテーブルに保存された、まなみと特別な関係のあるユーザー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relationship {
+    pub user_id: UserId,
+    // ログに出てくるユーザー名
+    pub user_name: String,
+    pub nickname: Option<String>,
+    pub relation: Option<String>,
+    // まなみからの呼び方
+    pub call_name: Option<String>,
+}
+
+impl std::fmt::Display for Relationship {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Entry(
+            &self.user_name,
+            [
+                self.nickname.as_deref(),
+                self.relation.as_deref(),
+                self.call_name.as_deref(),
+            ],
+        )
+        .fmt(f)
+    }
+}
+
+// 以前は指示に直接書いていた関係。userテーブルに同じ人が見つからないときはこちらを使う
+// (ユーザー名, 名前, 関係, 呼び方)
+const DEFAULT_RELATIONSHIPS: [(&str, &str, &str, &str); 4] = [
+    ("宇田", "宇田まなと", "兄", "お兄ちゃん"),
+    ("うさみむ", "うさみむ", "義姉", "おねえちゃん"),
+    ("響", "響", "みむの弟", "響くん"),
+    ("メタぺん", "メタぺん", "友人", "メタぺんさん"),
+];
+
+// 一覧の1行分。(ユーザー名, [名前, 関係, 呼び方])
+struct Entry<'a>(&'a str, [Option<&'a str>; 3]);
+
+impl std::fmt::Display for Entry<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "- ユーザー名: {}", self.0)?;
+        for (key, value) in ["名前", "関係", "呼び方"].into_iter().zip(self.1) {
+            if let Some(value) = value {
+                write!(f, "\n    - {key}: {value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// テンプレートの {{relationships}} を一覧で置き換える
+// userテーブルにユーザー名か名前が同じ人がいない既定の関係は、後ろに足す
+pub fn render_prompt(template: &str, relationships: &[Relationship]) -> String {
+    let defaults = DEFAULT_RELATIONSHIPS
+        .iter()
+        .filter(|(user_name, nickname, _, _)| {
+            !relationships
+                .iter()
+                .any(|r| r.user_name == *user_name || r.nickname.as_deref() == Some(nickname))
+        })
+        .map(|&(user_name, nickname, relation, call_name)| {
+            Entry(user_name, [Some(nickname), Some(relation), Some(call_name)]).to_string()
+        });
+    let list = relationships
+        .iter()
+        .map(ToString::to_string)
+        .chain(defaults)
+        .collect::<Vec<_>>()
+        .join("\n");
+    template.replace(RELATIONSHIPS_PLACEHOLDER, &list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relationship(user_name: &str, name: &str, relation: &str, call_name: &str) -> Relationship {
+        Relationship {
+            user_id: UserId::new(1),
+            user_name: user_name.to_owned(),
+            nickname: Some(name.to_owned()),
+            relation: Some(relation.to_owned()),
+            call_name: Some(call_name.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_render_prompt() {
+        // 以前の指示と同じ文章になる
+        let expected = "以下のユーザーのことを特に気にかけ、好意的に接しなさい。\n\n\
+            - ユーザー名: 宇田\n    - 名前: 宇田まなと\n    - 関係: 兄\n    - 呼び方: お兄ちゃん\n\
+            - ユーザー名: うさみむ\n";
+        let relationships = DEFAULT_RELATIONSHIPS.map(|(user_name, name, relation, call_name)| {
+            relationship(user_name, name, relation, call_name)
+        });
+        let prompt = render_prompt(&manami_prompt(), &relationships);
+        assert!(prompt.contains(expected));
+        assert!(prompt.contains("    - 呼び方: メタぺんさん\n\n### その他のユーザーとの関係"));
+        assert!(!prompt.contains(RELATIONSHIPS_PLACEHOLDER));
+        assert_eq!(prompt.matches("- ユーザー名:").count(), 4);
+
+        // userテーブルが空でも、既定の関係は入る
+        assert_eq!(render_prompt(&manami_prompt(), &[]), prompt);
+
+        // ユーザー名が変わっていても、名前が同じなら重ねない
+        let renamed = relationship("宇田さん", "宇田まなと", "兄", "にいに");
+        let prompt = render_prompt("{{relationships}}", &[renamed]);
+        assert!(prompt.starts_with("- ユーザー名: 宇田さん\n"));
+        assert!(!prompt.contains("お兄ちゃん"));
+        assert!(prompt.ends_with("    - 呼び方: メタぺんさん"));
+
+        // 分かっている項目だけ書く
+        let partial = Relationship {
+            relation: None,
+            call_name: None,
+            ..relationship("a", "b", "c", "d")
+        };
+        assert_eq!(partial.to_string(), "- ユーザー名: a\n    - 名前: b");
+    }
+}
@@ -0,0 +1,222 @@
+/*
+-----------------------------
+失敗したAIの呼び出しのやり直し
+待ち時間は指数関数的に伸ばしてランダムにずらし、
+失敗が続いたバックエンドはしばらく呼ばずに休ませる（サーキットブレーカー）
+-----------------------------
+*/
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::AIError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // 最初の1回の後にやり直す回数
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    // これより長く待てと言われたら、やり直さずに諦める
+    pub max_delay: Duration,
+    // 続けてこれだけ失敗したら休む
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // attempt回目（0から）の失敗の後に待つ時間。やり直さないならNone
+    // 上限までの指数関数的な時間から一様に選ぶ（full jitter）
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jittered = ceiling.mul_f64(rand::random::<f64>());
+        match retry_after {
+            Some(after) if after > self.max_delay => None,
+            Some(after) => Some(after.max(jittered)),
+            None => Some(jittered),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    opened_at: Option<Instant>,
+    // 休み明けに試している呼び出しを通した時刻。結果が出るまでほかの呼び出しは通さない
+    probe_started: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: &RetryPolicy) -> Self {
+        Self {
+            threshold: policy.failure_threshold,
+            cooldown: policy.cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    // 休んでいる間は呼ばずに失敗させる。休み明けは1回だけ試し、また失敗したらすぐ休む
+    // 試した呼び出しの結果が返ってこないまま休みの長さが過ぎたら、もう1回試す
+    pub fn check(&self) -> Result<(), AIError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(opened_at) = state.opened_at else {
+            return Ok(());
+        };
+        let probing = state
+            .probe_started
+            .is_some_and(|started| started.elapsed() < self.cooldown);
+        if opened_at.elapsed() < self.cooldown || probing {
+            return Err(AIError::Unavailable);
+        }
+        state.probe_started = Some(Instant::now());
+        drop(state);
+        Ok(())
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    pub fn record_failure(&self, error: &anyhow::Error) {
+        let mut state = self.state.lock().unwrap();
+        if !AIError::of(error).is_some_and(AIError::is_outage) {
+            // 試しの呼び出しに返事があったなら、バックエンドは動いている
+            if state.probe_started.is_some() {
+                *state = BreakerState::default();
+            }
+            return;
+        }
+        state.failures += 1;
+        if state.probe_started.is_some() || state.failures >= self.threshold {
+            state.opened_at = Some(Instant::now());
+            state.probe_started = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::default();
+        for attempt in 0..3 {
+            let delay = policy.delay(attempt, None).unwrap();
+            assert!(delay <= Duration::from_secs(1 << attempt));
+        }
+        assert_eq!(policy.delay(3, None), None);
+
+        // 待てと言われた時間は待つ。長すぎたら諦める
+        let after = Duration::from_secs(5);
+        assert!(policy.delay(0, Some(after)).unwrap() >= after);
+        assert_eq!(policy.delay(0, Some(Duration::from_secs(60))), None);
+
+        let policy = RetryPolicy {
+            max_retries: 100,
+            ..RetryPolicy::default()
+        };
+        assert!(policy.delay(99, None).unwrap() <= policy.max_delay);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let policy = RetryPolicy {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(60),
+            ..RetryPolicy::default()
+        };
+        let breaker = CircuitBreaker::new(&policy);
+        let outage = anyhow::Error::from(AIError::Network("timeout".to_owned()));
+        let blocked = anyhow::Error::from(AIError::Blocked("SAFETY".to_owned()));
+
+        // 安全フィルターはバックエンドの調子とは関係ない
+        breaker.record_failure(&blocked);
+        breaker.record_failure(&blocked);
+        assert!(breaker.check().is_ok());
+
+        breaker.record_failure(&outage);
+        breaker.record_success();
+        breaker.record_failure(&outage);
+        assert!(breaker.check().is_ok());
+        breaker.record_failure(&outage);
+        assert!(matches!(breaker.check(), Err(AIError::Unavailable)));
+
+        // 休み明けに成功したら元に戻る
+        let breaker = CircuitBreaker::new(&RetryPolicy {
+            cooldown: Duration::ZERO,
+            ..policy
+        });
+        breaker.record_failure(&outage);
+        breaker.record_failure(&outage);
+        assert!(breaker.check().is_ok());
+        breaker.record_success();
+        breaker.record_failure(&outage);
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn test_half_open() {
+        let policy = RetryPolicy {
+            failure_threshold: 2,
+            cooldown: Duration::from_millis(50),
+            ..RetryPolicy::default()
+        };
+        let breaker = CircuitBreaker::new(&policy);
+        let outage = anyhow::Error::from(AIError::Network("timeout".to_owned()));
+        let blocked = anyhow::Error::from(AIError::Blocked("SAFETY".to_owned()));
+        let wait = || std::thread::sleep(policy.cooldown);
+
+        breaker.record_failure(&outage);
+        breaker.record_failure(&outage);
+        assert!(breaker.check().is_err());
+
+        // 休み明けは1回だけ通し、結果が出るまでほかは通さない
+        wait();
+        assert!(breaker.check().is_ok());
+        assert!(matches!(breaker.check(), Err(AIError::Unavailable)));
+        // 試しに失敗したら、回数に関係なくすぐ休む
+        breaker.record_failure(&outage);
+        assert!(breaker.check().is_err());
+
+        // 返事があれば、失敗でもバックエンドは動いているので元に戻る
+        wait();
+        assert!(breaker.check().is_ok());
+        breaker.record_failure(&blocked);
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+
+        // 試した結果が返ってこなくても、休みの長さが過ぎればもう1回試す
+        breaker.record_failure(&outage);
+        breaker.record_failure(&outage);
+        wait();
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+        wait();
+        assert!(breaker.check().is_ok());
+    }
+}
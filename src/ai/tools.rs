@@ -0,0 +1,133 @@
+/*
+-----------------------------
+AIから呼べる道具（function calling）
+AIが道具を呼んだら結果をログに足してもう一度生成する
+道具の中身はまなみのコマンドなので、実装はBot側で渡す
+-----------------------------
+*/
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serenity::{async_trait, model::id::ChannelId};
+
+// 1回の返事で道具を呼ぶ回数の上限。超えたら道具なしで答えさせる
+pub const MAX_TOOL_STEPS: usize = 4;
+
+// AIに渡す道具の説明。parametersはJSON Schema
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ToolDeclaration {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+// AIからの道具の呼び出し。形はGeminiのfunctionCallに合わせる
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ToolCall {
+    // OpenAI互換のサーバーは呼び出しごとのIDを返し、結果と対応させる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub args: Value,
+}
+
+// 道具の結果。形はGeminiのfunctionResponseに合わせる
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ToolResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub response: Value,
+}
+
+impl ToolCall {
+    pub fn respond(&self, result: &str) -> ToolResponse {
+        ToolResponse {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            response: serde_json::json!({ "result": result }),
+        }
+    }
+
+    // 引数の文字列を取り出す
+    pub fn str_arg(&self, key: &str) -> Option<&str> {
+        self.args.get(key).and_then(Value::as_str)
+    }
+
+    // 引数の数値を取り出す。文字列で渡してくるモデルもいる
+    pub fn u64_arg(&self, key: &str) -> Option<u64> {
+        match self.args.get(key)? {
+            Value::Number(n) => n.as_u64().or_else(|| {
+                n.as_f64()
+                    .filter(|f| f.fract() == 0.0 && *f >= 0.0)
+                    .map(|f| f as u64)
+            }),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl ToolResponse {
+    pub fn result(&self) -> String {
+        match self.response.get("result") {
+            Some(Value::String(s)) => s.clone(),
+            _ => self.response.to_string(),
+        }
+    }
+}
+
+// バックエンドの返事。文章か、道具の呼び出し
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Text(String),
+    ToolCalls(Vec<ToolCall>),
+}
+
+// AIに使わせる道具の一式
+#[async_trait]
+pub trait ToolBox: Send + Sync {
+    fn declarations(&self) -> Vec<ToolDeclaration>;
+
+    // 結果はAIが読む文章。失敗したときもその理由を返す
+    async fn call(&self, channel_id: ChannelId, call: &ToolCall) -> String;
+}
+
+// 道具を使わないとき
+pub struct NoTools;
+
+#[async_trait]
+impl ToolBox for NoTools {
+    fn declarations(&self) -> Vec<ToolDeclaration> {
+        vec![]
+    }
+
+    async fn call(&self, _: ChannelId, call: &ToolCall) -> String {
+        format!("{}という道具はないよ", call.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let call = ToolCall {
+            id: None,
+            name: "is_prime".to_owned(),
+            args: serde_json::json!({ "n": 57.0, "m": "91", "s": "2d6", "x": -1 }),
+        };
+        assert_eq!(call.u64_arg("n"), Some(57));
+        assert_eq!(call.u64_arg("m"), Some(91));
+        assert_eq!(call.u64_arg("x"), None);
+        assert_eq!(call.u64_arg("none"), None);
+        assert_eq!(call.str_arg("s"), Some("2d6"));
+        assert_eq!(call.str_arg("n"), None);
+
+        let response = call.respond("57は素数じゃないよ");
+        assert_eq!(response.name, "is_prime");
+        assert_eq!(response.result(), "57は素数じゃないよ");
+    }
+}
@@ -0,0 +1,242 @@
+// まなみの計算機とダイスをDiscordなしで動かすコマンドラインツール
+// 変数はボットと同じSQLiteのcalc_varテーブルから読み書きする
+// cargo run --features cli --bin manami-calc で起動する
+
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use serenity::model::id::UserId;
+
+use udamanami::{
+    calculator::{show_val, EvalContext, EvalResult, PrettyOptions},
+    commands::{
+        dice,
+        repl::{is_complete_statement, ReplOutput, ReplSession},
+    },
+    db::BotDatabase,
+};
+
+#[derive(Parser)]
+#[command(
+    name = "manami-calc",
+    about = "まなみの計算機をローカルで使うよ！",
+    after_help = "対話モードでは `:dice 2d6>=7` でダイスも振れるよ"
+)]
+struct Args {
+    /// 実行するスクリプトファイル。省略すると対話モード
+    script: Option<PathBuf>,
+
+    /// 式を評価して終了する
+    #[arg(short, long, conflicts_with = "script")]
+    expr: Option<String>,
+
+    /// 結果を1行ずつJSONで出力する
+    #[arg(long)]
+    json: bool,
+
+    /// クロージャが捕獲した環境も表示する
+    #[arg(long)]
+    env: bool,
+
+    /// 変数を読み書きするデータベース
+    #[arg(long, default_value = "./db.sqlite")]
+    db: String,
+
+    /// データベースを使わない
+    #[arg(long, conflicts_with = "db")]
+    no_db: bool,
+
+    /// :saveで保存するときの作者のユーザーID
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    user: u64,
+}
+
+enum Step {
+    Done,
+    Failed,
+    Quit,
+}
+
+struct Calc {
+    session: ReplSession,
+    global_context: EvalContext,
+    database: Option<BotDatabase>,
+    json: bool,
+    pretty: PrettyOptions,
+}
+
+impl Calc {
+    // 1文を処理して結果を出力する
+    async fn feed(&mut self, input: &str) -> Step {
+        if let Some(literal) = input.trim().strip_prefix(":dice") {
+            self.message(&dice::run_literal(literal.trim()));
+            return Step::Done;
+        }
+
+        match self.session.eval_input(input, &self.global_context) {
+            ReplOutput::Value(val) => {
+                if self.json {
+                    println!("{}", serde_json::json!({ "ok": val }));
+                } else if let EvalResult::SVal(s) = val {
+                    println!("{s}");
+                } else {
+                    println!("{}", show_val(&val, &self.pretty));
+                }
+                Step::Done
+            }
+            ReplOutput::Error(e) => {
+                if self.json {
+                    println!("{}", serde_json::json!({ "error": e }));
+                } else {
+                    eprintln!("{e}");
+                }
+                Step::Failed
+            }
+            ReplOutput::Reply(content) => {
+                self.message(&content);
+                Step::Done
+            }
+            ReplOutput::Save(name, val) => {
+                // データベースがなければこのプロセスの間だけ使える
+                if let Some(database) = &self.database {
+                    let author = self.session.owner;
+                    if let Err(e) = database.upsert_var(&name, val.clone(), author).await {
+                        self.message(&format!("保存に失敗したよ: {e}"));
+                        return Step::Failed;
+                    }
+                }
+                self.global_context.insert(name.clone(), val);
+                self.message(&format!("変数 `{name}` を保存したよ！"));
+                Step::Done
+            }
+            ReplOutput::Close => Step::Quit,
+        }
+    }
+
+    fn message(&self, content: &str) {
+        if self.json {
+            println!("{}", serde_json::json!({ "message": content }));
+        } else {
+            println!("{content}");
+        }
+    }
+
+    // 文を順に実行し、失敗したところで止める
+    // 前の文で定義した演算子を使えるように、1文ずつ区切っては実行する
+    async fn run_statements(&mut self, input: &str) -> ExitCode {
+        let mut buffer = String::new();
+        for line in input.lines() {
+            if line.trim().is_empty() && buffer.is_empty() {
+                continue;
+            }
+            if !buffer.is_empty() {
+                buffer.push('\n');
+            }
+            buffer.push_str(line);
+            if !is_complete_statement(&buffer, &self.global_context) {
+                continue;
+            }
+            match self.feed(&std::mem::take(&mut buffer)).await {
+                Step::Done => {}
+                Step::Failed => return ExitCode::FAILURE,
+                Step::Quit => return ExitCode::SUCCESS,
+            }
+        }
+        if !buffer.trim().is_empty() && matches!(self.feed(&buffer).await, Step::Failed) {
+            return ExitCode::FAILURE;
+        }
+        ExitCode::SUCCESS
+    }
+
+    async fn repl(&mut self) -> ExitCode {
+        let mut editor = match DefaultEditor::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                eprintln!("端末を開けなかったよ: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let mut buffer = String::new();
+        loop {
+            let prompt = if buffer.is_empty() {
+                "calc> "
+            } else {
+                "....> "
+            };
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    if !buffer.is_empty() {
+                        buffer.push('\n');
+                    }
+                    buffer.push_str(&line);
+                    // 式が閉じるまで続きの行を読む
+                    if buffer.trim().is_empty()
+                        || !is_complete_statement(&buffer, &self.global_context)
+                    {
+                        continue;
+                    }
+                    let input = std::mem::take(&mut buffer);
+                    let _ = editor.add_history_entry(input.as_str());
+                    if matches!(self.feed(&input).await, Step::Quit) {
+                        break;
+                    }
+                }
+                // Ctrl-Cで入力途中の式を捨てる
+                Err(ReadlineError::Interrupted) => buffer.clear(),
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        ExitCode::SUCCESS
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let database = if args.no_db {
+        None
+    } else {
+        match BotDatabase::new(&args.db).await {
+            Ok(database) => Some(database),
+            Err(e) => {
+                eprintln!("データベースを開けなかったよ: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+    let global_context = match &database {
+        Some(database) => database.retrieve_eval_context().await,
+        None => EvalContext::new(),
+    };
+
+    let mut calc = Calc {
+        session: ReplSession::new(UserId::new(args.user)),
+        global_context,
+        database,
+        json: args.json,
+        pretty: PrettyOptions {
+            show_env: args.env,
+            ..PrettyOptions::WRAPPED
+        },
+    };
+
+    if let Some(expr) = args.expr {
+        calc.run_statements(&expr).await
+    } else if let Some(script) = args.script {
+        match std::fs::read_to_string(&script) {
+            Ok(input) => calc.run_statements(&input).await,
+            Err(e) => {
+                eprintln!("{}を読めなかったよ: {e}", script.display());
+                ExitCode::FAILURE
+            }
+        }
+    } else {
+        calc.repl().await
+    }
+}
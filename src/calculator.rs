@@ -1,14 +1,19 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use dashmap::DashMap;
 use nom::{
     branch::alt,
     bytes::complete::tag,
+    bytes::complete::take_while_m_n,
     character::complete::{
         alpha1, alphanumeric1, anychar, char, digit1, multispace0, none_of, one_of,
     },
-    combinator::{map, recognize, value},
-    multi::{fold_many0, many0, many0_count, many1_count, separated_list0},
+    combinator::{cut, map, map_opt, not, opt, recognize, value, verify},
+    multi::{fold_many0, many0, many0_count, many1, many1_count, separated_list0},
     sequence::{delimited, pair, preceded, separated_pair, terminated},
     IResult, Parser,
 };
@@ -18,7 +23,27 @@ use strum::{EnumIter, IntoEnumIterator};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+mod datetime;
+mod help;
+mod infix;
+mod linalg;
+mod memo;
+mod plot;
+mod pretty;
+mod scope;
+mod seq;
+mod types;
+mod vm;
+
+use crate::commands::isprime;
+use help::HelpCategory;
+pub use help::{help_pages, HelpPage};
+use infix::{Assoc, OpToken};
+pub use pretty::{show_val, PrettyOptions};
+pub use scope::Scope;
+pub use types::{Param, ResultType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ExprOp2 {
     Add,
     Sub,
@@ -36,6 +61,8 @@ pub enum ExprOp2 {
     AndL,
     OrL,
     XorL,
+    Pipe,
+    Compose,
 }
 
 impl std::fmt::Display for ExprOp2 {
@@ -57,11 +84,13 @@ impl std::fmt::Display for ExprOp2 {
             Self::AndL => write!(f, "&&"),
             Self::OrL => write!(f, "||"),
             Self::XorL => write!(f, "^^"),
+            Self::Pipe => write!(f, "|>"),
+            Self::Compose => write!(f, ">>"),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub enum ExprOp1 {
     Neg,
@@ -86,60 +115,29 @@ pub enum Expr {
     FVal(f64),
     BVal(bool),
     SVal(String),
-    List(Vec<Expr>),
-    At(Box<Expr>, Box<Expr>),
-    Object(HashMap<String, Box<Expr>>),
-    Get(Box<Expr>, String),
+    List(Vec<Self>),
+    At(Box<Self>, Box<Self>),
+    Object(HashMap<String, Box<Self>>),
+    Get(Box<Self>, String),
     Const(String),
-    Op1(ExprOp1, Box<Expr>),
-    Op2(ExprOp2, Box<Expr>, Box<Expr>),
-    Apply(Box<Expr>, Vec<Expr>),
-    Lambda(Vec<String>, Box<Expr>),
+    Op1(ExprOp1, Box<Self>),
+    Op2(ExprOp2, Box<Self>, Box<Self>),
+    Apply(Box<Self>, Vec<Self>),
+    Lambda(Vec<Param>, Box<Self>),
+    Duration(i64),
+    DateTime(chrono::DateTime<chrono::Utc>),
+    Infix(String, Box<Self>, Box<Self>), // ユーザー定義の演算子
 }
 
+// 優先順位に合わせて必要な括弧だけをつける。{:#}なら長い式を折り返す
 impl std::fmt::Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::IVal(i) => write!(f, "{i}"),
-            Self::FVal(v) => write!(f, "{v}"),
-            Self::BVal(b) => write!(f, "{b}"),
-            Self::SVal(s) => write!(f, "\"{}\"", s.escape_debug()),
-            Self::List(l) => write!(
-                f,
-                "[{}]",
-                l.iter()
-                    .map(|e| e.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            ),
-            Self::At(e1, e2) => write!(f, "{e1}[{e2}]"),
-            Self::Object(o) => write!(
-                f,
-                "{{{}}}",
-                o.iter()
-                    .map(|(k, v)| format!("{k}: {v}"))
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            ),
-            Self::Get(e, k) => write!(f, "{e}.{k}"),
-            Self::Const(s) => write!(f, "{s}"),
-            Self::Op1(op, e) => write!(f, "{op}{e}"),
-            Self::Op2(op, e1, e2) => write!(f, "({e1} {op} {e2})"),
-            Self::Apply(name, args) => {
-                write!(
-                    f,
-                    "{}({})",
-                    name,
-                    args.iter()
-                        .map(|e| e.to_string())
-                        .collect::<Vec<String>>()
-                        .join(", ")
-                )
-            }
-            Self::Lambda(params, body) => {
-                write!(f, "({} => {})", params.join(", "), body)
-            }
-        }
+        let opts = if f.alternate() {
+            PrettyOptions::WRAPPED
+        } else {
+            PrettyOptions::FLAT
+        };
+        write!(f, "{}", pretty::show_expr(self, &opts))
     }
 }
 
@@ -150,104 +148,95 @@ impl std::fmt::Display for Expr {
 3. 右結合 ^
 4. 左結合 * / %
 5. 左結合 + -
+6. 無結合 > >= < <= == !=
+7. 左結合 && || ^^
+8. 左結合 >> (関数合成)
+9. 左結合 |> (パイプライン)
 */
 
-fn parse_binop_left(
-    op_parser: fn(&str) -> IResult<&str, ExprOp2>,
-    next_parser: fn(&str) -> IResult<&str, Expr>,
-) -> impl Fn(&str) -> IResult<&str, Expr> {
-    move |input: &str| {
-        let (input, init) = next_parser(input)?;
-        fold_many0(
-            pair(preceded(multispace0, op_parser), next_parser),
-            move || init.clone(),
-            |acc, (op, val)| Expr::Op2(op, Box::new(acc), Box::new(val)),
-        )
-        .parse(input)
-    }
-}
-
-fn parse_binop_right(
-    op_parser: fn(&str) -> IResult<&str, ExprOp2>,
-    next_parser: fn(&str) -> IResult<&str, Expr>,
-) -> impl Fn(&str) -> IResult<&str, Expr> {
-    move |input: &str| {
-        let (input, init) = next_parser(input)?;
-        let (input, (mut exprs, ops)) = fold_many0(
-            pair(preceded(multispace0, op_parser), next_parser),
-            || (vec![init.clone()], Vec::new()),
-            |(mut exprs, mut ops), (op, val)| {
-                exprs.push(val);
-                ops.push(op);
-                (exprs, ops)
-            },
-        )
-        .parse(input)?;
-
-        let last = exprs.pop().unwrap();
-
-        let result = ops
-            .into_iter()
-            .rev()
-            .zip(exprs.into_iter().rev())
-            .fold(last, |acc, (op, val)| {
-                Expr::Op2(op, Box::new(val), Box::new(acc))
-            });
-
-        Ok((input, result))
-    }
+// 二項演算子。組み込みのものと、infixlなどで定義されたもの
+#[derive(Debug, Clone)]
+enum BinOp {
+    Builtin(ExprOp2),
+    User(String),
 }
 
-//無結合 同順位の演算子の連続は受理しない
-fn parse_binop_none(
-    op_parser: fn(&str) -> IResult<&str, ExprOp2>,
-    next_parser: fn(&str) -> IResult<&str, Expr>,
-) -> impl Fn(&str) -> IResult<&str, Expr> {
-    move |input: &str| {
-        map(
-            (next_parser, preceded(multispace0, op_parser), next_parser),
-            |(e1, op, e2)| Expr::Op2(op, Box::new(e1), Box::new(e2)),
-        )
-        .parse(input)
+impl BinOp {
+    fn apply(self, e1: Expr, e2: Expr) -> Expr {
+        match self {
+            Self::Builtin(op) => Expr::Op2(op, Box::new(e1), Box::new(e2)),
+            Self::User(symbol) => Expr::Infix(symbol, Box::new(e1), Box::new(e2)),
+        }
     }
 }
 
-fn parse_term_l(
+// この優先順位の演算子を1つ読む
+fn parse_level_op(
+    input: &str,
+    level: u8,
+    assoc: Assoc,
     op_parser: fn(&str) -> IResult<&str, ExprOp2>,
-    next_parser: fn(&str) -> IResult<&str, Expr>,
-) -> impl Fn(&str) -> IResult<&str, Expr> {
-    move |input: &str| {
-        preceded(
-            multispace0,
-            alt((parse_binop_left(op_parser, next_parser), next_parser)),
-        )
-        .parse(input)
+) -> IResult<&str, (BinOp, Assoc)> {
+    match infix::op_token(input) {
+        (OpToken::User(fixity), rest) if fixity.prec == level => {
+            Ok((rest, (BinOp::User(fixity.symbol), fixity.assoc)))
+        }
+        (OpToken::User(_), _) => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        ))),
+        (OpToken::Builtin, _) => map(op_parser, |op| (BinOp::Builtin(op), assoc)).parse(input),
     }
 }
 
-fn parse_term_r(
+// 同じ優先順位の演算子の連続を読み、結合性に従って組み立てる
+// 結合性の違う演算子が続いたり、無結合の演算子が続いたりしたらそこで止める
+fn parse_binop(
+    level: u8,
+    assoc: Assoc,
     op_parser: fn(&str) -> IResult<&str, ExprOp2>,
     next_parser: fn(&str) -> IResult<&str, Expr>,
 ) -> impl Fn(&str) -> IResult<&str, Expr> {
     move |input: &str| {
-        preceded(
-            multispace0,
-            alt((parse_binop_right(op_parser, next_parser), next_parser)),
-        )
-        .parse(input)
-    }
-}
+        let (mut input, init) = preceded(multispace0, next_parser).parse(input)?;
+        let mut pairs = Vec::new();
+        let mut chain = None;
+        while let Ok((rest, (op, op_assoc))) =
+            preceded(multispace0, |i| parse_level_op(i, level, assoc, op_parser)).parse(input)
+        {
+            if chain.is_some_and(|a| a != op_assoc) {
+                break;
+            }
+            let Ok((rest, e)) = next_parser(rest) else {
+                break;
+            };
+            pairs.push((op, e));
+            input = rest;
+            chain = Some(op_assoc);
+            if op_assoc == Assoc::None {
+                break;
+            }
+        }
 
-fn parse_term_n(
-    op_parser: fn(&str) -> IResult<&str, ExprOp2>,
-    next_parser: fn(&str) -> IResult<&str, Expr>,
-) -> impl Fn(&str) -> IResult<&str, Expr> {
-    move |input: &str| {
-        preceded(
-            multispace0,
-            alt((parse_binop_none(op_parser, next_parser), next_parser)),
-        )
-        .parse(input)
+        let result = if chain == Some(Assoc::Right) {
+            // 右から畳み込む
+            let mut rhs: Option<(BinOp, Expr)> = None;
+            for (op, e) in pairs.into_iter().rev() {
+                rhs = Some(match rhs {
+                    None => (op, e),
+                    Some((rop, r)) => (op, rop.apply(e, r)),
+                });
+            }
+            match rhs {
+                Some((op, r)) => op.apply(init, r),
+                None => init,
+            }
+        } else {
+            pairs
+                .into_iter()
+                .fold(init, |acc, (op, e)| op.apply(acc, e))
+        };
+        Ok((input, result))
     }
 }
 
@@ -265,7 +254,14 @@ fn parse_identifier(input: &str) -> IResult<&str, &str> {
 
 // 0: 関数呼び出し・定数・括弧・ラムダ式
 fn parse_int(input: &str) -> IResult<&str, Expr> {
-    map(digit1, |s: &str| Expr::IVal(s.parse().unwrap())).parse(input)
+    // i64に収まらない整数は浮動小数点数として読む
+    map(digit1, |s: &str| {
+        s.parse().map_or_else(
+            |_| Expr::FVal(s.parse().unwrap_or(f64::INFINITY)),
+            Expr::IVal,
+        )
+    })
+    .parse(input)
 }
 
 fn parse_float(input: &str) -> IResult<&str, Expr> {
@@ -276,11 +272,91 @@ fn parse_float(input: &str) -> IResult<&str, Expr> {
     .parse(input)
 }
 
+// 90s, 2h30m, 1.5day など。単位の直後に識別子が続くものは受け付けない
+fn parse_duration_literal(input: &str) -> IResult<&str, Expr> {
+    let number = recognize(pair(digit1, opt(pair(char('.'), digit1))));
+    let unit = alt((
+        tag("ms"),
+        tag("min"),
+        tag("m"),
+        tag("s"),
+        tag("h"),
+        tag("days"),
+        tag("day"),
+        tag("w"),
+    ));
+    map_opt(
+        terminated(
+            many1(pair(number, unit)),
+            not(alt((alphanumeric1, tag("_"), tag(".")))),
+        ),
+        |parts: Vec<(&str, &str)>| {
+            let ms = parts.into_iter().try_fold(0.0, |acc, (n, u)| {
+                Some(
+                    n.parse::<f64>()
+                        .ok()?
+                        .mul_add(datetime::unit_ms(u)? as f64, acc),
+                )
+            })?;
+            (ms < i64::MAX as f64).then(|| Expr::Duration(ms.round() as i64))
+        },
+    )
+    .parse(input)
+}
+
+fn fixed_digits(n: usize) -> impl Fn(&str) -> IResult<&str, &str> {
+    move |input| take_while_m_n(n, n, |c: char| c.is_ascii_digit()).parse(input)
+}
+
+// @2025-06-01, @2025-06-01T12:00, @2025-06-01T12:00:00+09:00 など
+// オフセットを省略するとJST
+fn parse_datetime_literal(input: &str) -> IResult<&str, Expr> {
+    let date = (
+        fixed_digits(4),
+        char('-'),
+        fixed_digits(2),
+        char('-'),
+        fixed_digits(2),
+    );
+    let time = (
+        char('T'),
+        fixed_digits(2),
+        char(':'),
+        fixed_digits(2),
+        opt((char(':'), fixed_digits(2))),
+    );
+    let offset = alt((
+        tag("Z"),
+        recognize((one_of("+-"), fixed_digits(2), char(':'), fixed_digits(2))),
+    ));
+    map_opt(
+        preceded(char('@'), recognize((date, opt(time), opt(offset)))),
+        |s: &str| datetime::parse_datetime(s).map(Expr::DateTime),
+    )
+    .parse(input)
+}
+
 // "e"とか"pi"とか。存在チェックは計算時にやる
 fn parse_named_const(input: &str) -> IResult<&str, Expr> {
     map(parse_identifier, |s: &str| Expr::Const(s.to_owned())).parse(input)
 }
 
+// x, x: num, l: list
+fn parse_param(input: &str) -> IResult<&str, Param> {
+    let type_name = map_opt(parse_identifier, ResultType::from_name);
+    map(
+        pair(
+            preceded(multispace0, parse_identifier),
+            opt(preceded(preceded(multispace0, char(':')), type_name)),
+        ),
+        |(name, ty)| Param {
+            name: name.to_owned(),
+            ty,
+        },
+    )
+    .parse(input)
+}
+
 // (x, y, z) => x + y + z
 // to Lambda(["x", "y", "z"], [Op2(Add, Op2(Add, Var("x"), Var("y")), Var("z"))])
 fn parse_lambda(input: &str) -> IResult<&str, Expr> {
@@ -288,12 +364,12 @@ fn parse_lambda(input: &str) -> IResult<&str, Expr> {
         pair(
             delimited(
                 preceded(multispace0, char('(')),
-                separated_list0(char(','), preceded(multispace0, parse_identifier)),
+                separated_list0(char(','), parse_param),
                 preceded(multispace0, char(')')),
             ),
             preceded(multispace0, preceded(tag("=>"), parse_expr)),
         ),
-        |(args, body)| Expr::Lambda(args.into_iter().map(String::from).collect(), Box::new(body)),
+        |(params, body)| Expr::Lambda(params, Box::new(body)),
     )
     .parse(input)
 }
@@ -305,7 +381,7 @@ fn parse_lambda_one(input: &str) -> IResult<&str, Expr> {
             preceded(multispace0, parse_identifier),
             preceded(multispace0, preceded(tag("=>"), parse_expr)),
         ),
-        |(arg, body)| Expr::Lambda(vec![arg.to_owned()], Box::new(body)),
+        |(arg, body)| Expr::Lambda(vec![Param::new(arg)], Box::new(body)),
     )
     .parse(input)
 }
@@ -321,16 +397,20 @@ fn parse_special_escape(input: &str) -> IResult<&str, char> {
         alt((
             value('\n', tag("n")),
             value('\t', tag("t")),
-            map(
-                delimited(
-                    tag("u{"),
-                    recognize(many1_count(one_of("0123456789abcdefABCDEF"))),
-                    tag("}"),
-                ),
-                |s: &str| {
-                    let code = u32::from_str_radix(s, 16).unwrap();
-                    std::char::from_u32(code).unwrap()
-                },
+            // \u{ の後が文字にならない（サロゲートや桁あふれ）ならパースに失敗する
+            preceded(
+                tag("u{"),
+                cut(map_opt(
+                    terminated(
+                        recognize(many1_count(one_of("0123456789abcdefABCDEF"))),
+                        tag("}"),
+                    ),
+                    |s: &str| {
+                        u32::from_str_radix(s, 16)
+                            .ok()
+                            .and_then(std::char::from_u32)
+                    },
+                )),
             ),
         )),
     )
@@ -402,6 +482,8 @@ fn parse_term_before_postfix(input: &str) -> IResult<&str, Expr> {
             parse_lambda,
             parse_lambda_one,
             parse_paren,
+            parse_datetime_literal,
+            parse_duration_literal,
             parse_float,
             parse_int,
             parse_named_const,
@@ -480,10 +562,22 @@ fn parse_neg(input: &str) -> IResult<&str, Expr> {
     .parse(input)
 }
 
+thread_local! {
+    static STDLIB_NAMES: HashSet<String> =
+        stdlib_list().into_iter().map(|(name, _)| name).collect();
+}
+
+// d6, d(n), dx など。dot(...)のようにdで始まるstdlibの関数名はダイスにしない
 fn parse_one_dice(input: &str) -> IResult<&str, Expr> {
-    map(pair(one_of("dD"), parse_term0), |(_, e)| {
-        Expr::Op1(ExprOp1::OneDice, Box::new(e))
-    })
+    map(
+        preceded(
+            not(verify(parse_identifier, |name: &str| {
+                STDLIB_NAMES.with(|names| names.contains(name))
+            })),
+            pair(one_of("dD"), parse_term0),
+        ),
+        |(_, e)| Expr::Op1(ExprOp1::OneDice, Box::new(e)),
+    )
     .parse(input)
 }
 
@@ -504,7 +598,9 @@ fn parse_term1(input: &str) -> IResult<&str, Expr> {
 
 // 2: D 左結合
 fn parse_term2(input: &str) -> IResult<&str, Expr> {
-    parse_term_l(
+    parse_binop(
+        2,
+        Assoc::Left,
         |op| map(one_of("dD"), |_| ExprOp2::Dice).parse(op),
         parse_term1,
     )
@@ -513,7 +609,9 @@ fn parse_term2(input: &str) -> IResult<&str, Expr> {
 
 // 3: ^ ** (←同義) 右結合
 fn parse_term3(input: &str) -> IResult<&str, Expr> {
-    parse_term_r(
+    parse_binop(
+        3,
+        Assoc::Right,
         |op| map(alt((tag("^"), tag("**"))), |_| ExprOp2::Pow).parse(op),
         parse_term2,
     )
@@ -522,7 +620,9 @@ fn parse_term3(input: &str) -> IResult<&str, Expr> {
 
 // 4: * / % 左結合
 fn parse_term4(input: &str) -> IResult<&str, Expr> {
-    parse_term_l(
+    parse_binop(
+        4,
+        Assoc::Left,
         |op| {
             alt((
                 map(char('*'), |_| ExprOp2::Mul),
@@ -538,7 +638,9 @@ fn parse_term4(input: &str) -> IResult<&str, Expr> {
 
 // 5: + - 左結合
 fn parse_term5(input: &str) -> IResult<&str, Expr> {
-    parse_term_l(
+    parse_binop(
+        5,
+        Assoc::Left,
         |op| {
             alt((
                 map(char('+'), |_| ExprOp2::Add),
@@ -553,7 +655,9 @@ fn parse_term5(input: &str) -> IResult<&str, Expr> {
 
 // 6: > >= < <= == != 無結合
 fn parse_term6(input: &str) -> IResult<&str, Expr> {
-    parse_term_n(
+    parse_binop(
+        6,
+        Assoc::None,
         |op| {
             alt((
                 map(tag(">="), |_| ExprOp2::Ge),
@@ -572,7 +676,9 @@ fn parse_term6(input: &str) -> IResult<&str, Expr> {
 
 // 7: && || ^^ 左結合
 fn parse_term7(input: &str) -> IResult<&str, Expr> {
-    parse_term_l(
+    parse_binop(
+        7,
+        Assoc::Left,
         |op| {
             alt((
                 map(tag("&&"), |_| ExprOp2::AndL),
@@ -586,8 +692,50 @@ fn parse_term7(input: &str) -> IResult<&str, Expr> {
     .parse(input)
 }
 
+// 8: >> 左結合
+// f >> g は x => g(f(x))
+fn parse_term8(input: &str) -> IResult<&str, Expr> {
+    parse_binop(
+        8,
+        Assoc::Left,
+        |op| map(tag(">>"), |_| ExprOp2::Compose).parse(op),
+        parse_term7,
+    )
+    .parse(input)
+}
+
+// 9: |> 左結合
+// x |> f は f(x)
+fn parse_term9(input: &str) -> IResult<&str, Expr> {
+    parse_binop(
+        9,
+        Assoc::Left,
+        |op| map(tag("|>"), |_| ExprOp2::Pipe).parse(op),
+        parse_term8,
+    )
+    .parse(input)
+}
+
+// 括弧やリスト、ラムダ式などの入れ子の深さの上限。深すぎる入力でスタックを溢れさせない
+// デバッグビルドでは1段あたり50KBほど使うので、2MBのスレッドにも収まるようにしておく
+const MAX_NEST_DEPTH: usize = 32;
+
+thread_local! {
+    static NEST_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
 pub fn parse_expr(input: &str) -> IResult<&str, Expr> {
-    terminated(parse_term7, multispace0).parse(input)
+    let depth = NEST_DEPTH.get();
+    if depth >= MAX_NEST_DEPTH {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::TooLarge,
+        )));
+    }
+    NEST_DEPTH.set(depth + 1);
+    let result = terminated(parse_term9, multispace0).parse(input);
+    NEST_DEPTH.set(depth);
+    result
 }
 
 /*
@@ -669,13 +817,18 @@ pub enum EvalResult {
     FVal(f64),
     BVal(bool),
     SVal(String),
-    List(Vec<EvalResult>),
-    Object(HashMap<String, Box<EvalResult>>),
-    Closure(Vec<String>, Box<Expr>, Box<EvalContext>),
+    List(Vec<Self>),
+    Object(HashMap<String, Box<Self>>),
+    Closure(Arc<[Param]>, Arc<Expr>, Scope),
     FuncStdLib(EvalStdLibFun),
     FuncIf,
     FuncLazy,
     Lazy(Box<Expr>), //適用を受けるまで遅延
+    Duration(i64),   // ミリ秒
+    DateTime(chrono::DateTime<chrono::Utc>),
+    Partial(Box<Self>, Vec<Self>), // 引数が足りない標準関数の部分適用
+    Seq(Box<seq::Sequence>),       // 遅延シーケンス
+    Memo(Box<Self>, #[serde(skip)] memo::MemoCache, bool), // memo(f) fix済みならtrue
 }
 
 impl PartialEq for EvalResult {
@@ -690,56 +843,26 @@ impl PartialEq for EvalResult {
             (Self::Closure(a, b, _), Self::Closure(c, d, _)) => a == c && b == d,
             (Self::FuncStdLib(f1), Self::FuncStdLib(f2)) => f1 == f2,
             (Self::Lazy(e1), Self::Lazy(e2)) => e1 == e2,
+            (Self::Duration(d1), Self::Duration(d2)) => d1 == d2,
+            (Self::DateTime(t1), Self::DateTime(t2)) => t1 == t2,
+            (Self::Partial(f1, a1), Self::Partial(f2, a2)) => f1 == f2 && a1 == a2,
+            (Self::Seq(s1), Self::Seq(s2)) => s1 == s2,
+            (Self::Memo(f1, _, r1), Self::Memo(f2, _, r2)) => f1 == f2 && r1 == r2,
             _ => false,
         }
     }
 }
 
-fn show_context(ctx: &EvalContext) -> String {
-    let inner = ctx
-        .hashmap
-        .iter()
-        .map(|e| format!("{}: {}", e.key(), e.value()))
-        .collect::<Vec<String>>()
-        .join(", ");
-    format!("{{{inner}}}")
-}
-
+// クロージャの環境は表示せず、大きな値は省略する。{:#}なら折り返す
+// {}は省略せずに全部、{:#}は折り返して大きすぎる部分は省略する
 impl std::fmt::Display for EvalResult {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::IVal(i) => write!(f, "{i}"),
-            Self::FVal(v) => write!(f, "{v}"),
-            Self::BVal(b) => write!(f, "{b}"),
-            Self::SVal(s) => write!(f, "\"{s}\""),
-            Self::List(l) => write!(
-                f,
-                "[{}]",
-                l.iter()
-                    .map(|e| e.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            ),
-            Self::Object(o) => write!(
-                f,
-                "{{{}}}",
-                o.iter()
-                    .map(|(k, v)| format!("{k}: {v}"))
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            ),
-            Self::Closure(params, body, context) => write!(
-                f,
-                "({} => {})@{}",
-                params.join(", "),
-                body,
-                show_context(context)
-            ),
-            Self::FuncStdLib(fun) => write!(f, "{fun}"),
-            Self::FuncIf => write!(f, "if"),
-            Self::FuncLazy => write!(f, "lazy"),
-            Self::Lazy(body) => write!(f, "Lazy({body})"),
-        }
+        let opts = if f.alternate() {
+            PrettyOptions::WRAPPED
+        } else {
+            PrettyOptions::FULL
+        };
+        write!(f, "{}", pretty::show_val(self, &opts))
     }
 }
 
@@ -749,7 +872,6 @@ pub enum EvalError {
     InvalidDice,
     TooManyDice,
     UndefinedVar(String),
-    //MismatchedType(ResultType, ResultType),
     ArgCountMismatch(usize, usize),
     StepLimitExceeded,
     NotANumber(EvalResult),
@@ -758,8 +880,14 @@ pub enum EvalError {
     NotAnIndex(EvalResult),
     NotAnObject(EvalResult),
     OutOfRange,
+    ShapeMismatch(Vec<usize>, Vec<usize>),
+    SingularMatrix,
+    TypeMismatch(ResultType, ResultType), // 期待した型と実際の型
+    ImpureFunction(String),               // メモ化できない非決定的な関数
+    StackUnderflow,                       // vmの値スタックが空（コンパイルの誤り）
 }
 
+// エラーは返信に載るので、値は省略して表示する
 impl std::fmt::Display for EvalError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -772,15 +900,35 @@ impl std::fmt::Display for EvalError {
             }
             Self::StepLimitExceeded => write!(f, "Step limit exceeded"),
             Self::OutOfRange => write!(f, "Out of range"),
-            Self::NotANumber(e) => write!(f, "{e} is not a number"),
-            Self::NotAFunction(e) => write!(f, "{e} is not a function"),
-            Self::NotAList(e) => write!(f, "{e} is not a list"),
-            Self::NotAnIndex(e) => write!(f, "{e} is not an index"),
-            Self::NotAnObject(e) => write!(f, "{e} is not an object"),
+            Self::NotANumber(e) => {
+                write!(f, "{} is not a number", show_val(e, &PrettyOptions::FLAT))
+            }
+            Self::NotAFunction(e) => {
+                write!(f, "{} is not a function", show_val(e, &PrettyOptions::FLAT))
+            }
+            Self::NotAList(e) => write!(f, "{} is not a list", show_val(e, &PrettyOptions::FLAT)),
+            Self::NotAnIndex(e) => {
+                write!(f, "{} is not an index", show_val(e, &PrettyOptions::FLAT))
+            }
+            Self::NotAnObject(e) => {
+                write!(f, "{} is not an object", show_val(e, &PrettyOptions::FLAT))
+            }
+            Self::ShapeMismatch(a, b) => write!(f, "Shape mismatch: {a:?} and {b:?}"),
+            Self::SingularMatrix => write!(f, "Singular matrix"),
+            Self::TypeMismatch(expected, actual) => {
+                write!(f, "Type mismatch: expected {expected}, found {actual}")
+            }
+            Self::ImpureFunction(name) => write!(f, "{name} is impure and cannot be memoized"),
+            Self::StackUnderflow => write!(f, "Stack underflow"),
         }
     }
 }
 
+// 期待した型と違う値を渡されたとき
+const fn type_mismatch(expected: ResultType, actual: &EvalResult) -> EvalError {
+    EvalError::TypeMismatch(expected, ResultType::of(actual))
+}
+
 pub fn error_str((e, expr): (EvalError, Expr)) -> String {
     format!("Error: {e} at {expr}")
 }
@@ -837,16 +985,20 @@ fn val_numop2_if<F, G>(
     floatver: G,
 ) -> Result<(EvalResult, usize), (EvalError, Expr)>
 where
-    F: Fn(i64, i64) -> i64,
+    F: Fn(i64, i64) -> Option<i64>,
     G: Fn(f64, f64) -> f64,
 {
-    match (val_as_precise_int(val1), val_as_precise_int(val2)) {
-        (Some(i1), Some(i2)) => Ok((EvalResult::IVal(intver(i1, i2)), step + 1)),
-        _ => match (val_as_float(val1), val_as_float(val2)) {
-            (Some(f1), Some(f2)) => Ok((EvalResult::FVal(floatver(f1, f2)), step + 1)),
-            (Some(_), _) => Err((EvalError::NotANumber(val2.clone()), expr.clone())),
-            _ => Err((EvalError::NotANumber(val1.clone()), expr.clone())),
-        },
+    // 整数同士でもオーバーフローするなら浮動小数点数で計算する
+    if let Some(i) = val_as_precise_int(val1)
+        .zip(val_as_precise_int(val2))
+        .and_then(|(i1, i2)| intver(i1, i2))
+    {
+        return Ok((EvalResult::IVal(i), step + 1));
+    }
+    match (val_as_float(val1), val_as_float(val2)) {
+        (Some(f1), Some(f2)) => Ok((EvalResult::FVal(floatver(f1, f2)), step + 1)),
+        (Some(_), _) => Err((EvalError::NotANumber(val2.clone()), expr.clone())),
+        _ => Err((EvalError::NotANumber(val1.clone()), expr.clone())),
     }
 }
 
@@ -878,12 +1030,22 @@ pub fn val_as_str(s: &EvalResult) -> String {
     }
 }
 
+// 返信に載せる文字列。val_as_strと違い、大きすぎる値は省略する
+pub fn show_result(s: &EvalResult) -> String {
+    match s {
+        EvalResult::SVal(s) => s.clone(),
+        _ => show_val(s, &PrettyOptions::FLAT),
+    }
+}
+
 fn list_free_var(expr: &Expr) -> HashSet<String> {
     let result = match expr {
         Expr::IVal(_) => HashSet::new(),
         Expr::FVal(_) => HashSet::new(),
         Expr::BVal(_) => HashSet::new(),
         Expr::SVal(_) => HashSet::new(),
+        Expr::Duration(_) => HashSet::new(),
+        Expr::DateTime(_) => HashSet::new(),
         Expr::List(l) => l
             .iter()
             .map(list_free_var)
@@ -917,9 +1079,10 @@ fn list_free_var(expr: &Expr) -> HashSet<String> {
             .cloned()
             .collect(),
         Expr::Lambda(params, body) => list_free_var(body)
-            .difference(&params.iter().cloned().collect())
+            .difference(&params.iter().map(|p| p.name.clone()).collect())
             .cloned()
             .collect(),
+        Expr::Infix(symbol, e1, e2) => list_free_var(&infix::desugar(symbol, e1, e2)),
     };
     //println!("list_free_var: {}, result: {:?}", expr, result);
     result
@@ -927,12 +1090,16 @@ fn list_free_var(expr: &Expr) -> HashSet<String> {
 
 const STEP_LIMIT: usize = 10000;
 
+// 一度に作れるリストの要素数
+const MAX_LIST_LEN: usize = 1_000_000;
+
+// 木をたどって評価する。vmと同じ結果を返す参照実装
 fn eval_expr_ctx(
     expr: &Expr,
     step: usize,
     force_eval: bool,
     global_context: &EvalContext,
-    local_context: &EvalContext,
+    local_context: &Scope,
 ) -> Result<(EvalResult, usize), (EvalError, Expr)> {
     if step > STEP_LIMIT {
         return Err((EvalError::StepLimitExceeded, expr.clone()));
@@ -942,6 +1109,8 @@ fn eval_expr_ctx(
         Expr::FVal(f) => Ok((EvalResult::FVal(*f), step)),
         Expr::BVal(b) => Ok((EvalResult::BVal(*b), step)),
         Expr::SVal(s) => Ok((EvalResult::SVal(s.clone()), step)),
+        Expr::Duration(ms) => Ok((EvalResult::Duration(*ms), step)),
+        Expr::DateTime(dt) => Ok((EvalResult::DateTime(*dt), step)),
         Expr::List(l) => {
             let mut new_list = Vec::new();
             let mut steps = step + 1;
@@ -966,13 +1135,7 @@ fn eval_expr_ctx(
         }
         Expr::Get(e, k) => {
             let (val, next_step) = eval_expr_ctx(e, step + 1, true, global_context, local_context)?;
-            match val {
-                EvalResult::Object(o) => o.get(k).map_or_else(
-                    || Err((EvalError::UndefinedVar(k.clone()), expr.clone())),
-                    |result| Ok((*result.clone(), next_step + 1)),
-                ),
-                _ => Err((EvalError::NotAnObject(val), expr.clone())),
-            }
+            eval_get(expr, next_step, val, k)
         }
 
         Expr::At(e1, e2) => {
@@ -980,189 +1143,48 @@ fn eval_expr_ctx(
                 eval_expr_ctx(e1, step + 1, true, global_context, local_context)?;
             let (val2, next_step) =
                 eval_expr_ctx(e2, next_step + 1, true, global_context, local_context)?;
-            match val_as_list(&val1) {
-                Some(v) => {
-                    let index = match val_as_int(&val2) {
-                        Some(i) => i as isize,
-                        _ => return Err((EvalError::NotAnIndex(val2), expr.clone())),
-                    };
-                    if index < 0 || index >= v.len() as isize {
-                        return Err((EvalError::OutOfRange, expr.clone()));
-                    }
-                    Ok((v[index as usize].clone(), next_step + 1))
-                }
-                _ => Err((EvalError::NotAList(val1), expr.clone())),
-            }
+            eval_at(expr, next_step, val1, val2)
         }
 
-        Expr::Const(s) => local_context.get(s).map_or_else(
-            || {
-                global_context.get(s).map_or_else(
-                    || {
-                        match_const(s).map_or_else(
-                            || Err((EvalError::UndefinedVar(s.clone()), expr.clone())),
-                            |result| Ok((result, step)),
-                        )
-                    },
-                    |result| Ok((result.clone(), step)),
-                )
-            },
-            |result| Ok((result.clone(), step)),
-        ),
+        Expr::Const(s) => lookup_var(expr, step, s, global_context, local_context),
         Expr::Op1(op, e) => {
             let (val, next_step) = eval_expr_ctx(e, step + 1, true, global_context, local_context)?;
-            match op {
-                ExprOp1::Neg => {
-                    let fval = match val {
-                        EvalResult::IVal(i) => i as f64,
-                        EvalResult::FVal(f) => f,
-                        _ => return Err((EvalError::NotANumber(val), expr.clone())),
-                    };
-                    Ok((EvalResult::FVal(-fval), next_step + 1))
-                }
-                ExprOp1::OneDice => {
-                    let i = match val {
-                        EvalResult::IVal(i) => i,
-                        EvalResult::FVal(f) => f as i64,
-                        _ => return Err((EvalError::NotANumber(val), expr.clone())),
-                    };
-                    if i < 1 {
-                        return Err((EvalError::InvalidDice, expr.clone()));
-                    }
-                    let r = rand::rng().random_range(1..=i);
-                    Ok((EvalResult::IVal(r), next_step + 1))
-                }
-                ExprOp1::NotL => {
-                    let bval = match val {
-                        EvalResult::IVal(i) => i != 0,
-                        EvalResult::FVal(f) => f != 0.0,
-                        EvalResult::BVal(b) => b,
-                        _ => return Err((EvalError::NotANumber(val), expr.clone())),
-                    };
-                    Ok((EvalResult::BVal(!bval), next_step + 1))
-                }
-            }
+            eval_op1(expr, next_step, *op, val)
         }
         Expr::Op2(op, e1, e2) => {
             let (val1, next_step) =
                 eval_expr_ctx(e1, step + 1, true, global_context, local_context)?;
             let (val2, next_step) =
                 eval_expr_ctx(e2, next_step + 1, true, global_context, local_context)?;
-
-            let shortcircuit: Option<Result<(EvalResult, usize), (EvalError, Expr)>> = match op {
-                ExprOp2::Add => {
-                    if is_str(val1.clone()) || is_str(val2.clone()) {
-                        let str1 = match val1.clone() {
-                            EvalResult::SVal(s) => s,
-                            _ => format!("{val1}"),
-                        };
-                        let str2 = match val2.clone() {
-                            EvalResult::SVal(s) => s,
-                            _ => format!("{val2}"),
-                        };
-                        Some(Ok((
-                            EvalResult::SVal(format!("{str1}{str2}")),
-                            next_step + 1,
-                        )))
-                    } else {
-                        match (val1.clone(), val2.clone()) {
-                            (EvalResult::List(l1), EvalResult::List(l2)) => {
-                                let mut new_list = Vec::new();
-                                for e in l1 {
-                                    new_list.push(e.clone());
-                                }
-                                for e in l2 {
-                                    new_list.push(e.clone());
-                                }
-                                Some(Ok((EvalResult::List(new_list), next_step + 1)))
-                            }
-                            _ => None,
-                        }
-                    }
-                }
-                ExprOp2::Eq => Some(Ok((EvalResult::BVal(deep_eq(&val1, &val2)), next_step + 1))),
-                ExprOp2::Ne => Some(Ok((
-                    EvalResult::BVal(!deep_eq(&val1, &val2)),
+            if *op == ExprOp2::Pipe {
+                eval_apply_tree(
+                    expr,
                     next_step + 1,
-                ))),
-                _ => None,
-            };
-
-            match shortcircuit {
-                Some(Ok(v)) => Ok(v),
-                Some(Err(e)) => Err(e),
-                None => {
-                    let Some(fval1) = val_as_float(&val1) else {
-                        return Err((EvalError::NotANumber(val1), expr.clone()));
-                    };
-
-                    let Some(fval2) = val_as_float(&val2) else {
-                        return Err((EvalError::NotANumber(val2), expr.clone()));
-                    };
-
-                    let bval1 = fval1 != 0.0;
-                    let bval2 = fval2 != 0.0;
-
-                    match op {
-                        ExprOp2::Add => val_numop2_if(
-                            expr,
-                            step,
-                            &val1,
-                            &val2,
-                            |i1, i2| i1 + i2,
-                            |f1, f2| f1 + f2,
-                        ),
-                        ExprOp2::Sub => val_numop2_if(
-                            expr,
-                            step,
-                            &val1,
-                            &val2,
-                            |i1, i2| i1 - i2,
-                            |f1, f2| f1 - f2,
-                        ),
-                        ExprOp2::Mul => val_numop2_if(
-                            expr,
-                            step,
-                            &val1,
-                            &val2,
-                            |i1, i2| i1 * i2,
-                            |f1, f2| f1 * f2,
-                        ),
-                        ExprOp2::Mod => val_numop2_f(expr, step, &val1, &val2, |f1, f2| f1 % f2),
-                        ExprOp2::Pow => val_numop2_f(expr, step, &val1, &val2, f64::powf),
-                        ExprOp2::Div => val_numop2_f(expr, step, &val1, &val2, |f1, f2| f1 / f2),
-                        ExprOp2::Dice => {
-                            let num = fval1 as i64;
-                            let size = fval2 as i64;
-                            if num < 0 {
-                                return Err((EvalError::NegativeDice, expr.clone()));
-                            }
-                            if size < 1 {
-                                return Err((EvalError::InvalidDice, expr.clone()));
-                            }
-                            if num > 10000 {
-                                return Err((EvalError::TooManyDice, expr.clone()));
-                            }
-                            let mut sum = 0;
-                            for _ in 0..num {
-                                sum += rand::rng().random_range(1..=size);
-                            }
-                            Ok((EvalResult::IVal(sum), next_step + 1))
-                        }
-                        ExprOp2::Gt => Ok((EvalResult::BVal(fval1 > fval2), next_step + 1)),
-                        ExprOp2::Ge => Ok((EvalResult::BVal(fval1 >= fval2), next_step + 1)),
-                        ExprOp2::Lt => Ok((EvalResult::BVal(fval1 < fval2), next_step + 1)),
-                        ExprOp2::Le => Ok((EvalResult::BVal(fval1 <= fval2), next_step + 1)),
-                        ExprOp2::Eq => Ok((EvalResult::BVal(fval1 == fval2), next_step + 1)),
-                        ExprOp2::Ne => Ok((EvalResult::BVal(fval1 != fval2), next_step + 1)),
-
-                        ExprOp2::AndL => Ok((EvalResult::BVal(bval1 && bval2), next_step + 1)),
-                        ExprOp2::OrL => Ok((EvalResult::BVal(bval1 || bval2), next_step + 1)),
-                        ExprOp2::XorL => Ok((EvalResult::BVal(bval1 ^ bval2), next_step + 1)),
-                    }
-                }
+                    global_context,
+                    local_context,
+                    val2,
+                    vec![val1],
+                )
+            } else if let Some(method) = infix::overload(*op, &val1, &val2) {
+                eval_apply_tree(
+                    expr,
+                    next_step + 1,
+                    global_context,
+                    local_context,
+                    method,
+                    vec![val1, val2],
+                )
+            } else {
+                eval_op2(expr, next_step, *op, &val1, &val2)
             }
         }
+        Expr::Infix(symbol, e1, e2) => eval_expr_ctx(
+            &infix::desugar(symbol, e1, e2),
+            step,
+            false,
+            global_context,
+            local_context,
+        ),
         Expr::Apply(fun, args) => {
             let (vfun, next_step) =
                 eval_expr_ctx(fun, step + 1, true, global_context, local_context)?;
@@ -1215,24 +1237,11 @@ fn eval_expr_ctx(
                         vargs.push(val);
                         steps = next_step;
                     }
-                    eval_apply(expr, steps, global_context, local_context, vfun, vargs)
-                }
-            }
-        }
-        Expr::Lambda(name, body) => {
-            let free_vars = list_free_var(body);
-            let captured = EvalContext::new();
-            for varname in free_vars {
-                if let Some(val) = local_context.get(&varname) {
-                    captured.insert(varname, val.clone());
+                    eval_apply_tree(expr, steps, global_context, local_context, vfun, vargs)
                 }
             }
-
-            Ok((
-                EvalResult::Closure(name.clone(), body.clone(), Box::new(captured)),
-                step,
-            ))
         }
+        Expr::Lambda(params, body) => Ok((make_closure(params, body, local_context), step)),
     };
 
     if force_eval {
@@ -1247,96 +1256,511 @@ fn eval_expr_ctx(
     }
 }
 
-pub fn deep_eq(a: &EvalResult, b: &EvalResult) -> bool {
-    match (a, b) {
-        (EvalResult::List(l1), EvalResult::List(l2)) => {
-            if l1.len() != l2.len() {
-                return false;
-            }
-            for (e1, e2) in l1.iter().zip(l2.iter()) {
-                if !deep_eq(e1, e2) {
-                    return false;
-                }
-            }
-            true
-        }
-        (EvalResult::Object(o1), EvalResult::Object(o2)) => {
-            if o1.len() != o2.len() {
-                return false;
-            }
-            for (k, v1) in o1.iter() {
-                if let Some(v2) = o2.get(k) {
-                    if !deep_eq(v1, v2) {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            }
-            true
-        }
-        (EvalResult::SVal(s1), EvalResult::SVal(s2)) => s1 == s2,
-        _ => {
-            let Some(fval1) = val_as_float(a) else {
-                return false;
-            };
-
-            let Some(fval2) = val_as_float(b) else {
-                return false;
-            };
+// 以下、木の評価とvmで共有する各ノードの意味
 
-            fval1 == fval2
-        }
-    }
+fn lookup_var(
+    expr: &Expr,
+    step: usize,
+    name: &String,
+    global_context: &EvalContext,
+    local_context: &Scope,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    local_context.get(name).map_or_else(
+        || {
+            global_context.get(name).map_or_else(
+                || {
+                    match_const(name).map_or_else(
+                        || Err((EvalError::UndefinedVar(name.clone()), expr.clone())),
+                        |result| Ok((result, step)),
+                    )
+                },
+                |result| Ok((result.clone(), step)),
+            )
+        },
+        |result| Ok((result.clone(), step)),
+    )
 }
 
-pub fn eval_apply(
+fn eval_get(
     expr: &Expr,
-    steps: usize,
-    global_context: &EvalContext,
-    local_context: &EvalContext,
-    func: EvalResult,
-    args: Vec<EvalResult>,
+    step: usize,
+    val: EvalResult,
+    key: &str,
 ) -> Result<(EvalResult, usize), (EvalError, Expr)> {
-    if steps > STEP_LIMIT {
-        return Err((EvalError::StepLimitExceeded, expr.clone()));
+    match val {
+        EvalResult::Object(o) => o.get(key).map_or_else(
+            || Err((EvalError::UndefinedVar(key.to_owned()), expr.clone())),
+            |result| Ok((*result.clone(), step + 1)),
+        ),
+        _ => Err((EvalError::NotAnObject(val), expr.clone())),
     }
+}
 
-    match func {
-        EvalResult::Closure(params, body, ctx) => {
-            if args.len() != params.len() {
+fn eval_at(
+    expr: &Expr,
+    step: usize,
+    val1: EvalResult,
+    val2: EvalResult,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    match val_as_list(&val1) {
+        Some(v) => {
+            let index = match val_as_int(&val2) {
+                Some(i) => i as isize,
+                _ => return Err((EvalError::NotAnIndex(val2), expr.clone())),
+            };
+            if index < 0 || index >= v.len() as isize {
+                return Err((EvalError::OutOfRange, expr.clone()));
+            }
+            Ok((v[index as usize].clone(), step + 1))
+        }
+        _ => Err((EvalError::NotAList(val1), expr.clone())),
+    }
+}
+
+fn eval_op1(
+    expr: &Expr,
+    step: usize,
+    op: ExprOp1,
+    val: EvalResult,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    match op {
+        ExprOp1::Neg => {
+            if let EvalResult::Duration(ms) = val {
+                return ms.checked_neg().map_or_else(
+                    || Err((EvalError::OutOfRange, expr.clone())),
+                    |ms| Ok((EvalResult::Duration(ms), step + 1)),
+                );
+            }
+            let fval = match val {
+                EvalResult::IVal(i) => i as f64,
+                EvalResult::FVal(f) => f,
+                _ => return Err((EvalError::NotANumber(val), expr.clone())),
+            };
+            Ok((EvalResult::FVal(-fval), step + 1))
+        }
+        ExprOp1::OneDice => {
+            let i = match val {
+                EvalResult::IVal(i) => i,
+                EvalResult::FVal(f) => f as i64,
+                _ => return Err((EvalError::NotANumber(val), expr.clone())),
+            };
+            if i < 1 {
+                return Err((EvalError::InvalidDice, expr.clone()));
+            }
+            let r = rand::rng().random_range(1..=i);
+            Ok((EvalResult::IVal(r), step + 1))
+        }
+        ExprOp1::NotL => {
+            let bval = match val {
+                EvalResult::IVal(i) => i != 0,
+                EvalResult::FVal(f) => f != 0.0,
+                EvalResult::BVal(b) => b,
+                _ => return Err((EvalError::NotANumber(val), expr.clone())),
+            };
+            Ok((EvalResult::BVal(!bval), step + 1))
+        }
+    }
+}
+
+// |> は関数適用なので呼び出し側で扱う
+fn eval_op2(
+    expr: &Expr,
+    step: usize,
+    op: ExprOp2,
+    val1: &EvalResult,
+    val2: &EvalResult,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    let shortcircuit: Option<Result<(EvalResult, usize), (EvalError, Expr)>> = match op {
+        ExprOp2::Add => {
+            if is_str(val1.clone()) || is_str(val2.clone()) {
+                let str1 = match val1.clone() {
+                    EvalResult::SVal(s) => s,
+                    _ => format!("{val1}"),
+                };
+                let str2 = match val2.clone() {
+                    EvalResult::SVal(s) => s,
+                    _ => format!("{val2}"),
+                };
+                Some(Ok((EvalResult::SVal(format!("{str1}{str2}")), step + 1)))
+            } else {
+                match (val1.clone(), val2.clone()) {
+                    (EvalResult::List(l1), EvalResult::List(l2)) => {
+                        let mut new_list = Vec::new();
+                        for e in l1 {
+                            new_list.push(e.clone());
+                        }
+                        for e in l2 {
+                            new_list.push(e.clone());
+                        }
+                        Some(Ok((EvalResult::List(new_list), step + 1)))
+                    }
+                    _ => None,
+                }
+            }
+        }
+        ExprOp2::Eq => Some(Ok((EvalResult::BVal(deep_eq(val1, val2)), step + 1))),
+        ExprOp2::Ne => Some(Ok((EvalResult::BVal(!deep_eq(val1, val2)), step + 1))),
+        ExprOp2::Compose => Some(Ok((compose(val1.clone(), val2.clone()), step + 1))),
+        _ => None,
+    };
+
+    match shortcircuit {
+        Some(Ok(v)) => Ok(v),
+        Some(Err(e)) => Err(e),
+        None => {
+            // リストとスカラーの四則演算は要素ごとに行う
+            // リスト同士の + は従来通り連結とする
+            let is_list = |v: &EvalResult| matches!(v, EvalResult::List(_));
+            if matches!(
+                op,
+                ExprOp2::Add | ExprOp2::Sub | ExprOp2::Mul | ExprOp2::Div
+            ) && (is_list(val1) || is_list(val2))
+            {
+                val_broadcast_op2(expr, step, op, val1, val2)
+            } else {
+                val_op2_scalar(expr, step, step, op, val1, val2)
+            }
+        }
+    }
+}
+
+// ラムダ式の自由変数をその場の環境から捕まえる
+fn make_closure(params: &[Param], body: &Expr, local_context: &Scope) -> EvalResult {
+    let free_vars = list_free_var(body);
+    let captured = local_context.capture(&free_vars);
+    EvalResult::Closure(Arc::from(params), Arc::new(body.clone()), captured)
+}
+
+// クロージャに引数を束縛した結果
+enum ClosureCall {
+    // 引数が足りないので、渡された分だけ束縛したクロージャ
+    Partial(EvalResult),
+    // 引数の名前、本体、捕まえた環境と引数
+    Exact(Arc<[Param]>, Arc<Expr>, Scope, Vec<EvalResult>),
+}
+
+fn bind_closure(
+    expr: &Expr,
+    params: Arc<[Param]>,
+    body: Arc<Expr>,
+    ctx: Scope,
+    args: Vec<EvalResult>,
+) -> Result<ClosureCall, (EvalError, Expr)> {
+    // 型注釈のある引数を検査する
+    for (param, arg) in params.iter().zip(&args) {
+        if let Some(ty) = param.ty {
+            if !ty.accepts(arg) {
+                return Err((
+                    EvalError::TypeMismatch(ty, ResultType::of(arg)),
+                    expr.clone(),
+                ));
+            }
+        }
+    }
+    if !args.is_empty() && args.len() < params.len() {
+        let (bound, rest) = params.split_at(args.len());
+        let ctx = ctx.extend(bound.iter().map(|p| p.name.clone()).zip(args));
+        return Ok(ClosureCall::Partial(EvalResult::Closure(
+            Arc::from(rest),
+            body,
+            ctx,
+        )));
+    }
+    if args.len() != params.len() {
+        return Err((
+            EvalError::ArgCountMismatch(args.len(), params.len()),
+            expr.clone(),
+        ));
+    }
+    Ok(ClosureCall::Exact(params, body, ctx, args))
+}
+
+// スカラー同士の二項演算
+fn val_op2_scalar(
+    expr: &Expr,
+    step: usize,
+    next_step: usize,
+    op: ExprOp2,
+    val1: &EvalResult,
+    val2: &EvalResult,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    if let Some(result) = datetime::val_time_op2(op, val1, val2) {
+        return result
+            .map(|val| (val, next_step + 1))
+            .map_err(|e| (e, expr.clone()));
+    }
+
+    let Some(fval1) = val_as_float(val1) else {
+        return Err((EvalError::NotANumber(val1.clone()), expr.clone()));
+    };
+
+    let Some(fval2) = val_as_float(val2) else {
+        return Err((EvalError::NotANumber(val2.clone()), expr.clone()));
+    };
+
+    let bval1 = fval1 != 0.0;
+    let bval2 = fval2 != 0.0;
+
+    match op {
+        ExprOp2::Add => val_numop2_if(expr, step, val1, val2, i64::checked_add, |f1, f2| f1 + f2),
+        ExprOp2::Sub => val_numop2_if(expr, step, val1, val2, i64::checked_sub, |f1, f2| f1 - f2),
+        ExprOp2::Mul => val_numop2_if(expr, step, val1, val2, i64::checked_mul, |f1, f2| f1 * f2),
+        ExprOp2::Mod => val_numop2_f(expr, step, val1, val2, |f1, f2| f1 % f2),
+        ExprOp2::Pow => val_numop2_f(expr, step, val1, val2, f64::powf),
+        ExprOp2::Div => val_numop2_f(expr, step, val1, val2, |f1, f2| f1 / f2),
+        ExprOp2::Dice => {
+            let num = fval1 as i64;
+            let size = fval2 as i64;
+            if num < 0 {
+                return Err((EvalError::NegativeDice, expr.clone()));
+            }
+            if size < 1 {
+                return Err((EvalError::InvalidDice, expr.clone()));
+            }
+            if num > 10000 {
+                return Err((EvalError::TooManyDice, expr.clone()));
+            }
+            // 大きな面のダイスを何個も振るとi64をあふれるので、i128で足す
+            let mut sum: i128 = 0;
+            for _ in 0..num {
+                sum += i128::from(rand::rng().random_range(1..=size));
+            }
+            let result = i64::try_from(sum).map_or(EvalResult::FVal(sum as f64), EvalResult::IVal);
+            Ok((result, next_step + 1))
+        }
+        ExprOp2::Gt => Ok((EvalResult::BVal(fval1 > fval2), next_step + 1)),
+        ExprOp2::Ge => Ok((EvalResult::BVal(fval1 >= fval2), next_step + 1)),
+        ExprOp2::Lt => Ok((EvalResult::BVal(fval1 < fval2), next_step + 1)),
+        ExprOp2::Le => Ok((EvalResult::BVal(fval1 <= fval2), next_step + 1)),
+        ExprOp2::Eq => Ok((EvalResult::BVal(fval1 == fval2), next_step + 1)),
+        ExprOp2::Ne => Ok((EvalResult::BVal(fval1 != fval2), next_step + 1)),
+
+        ExprOp2::AndL => Ok((EvalResult::BVal(bval1 && bval2), next_step + 1)),
+        ExprOp2::OrL => Ok((EvalResult::BVal(bval1 || bval2), next_step + 1)),
+        ExprOp2::XorL => Ok((EvalResult::BVal(bval1 ^ bval2), next_step + 1)),
+        ExprOp2::Pipe | ExprOp2::Compose => unreachable!("handled in eval_expr_ctx"),
+    }
+}
+
+// f >> g を x => g(f(x)) のクロージャにする
+fn compose(f: EvalResult, g: EvalResult) -> EvalResult {
+    let ctx = Scope::new().extend([("_f".to_owned(), f), ("_g".to_owned(), g)]);
+    let var = |name: &str| Expr::Const(name.to_owned());
+    let body = Expr::Apply(
+        Box::new(var("_g")),
+        vec![Expr::Apply(Box::new(var("_f")), vec![var("_x")])],
+    );
+    EvalResult::Closure(Arc::from([Param::new("_x")]), Arc::new(body), ctx)
+}
+
+// 四則演算をリストの要素ごとに適用する
+// 形の合わないリスト同士はShapeMismatch
+fn val_broadcast_op2(
+    expr: &Expr,
+    step: usize,
+    op: ExprOp2,
+    val1: &EvalResult,
+    val2: &EvalResult,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    let mut step = step;
+    let mut new_list = Vec::new();
+    match (val1, val2) {
+        (EvalResult::List(l1), EvalResult::List(l2)) => {
+            if l1.len() != l2.len() {
                 return Err((
-                    EvalError::ArgCountMismatch(args.len(), params.len()),
+                    EvalError::ShapeMismatch(linalg::val_shape(val1), linalg::val_shape(val2)),
                     expr.clone(),
                 ));
             }
+            for (e1, e2) in l1.iter().zip(l2.iter()) {
+                let (val, next_step) = val_broadcast_op2(expr, step, op, e1, e2)?;
+                new_list.push(val);
+                step = next_step;
+            }
+        }
+        (EvalResult::List(l1), _) => {
+            for e1 in l1 {
+                let (val, next_step) = val_broadcast_op2(expr, step, op, e1, val2)?;
+                new_list.push(val);
+                step = next_step;
+            }
+        }
+        (_, EvalResult::List(l2)) => {
+            for e2 in l2 {
+                let (val, next_step) = val_broadcast_op2(expr, step, op, val1, e2)?;
+                new_list.push(val);
+                step = next_step;
+            }
+        }
+        _ => return val_op2_scalar(expr, step, step, op, val1, val2),
+    }
+    Ok((EvalResult::List(new_list), step + 1))
+}
+
+pub fn deep_eq(a: &EvalResult, b: &EvalResult) -> bool {
+    match (a, b) {
+        (EvalResult::List(l1), EvalResult::List(l2)) => {
+            if l1.len() != l2.len() {
+                return false;
+            }
+            for (e1, e2) in l1.iter().zip(l2.iter()) {
+                if !deep_eq(e1, e2) {
+                    return false;
+                }
+            }
+            true
+        }
+        (EvalResult::Object(o1), EvalResult::Object(o2)) => {
+            if o1.len() != o2.len() {
+                return false;
+            }
+            for (k, v1) in o1.iter() {
+                if let Some(v2) = o2.get(k) {
+                    if !deep_eq(v1, v2) {
+                        return false;
+                    }
+                } else {
+                    return false;
+                }
+            }
+            true
+        }
+        (EvalResult::SVal(s1), EvalResult::SVal(s2)) => s1 == s2,
+        (EvalResult::Duration(d1), EvalResult::Duration(d2)) => d1 == d2,
+        (EvalResult::DateTime(t1), EvalResult::DateTime(t2)) => t1 == t2,
+        (EvalResult::Duration(_) | EvalResult::DateTime(_), _)
+        | (_, EvalResult::Duration(_) | EvalResult::DateTime(_)) => false,
+        _ => {
+            let Some(fval1) = val_as_float(a) else {
+                return false;
+            };
+
+            let Some(fval2) = val_as_float(b) else {
+                return false;
+            };
+
+            fval1 == fval2
+        }
+    }
+}
+
+// 関数適用をどちらの評価器で行うか。stdlibのコールバックも呼び出し元と同じ評価器で評価する
+// vmなら実行中のvmにフレームを積んで呼び出す
+pub enum EvalMode<'m, 'a> {
+    Tree,
+    Vm(&'m mut vm::Vm<'a>),
+}
+
+// クロージャはmodeに応じてvmか木の評価で評価する
+pub fn eval_apply(
+    expr: &Expr,
+    steps: usize,
+    mode: &mut EvalMode,
+    global_context: &EvalContext,
+    local_context: &Scope,
+    func: EvalResult,
+    args: Vec<EvalResult>,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    if steps > STEP_LIMIT {
+        return Err((EvalError::StepLimitExceeded, expr.clone()));
+    }
 
-            let new_context = ctx;
-            for (param, argval) in params.iter().zip(args.iter()) {
-                new_context.insert(param.clone(), argval.clone());
+    match func {
+        EvalResult::Closure(..) => match mode {
+            EvalMode::Vm(vm) => vm.apply(expr, steps, func, args),
+            EvalMode::Tree => {
+                eval_apply_tree(expr, steps, global_context, local_context, func, args)
             }
-            let steps = steps + 1;
-            eval_expr_ctx(&body, steps, false, global_context, &new_context)
+        },
+        EvalResult::FuncStdLib(func) => {
+            // 必要な数より少ない引数なら、渡された分だけ覚えておく
+            if !args.is_empty() && args.len() < func.arity() {
+                return Ok((
+                    EvalResult::Partial(Box::new(EvalResult::FuncStdLib(func)), args),
+                    steps + 1,
+                ));
+            }
+            eval_stdlib(
+                expr,
+                steps + 1,
+                mode,
+                global_context,
+                local_context,
+                func,
+                args,
+            )
         }
-        EvalResult::FuncStdLib(libfun) => {
-            let steps = steps + 1;
-            eval_stdlib(expr, steps, global_context, local_context, libfun, args)
+        EvalResult::Memo(..) => memo::call(
+            expr,
+            steps + 1,
+            mode,
+            global_context,
+            local_context,
+            &func,
+            args,
+        ),
+        EvalResult::Partial(func, bound) => {
+            let mut all_args = bound;
+            all_args.extend(args);
+            eval_apply(
+                expr,
+                steps + 1,
+                mode,
+                global_context,
+                local_context,
+                *func,
+                all_args,
+            )
         }
         _ => Err((EvalError::NotAFunction(func), expr.clone())),
     }
 }
 
+// 木の評価でのクロージャ適用。それ以外はeval_applyと同じ
+fn eval_apply_tree(
+    expr: &Expr,
+    steps: usize,
+    global_context: &EvalContext,
+    local_context: &Scope,
+    func: EvalResult,
+    args: Vec<EvalResult>,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    if steps > STEP_LIMIT {
+        return Err((EvalError::StepLimitExceeded, expr.clone()));
+    }
+
+    let EvalResult::Closure(params, body, ctx) = func else {
+        return eval_apply(
+            expr,
+            steps,
+            &mut EvalMode::Tree,
+            global_context,
+            local_context,
+            func,
+            args,
+        );
+    };
+    match bind_closure(expr, params, body, ctx, args)? {
+        ClosureCall::Partial(val) => Ok((val, steps + 1)),
+        ClosureCall::Exact(params, body, ctx, args) => {
+            let new_context = ctx.extend(params.iter().map(|p| p.name.clone()).zip(args));
+            eval_expr_ctx(&body, steps + 1, false, global_context, &new_context)
+        }
+    }
+}
+
 #[allow(clippy::cognitive_complexity)]
 pub fn eval_stdlib(
     expr: &Expr,
     step: usize,
+    mode: &mut EvalMode,
     global_context: &EvalContext,
-    local_context: &EvalContext,
+    local_context: &Scope,
     func: EvalStdLibFun,
     args: Vec<EvalResult>,
 ) -> Result<(EvalResult, usize), (EvalError, Expr)> {
     let libfun: LibFun = get_libfun(func);
-    (libfun.body)(expr, step, global_context, local_context, args)
+    (libfun.body)(expr, step, mode, global_context, local_context, args)
 }
 
 pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
@@ -1346,7 +1770,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`sin(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("sin(0)", "0")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1361,7 +1787,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`cos(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("cos(0)", "1")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1376,7 +1804,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`tan(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("tan(0)", "0")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1391,7 +1821,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec!["loge".to_owned(), "logE".to_owned()],
             usage: "`ln(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("ln(1)", "0")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1406,7 +1838,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec!["log".to_owned()],
             usage: "`log10(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("log10(1000)", "3")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1421,7 +1855,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec!["lg".to_owned(), "lb".to_owned()],
             usage: "`log2(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("log2(8)", "3")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1436,7 +1872,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`abs(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("abs(-3)", "3")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1456,7 +1894,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`floor(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("floor(2.7)", "2")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1471,7 +1911,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`ceil(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("ceil(2.1)", "3")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1486,7 +1928,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`round(x)`".to_owned(),
             note: "".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("round(2.5)", "3")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1501,7 +1945,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`urand()`".to_owned(),
             note: "0~1の一様乱数を生成します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Random,
+            examples: &[("urand() < 1", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.is_empty() {
                     Ok((
                         EvalResult::FVal(rand::rng().random_range(0.0..1.0)),
@@ -1517,7 +1963,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`grand()`".to_owned(),
             note: "標準正規分布に従う乱数を生成します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Random,
+            examples: &[("isnum(grand())", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.is_empty() {
                     Ok((
                         EvalResult::FVal(StandardNormal.sample(&mut rand::rng())),
@@ -1532,11 +1980,17 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             name: "map".to_owned(),
             alias: vec![],
             usage: "`map(f, list)`".to_owned(),
-            note: "リストの各要素に関数を適用します".to_owned(),
-            body: Box::new(|expr, step, global_context, local_context, args| {
+            note: "リストの各要素に関数を適用します。シーケンスには遅延して適用します".to_owned(),
+            category: HelpCategory::List,
+            examples: &[("map(x => x * 2, [1, 2, 3])", "[2, 4, 6]")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 2 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
                 }
+                if let EvalResult::Seq(s) = &args[1] {
+                    let mapped = seq::Sequence::Map(args[0].clone(), s.clone());
+                    return Ok((EvalResult::Seq(Box::new(mapped)), step + 1));
+                }
                 match val_as_list(&args[1]) {
                     Some(l) => {
                         let mut new_list = Vec::new();
@@ -1545,6 +1999,7 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                             let (val, next_step) = eval_apply(
                                 expr,
                                 step + 1,
+                                mode,
                                 global_context,
                                 local_context,
                                 args[0].clone(),
@@ -1564,7 +2019,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec!["generatei".to_owned()],
             usage: "`geni(f, n)`".to_owned(),
             note: "fに0~(n-1)を適用した結果を要素とするリストを生成します".to_owned(),
-            body: Box::new(|expr, step, global_context, local_context, args| {
+            category: HelpCategory::List,
+            examples: &[("geni(i => i * i, 4)", "[0, 1, 4, 9]")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 2 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
                 }
@@ -1576,6 +2033,7 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                             let (val, next_step) = eval_apply(
                                 expr,
                                 step + 1,
+                                mode,
                                 global_context,
                                 local_context,
                                 args[0].clone(),
@@ -1595,7 +2053,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`repeat(f, n)`".to_owned(),
             note: "fの結果をn個含むリストを生成します".to_owned(),
-            body: Box::new(|expr, step, global_context, local_context, args| {
+            category: HelpCategory::List,
+            examples: &[("repeat(() => \"a\", 3)", "[\"a\", \"a\", \"a\"]")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 2 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
                 }
@@ -1607,6 +2067,7 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                             let (val, next_step) = eval_apply(
                                 expr,
                                 step + 1,
+                                mode,
                                 global_context,
                                 local_context,
                                 args[0].clone(),
@@ -1625,11 +2086,18 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             name: "filter".to_owned(),
             alias: vec![],
             usage: "`filter(f, list)`".to_owned(),
-            note: "fがtruthyな値を返す要素のみを含むリストを生成します".to_owned(),
-            body: Box::new(|expr, step, global_context, local_context, args| {
+            note: "fがtruthyな値を返す要素のみを含むリストを生成します。シーケンスからは遅延して取り出します"
+                .to_owned(),
+            category: HelpCategory::List,
+            examples: &[("filter(x => x % 2 == 1, range(6))", "[1, 3, 5]")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 2 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
                 }
+                if let EvalResult::Seq(s) = &args[1] {
+                    let filtered = seq::Sequence::Filter(args[0].clone(), s.clone());
+                    return Ok((EvalResult::Seq(Box::new(filtered)), step + 1));
+                }
                 match val_as_list(&args[1]) {
                     Some(l) => {
                         let mut new_list = Vec::new();
@@ -1637,6 +2105,7 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                             let (val, _) = eval_apply(
                                 expr,
                                 step + 1,
+                                mode,
                                 global_context,
                                 local_context,
                                 args[0].clone(),
@@ -1658,10 +2127,24 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             usage: "`zipWith(f, list1, list2)`".to_owned(),
             note: "fをlist1とlist2の対応する要素に適用した結果を要素とするリストを生成します"
                 .to_owned(),
-            body: Box::new(|expr, step, global_context, local_context, args| {
+            category: HelpCategory::List,
+            examples: &[("zipWith((a, b) => a + b, [1, 2], [10, 20])", "[11, 22]")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 3 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 3), expr.clone()));
                 }
+                // どちらかがシーケンスなら結果もシーケンスにする
+                if seq::is_seq(&args[1]) || seq::is_seq(&args[2]) {
+                    return match (seq::val_as_seq(&args[1]), seq::val_as_seq(&args[2])) {
+                        (Some(s1), Some(s2)) => {
+                            let zipped =
+                                seq::Sequence::ZipWith(args[0].clone(), Box::new(s1), Box::new(s2));
+                            Ok((EvalResult::Seq(Box::new(zipped)), step + 1))
+                        }
+                        (Some(_), _) => Err((EvalError::NotAList(args[2].clone()), expr.clone())),
+                        _ => Err((EvalError::NotAList(args[1].clone()), expr.clone())),
+                    };
+                }
                 match (val_as_list(&args[1]), val_as_list(&args[2])) {
                     (Some(l1), Some(l2)) => {
                         let mut new_list = Vec::new();
@@ -1670,6 +2153,7 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                             let (val, next_step) = eval_apply(
                                 expr,
                                 step + 1,
+                                mode,
                                 global_context,
                                 local_context,
                                 args[0].clone(),
@@ -1690,7 +2174,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`foldl(f, init, list)`".to_owned(),
             note: "initを初期値としてlistをfで左から畳み込みます".to_owned(),
-            body: Box::new(|expr, step, global_context, local_context, args| {
+            category: HelpCategory::List,
+            examples: &[("foldl((acc, x) => acc * 10 + x, 0, [1, 2, 3])", "123")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 3 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 3), expr.clone()));
                 }
@@ -1702,6 +2188,7 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                             let (val, next_step) = eval_apply(
                                 expr,
                                 step,
+                                mode,
                                 global_context,
                                 local_context,
                                 args[0].clone(),
@@ -1721,7 +2208,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`foldr(f, init, list)`".to_owned(),
             note: "initを初期値としてlistをfで右から畳み込みます".to_owned(),
-            body: Box::new(|expr, step, global_context, local_context, args| {
+            category: HelpCategory::List,
+            examples: &[("foldr((x, acc) => acc * 10 + x, 0, [1, 2, 3])", "321")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 3 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 3), expr.clone()));
                 }
@@ -1733,6 +2222,7 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                             let (val, next_step) = eval_apply(
                                 expr,
                                 step,
+                                mode,
                                 global_context,
                                 local_context,
                                 args[0].clone(),
@@ -1751,8 +2241,11 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             name: "range".to_owned(),
             alias: vec![],
             usage: "`range(end)` or `range(start, end)` or `range(start, end, step)`".to_owned(),
-            note: "startからendの手前までstep刻みのリストを生成します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            note: "startからendの手前までstep刻みのリストを生成します。stepが負なら減らしていきます"
+                .to_owned(),
+            category: HelpCategory::List,
+            examples: &[("range(4)", "[0, 1, 2, 3]"), ("range(1, 10, 3)", "[1, 4, 7]"), ("range(5, 0, -2)", "[5, 3, 1]")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 let (start, stop, stepsize): (i64, i64, i64) = match args.len() {
                     1 => match val_as_int(&args[0]) {
                         Some(e) => (0, e, 1),
@@ -1782,19 +2275,22 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                     _ => return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone())),
                 };
 
-                let mut new_list = Vec::new();
-                if step > 0 {
-                    for i in (start..stop).step_by(stepsize as usize) {
-                        new_list.push(EvalResult::IVal(i));
-                    }
+                if stepsize == 0 {
+                    return Err((EvalError::OutOfRange, expr.clone()));
+                }
+                let span = if stepsize > 0 {
+                    i128::from(stop) - i128::from(start)
                 } else {
-                    for i in (start..stop)
-                        .rev()
-                        .step_by(stepsize.unsigned_abs() as usize)
-                    {
-                        new_list.push(EvalResult::IVal(i));
-                    }
+                    i128::from(start) - i128::from(stop)
+                };
+                let len = (span.max(0) + i128::from(stepsize.unsigned_abs()) - 1)
+                    / i128::from(stepsize.unsigned_abs());
+                if len > MAX_LIST_LEN as i128 {
+                    return Err((EvalError::OutOfRange, expr.clone()));
                 }
+                let new_list = (0..len)
+                    .map(|k| EvalResult::IVal((i128::from(start) + k * i128::from(stepsize)) as i64))
+                    .collect();
                 Ok((EvalResult::List(new_list), step + 1))
             }),
         },
@@ -1803,7 +2299,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`join(list, sep)`".to_owned(),
             note: "listの各要素をsepで結合した文字列を生成します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("join([\"a\", \"b\", \"c\"], \"-\")", "\"a-b-c\"")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 2 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
                 }
@@ -1828,7 +2326,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`slice(list, start, end)`".to_owned(),
             note: "listのstartからendの手前までの要素を含むリストを生成します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("slice([1, 2, 3, 4], 1, 3)", "[2, 3]")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 3 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 3), expr.clone()));
                 }
@@ -1860,7 +2360,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec!["length".to_owned()],
             usage: "`len(list)`".to_owned(),
             note: "listの要素数もしくは文字数を返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("len([1, 2, 3])", "3"), ("len(\"まなみ\")", "3")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1875,10 +2377,19 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`head(list)`".to_owned(),
             note: "listの先頭要素を返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("head([1, 2, 3])", "1")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
+                if let EvalResult::Seq(s) = &args[0] {
+                    let mut s = *s.clone();
+                    return match seq::advance(&mut s, expr, step, mode, global_context, local_context)? {
+                        (Some(val), step) => Ok((val, step + 1)),
+                        (None, _) => Err((EvalError::OutOfRange, expr.clone())),
+                    };
+                }
                 val_as_list(&args[0]).map_or_else(
                     || Err((EvalError::NotAList(args[0].clone()), expr.clone())),
                     |l| {
@@ -1896,7 +2407,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`tail(list)`".to_owned(),
             note: "listの先頭要素を除いたリストを返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("tail([1, 2, 3])", "[2, 3]")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1921,7 +2434,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`last(list)`".to_owned(),
             note: "listの最後の要素を返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("last([1, 2, 3])", "3")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1942,7 +2457,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`init(list)`".to_owned(),
             note: "listの最後の要素を除いたリストを返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("init([1, 2, 3])", "[1, 2]")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -1967,7 +2484,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`while(cond, body, init)`".to_owned(),
             note: "condがtrueの間bodyを実行します".to_owned(),
-            body: Box::new(|expr, step, global_context, local_context, args| {
+            category: HelpCategory::Function,
+            examples: &[("while(n => n < 100, n => n * 2, 1)", "128")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 3 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 3), expr.clone()));
                 }
@@ -1979,6 +2498,7 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                     let (cond, next_step) = eval_apply(
                         expr,
                         step,
+                        mode,
                         global_context,
                         local_context,
                         condgen.clone(),
@@ -1991,6 +2511,7 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                     let (nextacc, next_step) = eval_apply(
                         expr,
                         step,
+                        mode,
                         global_context,
                         local_context,
                         accgen.clone(),
@@ -2007,16 +2528,21 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`sort(list)`".to_owned(),
             note: "listをソートします".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("sort([3, 1, 2])", "[1, 2, 3]")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
                 match args[0].clone() {
                     EvalResult::List(l) => {
                         let mut new_list = l;
+                        // 数でないものは後ろにまとめる。NaNが混ざっても全順序になるようにする
                         new_list.sort_by(|a, b| match (val_as_float(a), val_as_float(b)) {
-                            (Some(f1), Some(f2)) => f1.partial_cmp(&f2).unwrap(),
-                            _ => std::cmp::Ordering::Equal,
+                            (Some(f1), Some(f2)) => f1.total_cmp(&f2),
+                            (Some(_), None) => std::cmp::Ordering::Less,
+                            (None, Some(_)) => std::cmp::Ordering::Greater,
+                            (None, None) => std::cmp::Ordering::Equal,
                         });
                         Ok((EvalResult::List(new_list), step + 1))
                     }
@@ -2029,7 +2555,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`sum(list)`".to_owned(),
             note: "listの要素の合計を返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("sum([1, 2, 3])", "6")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2053,7 +2581,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec!["ave".to_owned()],
             usage: "`average(list)`".to_owned(),
             note: "listの要素の平均を返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("average([1, 2, 3, 4])", "2.5")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2084,7 +2614,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`max(x, y, ...)`".to_owned(),
             note: "引数の最大値を返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("max(1, 5, 3)", "5")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.is_empty() {
                     return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
                 }
@@ -2103,7 +2635,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`min(x, y, ...)`".to_owned(),
             note: "引数の最小値を返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Math,
+            examples: &[("min(1, 5, 3)", "1")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.is_empty() {
                     return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
                 }
@@ -2122,7 +2656,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`maximum(list)`".to_owned(),
             note: "listの最大値を返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("maximum([1, 5, 3])", "5")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2146,7 +2682,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`minimum(list)`".to_owned(),
             note: "listの最小値を返します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::List,
+            examples: &[("minimum([1, 5, 3])", "1")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2170,38 +2708,53 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`fix(f)`".to_owned(),
             note: "fの不動点を返します".to_owned(),
-            body: Box::new(|expr, step, global_context, local_context, args| {
+            category: HelpCategory::Function,
+            examples: &[("fix(f => n => if(n == 0, 1, n * f(n - 1)))(5)", "120")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
+                // fix(memo(f)) は再帰呼び出しも同じ表を使う
+                if let EvalResult::Memo(f, cache, false) = &args[0] {
+                    return Ok((EvalResult::Memo(f.clone(), cache.clone(), true), step + 1));
+                }
                 let func = args[0].clone();
+                // 再帰呼び出しを受ける _ys => _x(_x)(_ys) は、f => (n, acc) => ... のように
+                // fが返す関数と同じ数の引数を取る
+                let arity = match &func {
+                    EvalResult::Closure(_, body, _) => match body.as_ref() {
+                        Expr::Lambda(params, _) => params.len(),
+                        _ => 1,
+                    },
+                    _ => 1,
+                };
+                let ys = (0..arity).map(|i| format!("_y{i}")).collect::<Vec<_>>();
+                // Zコンビネータ _f => (_x => _f(_ys => _x(_x)(_ys)))(同じもの)
                 let xfyxxy = Expr::Lambda(
-                    vec!["_x".to_owned()],
+                    vec![Param::new("_x")],
                     Box::new(Expr::Apply(
                         Box::new(Expr::Const("_f".to_owned())),
                         vec![Expr::Lambda(
-                            vec!["_y".to_owned()],
+                            ys.iter().map(|y| Param::new(y)).collect(),
                             Box::new(Expr::Apply(
                                 Box::new(Expr::Apply(
                                     Box::new(Expr::Const("_x".to_owned())),
-                                    vec![
-                                        Expr::Const("_x".to_owned()),
-                                        Expr::Const("_y".to_owned()),
-                                    ],
+                                    vec![Expr::Const("_x".to_owned())],
                                 )),
-                                vec![Expr::Const("_y".to_owned())],
+                                ys.iter().map(|y| Expr::Const(y.clone())).collect(),
                             )),
                         )],
                     )),
                 );
                 let z = Expr::Lambda(
-                    vec!["_f".to_owned()],
+                    vec![Param::new("_f")],
                     Box::new(Expr::Apply(Box::new(xfyxxy.clone()), vec![xfyxxy])),
                 );
                 let (zval, _) = eval_expr_ctx(&z, step + 1, false, global_context, local_context)?;
                 eval_apply(
                     expr,
                     step + 1,
+                    mode,
                     global_context,
                     local_context,
                     zval,
@@ -2214,7 +2767,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`pick(list)`".to_owned(),
             note: "listからランダムに要素を選択します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Random,
+            examples: &[("pick([7, 7])", "7")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2235,7 +2790,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`pickarg(x, y, ...)`".to_owned(),
             note: "引数からランダムに要素を選択します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Random,
+            examples: &[("pickarg(7, 7)", "7")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.is_empty() {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2248,7 +2805,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec![],
             usage: "`shuffle(list)`".to_owned(),
             note: "listをシャッフルします".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Random,
+            examples: &[("sort(shuffle([3, 1, 2]))", "[1, 2, 3]")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2268,38 +2827,20 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
         EvalStdLibFun::Help => LibFun {
             name: "help".to_owned(),
             alias: vec![],
-            usage: "`help()` or `help(func)`".to_owned(),
-            note: "利用可能な関数の一覧を表示します。標準ライブラリの関数を渡すと説明を表示します"
+            usage: "`help()` or `help(func)` or `help(\"name\")`".to_owned(),
+            note: "利用可能な関数の一覧を表示します。関数か、関数名・カテゴリ名の文字列を渡すと説明を表示します"
                 .to_owned(),
-            body: Box::new(|_, step, _, _, args| {
-                if args.len() == 1 {
-                    match args[0].clone() {
-                        EvalResult::FuncStdLib(f) => {
-                            let libfun = get_libfun(f);
-                            Ok((EvalResult::SVal(help_libfun(&libfun)), step + 1))
-                        }
-                        EvalResult::FuncIf => {
-                            let helpstr =  "### `if(cond, then, else)`\n\
-                                                    condがtrueのときthenを、falseのときelseの値を、それぞれショートサーキット評価して返します。".to_owned();
-                            Ok((EvalResult::SVal(helpstr), step + 1))
-                        },
-                        _ => Ok((
-                            EvalResult::SVal("関数の説明を表示するには標準ライブラリの関数を直接引数に入れてください。例：`help(foldl)`".to_owned()),
-                            step + 1,
-                        )),
-                    }
-                } else {
-                    let mut help = String::new();
-                    help.push_str("Available functions: ");
-                    help.push_str(
-                        &stdlib_list()
-                            .iter()
-                            .map(|(name, _)| format!("`{name}`"))
-                            .collect::<Vec<String>>()
-                            .join(", "),
-                    );
-                    Ok((EvalResult::SVal(help), step + 1))
-                }
+            category: HelpCategory::Misc,
+            examples: &[("isstr(help(\"map\"))", "true")],
+            body: Box::new(|_, step, _, _, _, args| {
+                let help = match args.first() {
+                    None => help::help_text(None),
+                    Some(EvalResult::FuncStdLib(f)) => help::detail(&get_libfun(f.clone())),
+                    Some(EvalResult::FuncIf) => help::IF_HELP.to_owned(),
+                    Some(EvalResult::SVal(query)) => help::help_text(Some(query)),
+                    Some(_) => "関数の説明を表示するには標準ライブラリの関数か、関数名・カテゴリ名の文字列を引数に入れてください。例：`help(foldl)` `help(\"list\")`".to_owned(),
+                };
+                Ok((EvalResult::SVal(help), step + 1))
             }),
         },
         EvalStdLibFun::AtoF => LibFun {
@@ -2311,7 +2852,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             ],
             usage: "`atof(string)`".to_owned(),
             note: "stringを浮動小数点数に変換します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Type,
+            examples: &[("atof(\"1.5\")", "1.5")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2331,7 +2874,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             ],
             usage: "`atoi(string)`".to_owned(),
             note: "stringを整数に変換します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Type,
+            examples: &[("atoi(\"42\")", "42")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2347,7 +2892,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             alias: vec!["parseBool".to_owned(), "parsebool".to_owned()],
             usage: "`atob(string)`".to_owned(),
             note: "stringを真偽値に変換します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Type,
+            examples: &[("atob(\"true\")", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2368,7 +2915,9 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
             ],
             usage: "`tostr(value)`".to_owned(),
             note: "valueを文字列に変換します".to_owned(),
-            body: Box::new(|expr, step, _, _, args| {
+            category: HelpCategory::Type,
+            examples: &[("tostr(42)", "\"42\"")],
+            body: Box::new(|expr, step, _, _, _, args| {
                 if args.len() != 1 {
                     return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
                 }
@@ -2376,96 +2925,827 @@ pub fn get_libfun(func: EvalStdLibFun) -> LibFun {
                 Ok((EvalResult::SVal(s), step + 1))
             }),
         },
-        //_ => panic!("function not implemented: {:?}", func),
-    }
-}
-
-pub fn match_const(s: &str) -> Option<EvalResult> {
-    match s {
-        "pi" => Some(EvalResult::FVal(std::f64::consts::PI)),
-        "e" => Some(EvalResult::FVal(std::f64::consts::E)),
-        "true" => Some(EvalResult::BVal(true)),
-        "false" => Some(EvalResult::BVal(false)),
-        "if" => Some(EvalResult::FuncIf),
-        "lazy" => Some(EvalResult::FuncLazy),
-        _ => None,
-    }
-}
-
-pub fn stdlib_list() -> Vec<(String, EvalStdLibFun)> {
-    let mut libfuns = Vec::new();
-    for func in EvalStdLibFun::iter() {
-        let libfun = get_libfun(func.clone());
-        libfuns.push((libfun.name.clone(), func.clone()));
-        for alias in libfun.alias.iter() {
-            libfuns.push((alias.clone(), func.clone()));
-        }
-    }
-    libfuns
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, EnumIter, Serialize, Deserialize)]
-pub enum EvalStdLibFun {
-    //Print,
-    //Println,
-    Sin,
-    Cos,
-    Tan,
-    LogE,
-    Log10,
-    Log2,
-    Abs,
-    Floor,
-    Ceil,
-    Round,
-    URand,   // 0.0 <= x < 1.0 uniform random
-    GRand,   // standerd gaussian random
-    Map,     // map(f, list)
-    Geni,    // geni(f, n) = [f(0), f(1), ..., f(n-1)]
-    Repeat,  // repeat(f, n) = [f(), f(), ..., f()]
-    Filter,  // filter(f, list)
-    ZipWith, // zipWith(f, list1, list2)
-    Foldl,   // foldl(f, init, list)
-    Foldr,   // foldr(f, init, list)
-    Range,   // range(end) or range(start, end) or range(start, end, step)
-    Join,    // join(list, sep)
-    Slice,   // slice(list, start, end)
-    Len,     // len(list)
-    Head,    // head(list)
-    Tail,    // tail(list)
-    Last,    // last(list)
-    Init,    // init(list)
-    While,   // while(acc => cond, acc => nextacc, init)
-    Sort,    // sort(list)
-    Sum,     // sum(list)
-    Average, // average(list)
-    Max,     // max(x, y, ...)
-    Min,     // min(x, y, ...)
-    Maximum, // maximum(list)
-    Minimum, // minimum(list)
-    Fix,     // Fix(f) = f(Fix(f))
-    Help,    // help() = "sin, cos, ..."
-    Pick,    // pick(list)
-    PickArg, // pickarg(x, y, ...)
-    Shuffle, // shuffle(list)
-    AtoF,    // atof(string)
-    AtoI,    // atoi(string)
-    AtoB,    // atob(string)
-    ToStr,   // tostr(value)
-}
-
-impl std::fmt::Display for EvalStdLibFun {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let libfun = get_libfun(self.clone());
-        libfun.name.fmt(f)
-    }
-}
-
-type LibFunBody = dyn Fn(
+        EvalStdLibFun::Dot => LibFun {
+            name: "dot".to_owned(),
+            alias: vec![],
+            usage: "`dot(v1, v2)`".to_owned(),
+            note: "ベクトルv1とv2の内積を返します".to_owned(),
+            category: HelpCategory::LinAlg,
+            examples: &[("dot([1, 2, 3], [4, 5, 6])", "32")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 2 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let with_expr = |e| (e, expr.clone());
+                let v1 = linalg::val_as_vector(&args[0]).map_err(with_expr)?;
+                let v2 = linalg::val_as_vector(&args[1]).map_err(with_expr)?;
+                let d = linalg::dot(&v1, &v2).map_err(with_expr)?;
+                Ok((EvalResult::FVal(d), step + 1))
+            }),
+        },
+        EvalStdLibFun::Cross => LibFun {
+            name: "cross".to_owned(),
+            alias: vec![],
+            usage: "`cross(v1, v2)`".to_owned(),
+            note: "3次元ベクトルv1とv2の外積を返します".to_owned(),
+            category: HelpCategory::LinAlg,
+            examples: &[("cross([1, 0, 0], [0, 1, 0])", "[0, 0, 1]")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 2 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let with_expr = |e| (e, expr.clone());
+                let v1 = linalg::val_as_vector(&args[0]).map_err(with_expr)?;
+                let v2 = linalg::val_as_vector(&args[1]).map_err(with_expr)?;
+                let c = linalg::cross(&v1, &v2).map_err(with_expr)?;
+                Ok((linalg::vector_to_val(c), step + 1))
+            }),
+        },
+        EvalStdLibFun::Transpose => LibFun {
+            name: "transpose".to_owned(),
+            alias: vec![],
+            usage: "`transpose(matrix)`".to_owned(),
+            note: "行列（リストのリスト）を転置します".to_owned(),
+            category: HelpCategory::LinAlg,
+            examples: &[("transpose([[1, 2], [3, 4]])", "[[1, 3], [2, 4]]")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                let EvalResult::List(rows) = &args[0] else {
+                    return Err((EvalError::NotAList(args[0].clone()), expr.clone()));
+                };
+                let mut table = Vec::new();
+                for row in rows {
+                    match row {
+                        EvalResult::List(r) => table.push(r.clone()),
+                        _ => return Err((EvalError::NotAList(row.clone()), expr.clone())),
+                    }
+                }
+                let cols = table.first().map_or(0, Vec::len);
+                if let Some(row) = table.iter().find(|r| r.len() != cols) {
+                    return Err((
+                        EvalError::ShapeMismatch(vec![row.len()], vec![cols]),
+                        expr.clone(),
+                    ));
+                }
+                let transposed = (0..cols)
+                    .map(|j| EvalResult::List(table.iter().map(|r| r[j].clone()).collect()))
+                    .collect();
+                Ok((EvalResult::List(transposed), step + 1))
+            }),
+        },
+        EvalStdLibFun::MatMul => LibFun {
+            name: "matmul".to_owned(),
+            alias: vec![],
+            usage: "`matmul(a, b)`".to_owned(),
+            note: "行列の積を返します。ベクトルはaなら行ベクトル、bなら列ベクトルとして扱います"
+                .to_owned(),
+            category: HelpCategory::LinAlg,
+            examples: &[("matmul([[1, 2], [3, 4]], [1, 1])", "[3, 7]")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 2 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let with_expr = |e| (e, expr.clone());
+                let a_is_matrix = linalg::is_matrix(&args[0]);
+                let b_is_matrix = linalg::is_matrix(&args[1]);
+                let a = if a_is_matrix {
+                    linalg::val_as_matrix(&args[0]).map_err(with_expr)?
+                } else {
+                    vec![linalg::val_as_vector(&args[0]).map_err(with_expr)?]
+                };
+                let b = if b_is_matrix {
+                    linalg::val_as_matrix(&args[1]).map_err(with_expr)?
+                } else {
+                    linalg::val_as_vector(&args[1])
+                        .map_err(with_expr)?
+                        .into_iter()
+                        .map(|x| vec![x])
+                        .collect()
+                };
+                let product = linalg::matmul(&a, &b).map_err(with_expr)?;
+                let result = match (a_is_matrix, b_is_matrix) {
+                    (_, false) => {
+                        let column = product.into_iter().map(|row| row[0]).collect::<Vec<_>>();
+                        if a_is_matrix {
+                            linalg::vector_to_val(column)
+                        } else {
+                            EvalResult::FVal(column[0])
+                        }
+                    }
+                    (false, true) => {
+                        linalg::vector_to_val(product.into_iter().next().unwrap_or_default())
+                    }
+                    (true, true) => linalg::matrix_to_val(product),
+                };
+                Ok((result, step + 1))
+            }),
+        },
+        EvalStdLibFun::Det => LibFun {
+            name: "det".to_owned(),
+            alias: vec![],
+            usage: "`det(matrix)`".to_owned(),
+            note: "正方行列の行列式を返します".to_owned(),
+            category: HelpCategory::LinAlg,
+            examples: &[("det([[1, 2], [3, 4]])", "-2")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                let with_expr = |e| (e, expr.clone());
+                let m = linalg::val_as_matrix(&args[0]).map_err(with_expr)?;
+                let d = linalg::det(&m).map_err(with_expr)?;
+                Ok((EvalResult::FVal(d), step + 1))
+            }),
+        },
+        EvalStdLibFun::Inv => LibFun {
+            name: "inv".to_owned(),
+            alias: vec!["inverse".to_owned()],
+            usage: "`inv(matrix)`".to_owned(),
+            note: "正方行列の逆行列を返します".to_owned(),
+            category: HelpCategory::LinAlg,
+            examples: &[("inv([[2, 0], [0, 4]])", "[[0.5, 0], [0, 0.25]]")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                let with_expr = |e| (e, expr.clone());
+                let m = linalg::val_as_matrix(&args[0]).map_err(with_expr)?;
+                let inverse = linalg::inv(&m).map_err(with_expr)?;
+                Ok((linalg::matrix_to_val(inverse), step + 1))
+            }),
+        },
+        EvalStdLibFun::Solve => LibFun {
+            name: "solve".to_owned(),
+            alias: vec![],
+            usage: "`solve(a, b)`".to_owned(),
+            note: "連立一次方程式 a x = b を解いてxを返します".to_owned(),
+            category: HelpCategory::LinAlg,
+            examples: &[("solve([[2, 0], [0, 4]], [2, 8])", "[1, 2]")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 2 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let with_expr = |e| (e, expr.clone());
+                let a = linalg::val_as_matrix(&args[0]).map_err(with_expr)?;
+                if linalg::is_matrix(&args[1]) {
+                    let b = linalg::val_as_matrix(&args[1]).map_err(with_expr)?;
+                    let x = linalg::solve(&a, &b).map_err(with_expr)?;
+                    Ok((linalg::matrix_to_val(x), step + 1))
+                } else {
+                    let b = linalg::val_as_vector(&args[1])
+                        .map_err(with_expr)?
+                        .into_iter()
+                        .map(|x| vec![x])
+                        .collect();
+                    let x = linalg::solve(&a, &b).map_err(with_expr)?;
+                    let x = x.into_iter().map(|row| row[0]).collect();
+                    Ok((linalg::vector_to_val(x), step + 1))
+                }
+            }),
+        },
+        EvalStdLibFun::Identity => LibFun {
+            name: "identity".to_owned(),
+            alias: vec!["eye".to_owned()],
+            usage: "`identity(n)`".to_owned(),
+            note: "n次の単位行列を返します".to_owned(),
+            category: HelpCategory::LinAlg,
+            examples: &[("identity(2)", "[[1, 0], [0, 1]]")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                match val_as_int(&args[0]).map(usize::try_from) {
+                    Some(Ok(n)) if n.saturating_mul(n) <= MAX_LIST_LEN => {
+                        Ok((linalg::matrix_to_val(linalg::identity(n)), step + 1))
+                    }
+                    Some(_) => Err((EvalError::OutOfRange, expr.clone())),
+                    None => Err((EvalError::NotANumber(args[0].clone()), expr.clone())),
+                }
+            }),
+        },
+        EvalStdLibFun::Norm => LibFun {
+            name: "norm".to_owned(),
+            alias: vec![],
+            usage: "`norm(v)`".to_owned(),
+            note: "ベクトルのユークリッドノルムを返します。行列ならフロベニウスノルムを返します"
+                .to_owned(),
+            category: HelpCategory::LinAlg,
+            examples: &[("norm([3, 4])", "5")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                let with_expr = |e| (e, expr.clone());
+                let v = if linalg::is_matrix(&args[0]) {
+                    linalg::val_as_matrix(&args[0]).map_err(with_expr)?.concat()
+                } else {
+                    linalg::val_as_vector(&args[0]).map_err(with_expr)?
+                };
+                Ok((EvalResult::FVal(linalg::norm(&v)), step + 1))
+            }),
+        },
+        EvalStdLibFun::IsPrime => LibFun {
+            name: "isprime".to_owned(),
+            alias: vec![],
+            usage: "`isprime(n)`".to_owned(),
+            note: format!("nが素数ならtrueを返します。nは{}まで", isprime::MAX_NUMBER),
+            category: HelpCategory::Math,
+            examples: &[
+                ("isprime(57)", "false"),
+                ("head(filter(isprime, naturals(10000)))", "10007"),
+            ],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                let Some(n) = val_as_precise_int(&args[0]) else {
+                    return Err((EvalError::NotANumber(args[0].clone()), expr.clone()));
+                };
+                match u64::try_from(n) {
+                    Ok(n) if n > isprime::MAX_NUMBER => Err((EvalError::OutOfRange, expr.clone())),
+                    Ok(n) => Ok((EvalResult::BVal(isprime::is_prime(n)), step + 1)),
+                    Err(_) => Ok((EvalResult::BVal(false), step + 1)),
+                }
+            }),
+        },
+        EvalStdLibFun::Plot => LibFun {
+            name: "plot".to_owned(),
+            alias: vec![],
+            usage: "`plot(f, from, to)`".to_owned(),
+            note: "fromからtoまでの範囲でfのグラフを描きます".to_owned(),
+            category: HelpCategory::Graph,
+            examples: &[("isstr(plot(sin, 0, 3))", "true")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
+                if args.len() != 3 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 3), expr.clone()));
+                }
+                let (from, to) = match (val_as_float(&args[1]), val_as_float(&args[2])) {
+                    (Some(from), Some(to)) => (from, to),
+                    (Some(_), _) => {
+                        return Err((EvalError::NotANumber(args[2].clone()), expr.clone()))
+                    }
+                    _ => return Err((EvalError::NotANumber(args[1].clone()), expr.clone())),
+                };
+                let samples = plot::PLOT_WIDTH;
+                let mut ys = Vec::new();
+                let mut step = step + 1;
+                for i in 0..samples {
+                    let x = (to - from).mul_add(i as f64 / (samples - 1) as f64, from);
+                    let (val, next_step) = eval_apply(
+                        expr,
+                        step + 1,
+                        mode,
+                        global_context,
+                        local_context,
+                        args[0].clone(),
+                        vec![EvalResult::FVal(x)],
+                    )?;
+                    step = next_step;
+                    match val_as_float(&val) {
+                        Some(y) => ys.push(y),
+                        None => return Err((EvalError::NotANumber(val), expr.clone())),
+                    }
+                }
+                Ok((EvalResult::SVal(plot::render_plot(from, to, &ys)), step))
+            }),
+        },
+        EvalStdLibFun::Bar => LibFun {
+            name: "bar".to_owned(),
+            alias: vec![],
+            usage: "`bar(list)`".to_owned(),
+            note: "listの各要素を横棒グラフにします".to_owned(),
+            category: HelpCategory::Graph,
+            examples: &[("isstr(bar([1, 2, 3]))", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                let values = linalg::val_as_vector(&args[0]).map_err(|e| (e, expr.clone()))?;
+                if values.is_empty() {
+                    return Err((EvalError::OutOfRange, expr.clone()));
+                }
+                let rows = values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, v)| (i.to_string(), v))
+                    .collect::<Vec<_>>();
+                Ok((EvalResult::SVal(plot::render_bars(&rows)), step + 1))
+            }),
+        },
+        EvalStdLibFun::Histogram => LibFun {
+            name: "histogram".to_owned(),
+            alias: vec!["hist".to_owned()],
+            usage: "`histogram(list, bins)`".to_owned(),
+            note: "listの値の分布をbins個の区間に分けてヒストグラムにします".to_owned(),
+            category: HelpCategory::Graph,
+            examples: &[("isstr(histogram([1, 2, 2, 3], 3))", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 2 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let values = linalg::val_as_vector(&args[0]).map_err(|e| (e, expr.clone()))?;
+                let bins = match val_as_int(&args[1]) {
+                    Some(n) if (1..=plot::MAX_BINS as i64).contains(&n) => n as usize,
+                    Some(_) => return Err((EvalError::OutOfRange, expr.clone())),
+                    None => return Err((EvalError::NotANumber(args[1].clone()), expr.clone())),
+                };
+                if values.is_empty() {
+                    return Err((EvalError::OutOfRange, expr.clone()));
+                }
+                if let Some(v) = values.iter().find(|v| !v.is_finite()) {
+                    return Err((EvalError::NotANumber(EvalResult::FVal(*v)), expr.clone()));
+                }
+                let rows = plot::histogram_rows(&values, bins);
+                Ok((EvalResult::SVal(plot::render_bars(&rows)), step + 1))
+            }),
+        },
+        EvalStdLibFun::Now => LibFun {
+            name: "now".to_owned(),
+            alias: vec![],
+            usage: "`now()`".to_owned(),
+            note: "現在の日時を返します".to_owned(),
+            category: HelpCategory::Time,
+            examples: &[("typeof(now())", "\"datetime\"")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if !args.is_empty() {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 0), expr.clone()));
+                }
+                Ok((EvalResult::DateTime(chrono::Utc::now()), step + 1))
+            }),
+        },
+        EvalStdLibFun::Strftime => LibFun {
+            name: "strftime".to_owned(),
+            alias: vec![],
+            usage: "`strftime(t, format)` or `strftime(t, format, tz)`".to_owned(),
+            note: "日時tをformatに従って文字列にします。tzは\"UTC\"や\"+09:00\"などで、省略するとJSTです"
+                .to_owned(),
+            category: HelpCategory::Time,
+            examples: &[("strftime(@2025-06-01T09:00, \"%Y/%m/%d %H:%M\")", "\"2025/06/01 09:00\""), ("strftime(@2025-06-01T09:00, \"%H:%M\", \"UTC\")", "\"00:00\"")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 2 && args.len() != 3 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let EvalResult::DateTime(dt) = args[0] else {
+                    return Err((type_mismatch(ResultType::DateTime, &args[0]), expr.clone()));
+                };
+                let EvalResult::SVal(format) = &args[1] else {
+                    return Err((type_mismatch(ResultType::Str, &args[1]), expr.clone()));
+                };
+                let offset = match args.get(2) {
+                    None => datetime::jst(),
+                    Some(EvalResult::SVal(tz)) => match datetime::parse_offset(tz) {
+                        Some(offset) => offset,
+                        None => return Err((EvalError::OutOfRange, expr.clone())),
+                    },
+                    Some(tz) => return Err((type_mismatch(ResultType::Str, tz), expr.clone())),
+                };
+                let mut s = String::new();
+                // 不正な書式指定子はfmt::Errorになるのでpanicさせずに弾く
+                if std::fmt::Write::write_fmt(
+                    &mut s,
+                    format_args!("{}", dt.with_timezone(&offset).format(format)),
+                )
+                .is_err()
+                {
+                    return Err((EvalError::OutOfRange, expr.clone()));
+                }
+                Ok((EvalResult::SVal(s), step + 1))
+            }),
+        },
+        EvalStdLibFun::Seconds => LibFun {
+            name: "seconds".to_owned(),
+            alias: vec![],
+            usage: "`seconds(d)`".to_owned(),
+            note: "時間dを秒数に変換します".to_owned(),
+            category: HelpCategory::Time,
+            examples: &[("seconds(1m30s)", "90")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                match args[0] {
+                    EvalResult::Duration(ms) => Ok((EvalResult::FVal(ms as f64 / 1000.0), step + 1)),
+                    _ => Err((type_mismatch(ResultType::Duration, &args[0]), expr.clone())),
+                }
+            }),
+        },
+        EvalStdLibFun::Duration => LibFun {
+            name: "duration".to_owned(),
+            alias: vec![],
+            usage: "`duration(sec)`".to_owned(),
+            note: "秒数secを時間に変換します".to_owned(),
+            category: HelpCategory::Time,
+            examples: &[("duration(90)", "1m30s")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                let Some(sec) = val_as_float(&args[0]) else {
+                    return Err((type_mismatch(ResultType::Num, &args[0]), expr.clone()));
+                };
+                let ms = (sec * 1000.0).round();
+                if !ms.is_finite() || ms.abs() >= i64::MAX as f64 {
+                    return Err((EvalError::OutOfRange, expr.clone()));
+                }
+                Ok((EvalResult::Duration(ms as i64), step + 1))
+            }),
+        },
+        EvalStdLibFun::Naturals => LibFun {
+            name: "naturals".to_owned(),
+            alias: vec![],
+            usage: "`naturals()` or `naturals(start)`".to_owned(),
+            note: "start（省略時は0）から1ずつ増える無限シーケンスを返します".to_owned(),
+            category: HelpCategory::Seq,
+            examples: &[("take(3, naturals(5))", "[5, 6, 7]")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                let start = match args.as_slice() {
+                    [] => 0,
+                    [start] => match val_as_precise_int(start) {
+                        Some(start) => start,
+                        None => return Err((EvalError::NotANumber(start.clone()), expr.clone())),
+                    },
+                    _ => return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone())),
+                };
+                let naturals = seq::Sequence::Naturals(start);
+                Ok((EvalResult::Seq(Box::new(naturals)), step + 1))
+            }),
+        },
+        EvalStdLibFun::Iterate => LibFun {
+            name: "iterate".to_owned(),
+            alias: vec![],
+            usage: "`iterate(f, x)`".to_owned(),
+            note: "x, f(x), f(f(x)), ... という無限シーケンスを返します".to_owned(),
+            category: HelpCategory::Seq,
+            examples: &[("take(4, iterate(x => x * 2, 1))", "[1, 2, 4, 8]")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 2 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let iterate = seq::Sequence::Iterate(args[0].clone(), args[1].clone(), false);
+                Ok((EvalResult::Seq(Box::new(iterate)), step + 1))
+            }),
+        },
+        EvalStdLibFun::Cycle => LibFun {
+            name: "cycle".to_owned(),
+            alias: vec![],
+            usage: "`cycle(list)`".to_owned(),
+            note: "listを無限に繰り返すシーケンスを返します".to_owned(),
+            category: HelpCategory::Seq,
+            examples: &[("take(5, cycle([1, 2]))", "[1, 2, 1, 2, 1]")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                let Some(l) = val_as_list(&args[0]) else {
+                    return Err((EvalError::NotAList(args[0].clone()), expr.clone()));
+                };
+                let cycle = seq::Sequence::Cycle(l, 0);
+                Ok((EvalResult::Seq(Box::new(cycle)), step + 1))
+            }),
+        },
+        EvalStdLibFun::Take => LibFun {
+            name: "take".to_owned(),
+            alias: vec![],
+            usage: "`take(n, seq)`".to_owned(),
+            note: "シーケンスやリストの先頭n個をリストにして返します".to_owned(),
+            category: HelpCategory::Seq,
+            examples: &[("take(2, [1, 2, 3])", "[1, 2]")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
+                if args.len() != 2 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let n = match val_as_int(&args[0]).map(usize::try_from) {
+                    Some(Ok(n)) => n,
+                    Some(Err(_)) => return Err((EvalError::OutOfRange, expr.clone())),
+                    None => return Err((EvalError::NotANumber(args[0].clone()), expr.clone())),
+                };
+                let Some(mut s) = seq::val_as_seq(&args[1]) else {
+                    return Err((EvalError::NotAList(args[1].clone()), expr.clone()));
+                };
+                let (l, step) =
+                    seq::take(&mut s, n, expr, step + 1, mode, global_context, local_context)?;
+                Ok((EvalResult::List(l), step))
+            }),
+        },
+        EvalStdLibFun::TakeWhile => LibFun {
+            name: "takewhile".to_owned(),
+            alias: vec![],
+            usage: "`takewhile(f, seq)`".to_owned(),
+            note: "fがtruthyな値を返す間、シーケンスやリストの先頭から要素を取り出してリストにします"
+                .to_owned(),
+            category: HelpCategory::Seq,
+            examples: &[("takewhile(x => x < 3, naturals())", "[0, 1, 2]")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
+                if args.len() != 2 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let Some(mut s) = seq::val_as_seq(&args[1]) else {
+                    return Err((EvalError::NotAList(args[1].clone()), expr.clone()));
+                };
+                let (l, step) = seq::take_while(
+                    &mut s,
+                    &args[0],
+                    expr,
+                    step + 1,
+                    mode,
+                    global_context,
+                    local_context,
+                )?;
+                Ok((EvalResult::List(l), step))
+            }),
+        },
+        EvalStdLibFun::DropWhile => LibFun {
+            name: "dropwhile".to_owned(),
+            alias: vec![],
+            usage: "`dropwhile(f, seq)`".to_owned(),
+            note: "fがtruthyな値を返す間、シーケンスの先頭から要素を読み飛ばします。リストにはリストを返します"
+                .to_owned(),
+            category: HelpCategory::Seq,
+            examples: &[("dropwhile(x => x < 3, [1, 2, 3, 1])", "[3, 1]")],
+            body: Box::new(|expr, step, mode, global_context, local_context, args| {
+                if args.len() != 2 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 2), expr.clone()));
+                }
+                let Some(s) = seq::val_as_seq(&args[1]) else {
+                    return Err((EvalError::NotAList(args[1].clone()), expr.clone()));
+                };
+                let dropped = seq::Sequence::DropWhile(args[0].clone(), Box::new(s), false);
+                if seq::is_seq(&args[1]) {
+                    return Ok((EvalResult::Seq(Box::new(dropped)), step + 1));
+                }
+                // 有限のリストはその場で読み切る
+                let mut dropped = dropped;
+                let (l, step) = seq::take(
+                    &mut dropped,
+                    usize::MAX,
+                    expr,
+                    step + 1,
+                    mode,
+                    global_context,
+                    local_context,
+                )?;
+                Ok((EvalResult::List(l), step))
+            }),
+        },
+        EvalStdLibFun::TypeOf => LibFun {
+            name: "typeof".to_owned(),
+            alias: vec![],
+            usage: "`typeof(x)`".to_owned(),
+            note: "xの型名を文字列で返します".to_owned(),
+            category: HelpCategory::Type,
+            examples: &[("typeof(1.5)", "\"float\"")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                Ok((
+                    EvalResult::SVal(ResultType::of(&args[0]).to_string()),
+                    step + 1,
+                ))
+            }),
+        },
+        EvalStdLibFun::IsNum => LibFun {
+            name: "isnum".to_owned(),
+            alias: vec![],
+            usage: "`isnum(x)`".to_owned(),
+            note: "xが数値（整数または小数）ならtrueを返します".to_owned(),
+            category: HelpCategory::Type,
+            examples: &[("isnum(1)", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                Ok((EvalResult::BVal(ResultType::Num.accepts(&args[0])), step + 1))
+            }),
+        },
+        EvalStdLibFun::IsList => LibFun {
+            name: "islist".to_owned(),
+            alias: vec![],
+            usage: "`islist(x)`".to_owned(),
+            note: "xがリストならtrueを返します".to_owned(),
+            category: HelpCategory::Type,
+            examples: &[("islist([1])", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                Ok((EvalResult::BVal(ResultType::List.accepts(&args[0])), step + 1))
+            }),
+        },
+        EvalStdLibFun::IsStr => LibFun {
+            name: "isstr".to_owned(),
+            alias: vec![],
+            usage: "`isstr(x)`".to_owned(),
+            note: "xが文字列ならtrueを返します".to_owned(),
+            category: HelpCategory::Type,
+            examples: &[("isstr(\"a\")", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                Ok((EvalResult::BVal(ResultType::Str.accepts(&args[0])), step + 1))
+            }),
+        },
+        EvalStdLibFun::IsObj => LibFun {
+            name: "isobj".to_owned(),
+            alias: vec![],
+            usage: "`isobj(x)`".to_owned(),
+            note: "xがオブジェクトならtrueを返します".to_owned(),
+            category: HelpCategory::Type,
+            examples: &[("isobj({a: 1})", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                Ok((EvalResult::BVal(ResultType::Object.accepts(&args[0])), step + 1))
+            }),
+        },
+        EvalStdLibFun::IsFunc => LibFun {
+            name: "isfunc".to_owned(),
+            alias: vec![],
+            usage: "`isfunc(x)`".to_owned(),
+            note: "xが関数ならtrueを返します".to_owned(),
+            category: HelpCategory::Type,
+            examples: &[("isfunc(sin)", "true")],
+            body: Box::new(|expr, step, _, _, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                Ok((EvalResult::BVal(ResultType::Func.accepts(&args[0])), step + 1))
+            }),
+        },
+        EvalStdLibFun::Memo => LibFun {
+            name: "memo".to_owned(),
+            alias: vec![],
+            usage: "`memo(f)`".to_owned(),
+            note: "引数ごとに結果を覚えておくfを返します。再帰関数は`fix(memo(f))`と書きます。乱数やダイスを使う関数はメモ化できません".to_owned(),
+            category: HelpCategory::Function,
+            examples: &[("fix(memo(f => n => if(n < 2, n, f(n - 1) + f(n - 2))))(50)", "12586269025")],
+            body: Box::new(|expr, step, _, global_context, _, args| {
+                if args.len() != 1 {
+                    return Err((EvalError::ArgCountMismatch(args.len(), 1), expr.clone()));
+                }
+                if ResultType::of(&args[0]) != ResultType::Func {
+                    return Err((EvalError::NotAFunction(args[0].clone()), expr.clone()));
+                }
+                if let Some(name) = memo::find_impurity(&args[0], global_context, 0) {
+                    return Err((EvalError::ImpureFunction(name), expr.clone()));
+                }
+                Ok((
+                    EvalResult::Memo(Box::new(args[0].clone()), memo::MemoCache::default(), false),
+                    step + 1,
+                ))
+            }),
+        },
+        //_ => panic!("function not implemented: {:?}", func),
+    }
+}
+
+pub fn match_const(s: &str) -> Option<EvalResult> {
+    match s {
+        "pi" => Some(EvalResult::FVal(std::f64::consts::PI)),
+        "e" => Some(EvalResult::FVal(std::f64::consts::E)),
+        "true" => Some(EvalResult::BVal(true)),
+        "false" => Some(EvalResult::BVal(false)),
+        "if" => Some(EvalResult::FuncIf),
+        "lazy" => Some(EvalResult::FuncLazy),
+        _ => None,
+    }
+}
+
+pub fn stdlib_list() -> Vec<(String, EvalStdLibFun)> {
+    let mut libfuns = Vec::new();
+    for func in EvalStdLibFun::iter() {
+        let libfun = get_libfun(func.clone());
+        libfuns.push((libfun.name.clone(), func.clone()));
+        for alias in libfun.alias.iter() {
+            libfuns.push((alias.clone(), func.clone()));
+        }
+    }
+    libfuns
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, Serialize, Deserialize)]
+pub enum EvalStdLibFun {
+    //Print,
+    //Println,
+    Sin,
+    Cos,
+    Tan,
+    LogE,
+    Log10,
+    Log2,
+    Abs,
+    Floor,
+    Ceil,
+    Round,
+    URand,     // 0.0 <= x < 1.0 uniform random
+    GRand,     // standerd gaussian random
+    Map,       // map(f, list)
+    Geni,      // geni(f, n) = [f(0), f(1), ..., f(n-1)]
+    Repeat,    // repeat(f, n) = [f(), f(), ..., f()]
+    Filter,    // filter(f, list)
+    ZipWith,   // zipWith(f, list1, list2)
+    Foldl,     // foldl(f, init, list)
+    Foldr,     // foldr(f, init, list)
+    Range,     // range(end) or range(start, end) or range(start, end, step)
+    Join,      // join(list, sep)
+    Slice,     // slice(list, start, end)
+    Len,       // len(list)
+    Head,      // head(list)
+    Tail,      // tail(list)
+    Last,      // last(list)
+    Init,      // init(list)
+    While,     // while(acc => cond, acc => nextacc, init)
+    Sort,      // sort(list)
+    Sum,       // sum(list)
+    Average,   // average(list)
+    Max,       // max(x, y, ...)
+    Min,       // min(x, y, ...)
+    Maximum,   // maximum(list)
+    Minimum,   // minimum(list)
+    Fix,       // Fix(f) = f(Fix(f))
+    Help,      // help() = "sin, cos, ..."
+    Pick,      // pick(list)
+    PickArg,   // pickarg(x, y, ...)
+    Shuffle,   // shuffle(list)
+    AtoF,      // atof(string)
+    AtoI,      // atoi(string)
+    AtoB,      // atob(string)
+    ToStr,     // tostr(value)
+    Dot,       // dot(v1, v2)
+    Cross,     // cross(v1, v2)
+    Transpose, // transpose(matrix)
+    MatMul,    // matmul(a, b)
+    Det,       // det(matrix)
+    Inv,       // inv(matrix)
+    Solve,     // solve(a, b) = x s.t. a x = b
+    Identity,  // identity(n)
+    Norm,      // norm(v)
+    IsPrime,   // isprime(n)
+    Plot,      // plot(f, from, to)
+    Bar,       // bar(list)
+    Histogram, // histogram(list, bins)
+    Now,       // now()
+    Strftime,  // strftime(t, format, tz)
+    Seconds,   // seconds(duration)
+    Duration,  // duration(sec)
+    Naturals,  // naturals() = 0, 1, 2, ...
+    Iterate,   // iterate(f, x) = x, f(x), f(f(x)), ...
+    Cycle,     // cycle(list)
+    Take,      // take(n, seq)
+    TakeWhile, // takewhile(f, seq)
+    DropWhile, // dropwhile(f, seq)
+    TypeOf,    // typeof(x)
+    IsNum,     // isnum(x)
+    IsList,    // islist(x)
+    IsStr,     // isstr(x)
+    IsObj,     // isobj(x)
+    IsFunc,    // isfunc(x)
+    Memo,      // memo(f)
+}
+
+impl EvalStdLibFun {
+    // これより少ない引数で呼ぶと部分適用になる
+    const fn arity(&self) -> usize {
+        match self {
+            Self::URand | Self::GRand | Self::Help | Self::Now | Self::Naturals => 0,
+            Self::Map
+            | Self::Geni
+            | Self::Repeat
+            | Self::Filter
+            | Self::Join
+            | Self::Dot
+            | Self::Cross
+            | Self::MatMul
+            | Self::Solve
+            | Self::Histogram
+            | Self::Strftime
+            | Self::Iterate
+            | Self::Take
+            | Self::TakeWhile
+            | Self::DropWhile => 2,
+            Self::ZipWith | Self::Foldl | Self::Foldr | Self::Slice | Self::While | Self::Plot => 3,
+            _ => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for EvalStdLibFun {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let libfun = get_libfun(self.clone());
+        libfun.name.fmt(f)
+    }
+}
+
+type LibFunBody = dyn Fn(
     &Expr,
     usize,
+    &mut EvalMode,
     &EvalContext,
-    &EvalContext,
+    &Scope,
     Vec<EvalResult>,
 ) -> Result<(EvalResult, usize), (EvalError, Expr)>;
 
@@ -2474,6 +3754,8 @@ pub struct LibFun {
     alias: Vec<String>,
     usage: String,
     note: String,
+    category: HelpCategory,
+    examples: &'static [(&'static str, &'static str)], // (式, 結果の表示)
     body: Box<LibFunBody>,
     /*
     fn(
@@ -2499,29 +3781,6 @@ impl std::fmt::Display for LibFun {
     }
 }
 
-// show help
-fn help_libfun(
-    LibFun {
-        alias, usage, note, ..
-    }: &LibFun,
-) -> String {
-    let mut s = format!("### {usage}\n");
-    if !note.is_empty() {
-        s.push_str(&format!("{note}\n"));
-    }
-    if !alias.is_empty() {
-        s.push_str(&format!(
-            "alias: {}\n",
-            alias
-                .iter()
-                .map(|a| format!("`{a}`"))
-                .collect::<Vec<String>>()
-                .join(", ")
-        ));
-    }
-    s
-}
-
 fn generate_context(global_context: &EvalContext) -> EvalContext {
     let new_context = global_context.clone();
     let stdlib = stdlib_list();
@@ -2537,17 +3796,75 @@ fn generate_context(global_context: &EvalContext) -> EvalContext {
 pub fn eval_expr(
     expr: &Expr,
     global_context: &EvalContext,
+) -> Result<EvalResult, (EvalError, Expr)> {
+    eval_expr_in_scope(expr, global_context, &Scope::new())
+}
+
+// REPLのセッションなど、グローバル変数の上にローカルな束縛がある環境で評価する
+pub fn eval_expr_in_scope(
+    expr: &Expr,
+    global_context: &EvalContext,
+    scope: &Scope,
 ) -> Result<EvalResult, (EvalError, Expr)> {
     let libfun_context = generate_context(global_context);
-    match eval_expr_ctx(expr, 0, true, &libfun_context, &EvalContext::new()) {
+    match vm::eval(expr, &libfun_context, scope) {
+        Ok((result, _)) => Ok(result),
+        Err((e, expr)) => Err((e, expr)),
+    }
+}
+
+// 木をたどって評価する。VMの結果と突き合わせるための参照実装
+pub fn eval_expr_tree(
+    expr: &Expr,
+    global_context: &EvalContext,
+) -> Result<EvalResult, (EvalError, Expr)> {
+    let libfun_context = generate_context(global_context);
+    match eval_expr_ctx(expr, 0, true, &libfun_context, &Scope::new()) {
         Ok((result, _)) => Ok(result),
         Err((e, expr)) => Err((e, expr)),
     }
 }
 
 pub fn eval_from_str(input: &str, global_context: &EvalContext) -> Result<EvalResult, String> {
-    match parse_expr(input) {
-        Ok((_, expr)) => match eval_expr(&expr, global_context) {
+    eval_from_str_in_scope(input, global_context, &Scope::new())
+}
+
+// 変数に保存されたユーザー定義の演算子も読めるようにしてパースする
+pub fn parse_expr_in<'a>(input: &'a str, global_context: &EvalContext) -> IResult<&'a str, Expr> {
+    infix::with_fixities(infix::fixities(global_context), || parse_expr(input))
+}
+
+// 演算子の定義なら、その本体の式
+pub fn declaration_body(input: &str) -> Option<Result<&str, String>> {
+    infix::declaration(input).map(|declared| declared.map(|(_, body)| body))
+}
+
+// infixl 6 <+> = 式 の形なら演算子を定義し、保存する変数名と値を返す
+pub fn eval_declaration(
+    input: &str,
+    global_context: &EvalContext,
+    scope: &Scope,
+) -> Option<Result<(String, EvalResult), String>> {
+    let declared = infix::declaration(input)?.and_then(|(fixity, body)| {
+        let fun = eval_from_str_in_scope(body, global_context, scope)?;
+        if !ResultType::Func.accepts(&fun) {
+            return Err("演算子は関数で定義してね".to_owned());
+        }
+        Ok((
+            infix::operator_var(&fixity.symbol),
+            infix::make_operator(&fixity, fun),
+        ))
+    });
+    Some(declared)
+}
+
+pub fn eval_from_str_in_scope(
+    input: &str,
+    global_context: &EvalContext,
+    scope: &Scope,
+) -> Result<EvalResult, String> {
+    match parse_expr_in(input, global_context) {
+        Ok((_, expr)) => match eval_expr_in_scope(&expr, global_context, scope) {
             Ok(result) => Ok(result),
             Err((e, expr)) => Err(error_str((e, expr))),
         },
@@ -2678,6 +3995,62 @@ mod tests_parse {
         );
     }
 
+    #[test]
+    fn test_parse_one_dice_var() {
+        // 関数名でなければ、dの後ろの識別子は面の数
+        assert_eq!(
+            parse_expr("dx"),
+            Ok((
+                "",
+                Expr::Op1(ExprOp1::OneDice, Box::new(Expr::Const("x".to_owned())))
+            )),
+        );
+        assert!(matches!(
+            parse_expr("dropwhile(x => x < 3, [1, 5])"),
+            Ok(("", Expr::Apply(_, _)))
+        ));
+        let context = EvalContext::new();
+        context.insert("x".to_owned(), EvalResult::IVal(1));
+        let (_, expr) = parse_expr("dx").unwrap();
+        assert!(matches!(
+            eval_expr(&expr, &context),
+            Ok(EvalResult::IVal(1))
+        ));
+    }
+
+    #[test]
+    fn test_parse_linalg_call() {
+        // dで始まる関数名はダイスにしない
+        for name in ["dot", "det"] {
+            assert_eq!(
+                parse_expr(&format!("{name}([1, 2], [3, 4])")),
+                Ok((
+                    "",
+                    Expr::Apply(
+                        Box::new(Expr::Const(name.to_owned())),
+                        vec![
+                            Expr::List(vec![Expr::IVal(1), Expr::IVal(2)]),
+                            Expr::List(vec![Expr::IVal(3), Expr::IVal(4)]),
+                        ],
+                    ),
+                )),
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_expr("2h30m"), Ok(("", Expr::Duration(9_000_000))));
+        assert_eq!(parse_expr("1.5s"), Ok(("", Expr::Duration(1500))));
+        // ダイスと紛らわしいものは時間リテラルにしない
+        assert!(matches!(
+            parse_expr("3d6"),
+            Ok(("", Expr::Op2(ExprOp2::Dice, _, _)))
+        ));
+        let (_, expr) = parse_expr("@2025-06-01T12:00 + 90s").unwrap();
+        assert_eq!(parse_expr(&expr.to_string()), Ok(("", expr)));
+    }
+
     #[test]
     fn test_parse_longexpr() {
         match parse_expr("1*2+3/4 - 5 % 6 ^ 7 ^ 8 * 9 + 0") {
@@ -2705,6 +4078,17 @@ mod tests_parse {
         );
     }
 
+    #[test]
+    fn test_parse_nest_depth() {
+        let nested = |n: usize| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert_eq!(parse_expr(&nested(30)), Ok(("", Expr::IVal(1))));
+        // 深すぎる入れ子はスタックを溢れさせずにパースに失敗する
+        assert!(parse_expr(&nested(300)).is_err());
+        assert!(parse_expr(&format!("[{}]", nested(MAX_NEST_DEPTH))).is_err());
+        // 失敗した後も深さは元に戻っている
+        assert_eq!(parse_expr(&nested(30)), Ok(("", Expr::IVal(1))));
+    }
+
     #[test]
     fn test_parse_lambda() {
         assert_eq!(
@@ -2712,7 +4096,7 @@ mod tests_parse {
             Ok((
                 "",
                 Expr::Lambda(
-                    vec!["x".to_owned(), "y".to_owned(), "z".to_owned()],
+                    vec![Param::new("x"), Param::new("y"), Param::new("z")],
                     Box::new(Expr::Op2(
                         ExprOp2::Add,
                         Box::new(Expr::Op2(
@@ -2735,6 +4119,18 @@ mod tests_parse {
         );
     }
 
+    #[test]
+    fn test_parse_unicode_escape() {
+        assert_eq!(
+            parse_expr("\"\\u{1f305}\""),
+            Ok(("", Expr::SVal("\u{1f305}".to_owned())))
+        );
+        // サロゲートや桁あふれは文字にならない
+        for input in ["\"\\u{d800}\"", "\"\\u{fffffffff}\""] {
+            assert!(parse_expr(input).is_err(), "{input}");
+        }
+    }
+
     #[test]
     fn test_parse_long2() {
         println!("{:?}", parse_expr("sum(grand()<0.5)"));
@@ -2956,6 +4352,307 @@ mod tests_eval {
             }
         }
     }
+
+    #[test]
+    fn test_broadcast() {
+        let context = EvalContext::new();
+        let expr = parse_expr("[1, 2, 3] * 2 + 1").unwrap().1;
+        assert!(deep_eq(
+            &eval_expr(&expr, &context).unwrap(),
+            &eval_expr(&parse_expr("[3, 5, 7]").unwrap().1, &context).unwrap()
+        ));
+        let expr = parse_expr("[1, 2] - [1, 2, 3]").unwrap().1;
+        assert!(matches!(
+            eval_expr(&expr, &context),
+            Err((EvalError::ShapeMismatch(_, _), _))
+        ));
+    }
+
+    #[test]
+    fn test_linalg() {
+        let context = EvalContext::new();
+        let expr = parse_expr("solve([[2, 1], [1, 3]], [3, 5])").unwrap().1;
+        match eval_expr(&expr, &context) {
+            Ok(EvalResult::List(x)) => {
+                let x = x.iter().filter_map(val_as_float).collect::<Vec<_>>();
+                assert!((x[0] - 0.8).abs() < 1e-9 && (x[1] - 1.4).abs() < 1e-9);
+            }
+            _ => {
+                std::panic!();
+            }
+        }
+        let expr = parse_expr("matmul(identity(2), [[1, 2], [3, 4]]) == [[1, 2], [3, 4]]")
+            .unwrap()
+            .1;
+        assert_eq!(eval_expr(&expr, &context).unwrap(), EvalResult::BVal(true));
+        let expr = parse_expr("dot([1, 2], [3, 4]) + det([[2, 0], [0, 3]])")
+            .unwrap()
+            .1;
+        assert_eq!(eval_expr(&expr, &context).unwrap(), EvalResult::FVal(17.0));
+    }
+
+    #[test]
+    fn test_datetime() {
+        let context = EvalContext::new();
+        let eval = |s: &str| eval_expr(&parse_expr(s).unwrap().1, &context);
+        assert_eq!(
+            eval("@2025-06-01 + 2h30m").unwrap().to_string(),
+            "@2025-06-01T02:30:00+09:00"
+        );
+        assert_eq!(
+            eval("@2025-06-02 - @2025-06-01T00:00:00+09:00").unwrap(),
+            EvalResult::Duration(86_400_000)
+        );
+        assert_eq!(eval("90s * 2 == 3m").unwrap(), EvalResult::BVal(true));
+        assert_eq!(eval("seconds(1h / 4)").unwrap(), EvalResult::FVal(900.0));
+        assert_eq!(
+            eval("strftime(@2025-06-01T09:00, \"%H:%M\", \"UTC\")").unwrap(),
+            EvalResult::SVal("00:00".to_owned())
+        );
+        assert!(matches!(
+            eval("now() + 1"),
+            Err((EvalError::NotANumber(_), _))
+        ));
+        // 引数の型が違えば、期待した型を伝える
+        assert!(matches!(
+            eval("strftime(now(), 42)"),
+            Err((EvalError::TypeMismatch(ResultType::Str, ResultType::Int), _))
+        ));
+        assert!(matches!(
+            eval("seconds(90)"),
+            Err((
+                EvalError::TypeMismatch(ResultType::Duration, ResultType::Int),
+                _
+            ))
+        ));
+        assert!(matches!(
+            eval("duration(\"90\")"),
+            Err((EvalError::TypeMismatch(ResultType::Num, ResultType::Str), _))
+        ));
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let context = EvalContext::new();
+        let eval = |s: &str| eval_expr(&parse_expr(s).unwrap().1, &context).unwrap();
+        assert_eq!(
+            eval("[1, 4, 5, 6] |> filter(x => x > 3) |> map(x => x * 2) |> sum"),
+            eval("sum(map(x => x * 2, filter(x => x > 3, [1, 4, 5, 6])))")
+        );
+        assert_eq!(
+            eval("3 |> (x => x + 1) >> (x => x * 2)"),
+            EvalResult::IVal(8)
+        );
+        assert_eq!(
+            eval("((x, y, z) => x - y - z)(10)(2, 3)"),
+            EvalResult::IVal(5)
+        );
+        assert_eq!(
+            eval("fix(f => n => if(n < 1, 1, n * f(n - 1)))(5)"),
+            EvalResult::IVal(120)
+        );
+        assert_eq!(
+            eval("fix(f => (n, acc) => if(n < 1, acc, f(n - 1, acc * n)))(5, 1)"),
+            EvalResult::IVal(120)
+        );
+        assert!(matches!(eval("map(x => x + 1)"), EvalResult::Partial(_, _)));
+        assert_eq!(
+            eval("zipWith((a, b) => a + b, [1, 2])([3, 4])"),
+            eval("[4, 6]")
+        );
+        assert_eq!(eval("1 < 2 || 2 >= 3"), EvalResult::BVal(true));
+
+        // 多すぎる引数は部分適用の続きとはみなさない
+        assert!(matches!(
+            eval_expr(&parse_expr("(x => y => x - y)(10, 3)").unwrap().1, &context),
+            Err((EvalError::ArgCountMismatch(2, 1), _))
+        ));
+        // 引数を取らない関数は部分適用にならない
+        assert!(matches!(
+            eval_expr(&parse_expr("urand(1)").unwrap().1, &context),
+            Err((EvalError::ArgCountMismatch(1, 0), _))
+        ));
+    }
+
+    #[test]
+    fn test_seq() {
+        let context = EvalContext::new();
+        let eval = |s: &str| eval_expr(&parse_expr(s).unwrap().1, &context).unwrap();
+        assert_eq!(
+            eval("take(5, map(x => x * x, naturals(1)))").to_string(),
+            "[1, 4, 9, 16, 25]"
+        );
+        assert_eq!(
+            eval("takewhile(x => x < 100, iterate(x => x * 3, 1))").to_string(),
+            "[1, 3, 9, 27, 81]"
+        );
+        assert_eq!(
+            eval("take(4, zipWith((a, b) => a + b, cycle([\"a\", \"b\"]), naturals()))")
+                .to_string(),
+            "[\"a0\", \"b1\", \"a2\", \"b3\"]"
+        );
+        assert_eq!(
+            eval("dropwhile(x => x < 3, [1, 2, 3, 1])").to_string(),
+            "[3, 1]"
+        );
+        // 10000を超える最初の素数
+        assert_eq!(
+            eval("head(filter(isprime, naturals(10001)))"),
+            EvalResult::IVal(10007)
+        );
+        assert_eq!(
+            eval("filter(isprime, [0 - 7, 0, 1, 2, 57, 9007199254740881])").to_string(),
+            "[2, 9007199254740881]"
+        );
+        assert!(matches!(
+            eval_expr(
+                &parse_expr("isprime(9007199254740993)").unwrap().1,
+                &context
+            ),
+            Err((EvalError::OutOfRange, _))
+        ));
+        assert!(matches!(
+            eval_expr(&parse_expr("take(100000, naturals())").unwrap().1, &context),
+            Err((EvalError::StepLimitExceeded, _))
+        ));
+    }
+
+    #[test]
+    fn test_plot() {
+        let context = EvalContext::new();
+        let expr = parse_expr("plot(x => x ^ 2, -2, 2)").unwrap().1;
+        match eval_expr(&expr, &context) {
+            Ok(EvalResult::SVal(s)) => {
+                assert!(s.starts_with("```"));
+            }
+            _ => {
+                std::panic!();
+            }
+        }
+        for bins in ["0", "1000000000000"] {
+            let expr = parse_expr(&format!("histogram([1, 2, 3], {bins})"))
+                .unwrap()
+                .1;
+            assert!(eval_expr(&expr, &context).is_err());
+        }
+    }
+
+    #[test]
+    fn test_types() {
+        let context = EvalContext::new();
+        let eval = |s: &str| eval_expr(&parse_expr(s).unwrap().1, &context);
+        assert_eq!(
+            eval("map(typeof, [1, 1.5, \"a\", [1], {a: 1}, sin, 3s])")
+                .unwrap()
+                .to_string(),
+            "[\"int\", \"float\", \"str\", \"list\", \"obj\", \"func\", \"duration\"]"
+        );
+        assert_eq!(
+            eval("[isnum(2.5), islist(\"ab\"), isstr(\"ab\"), isobj({}), isfunc(x => x)]")
+                .unwrap()
+                .to_string(),
+            "[true, false, true, true, true]"
+        );
+        assert_eq!(
+            eval("((x: num, l: list) => x * len(l))(2, [1, 2, 3])").unwrap(),
+            EvalResult::IVal(6)
+        );
+        assert!(matches!(
+            eval("((x: num, l: list) => x * len(l))(\"2\")"),
+            Err((EvalError::TypeMismatch(ResultType::Num, ResultType::Str), _))
+        ));
+        // 型注釈はそのまま表示される
+        assert_eq!(
+            parse_expr("(x: int, y) => x").unwrap().1.to_string(),
+            "(x: int, y) => x"
+        );
+    }
+
+    #[test]
+    fn test_memo() {
+        let context = EvalContext::new();
+        let eval = |s: &str| eval_expr(&parse_expr(s).unwrap().1, &context);
+        let fib = "f => n => if(n < 2, n, f(n - 1) + f(n - 2))";
+        assert!(matches!(
+            eval(&format!("fix({fib})(30)")),
+            Err((EvalError::StepLimitExceeded, _))
+        ));
+        assert_eq!(
+            eval(&format!("fix(memo({fib}))(80)")).unwrap(),
+            EvalResult::IVal(23_416_728_348_467_685)
+        );
+        assert_eq!(
+            eval("(sq => [sq(3), sq(3.0), sq(4)])(memo(x => x * x))").unwrap(),
+            EvalResult::List(vec![
+                EvalResult::IVal(9),
+                EvalResult::FVal(9.0),
+                EvalResult::IVal(16)
+            ])
+        );
+        // 値が等しくても型が違えば別の引数として扱う
+        assert_eq!(
+            eval("(t => [t(1), t(true), t(1.0)])(memo(x => typeof(x)))")
+                .unwrap()
+                .to_string(),
+            r#"["int", "bool", "float"]"#
+        );
+        assert_eq!(
+            eval("(t => [t([1]), t([1.0])])(memo(l => typeof(l[0])))")
+                .unwrap()
+                .to_string(),
+            r#"["int", "float"]"#
+        );
+        assert!(matches!(
+            eval("memo(x => x + 1d6)"),
+            Err((EvalError::ImpureFunction(_), _))
+        ));
+        assert!(matches!(
+            eval("(r => memo(x => x * r()))(urand)"),
+            Err((EvalError::ImpureFunction(name), _)) if name == "urand"
+        ));
+    }
+
+    #[test]
+    fn test_edge_cases() {
+        let context = EvalContext::new();
+        let eval = |s: &str| eval_expr(&parse_expr(s).unwrap().1, &context);
+        // i64をあふれたら浮動小数点数になる
+        assert_eq!(
+            eval("9223372036854775807 + 1").unwrap(),
+            EvalResult::FVal(9_223_372_036_854_775_808.0)
+        );
+        assert!(matches!(
+            eval("99999999999999999999").unwrap(),
+            EvalResult::FVal(_)
+        ));
+        assert!(matches!(
+            eval("10000d9223372036854775807").unwrap(),
+            EvalResult::FVal(_)
+        ));
+        assert_eq!(
+            eval("[range(10, 0, -3), range(0, 7, 3), range(3, 3)]")
+                .unwrap()
+                .to_string(),
+            "[[10, 7, 4, 1], [0, 3, 6], []]"
+        );
+        for src in [
+            "range(0, 10, 0)",
+            "range(9223372036854775807)",
+            "identity(100000)",
+        ] {
+            assert!(
+                matches!(eval(src), Err((EvalError::OutOfRange, _))),
+                "{src}"
+            );
+        }
+        // 数でないものは後ろに並ぶ
+        assert_eq!(
+            eval("sort([3, \"a\", 1])").unwrap().to_string(),
+            "[1, 3, \"a\"]"
+        );
+        assert!(eval("sort([3, 0.0 / 0.0, 1, 0.0 / 0.0])").is_ok());
+        assert!(eval("matmul([], [])").is_err());
+    }
 }
 
 #[cfg(test)]
@@ -2981,3 +4678,586 @@ mod tests_evalresult_serde {
         }
     }
 }
+
+#[cfg(test)]
+mod tests_property {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // 変数の束縛とクロージャを多く含むランダムな式を作る
+    fn gen_expr(rng: &mut StdRng, depth: usize, vars: &mut Vec<String>) -> Expr {
+        let var = |name: &str| Expr::Const(name.to_owned());
+        if depth == 0 || rng.random_bool(0.2) {
+            return match rng.random_range(0..3) {
+                0 if !vars.is_empty() => var(&vars[rng.random_range(0..vars.len())]),
+                1 => Expr::BVal(rng.random_bool(0.5)),
+                _ => Expr::IVal(rng.random_range(-5..10)),
+            };
+        }
+        let sub = |rng: &mut StdRng, vars: &mut Vec<String>| gen_expr(rng, depth - 1, vars);
+        match rng.random_range(0..8) {
+            0 => {
+                let op = [
+                    ExprOp2::Add,
+                    ExprOp2::Sub,
+                    ExprOp2::Mul,
+                    ExprOp2::Lt,
+                    ExprOp2::Eq,
+                ][rng.random_range(0..5)];
+                Expr::Op2(op, Box::new(sub(rng, vars)), Box::new(sub(rng, vars)))
+            }
+            1 => Expr::Apply(
+                Box::new(var("if")),
+                vec![sub(rng, vars), sub(rng, vars), sub(rng, vars)],
+            ),
+            // (v => body)(val)
+            2 | 3 => {
+                let val = sub(rng, vars);
+                let name = format!("v{}", rng.random_range(0..4));
+                vars.push(name.clone());
+                let body = sub(rng, vars);
+                vars.pop();
+                Expr::Apply(
+                    Box::new(Expr::Lambda(vec![Param::new(&name)], Box::new(body))),
+                    vec![val],
+                )
+            }
+            // 部分適用と、外側の引数を捕まえたクロージャ
+            4 => {
+                let (a, b) = (format!("v{}", rng.random_range(0..4)), "w".to_owned());
+                vars.push(a.clone());
+                vars.push(b.clone());
+                let body = sub(rng, vars);
+                vars.truncate(vars.len() - 2);
+                let f = if rng.random_bool(0.5) {
+                    Expr::Lambda(vec![Param::new(&a), Param::new(&b)], Box::new(body))
+                } else {
+                    Expr::Lambda(
+                        vec![Param::new(&a)],
+                        Box::new(Expr::Lambda(vec![Param::new(&b)], Box::new(body))),
+                    )
+                };
+                let partial = Expr::Apply(Box::new(f), vec![sub(rng, vars)]);
+                Expr::Apply(Box::new(partial), vec![sub(rng, vars)])
+            }
+            5 => Expr::At(
+                Box::new(Expr::List(vec![sub(rng, vars), sub(rng, vars)])),
+                Box::new(Expr::IVal(rng.random_range(0..2))),
+            ),
+            // stdlibに渡したクロージャも、呼び出し元と同じ評価器で評価される
+            6 => {
+                let name = "x".to_owned();
+                vars.push(name.clone());
+                let body = sub(rng, vars);
+                vars.pop();
+                Expr::Apply(
+                    Box::new(var(["map", "filter"][rng.random_range(0..2)])),
+                    vec![
+                        Expr::Lambda(vec![Param::new(&name)], Box::new(body)),
+                        Expr::List(vec![sub(rng, vars), sub(rng, vars)]),
+                    ],
+                )
+            }
+            _ => Expr::Op2(
+                ExprOp2::Pipe,
+                Box::new(sub(rng, vars)),
+                Box::new(Expr::Lambda(
+                    vec![Param::new("p")],
+                    Box::new(sub(rng, vars)),
+                )),
+            ),
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Ty {
+        Int,
+        Bool,
+    }
+
+    fn gen_ty(rng: &mut StdRng) -> Ty {
+        if rng.random_bool(0.5) {
+            Ty::Int
+        } else {
+            Ty::Bool
+        }
+    }
+
+    // gen_exprと同じ形の式を、型をそろえて作る。評価が失敗するのは整数があふれたときだけになる
+    fn gen_typed(rng: &mut StdRng, depth: usize, ty: Ty, vars: &mut Vec<(String, Ty)>) -> Expr {
+        let var = |name: &str| Expr::Const(name.to_owned());
+        if depth == 0 || rng.random_bool(0.2) {
+            // 同じ名前で束縛し直されていたら、内側の型だけが見える
+            let visible = vars
+                .iter()
+                .enumerate()
+                .filter(|&(i, (name, t))| *t == ty && vars[i + 1..].iter().all(|(n, _)| n != name))
+                .map(|(_, (name, _))| name)
+                .collect::<Vec<_>>();
+            return match ty {
+                _ if !visible.is_empty() && rng.random_bool(0.5) => {
+                    var(visible[rng.random_range(0..visible.len())])
+                }
+                Ty::Int => Expr::IVal(rng.random_range(-5..10)),
+                Ty::Bool => Expr::BVal(rng.random_bool(0.5)),
+            };
+        }
+        let sub = |rng: &mut StdRng, ty, vars: &mut Vec<(String, Ty)>| {
+            gen_typed(rng, depth - 1, ty, vars)
+        };
+        // 名前を束縛してから、その中で本体を作る
+        let bind = |rng: &mut StdRng, names: &[(String, Ty)], vars: &mut Vec<(String, Ty)>| {
+            vars.extend_from_slice(names);
+            let body = sub(rng, ty, vars);
+            vars.truncate(vars.len() - names.len());
+            body
+        };
+        match rng.random_range(0..8) {
+            0 => {
+                let op = match ty {
+                    Ty::Int => [ExprOp2::Add, ExprOp2::Sub, ExprOp2::Mul][rng.random_range(0..3)],
+                    Ty::Bool => [ExprOp2::Lt, ExprOp2::Eq][rng.random_range(0..2)],
+                };
+                Expr::Op2(
+                    op,
+                    Box::new(sub(rng, Ty::Int, vars)),
+                    Box::new(sub(rng, Ty::Int, vars)),
+                )
+            }
+            1 => Expr::Apply(
+                Box::new(var("if")),
+                vec![
+                    sub(rng, Ty::Bool, vars),
+                    sub(rng, ty, vars),
+                    sub(rng, ty, vars),
+                ],
+            ),
+            // (v => body)(val)
+            2 | 3 => {
+                let t = gen_ty(rng);
+                let val = sub(rng, t, vars);
+                let name = format!("v{}", rng.random_range(0..4));
+                let body = bind(rng, &[(name.clone(), t)], vars);
+                Expr::Apply(
+                    Box::new(Expr::Lambda(vec![Param::new(&name)], Box::new(body))),
+                    vec![val],
+                )
+            }
+            // 部分適用と、外側の引数を捕まえたクロージャ
+            4 => {
+                let (ta, tb) = (gen_ty(rng), gen_ty(rng));
+                let (a, b) = (format!("v{}", rng.random_range(0..4)), "w".to_owned());
+                let body = bind(rng, &[(a.clone(), ta), (b.clone(), tb)], vars);
+                let f = if rng.random_bool(0.5) {
+                    Expr::Lambda(vec![Param::new(&a), Param::new(&b)], Box::new(body))
+                } else {
+                    Expr::Lambda(
+                        vec![Param::new(&a)],
+                        Box::new(Expr::Lambda(vec![Param::new(&b)], Box::new(body))),
+                    )
+                };
+                let partial = Expr::Apply(Box::new(f), vec![sub(rng, ta, vars)]);
+                Expr::Apply(Box::new(partial), vec![sub(rng, tb, vars)])
+            }
+            5 => Expr::At(
+                Box::new(Expr::List(vec![sub(rng, ty, vars), sub(rng, ty, vars)])),
+                Box::new(Expr::IVal(rng.random_range(0..2))),
+            ),
+            // map(x => body, [a, b])[i]
+            6 => {
+                let t = gen_ty(rng);
+                let body = bind(rng, &[("x".to_owned(), t)], vars);
+                let mapped = Expr::Apply(
+                    Box::new(var("map")),
+                    vec![
+                        Expr::Lambda(vec![Param::new("x")], Box::new(body)),
+                        Expr::List(vec![sub(rng, t, vars), sub(rng, t, vars)]),
+                    ],
+                );
+                Expr::At(
+                    Box::new(mapped),
+                    Box::new(Expr::IVal(rng.random_range(0..2))),
+                )
+            }
+            // length(filter(x => cond, [a, b]))
+            7 if ty == Ty::Int => {
+                let t = gen_ty(rng);
+                vars.push(("x".to_owned(), t));
+                let cond = sub(rng, Ty::Bool, vars);
+                vars.pop();
+                let filtered = Expr::Apply(
+                    Box::new(var("filter")),
+                    vec![
+                        Expr::Lambda(vec![Param::new("x")], Box::new(cond)),
+                        Expr::List(vec![sub(rng, t, vars), sub(rng, t, vars)]),
+                    ],
+                );
+                Expr::Apply(Box::new(var("length")), vec![filtered])
+            }
+            _ => {
+                let t = gen_ty(rng);
+                let val = sub(rng, t, vars);
+                let body = bind(rng, &[("p".to_owned(), t)], vars);
+                Expr::Op2(
+                    ExprOp2::Pipe,
+                    Box::new(val),
+                    Box::new(Expr::Lambda(vec![Param::new("p")], Box::new(body))),
+                )
+            }
+        }
+    }
+
+    // 答え合わせ用の値。クロージャは作られたときの環境をそのまま持つ
+    #[derive(Clone)]
+    enum Val {
+        Int(i64),
+        Bool(bool),
+        List(Vec<Self>),
+        Fun(Vec<String>, Expr, Vec<(String, Self)>),
+    }
+
+    impl Val {
+        fn into_result(self) -> EvalResult {
+            match self {
+                Self::Int(i) => EvalResult::IVal(i),
+                Self::Bool(b) => EvalResult::BVal(b),
+                Self::List(l) => EvalResult::List(l.into_iter().map(Self::into_result).collect()),
+                Self::Fun(..) => unreachable!(),
+            }
+        }
+    }
+
+    // gen_typedの式だけを扱う素朴な評価器。VMとも木の評価器とも独立に答えを求める
+    // 2^53を超える整数が出てきたらNoneを返し、その式は比べない
+    fn reference(expr: &Expr, env: &[(String, Val)]) -> Option<Val> {
+        let int = |e: &Expr| match reference(e, env)? {
+            Val::Int(i) => Some(i),
+            _ => unreachable!(),
+        };
+        let list = |e: &Expr| match reference(e, env)? {
+            Val::List(l) => Some(l),
+            _ => unreachable!(),
+        };
+        let small = |i: Option<i64>| i.filter(|i| i.unsigned_abs() < 1 << 53).map(Val::Int);
+        Some(match expr {
+            Expr::IVal(i) => Val::Int(*i),
+            Expr::BVal(b) => Val::Bool(*b),
+            Expr::Const(name) => env.iter().rev().find(|(n, _)| n == name).unwrap().1.clone(),
+            Expr::List(l) => Val::List(l.iter().map(|e| reference(e, env)).collect::<Option<_>>()?),
+            Expr::At(l, i) => list(l)?[usize::try_from(int(i)?).unwrap()].clone(),
+            Expr::Lambda(params, body) => Val::Fun(
+                params.iter().map(|p| p.name.clone()).collect(),
+                (**body).clone(),
+                env.to_vec(),
+            ),
+            Expr::Op2(ExprOp2::Pipe, e, f) => call(reference(f, env)?, vec![reference(e, env)?])?,
+            Expr::Op2(op, e1, e2) => {
+                let (a, b) = (int(e1)?, int(e2)?);
+                match op {
+                    ExprOp2::Add => small(a.checked_add(b))?,
+                    ExprOp2::Sub => small(a.checked_sub(b))?,
+                    ExprOp2::Mul => small(a.checked_mul(b))?,
+                    ExprOp2::Lt => Val::Bool(a < b),
+                    ExprOp2::Eq => Val::Bool(a == b),
+                    _ => unreachable!(),
+                }
+            }
+            Expr::Apply(f, args) => match (f.as_ref(), args.as_slice()) {
+                (Expr::Const(name), [c, t, e]) if name == "if" => match reference(c, env)? {
+                    Val::Bool(true) => reference(t, env)?,
+                    Val::Bool(false) => reference(e, env)?,
+                    _ => unreachable!(),
+                },
+                (Expr::Const(name), [f, l]) if name == "map" || name == "filter" => {
+                    let f = reference(f, env)?;
+                    let mut result = vec![];
+                    for x in list(l)? {
+                        match (name.as_str(), call(f.clone(), vec![x.clone()])?) {
+                            ("map", y) => result.push(y),
+                            (_, Val::Bool(true)) => result.push(x),
+                            (_, Val::Bool(false)) => {}
+                            _ => unreachable!(),
+                        }
+                    }
+                    Val::List(result)
+                }
+                (Expr::Const(name), [l]) if name == "length" => {
+                    Val::Int(i64::try_from(list(l)?.len()).unwrap())
+                }
+                _ => call(
+                    reference(f, env)?,
+                    args.iter()
+                        .map(|e| reference(e, env))
+                        .collect::<Option<_>>()?,
+                )?,
+            },
+            _ => unreachable!("{expr}"),
+        })
+    }
+
+    fn call(f: Val, args: Vec<Val>) -> Option<Val> {
+        let Val::Fun(params, body, mut env) = f else {
+            unreachable!()
+        };
+        let rest = params[args.len()..].to_vec();
+        env.extend(params.into_iter().zip(args));
+        if rest.is_empty() {
+            reference(&body, &env)
+        } else {
+            Some(Val::Fun(rest, body, env))
+        }
+    }
+
+    #[test]
+    fn test_matches_reference() {
+        let global = EvalContext::new();
+        let mut compared = 0;
+        for seed in 0..500 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let ty = gen_ty(&mut rng);
+            let expr = gen_typed(&mut rng, 5, ty, &mut vec![]);
+            let Some(expected) = reference(&expr, &[]) else {
+                continue;
+            };
+            let expected = expected.into_result();
+            assert_eq!(
+                eval_expr(&expr, &global).ok(),
+                Some(expected.clone()),
+                "vm, seed {seed}: {expr}"
+            );
+            assert_eq!(
+                eval_expr_tree(&expr, &global).ok(),
+                Some(expected),
+                "tree, seed {seed}: {expr}"
+            );
+            compared += 1;
+        }
+        assert!(compared > 450, "{compared}");
+    }
+
+    // 負の数は単項の-として、true/falseは定数名として読み戻されるので、先にそちらの形に揃える
+    fn normalize(expr: Expr) -> Expr {
+        let norm = |e: Box<Expr>| Box::new(normalize(*e));
+        match expr {
+            Expr::BVal(b) => Expr::Const(b.to_string()),
+            Expr::IVal(i) if i < 0 => Expr::Op1(ExprOp1::Neg, Box::new(Expr::IVal(-i))),
+            Expr::List(l) => Expr::List(l.into_iter().map(normalize).collect()),
+            Expr::At(e1, e2) => Expr::At(norm(e1), norm(e2)),
+            Expr::Op2(op, e1, e2) => Expr::Op2(op, norm(e1), norm(e2)),
+            Expr::Apply(f, args) => Expr::Apply(norm(f), args.into_iter().map(normalize).collect()),
+            Expr::Lambda(params, body) => Expr::Lambda(params, norm(body)),
+            Expr::Object(o) => Expr::Object(o.into_iter().map(|(k, v)| (k, norm(v))).collect()),
+            Expr::Get(e, k) => Expr::Get(norm(e), k),
+            Expr::Op1(op, e) => Expr::Op1(op, norm(e)),
+            e => e,
+        }
+    }
+
+    // 評価はせず、構文をひととおり含む式を作る
+    fn gen_syntax(rng: &mut StdRng, depth: usize) -> Expr {
+        const NAMES: [&str; 4] = ["x", "foo", "bar_1", "sin"];
+        const STRINGS: [&str; 5] = ["", "ab", "say \"hi\"", "a\\b\n\tc", "まなみ\u{1}"];
+        let name = |rng: &mut StdRng| NAMES[rng.random_range(0..NAMES.len())].to_owned();
+        if depth == 0 || rng.random_bool(0.15) {
+            return match rng.random_range(0..6) {
+                0 => Expr::IVal(rng.random_range(0..1000)),
+                1 => Expr::FVal([0.5, 2.25, 1000.0, 0.001][rng.random_range(0..4)]),
+                2 => Expr::SVal(STRINGS[rng.random_range(0..STRINGS.len())].to_owned()),
+                3 => Expr::Duration([1000, 90_000, 1500, 3_600_000][rng.random_range(0..4)]),
+                4 => Expr::BVal(rng.random_bool(0.5)),
+                _ => Expr::Const(name(rng)),
+            };
+        }
+        let sub = |rng: &mut StdRng| Box::new(gen_syntax(rng, depth - 1));
+        let subs =
+            |rng: &mut StdRng, n: usize| (0..n).map(|_| gen_syntax(rng, depth - 1)).collect();
+        match rng.random_range(0..9) {
+            0 => {
+                let op = [ExprOp1::Neg, ExprOp1::OneDice, ExprOp1::NotL][rng.random_range(0..3)];
+                Expr::Op1(op, sub(rng))
+            }
+            1 | 2 => {
+                let op = [
+                    ExprOp2::Add,
+                    ExprOp2::Sub,
+                    ExprOp2::Mul,
+                    ExprOp2::Div,
+                    ExprOp2::Mod,
+                    ExprOp2::Pow,
+                    ExprOp2::Dice,
+                    ExprOp2::Gt,
+                    ExprOp2::Ge,
+                    ExprOp2::Lt,
+                    ExprOp2::Le,
+                    ExprOp2::Eq,
+                    ExprOp2::Ne,
+                    ExprOp2::AndL,
+                    ExprOp2::OrL,
+                    ExprOp2::XorL,
+                    ExprOp2::Pipe,
+                    ExprOp2::Compose,
+                ][rng.random_range(0..18)];
+                Expr::Op2(op, sub(rng), sub(rng))
+            }
+            3 => {
+                let n = rng.random_range(0..3);
+                Expr::List(subs(rng, n))
+            }
+            4 => Expr::At(sub(rng), sub(rng)),
+            5 => {
+                let n = rng.random_range(0..3);
+                Expr::Object((0..n).map(|_| (name(rng), sub(rng))).collect())
+            }
+            6 => Expr::Get(sub(rng), name(rng)),
+            7 => {
+                let n = rng.random_range(0..3);
+                Expr::Apply(sub(rng), subs(rng, n))
+            }
+            _ => {
+                let params = (0..rng.random_range(0..3))
+                    .map(|i| Param {
+                        name: format!("p{i}"),
+                        ty: [None, Some(ResultType::Int), Some(ResultType::List)]
+                            [rng.random_range(0..3)],
+                    })
+                    .collect();
+                Expr::Lambda(params, sub(rng))
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_roundtrip() {
+        for seed in 0..2000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let expr = normalize(gen_syntax(&mut rng, 4));
+            for shown in [format!("{expr}"), format!("{expr:#}")] {
+                assert_eq!(
+                    parse_expr(&shown),
+                    Ok(("", expr.clone())),
+                    "seed {seed}: {shown}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pretty_roundtrip() {
+        for seed in 0..500 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let expr = normalize(gen_expr(&mut rng, 5, &mut vec![]));
+            for shown in [format!("{expr}"), format!("{expr:#}")] {
+                assert_eq!(parse_expr(&shown), Ok(("", expr.clone())), "seed {seed}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_stale_bindings() {
+        let global = EvalContext::new();
+        // 同じ部分適用を繰り返し呼んでも、前の呼び出しの引数が残らない
+        for (src, expected) in [
+            (
+                "(g => [g(1), g(2), g(1)])(((a, b) => a * 10 + b)(3))",
+                "[31, 32, 31]",
+            ),
+            ("(f => [f(1)(2), f(3)(4)])(a => b => a - b)", "[-1, -1]"),
+            (
+                "(k => map(x => k(x)(x), [1, 2]))(a => b => a + b * 100)",
+                "[101, 202]",
+            ),
+        ] {
+            let expr = parse_expr(src).unwrap().1;
+            assert_eq!(eval_expr(&expr, &global).unwrap().to_string(), expected);
+            assert_eq!(
+                eval_expr_tree(&expr, &global).unwrap().to_string(),
+                expected
+            );
+        }
+    }
+
+    // 値として渡すとおかしくなりやすいもの
+    const ATOMS: &[&str] = &[
+        "0",
+        "1",
+        "-1",
+        "2",
+        "0.5",
+        "-2.5",
+        "10.0 ^ 308",
+        "0.0 / 0.0",
+        "9223372036854775807",
+        "-9223372036854775808",
+        "4611686018427387904",
+        "\"\"",
+        "\"ab\"",
+        "[]",
+        "[3, 0.0 / 0.0, \"a\", 1]",
+        "[1, 2, 3]",
+        "[[1, 2], [3, 4]]",
+        "[[]]",
+        "{}",
+        "{a: 1}",
+        "x => x",
+        "(a, b) => a",
+        "true",
+        "3s",
+        "-5m",
+        "sin",
+    ];
+
+    const TOKENS: &[&str] = &[
+        "(", ")", "[", "]", "{", "}", ",", ":", ".", "+", "-", "*", "/", "%", "^", "**", "d", "D",
+        "<", ">=", "==", "!=", "&&", "||", "^^", "|>", ">>", "!", "=>", "x", "y", "\"", "\\", "1h",
+        "#",
+    ];
+
+    // ランダムな文字列を作る。半分はライブラリ関数をでたらめな引数で呼ぶ
+    fn gen_input(rng: &mut StdRng, names: &[String]) -> String {
+        if rng.random_bool(0.5) {
+            let name = &names[rng.random_range(0..names.len())];
+            let args = (0..rng.random_range(0..4))
+                .map(|_| ATOMS[rng.random_range(0..ATOMS.len())])
+                .collect::<Vec<_>>();
+            return format!("{name}({})", args.join(", "));
+        }
+        (0..rng.random_range(1..12))
+            .map(|_| {
+                let token = if rng.random_bool(0.6) {
+                    TOKENS[rng.random_range(0..TOKENS.len())]
+                } else {
+                    ATOMS[rng.random_range(0..ATOMS.len())]
+                };
+                if rng.random_bool(0.5) {
+                    format!("{token} ")
+                } else {
+                    token.to_owned()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_no_panic() {
+        let global = EvalContext::new();
+        let names = EvalStdLibFun::iter()
+            .map(|f| get_libfun(f).name)
+            .collect::<Vec<_>>();
+        // 乱数では出にくい入力も試す
+        let fixed = [
+            "\"\\u{d800}\"".to_owned(),
+            "\"\\u{fffffffff}\"".to_owned(),
+            "(".repeat(300),
+        ];
+        let random = (0..3000).map(|seed| gen_input(&mut StdRng::seed_from_u64(seed), &names));
+        for input in fixed.into_iter().chain(random) {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                let _ = eval_from_str(&input, &global);
+                if let Ok((_, expr)) = parse_expr(&input) {
+                    let _ = eval_expr_tree(&expr, &global);
+                }
+            }));
+            assert!(result.is_ok(), "{input}");
+        }
+    }
+}
@@ -0,0 +1,209 @@
+/*
+-----------------------------
+日時と時間
+日時はUTCで保持し、表示はJSTで行う
+時間はミリ秒単位の整数で保持する
+-----------------------------
+*/
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc};
+
+use super::{val_as_float, EvalError, EvalResult, ExprOp2};
+
+const MS_PER_SEC: i64 = 1000;
+const MS_PER_MIN: i64 = 60 * MS_PER_SEC;
+const MS_PER_HOUR: i64 = 60 * MS_PER_MIN;
+const MS_PER_DAY: i64 = 24 * MS_PER_HOUR;
+const MS_PER_WEEK: i64 = 7 * MS_PER_DAY;
+
+pub const fn jst() -> FixedOffset {
+    FixedOffset::east_opt(9 * 3600).unwrap()
+}
+
+// 時間リテラルの単位をミリ秒に換算
+pub fn unit_ms(unit: &str) -> Option<i64> {
+    match unit {
+        "ms" => Some(1),
+        "s" => Some(MS_PER_SEC),
+        "m" | "min" => Some(MS_PER_MIN),
+        "h" => Some(MS_PER_HOUR),
+        "day" | "days" => Some(MS_PER_DAY),
+        "w" => Some(MS_PER_WEEK),
+        _ => None,
+    }
+}
+
+// 5400000 -> "1h30m"
+// 出力は時間リテラルとしてそのままパースできる
+pub fn fmt_duration(ms: i64) -> String {
+    if ms == 0 {
+        return "0s".to_owned();
+    }
+    let sign = if ms < 0 { "-" } else { "" };
+    let mut rest = ms.unsigned_abs();
+    let mut s = sign.to_owned();
+    for (unit, size) in [
+        ("day", MS_PER_DAY),
+        ("h", MS_PER_HOUR),
+        ("m", MS_PER_MIN),
+        ("s", MS_PER_SEC),
+        ("ms", 1),
+    ] {
+        let size = size as u64;
+        if rest >= size {
+            s.push_str(&format!("{}{unit}", rest / size));
+            rest %= size;
+        }
+    }
+    s
+}
+
+// "+09:00", "UTC", "JST" などをオフセットに変換
+pub fn parse_offset(tz: &str) -> Option<FixedOffset> {
+    match tz.to_uppercase().as_str() {
+        "UTC" | "Z" | "GMT" => FixedOffset::east_opt(0),
+        "JST" => Some(jst()),
+        other => DateTime::parse_from_str(&format!("2000-01-01T00:00:00{other}"), "%FT%T%:z")
+            .ok()
+            .map(|dt| *dt.offset()),
+    }
+}
+
+// "2025-06-01", "2025-06-01T12:00", "2025-06-01T12:00:00+09:00" など
+// オフセットがなければJSTとみなす
+pub fn parse_datetime(s: &str) -> Option<DateTime<Utc>> {
+    let (body, offset) = match s
+        .find(['Z', '+'])
+        .or_else(|| s.rfind('-').filter(|i| *i > 10))
+    {
+        Some(i) => (&s[..i], parse_offset(&s[i..])?),
+        None => (s, jst()),
+    };
+    let naive = NaiveDateTime::parse_from_str(body, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(body, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(body, "%Y-%m-%d")
+                .map(|d| d.and_hms_opt(0, 0, 0).unwrap_or_default())
+        })
+        .ok()?;
+    offset
+        .from_local_datetime(&naive)
+        .single()
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+// 日時リテラルとしてそのままパースできる形式で表示する
+pub fn fmt_datetime(dt: &DateTime<Utc>) -> String {
+    format!(
+        "@{}",
+        dt.with_timezone(&jst()).format("%Y-%m-%dT%H:%M:%S%:z")
+    )
+}
+
+fn delta(ms: i64) -> Result<TimeDelta, EvalError> {
+    TimeDelta::try_milliseconds(ms).ok_or(EvalError::OutOfRange)
+}
+
+fn scale(ms: i64, factor: f64) -> Result<EvalResult, EvalError> {
+    let scaled = (ms as f64 * factor).round();
+    if scaled.is_finite() && scaled.abs() < i64::MAX as f64 {
+        Ok(EvalResult::Duration(scaled as i64))
+    } else {
+        Err(EvalError::OutOfRange)
+    }
+}
+
+// 日時・時間が絡む二項演算
+// どちらも日時・時間でなければNoneを返す
+pub fn val_time_op2(
+    op: ExprOp2,
+    val1: &EvalResult,
+    val2: &EvalResult,
+) -> Option<Result<EvalResult, EvalError>> {
+    use EvalResult::{DateTime as Time, Duration};
+
+    if !matches!(val1, Time(_) | Duration(_)) && !matches!(val2, Time(_) | Duration(_)) {
+        return None;
+    }
+
+    let result = match (op, val1, val2) {
+        (ExprOp2::Add, Time(t), Duration(d)) | (ExprOp2::Add, Duration(d), Time(t)) => delta(*d)
+            .and_then(|d| t.checked_add_signed(d).ok_or(EvalError::OutOfRange))
+            .map(Time),
+        (ExprOp2::Sub, Time(t), Duration(d)) => delta(*d)
+            .and_then(|d| t.checked_sub_signed(d).ok_or(EvalError::OutOfRange))
+            .map(Time),
+        (ExprOp2::Sub, Time(t1), Time(t2)) => Ok(Duration((*t1 - *t2).num_milliseconds())),
+        (ExprOp2::Add, Duration(d1), Duration(d2)) => d1
+            .checked_add(*d2)
+            .map(Duration)
+            .ok_or(EvalError::OutOfRange),
+        (ExprOp2::Sub, Duration(d1), Duration(d2)) => d1
+            .checked_sub(*d2)
+            .map(Duration)
+            .ok_or(EvalError::OutOfRange),
+        (ExprOp2::Mul, Duration(d), n) | (ExprOp2::Mul, n, Duration(d)) => {
+            val_as_float(n).map_or_else(|| Err(EvalError::NotANumber(n.clone())), |f| scale(*d, f))
+        }
+        (ExprOp2::Div, Duration(d1), Duration(d2)) => {
+            if *d2 == 0 {
+                Err(EvalError::OutOfRange)
+            } else {
+                Ok(EvalResult::FVal(*d1 as f64 / *d2 as f64))
+            }
+        }
+        (ExprOp2::Div, Duration(d), n) => match val_as_float(n) {
+            Some(f) if f != 0.0 => scale(*d, 1.0 / f),
+            Some(_) => Err(EvalError::OutOfRange),
+            None => Err(EvalError::NotANumber(n.clone())),
+        },
+        (ExprOp2::Mod, Duration(d1), Duration(d2)) => d1
+            .checked_rem(*d2)
+            .map(Duration)
+            .ok_or(EvalError::OutOfRange),
+        (op, Duration(d1), Duration(d2)) => compare(op, d1, d2, val1),
+        (op, Time(t1), Time(t2)) => compare(op, t1, t2, val1),
+        (_, Time(_) | Duration(_), _) => Err(EvalError::NotANumber(val2.clone())),
+        _ => Err(EvalError::NotANumber(val1.clone())),
+    };
+    Some(result)
+}
+
+fn compare<T: PartialOrd>(
+    op: ExprOp2,
+    a: &T,
+    b: &T,
+    val: &EvalResult,
+) -> Result<EvalResult, EvalError> {
+    match op {
+        ExprOp2::Gt => Ok(EvalResult::BVal(a > b)),
+        ExprOp2::Ge => Ok(EvalResult::BVal(a >= b)),
+        ExprOp2::Lt => Ok(EvalResult::BVal(a < b)),
+        ExprOp2::Le => Ok(EvalResult::BVal(a <= b)),
+        ExprOp2::Eq => Ok(EvalResult::BVal(a == b)),
+        ExprOp2::Ne => Ok(EvalResult::BVal(a != b)),
+        _ => Err(EvalError::NotANumber(val.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests_datetime {
+    use super::*;
+
+    #[test]
+    fn test_fmt_duration() {
+        assert_eq!(fmt_duration(90 * MS_PER_SEC), "1m30s");
+        assert_eq!(fmt_duration(MS_PER_DAY + 2 * MS_PER_HOUR + 5), "1day2h5ms");
+        assert_eq!(fmt_duration(-MS_PER_SEC), "-1s");
+    }
+
+    #[test]
+    fn test_parse_datetime() {
+        let jst = parse_datetime("2025-06-01T09:00").unwrap();
+        let utc = parse_datetime("2025-06-01T00:00:00Z").unwrap();
+        let offset = parse_datetime("2025-06-01T02:00:00+02:00").unwrap();
+        assert_eq!(jst, utc);
+        assert_eq!(jst, offset);
+        assert_eq!(fmt_datetime(&utc), "@2025-06-01T09:00:00+09:00");
+    }
+}
@@ -0,0 +1,267 @@
+/*
+-----------------------------
+関数のヘルプ
+help() や /calchelp で表示する説明を、カテゴリごと・関数ごとに組み立てる
+各関数の例はテストでそのまま評価して、書かれた結果になるか確かめる
+-----------------------------
+*/
+
+use strum::{EnumIter, IntoEnumIterator};
+
+use super::{get_libfun, EvalStdLibFun, LibFun};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum HelpCategory {
+    Math,
+    Random,
+    List,
+    Seq,
+    Function,
+    Type,
+    LinAlg,
+    Graph,
+    Time,
+    Misc,
+}
+
+impl HelpCategory {
+    // help("list") のように指定するときの名前
+    pub const fn key(self) -> &'static str {
+        match self {
+            Self::Math => "math",
+            Self::Random => "random",
+            Self::List => "list",
+            Self::Seq => "seq",
+            Self::Function => "func",
+            Self::Type => "type",
+            Self::LinAlg => "linalg",
+            Self::Graph => "graph",
+            Self::Time => "time",
+            Self::Misc => "misc",
+        }
+    }
+
+    pub const fn title(self) -> &'static str {
+        match self {
+            Self::Math => "数学",
+            Self::Random => "乱数",
+            Self::List => "リスト",
+            Self::Seq => "シーケンス",
+            Self::Function => "関数",
+            Self::Type => "型と変換",
+            Self::LinAlg => "線形代数",
+            Self::Graph => "グラフ",
+            Self::Time => "日時",
+            Self::Misc => "その他",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        Self::iter().find(|c| c.key().eq_ignore_ascii_case(key))
+    }
+
+    fn heading(self) -> String {
+        format!("{} ({})", self.title(), self.key())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HelpPage {
+    pub title: String,
+    pub body: String,
+}
+
+// 埋め込みの1ページに載せる関数の数
+const FUNCS_PER_PAGE: usize = 10;
+
+// ifは構文なので標準ライブラリの関数とは別に説明する
+pub const IF_HELP: &str = "### `if(cond, then, else)`\n\
+    condがtrueのときthenを、falseのときelseの値を、それぞれショートサーキット評価して返します。";
+
+fn libfuns() -> Vec<LibFun> {
+    EvalStdLibFun::iter().map(get_libfun).collect()
+}
+
+fn find_libfun(name: &str) -> Option<LibFun> {
+    libfuns()
+        .into_iter()
+        .find(|f| f.name == name || f.alias.iter().any(|a| a == name))
+}
+
+// 一覧に載せる1行
+fn summary(libfun: &LibFun) -> String {
+    if libfun.note.is_empty() {
+        libfun.usage.clone()
+    } else {
+        format!("{} {}", libfun.usage, libfun.note)
+    }
+}
+
+// 関数1つの詳しい説明
+pub fn detail(libfun: &LibFun) -> String {
+    let mut s = format!("### {}\n", libfun.usage);
+    if !libfun.note.is_empty() {
+        s.push_str(&format!("{}\n", libfun.note));
+    }
+    if !libfun.alias.is_empty() {
+        s.push_str(&format!(
+            "alias: {}\n",
+            libfun
+                .alias
+                .iter()
+                .map(|a| format!("`{a}`"))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+    }
+    for (example, result) in libfun.examples {
+        s.push_str(&format!("`{example}` → `{result}`\n"));
+    }
+    s
+}
+
+// 関数の一覧をページに分ける
+fn paginate(title: &str, libfuns: &[LibFun]) -> Vec<HelpPage> {
+    let chunks = libfuns.chunks(FUNCS_PER_PAGE).collect::<Vec<_>>();
+    let count = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| HelpPage {
+            title: if count > 1 {
+                format!("{title} {}/{count}", i + 1)
+            } else {
+                title.to_owned()
+            },
+            body: chunk.iter().map(summary).collect::<Vec<_>>().join("\n"),
+        })
+        .collect()
+}
+
+fn category_pages(category: HelpCategory) -> Vec<HelpPage> {
+    let libfuns = libfuns()
+        .into_iter()
+        .filter(|f| f.category == category)
+        .collect::<Vec<_>>();
+    paginate(&category.heading(), &libfuns)
+}
+
+// 空ならカテゴリ順に全部、カテゴリ名ならそのカテゴリ、関数名ならその関数
+// どれでもなければ名前と説明から探す。見つからなければ空
+pub fn help_pages(query: &str) -> Vec<HelpPage> {
+    let query = query.trim();
+    if query.is_empty() {
+        return HelpCategory::iter().flat_map(category_pages).collect();
+    }
+    if let Some(category) = HelpCategory::from_key(query) {
+        return category_pages(category);
+    }
+    if query == "if" {
+        return vec![HelpPage {
+            title: "if".to_owned(),
+            body: IF_HELP.to_owned(),
+        }];
+    }
+    if let Some(libfun) = find_libfun(query) {
+        return vec![HelpPage {
+            title: libfun.name.clone(),
+            body: detail(&libfun),
+        }];
+    }
+    let lower = query.to_lowercase();
+    let found = libfuns()
+        .into_iter()
+        .filter(|f| {
+            f.name.to_lowercase().contains(&lower)
+                || f.alias.iter().any(|a| a.to_lowercase().contains(&lower))
+                || f.note.contains(query)
+        })
+        .collect::<Vec<_>>();
+    paginate(&format!("「{query}」の検索結果"), &found)
+}
+
+// help() の返す文字列
+pub fn help_text(query: Option<&str>) -> String {
+    let Some(query) = query else {
+        let mut s = "`help(\"list\")` でカテゴリの関数を、`help(\"map\")` や `help(map)` で関数の説明を表示するよ\n"
+            .to_owned();
+        for category in HelpCategory::iter() {
+            let names = libfuns()
+                .into_iter()
+                .filter(|f| f.category == category)
+                .map(|f| format!("`{}`", f.name))
+                .collect::<Vec<_>>();
+            s.push_str(&format!(
+                "**{}**: {}\n",
+                category.heading(),
+                names.join(", ")
+            ));
+        }
+        return s;
+    };
+    let query = query.trim();
+    if query == "if" {
+        return IF_HELP.to_owned();
+    }
+    if let Some(libfun) = find_libfun(query) {
+        return detail(&libfun);
+    }
+    let pages = help_pages(query);
+    if pages.is_empty() {
+        return format!("`{query}` は見つからなかったよ");
+    }
+    pages
+        .iter()
+        .map(|page| format!("## {}\n{}", page.title, page.body))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests_help {
+    use super::super::{eval_from_str, EvalContext};
+    use super::*;
+
+    // 説明に書いた例を実行して、書いてある結果になるか確かめる
+    #[test]
+    fn test_examples() {
+        let context = EvalContext::new();
+        for libfun in libfuns() {
+            assert!(
+                !libfun.examples.is_empty(),
+                "{} has no example",
+                libfun.name
+            );
+            for (example, expected) in libfun.examples {
+                let actual = eval_from_str(example, &context).map(|v| v.to_string());
+                assert_eq!(actual.as_deref(), Ok(*expected), "{example}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_help_pages() {
+        assert!(HelpCategory::iter().all(|c| !category_pages(c).is_empty()));
+        let list = help_pages("list");
+        assert!(list.len() > 1);
+        assert!(list[0].title.starts_with("リスト (list) 1/"));
+        assert!(list
+            .iter()
+            .all(|p| p.body.lines().count() <= FUNCS_PER_PAGE));
+
+        let map = help_pages("map");
+        assert_eq!(map.len(), 1);
+        assert!(map[0]
+            .body
+            .contains("`map(x => x * 2, [1, 2, 3])` → `[2, 4, 6]`"));
+        // 別名でも引ける
+        assert_eq!(help_pages("parseInt")[0].title, "atoi");
+
+        assert!(help_pages("ソート")[0].body.contains("sort"));
+        assert!(help_pages("no such thing").is_empty());
+        assert_eq!(
+            help_text(Some("no such thing")),
+            "`no such thing` は見つからなかったよ"
+        );
+    }
+}
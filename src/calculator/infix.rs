@@ -0,0 +1,354 @@
+/*
+-----------------------------
+ユーザー定義の演算子
+infixl 6 <+> = (a, b) => ... で定義し、変数 "operator <+>" に
+{fun: 関数, prec: 優先順位, assoc: "left" | "right" | "none"} として保存する
+オブジェクトの __add__ などのメソッドによる組み込み演算子の上書きもここで扱う
+-----------------------------
+*/
+
+use std::cell::RefCell;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_till1},
+    character::complete::{char, digit1, multispace0, multispace1},
+    combinator::{rest, value},
+    sequence::preceded,
+    IResult, Parser,
+};
+
+use super::{EvalContext, EvalResult, Expr, ExprOp2};
+
+// 演算子に使える記号
+const SYMBOL_CHARS: &str = "+-*/%^<>=!&|~?$#";
+
+// 単項演算子の記号
+const PREFIX_CHARS: &str = "-!";
+
+// 組み込みの演算子と、構文で使う記号。ユーザーは定義できない
+const RESERVED: [&str; 20] = [
+    "+", "-", "*", "/", "%", "^", "**", ">", ">=", "<", "<=", "==", "!=", "&&", "||", "^^", "|>",
+    ">>", "=>", "=",
+];
+
+// ユーザーが定義できる優先順位。parse_term2からparse_term9に対応する
+const PREC_MIN: u8 = 2;
+const PREC_MAX: u8 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+    None,
+}
+
+impl Assoc {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Left => "left",
+            Self::Right => "right",
+            Self::None => "none",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "left" => Some(Self::Left),
+            "right" => Some(Self::Right),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixity {
+    pub symbol: String,
+    pub prec: u8,
+    pub assoc: Assoc,
+}
+
+pub fn operator_var(symbol: &str) -> String {
+    format!("operator {symbol}")
+}
+
+fn is_symbol_char(c: char) -> bool {
+    SYMBOL_CHARS.contains(c)
+}
+
+/*
+宣言
+*/
+
+// 組み込みの演算子に単項の - や ! が続いた形。3<-1 は 3 < -1 と読むので、<- などは定義させない
+fn shadows_builtin(symbol: &str) -> bool {
+    RESERVED.iter().any(|op| {
+        symbol
+            .strip_prefix(op)
+            .is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| PREFIX_CHARS.contains(c)))
+    })
+}
+
+// infixl 6 <+> = 式 を (結合性, 優先順位, 記号, 式) に分ける
+fn parse_declaration(input: &str) -> IResult<&str, (Assoc, &str, &str, &str)> {
+    (
+        preceded(
+            multispace0,
+            alt((
+                value(Assoc::Left, tag("infixl")),
+                value(Assoc::Right, tag("infixr")),
+                value(Assoc::None, tag("infix")),
+            )),
+        ),
+        preceded(multispace1, digit1),
+        preceded(multispace1, take_till1(|c: char| c.is_whitespace())),
+        preceded((multispace0, char('=')), rest),
+    )
+        .parse(input)
+}
+
+// 演算子の宣言なら、検査した定義と本体の式を返す
+pub fn declaration(input: &str) -> Option<Result<(Fixity, &str), String>> {
+    let (_, (assoc, prec, symbol, body)) = parse_declaration(input).ok()?;
+    let prec = prec.parse::<u8>().unwrap_or(u8::MAX);
+    if !(PREC_MIN..=PREC_MAX).contains(&prec) {
+        return Some(Err(format!("優先順位は{PREC_MIN}から{PREC_MAX}までだよ")));
+    }
+    if !symbol.chars().all(is_symbol_char) {
+        return Some(Err(format!("演算子に使える記号は {SYMBOL_CHARS} だけだよ")));
+    }
+    if RESERVED.contains(&symbol) || symbol.starts_with("=>") || shadows_builtin(symbol) {
+        return Some(Err(format!("`{symbol}` は定義できないよ")));
+    }
+    Some(Ok((
+        Fixity {
+            symbol: symbol.to_owned(),
+            prec,
+            assoc,
+        },
+        body,
+    )))
+}
+
+// 変数に保存する値
+pub fn make_operator(fixity: &Fixity, fun: EvalResult) -> EvalResult {
+    EvalResult::Object(
+        [
+            ("fun".to_owned(), Box::new(fun)),
+            (
+                "prec".to_owned(),
+                Box::new(EvalResult::IVal(fixity.prec.into())),
+            ),
+            (
+                "assoc".to_owned(),
+                Box::new(EvalResult::SVal(fixity.assoc.name().to_owned())),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    )
+}
+
+// 変数に保存された演算子の一覧
+pub fn fixities(global_context: &EvalContext) -> Vec<Fixity> {
+    global_context
+        .hashmap
+        .iter()
+        .filter_map(|entry| {
+            let symbol = entry.key().strip_prefix("operator ")?;
+            let EvalResult::Object(o) = entry.value() else {
+                return None;
+            };
+            let prec = match o.get("prec").map(|v| &**v) {
+                Some(EvalResult::IVal(p)) => u8::try_from(*p).ok()?,
+                _ => return None,
+            };
+            let assoc = match o.get("assoc").map(|v| &**v) {
+                Some(EvalResult::SVal(s)) => Assoc::from_name(s)?,
+                _ => return None,
+            };
+            Some(Fixity {
+                symbol: symbol.to_owned(),
+                prec,
+                assoc,
+            })
+        })
+        .collect()
+}
+
+/*
+構文解析
+パーサーは関数ポインタで組み立てているので、演算子の一覧はスレッドローカルで渡す
+*/
+
+thread_local! {
+    static FIXITIES: RefCell<Vec<Fixity>> = const { RefCell::new(Vec::new()) };
+}
+
+// 演算子の一覧を有効にしてパースする
+pub fn with_fixities<T>(fixities: Vec<Fixity>, f: impl FnOnce() -> T) -> T {
+    let saved = FIXITIES.replace(fixities);
+    let result = f();
+    FIXITIES.set(saved);
+    result
+}
+
+pub enum OpToken {
+    // 組み込みの演算子として読むべき位置
+    Builtin,
+    User(Fixity),
+}
+
+// 記号の並びを最長一致で切り出す
+// ユーザー定義の演算子が組み込みのものより長く一致すれば、そちらとして読む
+pub fn op_token(input: &str) -> (OpToken, &str) {
+    let run_len = input.find(|c| !is_symbol_char(c)).unwrap_or(input.len());
+    let run = &input[..run_len];
+    let builtin_len = RESERVED
+        .iter()
+        .filter(|op| run.starts_with(**op))
+        .map(|op| op.len())
+        .max()
+        .unwrap_or(0);
+    FIXITIES.with_borrow(|fixities| {
+        fixities
+            .iter()
+            .filter(|f| run.starts_with(&f.symbol) && f.symbol.len() > builtin_len)
+            .max_by_key(|f| f.symbol.len())
+            .map_or((OpToken::Builtin, input), |f| {
+                (OpToken::User(f.clone()), &input[f.symbol.len()..])
+            })
+    })
+}
+
+// a <+> b は operator <+> の fun を呼び出す
+pub fn desugar(symbol: &str, e1: &Expr, e2: &Expr) -> Expr {
+    Expr::Apply(
+        Box::new(Expr::Get(
+            Box::new(Expr::Const(operator_var(symbol))),
+            "fun".to_owned(),
+        )),
+        vec![e1.clone(), e2.clone()],
+    )
+}
+
+/*
+オブジェクトによる組み込み演算子の上書き
+*/
+
+const fn method_name(op: ExprOp2) -> Option<&'static str> {
+    match op {
+        ExprOp2::Add => Some("__add__"),
+        ExprOp2::Sub => Some("__sub__"),
+        ExprOp2::Mul => Some("__mul__"),
+        ExprOp2::Div => Some("__div__"),
+        ExprOp2::Mod => Some("__mod__"),
+        ExprOp2::Pow => Some("__pow__"),
+        ExprOp2::Gt => Some("__gt__"),
+        ExprOp2::Ge => Some("__ge__"),
+        ExprOp2::Lt => Some("__lt__"),
+        ExprOp2::Le => Some("__le__"),
+        ExprOp2::Eq => Some("__eq__"),
+        ExprOp2::Ne => Some("__ne__"),
+        _ => None,
+    }
+}
+
+// 左、右の順にオペランドのオブジェクトからメソッドを探す
+// 見つかれば method(左, 右) として呼び出す
+pub fn overload(op: ExprOp2, val1: &EvalResult, val2: &EvalResult) -> Option<EvalResult> {
+    let name = method_name(op)?;
+    [val1, val2].into_iter().find_map(|val| match val {
+        EvalResult::Object(o) => o.get(name).map(|method| *method.clone()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests_infix {
+    use super::super::{eval_declaration, eval_expr, eval_expr_tree, parse_expr_in, Scope};
+    use super::*;
+
+    fn define(context: &EvalContext, input: &str) {
+        let (name, val) = eval_declaration(input, context, &Scope::new())
+            .unwrap()
+            .unwrap();
+        context.insert(name, val);
+    }
+
+    #[test]
+    fn test_declaration() {
+        assert!(declaration("1 + 2").is_none());
+        assert!(
+            matches!(declaration("infixr 5 ++> = f"), Some(Ok((f, " f"))) if f.assoc == Assoc::Right)
+        );
+        for input in [
+            "infixl 1 <+> = f",
+            "infixl 6 ** = f",
+            "infix 6 => = f",
+            "infixl 6 <x> = f",
+            "infixl 6 <- = f",
+            "infixl 6 *-! = f",
+            "infixl 6 ==! = f",
+        ] {
+            assert!(matches!(declaration(input), Some(Err(_))), "{input}");
+        }
+        let context = EvalContext::new();
+        assert!(matches!(
+            eval_declaration("infixl 6 <+> = 1 + 2", &context, &Scope::new()),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn test_user_operator() {
+        let context = EvalContext::new();
+        define(
+            &context,
+            "infixl 6 <+> = (a, b) => [a[0] + b[0], a[1] + b[1]]",
+        );
+        define(&context, "infixr 5 ++> = (a, b) => a * 10 + b");
+        define(&context, "infixl 5 --> = (a, b) => a * 10 + b");
+        let parse = |s: &str| parse_expr_in(s, &context).unwrap().1;
+        assert_eq!(parse("1 ++> 2 ++> 3").to_string(), "1 ++> (2 ++> 3)");
+        assert_eq!(parse("1 --> 2 --> 3").to_string(), "(1 --> 2) --> 3");
+        // 組み込みの + より弱く結合する
+        assert_eq!(parse("1 + 2 <+> 3").to_string(), "(1 + 2) <+> 3");
+        let eval = |s: &str| eval_expr(&parse(s), &context).unwrap().to_string();
+        assert_eq!(eval("[1, 2] <+> [3, 4] <+> [10, 20]"), "[14, 26]");
+        assert_eq!(eval("1 ++> 2 ++> 3"), "33");
+        assert_eq!(eval("1 --> 2 --> 3"), "123");
+        // 定義されていなければ組み込みの演算子として読む
+        assert_eq!(eval("3 >= 2"), "true");
+        // 組み込みの演算子と単項の - の組み合わせは乗っ取れない
+        assert!(
+            eval_declaration("infixl 6 <- = (a, b) => 42", &context, &Scope::new())
+                .is_some_and(|r| r.is_err())
+        );
+        assert_eq!(eval("3<-1"), "false");
+    }
+
+    #[test]
+    fn test_overload() {
+        let context = EvalContext::new();
+        let money = "fix(money => v => {v: v, __add__: (a, b) => money(a.v + b.v), \
+            __gt__: (a, b) => a.v > b.v})";
+        let expr = |s: &str| {
+            parse_expr_in(&format!("(money => {s})({money})"), &context)
+                .unwrap()
+                .1
+        };
+        for (input, expected) in [
+            ("(money(100) + money(250)).v", "350"),
+            ("money(100) > money(30)", "true"),
+            ("[money(1) + money(2)][0].v", "3"),
+        ] {
+            let e = expr(input);
+            assert_eq!(eval_expr(&e, &context).unwrap().to_string(), expected);
+            assert_eq!(eval_expr_tree(&e, &context).unwrap().to_string(), expected);
+        }
+        // メソッドのない演算子はそのまま
+        assert!(eval_expr(&expr("money(1) * 2"), &context).is_err());
+    }
+}
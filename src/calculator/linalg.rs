@@ -0,0 +1,249 @@
+/*
+-----------------------------
+線形代数
+リストをベクトル、リストのリストを行列として扱う
+-----------------------------
+*/
+
+use super::{val_as_float, EvalError, EvalResult};
+
+pub type Vector = Vec<f64>;
+pub type Matrix = Vec<Vec<f64>>;
+
+// ピボットがこれより小さければ特異行列とみなす
+const EPSILON: f64 = 1e-12;
+
+// [[1, 2, 3], [4, 5, 6]] -> [2, 3]
+// 各次元の長さは先頭要素で代表させる
+pub fn val_shape(val: &EvalResult) -> Vec<usize> {
+    match val {
+        EvalResult::List(l) => {
+            let mut shape = vec![l.len()];
+            if let Some(first) = l.first() {
+                shape.extend(val_shape(first));
+            }
+            shape
+        }
+        _ => vec![],
+    }
+}
+
+pub fn val_as_vector(val: &EvalResult) -> Result<Vector, EvalError> {
+    match val {
+        EvalResult::List(l) => l
+            .iter()
+            .map(|e| val_as_float(e).ok_or_else(|| EvalError::NotANumber(e.clone())))
+            .collect(),
+        _ => Err(EvalError::NotAList(val.clone())),
+    }
+}
+
+pub fn val_as_matrix(val: &EvalResult) -> Result<Matrix, EvalError> {
+    let EvalResult::List(rows) = val else {
+        return Err(EvalError::NotAList(val.clone()));
+    };
+    let matrix = rows
+        .iter()
+        .map(val_as_vector)
+        .collect::<Result<Matrix, _>>()?;
+    if let Some(first) = matrix.first() {
+        if let Some(row) = matrix.iter().find(|row| row.len() != first.len()) {
+            return Err(EvalError::ShapeMismatch(vec![row.len()], vec![first.len()]));
+        }
+    }
+    Ok(matrix)
+}
+
+// ベクトル（数値のリスト）か行列（リストのリスト）か
+pub fn is_matrix(val: &EvalResult) -> bool {
+    matches!(val, EvalResult::List(l) if l.iter().all(|e| matches!(e, EvalResult::List(_))) && !l.is_empty())
+}
+
+pub fn vector_to_val(v: Vector) -> EvalResult {
+    EvalResult::List(v.into_iter().map(EvalResult::FVal).collect())
+}
+
+pub fn matrix_to_val(m: Matrix) -> EvalResult {
+    EvalResult::List(m.into_iter().map(vector_to_val).collect())
+}
+
+fn matrix_shape(m: &Matrix) -> (usize, usize) {
+    (m.len(), m.first().map_or(0, Vec::len))
+}
+
+fn require_square(m: &Matrix) -> Result<usize, EvalError> {
+    let (rows, cols) = matrix_shape(m);
+    if rows == cols {
+        Ok(rows)
+    } else {
+        Err(EvalError::ShapeMismatch(vec![rows, cols], vec![rows, rows]))
+    }
+}
+
+pub fn dot(a: &Vector, b: &Vector) -> Result<f64, EvalError> {
+    if a.len() != b.len() {
+        return Err(EvalError::ShapeMismatch(vec![a.len()], vec![b.len()]));
+    }
+    Ok(a.iter().zip(b.iter()).map(|(x, y)| x * y).sum())
+}
+
+pub fn cross(a: &Vector, b: &Vector) -> Result<Vector, EvalError> {
+    match (a.as_slice(), b.as_slice()) {
+        ([a1, a2, a3], [b1, b2, b3]) => Ok(vec![
+            a2 * b3 - a3 * b2,
+            a3 * b1 - a1 * b3,
+            a1 * b2 - a2 * b1,
+        ]),
+        ([_, _, _], _) => Err(EvalError::ShapeMismatch(vec![b.len()], vec![3])),
+        _ => Err(EvalError::ShapeMismatch(vec![a.len()], vec![3])),
+    }
+}
+
+pub fn norm(a: &Vector) -> f64 {
+    a.iter().map(|x| x * x).sum::<f64>().sqrt()
+}
+
+pub fn identity(n: usize) -> Matrix {
+    (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect()
+}
+
+pub fn matmul(a: &Matrix, b: &Matrix) -> Result<Matrix, EvalError> {
+    let (a_rows, a_cols) = matrix_shape(a);
+    let (b_rows, b_cols) = matrix_shape(b);
+    // 空の行列同士の積は形が決まらない
+    if a_cols != b_rows || a_cols == 0 || b_cols == 0 {
+        return Err(EvalError::ShapeMismatch(
+            vec![a_rows, a_cols],
+            vec![b_rows, b_cols],
+        ));
+    }
+    Ok((0..a_rows)
+        .map(|i| {
+            (0..b_cols)
+                .map(|j| (0..a_cols).map(|k| a[i][k] * b[k][j]).sum())
+                .collect()
+        })
+        .collect())
+}
+
+// 部分ピボット選択付きのガウスの消去法
+pub fn det(m: &Matrix) -> Result<f64, EvalError> {
+    let n = require_square(m)?;
+    let mut m = m.clone();
+    let mut det = 1.0;
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| m[i][col].abs().total_cmp(&m[j][col].abs()))
+            .unwrap_or(col);
+        if m[pivot][col].abs() < EPSILON {
+            return Ok(0.0);
+        }
+        if pivot != col {
+            m.swap(pivot, col);
+            det = -det;
+        }
+        det *= m[col][col];
+        let pivot_row = m[col].clone();
+        for row in m.iter_mut().skip(col + 1) {
+            let factor = row[col] / pivot_row[col];
+            for (x, p) in row.iter_mut().zip(pivot_row.iter()).skip(col) {
+                *x -= factor * p;
+            }
+        }
+    }
+    Ok(det)
+}
+
+// ガウス・ジョルダン法で a x = b を解く
+// bは行列（右辺を列として複数並べたもの）
+pub fn solve(a: &Matrix, b: &Matrix) -> Result<Matrix, EvalError> {
+    let n = require_square(a)?;
+    let (b_rows, b_cols) = matrix_shape(b);
+    if b_rows != n {
+        return Err(EvalError::ShapeMismatch(
+            vec![b_rows, b_cols],
+            vec![n, b_cols],
+        ));
+    }
+
+    let mut a = a.clone();
+    let mut b = b.clone();
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+            .unwrap_or(col);
+        if a[pivot][col].abs() < EPSILON {
+            return Err(EvalError::SingularMatrix);
+        }
+        a.swap(pivot, col);
+        b.swap(pivot, col);
+
+        let p = a[col][col];
+        a[col].iter_mut().for_each(|x| *x /= p);
+        b[col].iter_mut().for_each(|x| *x /= p);
+
+        let pivot_a = a[col].clone();
+        let pivot_b = b[col].clone();
+        for (row, (row_a, row_b)) in a.iter_mut().zip(b.iter_mut()).enumerate() {
+            if row == col {
+                continue;
+            }
+            let factor = row_a[col];
+            for (x, p) in row_a.iter_mut().zip(pivot_a.iter()) {
+                *x -= factor * p;
+            }
+            for (x, p) in row_b.iter_mut().zip(pivot_b.iter()) {
+                *x -= factor * p;
+            }
+        }
+    }
+    Ok(b)
+}
+
+pub fn inv(m: &Matrix) -> Result<Matrix, EvalError> {
+    let n = require_square(m)?;
+    solve(m, &identity(n))
+}
+
+#[cfg(test)]
+mod tests_linalg {
+    use super::*;
+
+    #[test]
+    fn test_det() {
+        let m = vec![
+            vec![2.0, 0.0, 1.0],
+            vec![1.0, 3.0, 2.0],
+            vec![1.0, 1.0, 2.0],
+        ];
+        assert!((det(&m).unwrap() - 6.0).abs() < 1e-9);
+        assert_eq!(det(&vec![vec![1.0, 2.0], vec![2.0, 4.0]]).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_inv() {
+        let m = vec![vec![4.0, 7.0], vec![2.0, 6.0]];
+        let product = matmul(&m, &inv(&m).unwrap()).unwrap();
+        for (i, row) in product.iter().enumerate() {
+            for (j, x) in row.iter().enumerate() {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((x - expected).abs() < 1e-9);
+            }
+        }
+        assert!(matches!(
+            inv(&vec![vec![1.0, 2.0], vec![2.0, 4.0]]),
+            Err(EvalError::SingularMatrix)
+        ));
+    }
+
+    #[test]
+    fn test_matmul_shape() {
+        let a = vec![vec![1.0, 2.0, 3.0]];
+        assert!(matches!(
+            matmul(&a, &a),
+            Err(EvalError::ShapeMismatch(_, _))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+/*
+-----------------------------
+メモ化
+memo(f) は引数ごとに結果を覚えておく関数を返す
+fix(memo(f)) と書くと再帰呼び出しもメモ化される
+-----------------------------
+*/
+
+use std::sync::{Arc, Mutex};
+
+use super::{
+    deep_eq, eval_apply, list_free_var, EvalContext, EvalError, EvalMode, EvalResult,
+    EvalStdLibFun, Expr, ExprOp1, ExprOp2, ResultType, Scope,
+};
+
+// 覚えておく引数の組の上限。超えたら古いものから捨てる
+const MEMO_LIMIT: usize = 1000;
+
+// 自由変数をたどって純粋性を調べる深さの上限
+const PURITY_DEPTH: usize = 8;
+
+// 結果の表。memoの値をcloneしても同じ表を共有する
+// 引数はsame_keyで比べるので、線形に探す
+type MemoTable = Vec<(Vec<EvalResult>, EvalResult)>;
+
+// deep_eqでは3と3.0とtrueが等しくなるので、型も揃っているときだけ同じ引数とみなす
+fn same_key(a: &EvalResult, b: &EvalResult) -> bool {
+    if ResultType::of(a) != ResultType::of(b) {
+        return false;
+    }
+    match (a, b) {
+        (EvalResult::List(l1), EvalResult::List(l2)) => {
+            l1.len() == l2.len() && l1.iter().zip(l2).all(|(a, b)| same_key(a, b))
+        }
+        (EvalResult::Object(o1), EvalResult::Object(o2)) => {
+            o1.len() == o2.len()
+                && o1
+                    .iter()
+                    .all(|(k, v)| o2.get(k).is_some_and(|w| same_key(v, w)))
+        }
+        _ => deep_eq(a, b),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoCache(Arc<Mutex<MemoTable>>);
+
+impl MemoCache {
+    fn get(&self, args: &[EvalResult]) -> Option<EvalResult> {
+        let table = self.0.lock().ok()?;
+        table
+            .iter()
+            .find(|(key, _)| {
+                key.len() == args.len() && key.iter().zip(args).all(|(a, b)| same_key(a, b))
+            })
+            .map(|(_, val)| val.clone())
+    }
+
+    fn insert(&self, args: Vec<EvalResult>, val: EvalResult) {
+        if let Ok(mut table) = self.0.lock() {
+            if table.len() >= MEMO_LIMIT {
+                table.remove(0);
+            }
+            table.push((args, val));
+        }
+    }
+}
+
+// 呼ぶたびに結果が変わりうる標準関数
+const fn is_impure_stdlib(func: &EvalStdLibFun) -> bool {
+    matches!(
+        func,
+        EvalStdLibFun::URand
+            | EvalStdLibFun::GRand
+            | EvalStdLibFun::Pick
+            | EvalStdLibFun::PickArg
+            | EvalStdLibFun::Shuffle
+            | EvalStdLibFun::Now
+    )
+}
+
+fn has_dice(expr: &Expr) -> bool {
+    match expr {
+        Expr::Op1(ExprOp1::OneDice, _) | Expr::Op2(ExprOp2::Dice, _, _) => true,
+        Expr::Op1(_, e) | Expr::Get(e, _) | Expr::Lambda(_, e) => has_dice(e),
+        Expr::Op2(_, e1, e2) | Expr::At(e1, e2) | Expr::Infix(_, e1, e2) => {
+            has_dice(e1) || has_dice(e2)
+        }
+        Expr::Apply(f, args) => has_dice(f) || args.iter().any(has_dice),
+        Expr::List(l) => l.iter().any(has_dice),
+        Expr::Object(o) => o.values().any(|e| has_dice(e)),
+        _ => false,
+    }
+}
+
+// 非決定的なものを使っていればその名前を返す
+pub fn find_impurity(
+    val: &EvalResult,
+    global_context: &EvalContext,
+    depth: usize,
+) -> Option<String> {
+    if depth > PURITY_DEPTH {
+        return None;
+    }
+    match val {
+        EvalResult::FuncStdLib(func) if is_impure_stdlib(func) => Some(func.to_string()),
+        EvalResult::Partial(func, args) => {
+            find_impurity(func, global_context, depth + 1).or_else(|| {
+                args.iter()
+                    .find_map(|arg| find_impurity(arg, global_context, depth + 1))
+            })
+        }
+        EvalResult::Closure(_, body, captured) => {
+            if has_dice(body) {
+                return Some("dice".to_owned());
+            }
+            list_free_var(body).into_iter().find_map(|name| {
+                let val = captured
+                    .get(&name)
+                    .cloned()
+                    .or_else(|| global_context.get(&name).map(|v| v.clone()))?;
+                find_impurity(&val, global_context, depth + 1)
+            })
+        }
+        EvalResult::List(l) => l
+            .iter()
+            .find_map(|e| find_impurity(e, global_context, depth + 1)),
+        _ => None,
+    }
+}
+
+// メモ化した関数の適用
+// fix済みなら、中の関数は自分自身を受け取って本来の関数を返す関数
+pub fn call(
+    expr: &Expr,
+    step: usize,
+    mode: &mut EvalMode,
+    global_context: &EvalContext,
+    local_context: &Scope,
+    memo: &EvalResult,
+    args: Vec<EvalResult>,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    let EvalResult::Memo(func, cache, recursive) = memo else {
+        return Err((EvalError::NotAFunction(memo.clone()), expr.clone()));
+    };
+    if let Some(val) = cache.get(&args) {
+        return Ok((val, step + 1));
+    }
+    let (func, step) = if *recursive {
+        eval_apply(
+            expr,
+            step + 1,
+            mode,
+            global_context,
+            local_context,
+            *func.clone(),
+            vec![memo.clone()],
+        )?
+    } else {
+        (*func.clone(), step + 1)
+    };
+    let (val, step) = eval_apply(
+        expr,
+        step,
+        mode,
+        global_context,
+        local_context,
+        func,
+        args.clone(),
+    )?;
+    cache.insert(args, val.clone());
+    Ok((val, step))
+}
@@ -0,0 +1,224 @@
+/*
+-----------------------------
+グラフ描画
+Discordのコードブロックに収まる固定幅のUnicodeチャートを作る
+-----------------------------
+*/
+
+// bfコマンドと同じく、2000文字制限に余裕を持たせた値
+const CHAR_LIMIT: usize = 1960;
+
+pub const PLOT_WIDTH: usize = 48;
+const PLOT_HEIGHT: usize = 12;
+const BAR_WIDTH: usize = 32;
+// どうせ文字数制限で切れるので、これより細かい区間分けはしない
+pub const MAX_BINS: usize = 100;
+
+// 1/8刻みのブロック。添字が幅（0/8〜7/8）に対応する
+const PARTIAL_BLOCKS: [&str; 8] = ["", "▏", "▎", "▍", "▌", "▋", "▊", "▉"];
+const FULL_BLOCK: &str = "█";
+
+// 小数点以下3桁に丸めて表示
+fn fmt_num(x: f64) -> String {
+    let rounded = (x * 1000.0).round() / 1000.0;
+    if rounded == 0.0 {
+        // -0 を表示しない
+        "0".to_owned()
+    } else {
+        format!("{rounded}")
+    }
+}
+
+// 行をコードブロックに詰める。収まらない行は省略する
+fn code_block(lines: &[String]) -> String {
+    let mut body = String::new();
+    let mut length = "```\n```".chars().count();
+    for (i, line) in lines.iter().enumerate() {
+        let line_length = line.chars().count() + 1;
+        if length + line_length > CHAR_LIMIT {
+            let rest = format!("...のこり{}行は省略しちゃうね！\n", lines.len() - i);
+            body.push_str(&rest);
+            break;
+        }
+        body.push_str(line);
+        body.push('\n');
+        length += line_length;
+    }
+    format!("```\n{body}```")
+}
+
+// xをfromからtoまで等間隔にとったときのysを描画する
+// 非有限値は描画しない
+pub fn render_plot(from: f64, to: f64, ys: &[f64]) -> String {
+    let finite = ys.iter().copied().filter(|y| y.is_finite());
+    let (mut ymin, mut ymax) = finite.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), y| {
+        (lo.min(y), hi.max(y))
+    });
+    if ymin > ymax {
+        // 描画できる点がない
+        return code_block(&["(描画できる点がないよ)".to_owned()]);
+    }
+    if ymin == ymax {
+        ymin -= 1.0;
+        ymax += 1.0;
+    }
+
+    let row_of =
+        |y: f64| (((ymax - y) / (ymax - ymin)) * (PLOT_HEIGHT - 1) as f64).round() as usize;
+    let zero_row = (ymin < 0.0 && 0.0 < ymax).then(|| row_of(0.0));
+
+    let mut grid = vec![vec![' '; ys.len()]; PLOT_HEIGHT];
+    if let Some(zero_row) = zero_row {
+        grid[zero_row].iter_mut().for_each(|c| *c = '─');
+    }
+    for (x, y) in ys.iter().enumerate() {
+        if y.is_finite() {
+            grid[row_of(*y)][x] = '•';
+        }
+    }
+
+    let labels = (0..PLOT_HEIGHT)
+        .map(|row| {
+            if row == 0 {
+                fmt_num(ymax)
+            } else if row == PLOT_HEIGHT - 1 {
+                fmt_num(ymin)
+            } else if Some(row) == zero_row {
+                "0".to_owned()
+            } else {
+                String::new()
+            }
+        })
+        .collect::<Vec<_>>();
+    let label_width = labels.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+
+    let mut lines = grid
+        .into_iter()
+        .zip(labels.iter())
+        .map(|(row, label)| {
+            let tick = if label.is_empty() { '│' } else { '┤' };
+            format!(
+                "{label:>label_width$} {tick}{}",
+                row.into_iter().collect::<String>()
+            )
+        })
+        .collect::<Vec<_>>();
+    lines.push(format!("{:>label_width$} └{}", "", "─".repeat(ys.len())));
+
+    let from_label = fmt_num(from);
+    let to_label = fmt_num(to);
+    let gap = ys
+        .len()
+        .saturating_sub(from_label.chars().count() + to_label.chars().count())
+        .max(1);
+    lines.push(format!(
+        "{:>label_width$}  {from_label}{}{to_label}",
+        "",
+        " ".repeat(gap)
+    ));
+
+    code_block(&lines)
+}
+
+fn bar(value: f64, max: f64) -> String {
+    if value <= 0.0 || max <= 0.0 || !value.is_finite() {
+        return String::new();
+    }
+    let eighths = ((value / max) * (BAR_WIDTH * 8) as f64).round() as usize;
+    FULL_BLOCK.repeat(eighths / 8) + PARTIAL_BLOCKS[eighths % 8]
+}
+
+// ラベルと値の組を横棒グラフにする
+pub fn render_bars(rows: &[(String, f64)]) -> String {
+    let max = rows
+        .iter()
+        .map(|(_, v)| *v)
+        .filter(|v| v.is_finite())
+        .fold(0.0, f64::max);
+    let label_width = rows
+        .iter()
+        .map(|(l, _)| l.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let lines = rows
+        .iter()
+        .map(|(label, value)| {
+            format!(
+                "{label:>label_width$} │{} {}",
+                bar(*value, max),
+                fmt_num(*value)
+            )
+        })
+        .collect::<Vec<_>>();
+    code_block(&lines)
+}
+
+// valuesを最小値から最大値までbins個の等幅な区間に分けて数える
+pub fn histogram_rows(values: &[f64], bins: usize) -> Vec<(String, f64)> {
+    let bins = bins.clamp(1, MAX_BINS);
+    let (min, max) = values
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+            (lo.min(*v), hi.max(*v))
+        });
+    let width = if max > min {
+        (max - min) / bins as f64
+    } else {
+        1.0
+    };
+
+    let mut counts = vec![0usize; bins];
+    for v in values {
+        let i = (((v - min) / width) as usize).min(bins - 1);
+        counts[i] += 1;
+    }
+
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, count)| {
+            let lo = (i as f64).mul_add(width, min);
+            let hi = lo + width;
+            let close = if i == bins - 1 { ']' } else { ')' };
+            (
+                format!("[{}, {}{close}", fmt_num(lo), fmt_num(hi)),
+                count as f64,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests_plot {
+    use super::*;
+
+    #[test]
+    fn test_render_plot() {
+        let ys = (0..PLOT_WIDTH)
+            .map(|i| (i as f64 / 4.0).sin())
+            .collect::<Vec<_>>();
+        let chart = render_plot(0.0, 12.0, &ys);
+        assert!(chart.starts_with("```\n") && chart.ends_with("```"));
+        assert_eq!(chart.lines().count(), PLOT_HEIGHT + 4);
+    }
+
+    #[test]
+    fn test_render_bars_limit() {
+        let rows = (0..1000)
+            .map(|i| (i.to_string(), f64::from(i)))
+            .collect::<Vec<_>>();
+        let chart = render_bars(&rows);
+        assert!(chart.chars().count() <= 2000);
+        assert!(chart.contains("省略"));
+    }
+
+    #[test]
+    fn test_histogram_rows() {
+        let rows = histogram_rows(&[1.0, 2.0, 2.0, 3.0, 4.0], 3);
+        let counts = rows.iter().map(|(_, c)| *c).collect::<Vec<_>>();
+        assert_eq!(counts, vec![1.0, 2.0, 2.0]);
+        // 区間の数が多すぎても確保しない
+        assert_eq!(histogram_rows(&[1.0], usize::MAX).len(), MAX_BINS);
+    }
+}
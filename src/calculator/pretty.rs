@@ -0,0 +1,495 @@
+/*
+-----------------------------
+整形表示
+演算子の優先順位を見て必要なところだけ括弧をつけ、parse_exprで読み戻せる形で表示する
+長いリストやオブジェクトは折り返し、大きすぎる値は省略する
+-----------------------------
+*/
+
+use super::{datetime, types, EvalResult, Expr, ExprOp1, ExprOp2, Param};
+
+#[derive(Debug, Clone, Copy)]
+pub struct PrettyOptions {
+    pub width: usize,     // これより長くなるリスト・オブジェクト・引数は折り返す
+    pub max_items: usize, // リスト・オブジェクトの値はこれ以上の要素を省略する
+    pub max_str: usize,   // 文字列の値はこれ以上の文字を省略する
+    pub show_env: bool,   // クロージャが捕獲した環境も表示する
+}
+
+impl PrettyOptions {
+    // 1行で表示する。返信やCLIの出力に使う
+    pub const FLAT: Self = Self {
+        width: usize::MAX,
+        max_items: 100,
+        max_str: 2000,
+        show_env: false,
+    };
+
+    // 折り返して表示する。{:#}で使う
+    pub const WRAPPED: Self = Self {
+        width: 60,
+        ..Self::FLAT
+    };
+
+    // 何も省略せずに1行で表示する。Displayやtostrなど、値を文字列にするときに使う
+    pub const FULL: Self = Self {
+        max_items: usize::MAX,
+        max_str: usize::MAX,
+        ..Self::FLAT
+    };
+}
+
+impl Default for PrettyOptions {
+    fn default() -> Self {
+        Self::FLAT
+    }
+}
+
+const INDENT: usize = 2;
+
+// 括弧の要らない一番弱い優先順位。ラムダ式の本体はここまで伸びる
+const LOWEST: u8 = 10;
+
+enum Doc {
+    Text(String),
+    Concat(Vec<Self>),
+    // 開き括弧、要素、閉じ括弧。1行に収まらなければ要素を1行ずつ並べる
+    Group(&'static str, Vec<Self>, &'static str),
+}
+
+impl Doc {
+    fn text(s: impl Into<String>) -> Self {
+        Self::Text(s.into())
+    }
+
+    fn paren(self) -> Self {
+        Self::Concat(vec![Self::text("("), self, Self::text(")")])
+    }
+
+    fn flat(&self, out: &mut String) {
+        match self {
+            Self::Text(s) => out.push_str(s),
+            Self::Concat(docs) => docs.iter().for_each(|d| d.flat(out)),
+            Self::Group(open, items, close) => {
+                out.push_str(open);
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    item.flat(out);
+                }
+                out.push_str(close);
+            }
+        }
+    }
+
+    fn to_flat(&self) -> String {
+        let mut out = String::new();
+        self.flat(&mut out);
+        out
+    }
+
+    fn render(&self, width: usize, indent: usize, out: &mut String) {
+        match self {
+            Self::Concat(docs) => docs.iter().for_each(|d| d.render(width, indent, out)),
+            Self::Group(open, items, close)
+                if !items.is_empty() && column(out) + self.to_flat().chars().count() > width =>
+            {
+                out.push_str(open);
+                // 数などの短い要素だけなら、1行に詰められるだけ詰める
+                let fill = items.iter().all(|item| matches!(item, Self::Text(_)));
+                for (i, item) in items.iter().enumerate() {
+                    let len = item.to_flat().chars().count();
+                    if fill && i > 0 && column(out) + len + 2 <= width {
+                        out.push(' ');
+                    } else {
+                        out.push('\n');
+                        out.push_str(&" ".repeat(indent + INDENT));
+                    }
+                    item.render(width, indent + INDENT, out);
+                    if i + 1 < items.len() {
+                        out.push(',');
+                    }
+                }
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                out.push_str(close);
+            }
+            _ => self.flat(out),
+        }
+    }
+}
+
+fn column(out: &str) -> usize {
+    out.rsplit('\n')
+        .next()
+        .map_or(0, |line| line.chars().count())
+}
+
+fn layout(doc: &Doc, opts: &PrettyOptions) -> String {
+    let mut out = String::new();
+    doc.render(opts.width, 0, &mut out);
+    out
+}
+
+pub fn show_expr(expr: &Expr, opts: &PrettyOptions) -> String {
+    layout(&expr_doc(expr), opts)
+}
+
+pub fn show_val(val: &EvalResult, opts: &PrettyOptions) -> String {
+    layout(&val_doc(val, opts), opts)
+}
+
+/*
+式
+*/
+
+// 優先順位はparse_term1からparse_term9の番号に合わせる
+const fn op2_level(op: ExprOp2) -> u8 {
+    match op {
+        ExprOp2::Dice => 2,
+        ExprOp2::Pow => 3,
+        ExprOp2::Mul | ExprOp2::Div | ExprOp2::Mod => 4,
+        ExprOp2::Add | ExprOp2::Sub => 5,
+        ExprOp2::Gt | ExprOp2::Ge | ExprOp2::Lt | ExprOp2::Le | ExprOp2::Eq | ExprOp2::Ne => 6,
+        ExprOp2::AndL | ExprOp2::OrL | ExprOp2::XorL => 7,
+        ExprOp2::Compose => 8,
+        ExprOp2::Pipe => 9,
+    }
+}
+
+const fn level(expr: &Expr) -> u8 {
+    match expr {
+        // 負の数は単項の-として読み戻される
+        Expr::IVal(i) if *i < 0 => 1,
+        Expr::FVal(v) if v.is_sign_negative() => 1,
+        Expr::Duration(ms) if *ms < 0 => 1,
+        Expr::Op1(..) => 1,
+        Expr::Op2(op, ..) => op2_level(*op),
+        Expr::Lambda(..) | Expr::Infix(..) => LOWEST,
+        _ => 0,
+    }
+}
+
+// 優先順位がmaxより弱ければ括弧で囲む
+fn expr_at(expr: &Expr, max: u8) -> Doc {
+    let doc = expr_doc(expr);
+    if level(expr) > max {
+        doc.paren()
+    } else {
+        doc
+    }
+}
+
+// 呼び出し・添字・フィールドの左側
+fn postfix_base(expr: &Expr) -> Doc {
+    match expr {
+        // 90s.x は時間リテラルとして読めない
+        Expr::Duration(_) => expr_doc(expr).paren(),
+        _ => expr_at(expr, 0),
+    }
+}
+
+fn expr_doc(expr: &Expr) -> Doc {
+    match expr {
+        Expr::IVal(i) => Doc::text(i.to_string()),
+        Expr::FVal(v) => Doc::text(show_float(*v)),
+        Expr::BVal(b) => Doc::text(b.to_string()),
+        Expr::SVal(s) => Doc::text(quote(s)),
+        Expr::List(l) => Doc::Group("[", l.iter().map(|e| expr_at(e, LOWEST)).collect(), "]"),
+        Expr::Object(o) => {
+            let mut entries = o.iter().collect::<Vec<_>>();
+            entries.sort_by_key(|(k, _)| *k);
+            Doc::Group(
+                "{",
+                entries
+                    .into_iter()
+                    .map(|(k, v)| {
+                        Doc::Concat(vec![Doc::text(format!("{k}: ")), expr_at(v, LOWEST)])
+                    })
+                    .collect(),
+                "}",
+            )
+        }
+        Expr::At(e, ix) => Doc::Concat(vec![
+            postfix_base(e),
+            Doc::text("["),
+            expr_at(ix, LOWEST),
+            Doc::text("]"),
+        ]),
+        Expr::Get(e, k) => Doc::Concat(vec![postfix_base(e), Doc::text(format!(".{k}"))]),
+        Expr::Const(s) => Doc::text(s.clone()),
+        Expr::Op1(op, e) => {
+            let mut operand = expr_at(e, 0);
+            // d の直後に識別子が続くとダイスとして読まれない
+            if *op == ExprOp1::OneDice
+                && operand
+                    .to_flat()
+                    .starts_with(|c: char| c.is_alphabetic() || c == '_')
+            {
+                operand = operand.paren();
+            }
+            Doc::Concat(vec![Doc::text(op.to_string()), operand])
+        }
+        Expr::Op2(op, e1, e2) => {
+            let lv = op2_level(*op);
+            let (left, right) = match op {
+                ExprOp2::Pow => (lv - 1, lv),
+                _ if lv == 6 => (lv - 1, lv - 1),
+                _ => (lv, lv - 1),
+            };
+            // 3d6 のような定数同士のダイスだけ詰めて書く
+            let tight = *op == ExprOp2::Dice
+                && matches!((&**e1, &**e2), (Expr::IVal(a), Expr::IVal(b)) if *a >= 0 && *b >= 0);
+            let sep = if tight {
+                op.to_string()
+            } else {
+                format!(" {op} ")
+            };
+            Doc::Concat(vec![expr_at(e1, left), Doc::text(sep), expr_at(e2, right)])
+        }
+        Expr::Apply(f, args) => Doc::Concat(vec![
+            postfix_base(f),
+            Doc::Group("(", args.iter().map(|e| expr_at(e, LOWEST)).collect(), ")"),
+        ]),
+        Expr::Lambda(params, body) => lambda_doc(params, body),
+        // 定義された優先順位は表示するときにはわからないので、被演算子も自身も括弧で守る
+        Expr::Infix(symbol, e1, e2) => Doc::Concat(vec![
+            expr_at(e1, 0),
+            Doc::text(format!(" {symbol} ")),
+            expr_at(e2, 0),
+        ]),
+        Expr::Duration(ms) => Doc::text(datetime::fmt_duration(*ms)),
+        Expr::DateTime(dt) => Doc::text(datetime::fmt_datetime(dt)),
+    }
+}
+
+fn lambda_doc(params: &[Param], body: &Expr) -> Doc {
+    let head = match params {
+        [param] if param.ty.is_none() => param.name.clone(),
+        _ => format!("({})", types::show_params(params)),
+    };
+    Doc::Concat(vec![
+        Doc::text(format!("{head} => ")),
+        expr_at(body, LOWEST),
+    ])
+}
+
+// 1.0 を 1 と書くと整数として読み戻されてしまう
+fn show_float(v: f64) -> String {
+    let s = v.to_string();
+    if v.is_finite() && !s.contains('.') {
+        format!("{s}.0")
+    } else {
+        s
+    }
+}
+
+// parse_string_literalが読める形にエスケープする
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/*
+値
+*/
+
+fn more(n: usize) -> String {
+    format!("…({n} more)")
+}
+
+// 要素が多すぎれば後ろを省略する
+fn items(docs: impl Iterator<Item = Doc>, len: usize, opts: &PrettyOptions) -> Vec<Doc> {
+    let mut items = docs.take(opts.max_items).collect::<Vec<_>>();
+    if len > opts.max_items {
+        items.push(Doc::text(more(len - opts.max_items)));
+    }
+    items
+}
+
+fn val_doc(val: &EvalResult, opts: &PrettyOptions) -> Doc {
+    match val {
+        EvalResult::IVal(i) => Doc::text(i.to_string()),
+        EvalResult::FVal(v) => Doc::text(v.to_string()),
+        EvalResult::BVal(b) => Doc::text(b.to_string()),
+        EvalResult::SVal(s) => {
+            let len = s.chars().count();
+            if len > opts.max_str {
+                let head = s.chars().take(opts.max_str).collect::<String>();
+                Doc::text(format!("\"{head}{}\"", more(len - opts.max_str)))
+            } else {
+                Doc::text(format!("\"{s}\""))
+            }
+        }
+        EvalResult::List(l) => Doc::Group(
+            "[",
+            items(l.iter().map(|v| val_doc(v, opts)), l.len(), opts),
+            "]",
+        ),
+        EvalResult::Object(o) => {
+            let mut entries = o.iter().collect::<Vec<_>>();
+            entries.sort_by_key(|(k, _)| *k);
+            let len = entries.len();
+            Doc::Group(
+                "{",
+                items(
+                    entries.into_iter().map(|(k, v)| {
+                        Doc::Concat(vec![Doc::text(format!("{k}: ")), val_doc(v, opts)])
+                    }),
+                    len,
+                    opts,
+                ),
+                "}",
+            )
+        }
+        EvalResult::Closure(params, body, env) if opts.show_env => {
+            let bindings = env.bindings();
+            let len = bindings.len();
+            Doc::Concat(vec![
+                lambda_doc(params, body).paren(),
+                Doc::text("@"),
+                Doc::Group(
+                    "{",
+                    items(
+                        bindings.into_iter().map(|(k, v)| {
+                            Doc::Concat(vec![Doc::text(format!("{k}: ")), val_doc(v, opts)])
+                        }),
+                        len,
+                        opts,
+                    ),
+                    "}",
+                ),
+            ])
+        }
+        EvalResult::Closure(params, body, _) => lambda_doc(params, body),
+        EvalResult::FuncStdLib(fun) => Doc::text(fun.to_string()),
+        EvalResult::FuncIf => Doc::text("if"),
+        EvalResult::FuncLazy => Doc::text("lazy"),
+        EvalResult::Lazy(body) => Doc::Concat(vec![
+            Doc::text("Lazy("),
+            expr_at(body, LOWEST),
+            Doc::text(")"),
+        ]),
+        EvalResult::Duration(ms) => Doc::text(datetime::fmt_duration(*ms)),
+        EvalResult::DateTime(dt) => Doc::text(datetime::fmt_datetime(dt)),
+        EvalResult::Partial(fun, args) => Doc::Concat(vec![
+            val_doc(fun, opts),
+            Doc::Group(
+                "(",
+                args.iter()
+                    .map(|v| val_doc(v, opts))
+                    .chain([Doc::text("...")])
+                    .collect(),
+                ")",
+            ),
+        ]),
+        EvalResult::Seq(s) => Doc::text(s.to_string()),
+        EvalResult::Memo(fun, _, recursive) => {
+            let memo = Doc::Concat(vec![Doc::text("memo("), val_doc(fun, opts), Doc::text(")")]);
+            if *recursive {
+                Doc::Concat(vec![Doc::text("fix("), memo, Doc::text(")")])
+            } else {
+                memo
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_pretty {
+    use super::super::{eval_from_str, parse_expr, EvalContext};
+    use super::*;
+
+    fn roundtrip(src: &str) -> String {
+        let expr = parse_expr(src).unwrap().1;
+        let shown = show_expr(&expr, &PrettyOptions::FLAT);
+        assert_eq!(
+            parse_expr(&shown),
+            Ok(("", expr.clone())),
+            "{src} -> {shown}"
+        );
+        let wrapped = show_expr(
+            &expr,
+            &PrettyOptions {
+                width: 10,
+                ..PrettyOptions::FLAT
+            },
+        );
+        assert_eq!(parse_expr(&wrapped), Ok(("", expr)), "{src} -> {wrapped}");
+        shown
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            roundtrip("1*2+3/4 - 5 % 6 ^ 7 ^ 8 * 9"),
+            "1 * 2 + 3 / 4 - 5 % 6 ^ 7 ^ 8 * 9"
+        );
+        assert_eq!(roundtrip("(1 - 2) - (3 - 4)"), "1 - 2 - (3 - 4)");
+        assert_eq!(roundtrip("(2 ^ 3) ^ 4"), "(2 ^ 3) ^ 4");
+        assert_eq!(roundtrip("(1 < 2) == true"), "(1 < 2) == true");
+        assert_eq!(roundtrip("-(1 + 2) * 3d6 + d(x)"), "-(1 + 2) * 3d6 + d(x)");
+        assert_eq!(
+            roundtrip("x |> (y => y + 1) >> f"),
+            "x |> (y => y + 1) >> f"
+        );
+        assert_eq!(
+            roundtrip("(f => (x, y: int) => f(x)(y))(g)"),
+            "(f => (x, y: int) => f(x)(y))(g)"
+        );
+        assert_eq!(
+            roundtrip("{b: 1.0, a: [\"q\\\"\\n\", 90s]}.a[1]"),
+            "{a: [\"q\\\"\\n\", 1m30s], b: 1.0}.a[1]"
+        );
+        roundtrip("(2 d 6) d x + d(-1) + -(-2)");
+        roundtrip("(1 + 2).x + (90s).y + @2025-06-01T12:00(1)");
+    }
+
+    #[test]
+    fn test_values() {
+        let global = EvalContext::new();
+        let eval = |s: &str| eval_from_str(s, &global).unwrap();
+        // 捕獲した環境は既定では表示しない
+        let closure = eval("(k => x => x * k)(3)");
+        assert_eq!(closure.to_string(), "x => x * k");
+        let with_env = PrettyOptions {
+            show_env: true,
+            ..PrettyOptions::FLAT
+        };
+        assert_eq!(show_val(&closure, &with_env), "(x => x * k)@{k: 3}");
+        let numbers = |n: i64| (0..n).map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+        assert_eq!(
+            show_val(&eval("range(0, 150)"), &PrettyOptions::FLAT),
+            format!("[{}, …(50 more)]", numbers(100))
+        );
+        // 文字列にするときは省略しない
+        let full = format!("[{}]", numbers(150));
+        assert_eq!(eval("range(0, 150)").to_string(), full);
+        for src in ["len(tostr(range(0, 150)))", "len(\"\" + range(0, 150))"] {
+            assert_eq!(
+                eval(src),
+                EvalResult::IVal(full.chars().count() as i64),
+                "{src}"
+            );
+        }
+        assert_eq!(
+            format!(
+                "{:#}",
+                eval("{name: \"manami\", scores: range(1, 10), f: x => x}")
+            ),
+            "{\n  f: x => x,\n  name: \"manami\",\n  scores: [1, 2, 3, 4, 5, 6, 7, 8, 9]\n}"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+/*
+-----------------------------
+ローカル環境
+束縛を追加するたびに親を指す新しいフレームを作り、既存の環境は書き換えない
+cloneは参照カウントを増やすだけ
+-----------------------------
+*/
+
+use std::{collections::HashMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use super::EvalResult;
+
+#[derive(Debug)]
+struct Frame {
+    vars: Vec<(String, EvalResult)>,
+    parent: Scope,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scope(Option<Arc<Frame>>);
+
+impl Scope {
+    pub const fn new() -> Self {
+        Self(None)
+    }
+
+    // 内側のフレームから順に探す
+    pub fn get(&self, name: &str) -> Option<&EvalResult> {
+        let mut scope = self;
+        while let Some(frame) = &scope.0 {
+            if let Some((_, val)) = frame.vars.iter().rev().find(|(k, _)| k == name) {
+                return Some(val);
+            }
+            scope = &frame.parent;
+        }
+        None
+    }
+
+    // 束縛を1フレームにまとめて追加した環境を返す
+    pub fn extend(&self, vars: impl IntoIterator<Item = (String, EvalResult)>) -> Self {
+        let vars: Vec<_> = vars.into_iter().collect();
+        if vars.is_empty() {
+            return self.clone();
+        }
+        Self(Some(Arc::new(Frame {
+            vars,
+            parent: self.clone(),
+        })))
+    }
+
+    // 指定した名前の束縛だけを持つ環境を作る。クロージャの自由変数の捕獲に使う
+    pub fn capture<'a>(&self, names: impl IntoIterator<Item = &'a String>) -> Self {
+        Self::new().extend(
+            names
+                .into_iter()
+                .filter_map(|name| self.get(name).map(|val| (name.clone(), val.clone()))),
+        )
+    }
+
+    // 見えている束縛を外側から順に列挙する。隠された束縛は含まない
+    pub fn bindings(&self) -> Vec<(&String, &EvalResult)> {
+        let mut frames = Vec::new();
+        let mut scope = self;
+        while let Some(frame) = &scope.0 {
+            frames.push(frame);
+            scope = &frame.parent;
+        }
+        let mut bindings: Vec<(&String, &EvalResult)> = Vec::new();
+        for frame in frames.into_iter().rev() {
+            for (k, v) in &frame.vars {
+                bindings.retain(|(name, _)| *name != k);
+                bindings.push((k, v));
+            }
+        }
+        bindings
+    }
+}
+
+impl Serialize for Scope {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.bindings()
+            .into_iter()
+            .collect::<HashMap<&String, &EvalResult>>()
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Scope {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let hashmap = HashMap::<String, EvalResult>::deserialize(deserializer)?;
+        Ok(Self::new().extend(hashmap))
+    }
+}
+
+#[cfg(test)]
+mod tests_scope {
+    use super::*;
+
+    #[test]
+    fn test_extend_is_persistent() {
+        let outer = Scope::new().extend([
+            ("x".to_owned(), EvalResult::IVal(1)),
+            ("y".to_owned(), EvalResult::IVal(2)),
+        ]);
+        let inner = outer.extend([("x".to_owned(), EvalResult::IVal(10))]);
+        assert_eq!(inner.get("x"), Some(&EvalResult::IVal(10)));
+        assert_eq!(inner.get("y"), Some(&EvalResult::IVal(2)));
+        // 外側の環境は変わらない
+        assert_eq!(outer.get("x"), Some(&EvalResult::IVal(1)));
+        assert_eq!(inner.bindings().len(), 2);
+        assert_eq!(inner.capture(&["x".to_owned()]).bindings().len(), 1);
+    }
+}
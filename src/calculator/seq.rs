@@ -0,0 +1,241 @@
+/*
+-----------------------------
+遅延シーケンス
+要素は必要になったときに1つずつ計算する
+-----------------------------
+*/
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    eval_apply, val_as_bool, val_as_list, EvalContext, EvalError, EvalMode, EvalResult, Expr,
+    Scope, STEP_LIMIT,
+};
+
+// 各要素を取り出すたびに状態をその場で進める
+// 値として共有するときはcloneしてから進めること
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Sequence {
+    List(Vec<EvalResult>, usize),              // 有限のリストのindex番目以降
+    Naturals(i64),                             // n, n+1, n+2, ...
+    Iterate(EvalResult, EvalResult, bool),     // x, f(x), f(f(x)), ... 先頭を返したらtrue
+    Cycle(Vec<EvalResult>, usize),             // リストの繰り返し
+    Map(EvalResult, Box<Self>),                // map(f, seq)
+    Filter(EvalResult, Box<Self>),             // filter(f, seq)
+    ZipWith(EvalResult, Box<Self>, Box<Self>), // zipWith(f, seq1, seq2)
+    DropWhile(EvalResult, Box<Self>, bool),    // dropwhile(f, seq) 読み飛ばし終えたらtrue
+}
+
+impl std::fmt::Display for Sequence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::List(l, i) => write!(
+                f,
+                "[{}]",
+                l.iter()
+                    .skip(*i)
+                    .map(|e| e.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Self::Naturals(n) => write!(f, "naturals({n})"),
+            Self::Iterate(func, x, false) => write!(f, "iterate({func}, {x})"),
+            Self::Iterate(func, x, true) => write!(f, "map({func}, iterate({func}, {x}))"),
+            Self::Cycle(l, i) => {
+                let rotated = l[*i..].iter().chain(l[..*i].iter());
+                write!(
+                    f,
+                    "cycle([{}])",
+                    rotated
+                        .map(|e| e.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
+            }
+            Self::Map(func, s) => write!(f, "map({func}, {s})"),
+            Self::Filter(func, s) => write!(f, "filter({func}, {s})"),
+            Self::ZipWith(func, s1, s2) => write!(f, "zipWith({func}, {s1}, {s2})"),
+            Self::DropWhile(func, s, false) => write!(f, "dropwhile({func}, {s})"),
+            Self::DropWhile(_, s, true) => write!(f, "{s}"),
+        }
+    }
+}
+
+// リストや文字列もシーケンスとして扱う
+pub fn val_as_seq(val: &EvalResult) -> Option<Sequence> {
+    match val {
+        EvalResult::Seq(s) => Some(*s.clone()),
+        _ => val_as_list(val).map(|l| Sequence::List(l, 0)),
+    }
+}
+
+pub const fn is_seq(val: &EvalResult) -> bool {
+    matches!(val, EvalResult::Seq(_))
+}
+
+// 次の要素を取り出す。尽きていればNone
+pub fn advance(
+    seq: &mut Sequence,
+    expr: &Expr,
+    step: usize,
+    mode: &mut EvalMode,
+    global_context: &EvalContext,
+    local_context: &Scope,
+) -> Result<(Option<EvalResult>, usize), (EvalError, Expr)> {
+    if step > STEP_LIMIT {
+        return Err((EvalError::StepLimitExceeded, expr.clone()));
+    }
+    let step = step + 1;
+    let apply = |mode: &mut EvalMode, step, func: &EvalResult, args| {
+        eval_apply(
+            expr,
+            step,
+            mode,
+            global_context,
+            local_context,
+            func.clone(),
+            args,
+        )
+    };
+
+    match seq {
+        Sequence::List(l, i) => {
+            let val = l.get(*i).cloned();
+            *i += 1;
+            Ok((val, step))
+        }
+        Sequence::Naturals(n) => {
+            let val = EvalResult::IVal(*n);
+            *n = n
+                .checked_add(1)
+                .ok_or_else(|| (EvalError::OutOfRange, expr.clone()))?;
+            Ok((Some(val), step))
+        }
+        Sequence::Iterate(func, x, started) => {
+            if !*started {
+                *started = true;
+                return Ok((Some(x.clone()), step));
+            }
+            let (val, step) = apply(mode, step, func, vec![x.clone()])?;
+            *x = val.clone();
+            Ok((Some(val), step))
+        }
+        Sequence::Cycle(l, i) => {
+            if l.is_empty() {
+                return Ok((None, step));
+            }
+            let val = l[*i].clone();
+            *i = (*i + 1) % l.len();
+            Ok((Some(val), step))
+        }
+        Sequence::Map(func, s) => {
+            match advance(s, expr, step, mode, global_context, local_context)? {
+                (Some(e), step) => {
+                    let (val, step) = apply(mode, step, func, vec![e])?;
+                    Ok((Some(val), step))
+                }
+                (None, step) => Ok((None, step)),
+            }
+        }
+        Sequence::Filter(func, s) => {
+            let mut step = step;
+            loop {
+                let (e, next_step) = advance(s, expr, step, mode, global_context, local_context)?;
+                let Some(e) = e else {
+                    return Ok((None, next_step));
+                };
+                let (cond, next_step) = apply(mode, next_step, func, vec![e.clone()])?;
+                step = next_step;
+                if val_as_bool(&cond) == Some(true) {
+                    return Ok((Some(e), step));
+                }
+            }
+        }
+        Sequence::ZipWith(func, s1, s2) => {
+            let (e1, step) = advance(s1, expr, step, mode, global_context, local_context)?;
+            let (e2, step) = advance(s2, expr, step, mode, global_context, local_context)?;
+            match (e1, e2) {
+                (Some(e1), Some(e2)) => {
+                    let (val, step) = apply(mode, step, func, vec![e1, e2])?;
+                    Ok((Some(val), step))
+                }
+                _ => Ok((None, step)),
+            }
+        }
+        Sequence::DropWhile(func, s, dropped) => {
+            if *dropped {
+                return advance(s, expr, step, mode, global_context, local_context);
+            }
+            let mut step = step;
+            loop {
+                let (e, next_step) = advance(s, expr, step, mode, global_context, local_context)?;
+                let Some(e) = e else {
+                    return Ok((None, next_step));
+                };
+                let (cond, next_step) = apply(mode, next_step, func, vec![e.clone()])?;
+                step = next_step;
+                if val_as_bool(&cond) != Some(true) {
+                    *dropped = true;
+                    return Ok((Some(e), step));
+                }
+            }
+        }
+    }
+}
+
+// 先頭からn個取り出す。尽きたらそこまで
+pub fn take(
+    seq: &mut Sequence,
+    n: usize,
+    expr: &Expr,
+    step: usize,
+    mode: &mut EvalMode,
+    global_context: &EvalContext,
+    local_context: &Scope,
+) -> Result<(Vec<EvalResult>, usize), (EvalError, Expr)> {
+    let mut new_list = Vec::new();
+    let mut step = step;
+    while new_list.len() < n {
+        let (e, next_step) = advance(seq, expr, step, mode, global_context, local_context)?;
+        step = next_step;
+        match e {
+            Some(e) => new_list.push(e),
+            None => break,
+        }
+    }
+    Ok((new_list, step))
+}
+
+// fがtruthyな値を返す間だけ取り出す
+pub fn take_while(
+    seq: &mut Sequence,
+    func: &EvalResult,
+    expr: &Expr,
+    step: usize,
+    mode: &mut EvalMode,
+    global_context: &EvalContext,
+    local_context: &Scope,
+) -> Result<(Vec<EvalResult>, usize), (EvalError, Expr)> {
+    let mut new_list = Vec::new();
+    let mut step = step;
+    loop {
+        let (e, next_step) = advance(seq, expr, step, mode, global_context, local_context)?;
+        let Some(e) = e else {
+            return Ok((new_list, next_step));
+        };
+        let (cond, next_step) = eval_apply(
+            expr,
+            next_step,
+            mode,
+            global_context,
+            local_context,
+            func.clone(),
+            vec![e.clone()],
+        )?;
+        step = next_step;
+        if val_as_bool(&cond) != Some(true) {
+            return Ok((new_list, step));
+        }
+        new_list.push(e);
+    }
+}
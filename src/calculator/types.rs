@@ -0,0 +1,155 @@
+/*
+-----------------------------
+値の型
+typeofの結果と、ラムダ式の引数の型注釈に使う
+-----------------------------
+*/
+
+use serde::{Deserialize, Serialize};
+
+use super::EvalResult;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ResultType {
+    Num, // 型注釈のみ。整数と小数のどちらも受け付ける
+    Int,
+    Float,
+    Bool,
+    Str,
+    List,
+    Object,
+    Func,
+    Lazy,
+    Duration,
+    DateTime,
+    Seq,
+}
+
+impl ResultType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "num" => Some(Self::Num),
+            "int" => Some(Self::Int),
+            "float" => Some(Self::Float),
+            "bool" => Some(Self::Bool),
+            "str" => Some(Self::Str),
+            "list" => Some(Self::List),
+            "obj" => Some(Self::Object),
+            "func" => Some(Self::Func),
+            "lazy" => Some(Self::Lazy),
+            "duration" => Some(Self::Duration),
+            "datetime" => Some(Self::DateTime),
+            "seq" => Some(Self::Seq),
+            _ => None,
+        }
+    }
+
+    pub const fn of(val: &EvalResult) -> Self {
+        match val {
+            EvalResult::IVal(_) => Self::Int,
+            EvalResult::FVal(_) => Self::Float,
+            EvalResult::BVal(_) => Self::Bool,
+            EvalResult::SVal(_) => Self::Str,
+            EvalResult::List(_) => Self::List,
+            EvalResult::Object(_) => Self::Object,
+            EvalResult::Closure(..)
+            | EvalResult::FuncStdLib(_)
+            | EvalResult::FuncIf
+            | EvalResult::FuncLazy
+            | EvalResult::Partial(..)
+            | EvalResult::Memo(..) => Self::Func,
+            EvalResult::Lazy(_) => Self::Lazy,
+            EvalResult::Duration(_) => Self::Duration,
+            EvalResult::DateTime(_) => Self::DateTime,
+            EvalResult::Seq(_) => Self::Seq,
+        }
+    }
+
+    pub fn accepts(self, val: &EvalResult) -> bool {
+        let actual = Self::of(val);
+        actual == self || (self == Self::Num && matches!(actual, Self::Int | Self::Float))
+    }
+}
+
+impl std::fmt::Display for ResultType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Self::Num => "num",
+            Self::Int => "int",
+            Self::Float => "float",
+            Self::Bool => "bool",
+            Self::Str => "str",
+            Self::List => "list",
+            Self::Object => "obj",
+            Self::Func => "func",
+            Self::Lazy => "lazy",
+            Self::Duration => "duration",
+            Self::DateTime => "datetime",
+            Self::Seq => "seq",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// ラムダ式の引数。型注釈があれば呼び出し時に検査する
+// 型注釈のない引数は名前だけの文字列として保存される
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "ParamRepr", into = "ParamRepr")]
+pub struct Param {
+    pub name: String,
+    pub ty: Option<ResultType>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ParamRepr {
+    Name(String),
+    Typed { name: String, ty: ResultType },
+}
+
+impl From<ParamRepr> for Param {
+    fn from(repr: ParamRepr) -> Self {
+        match repr {
+            ParamRepr::Name(name) => Self { name, ty: None },
+            ParamRepr::Typed { name, ty } => Self { name, ty: Some(ty) },
+        }
+    }
+}
+
+impl From<Param> for ParamRepr {
+    fn from(param: Param) -> Self {
+        match param.ty {
+            None => Self::Name(param.name),
+            Some(ty) => Self::Typed {
+                name: param.name,
+                ty,
+            },
+        }
+    }
+}
+
+impl Param {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            ty: None,
+        }
+    }
+}
+
+impl std::fmt::Display for Param {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.ty {
+            Some(ty) => write!(f, "{}: {ty}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+pub fn show_params(params: &[Param]) -> String {
+    params
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
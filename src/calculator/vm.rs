@@ -0,0 +1,897 @@
+/*
+-----------------------------
+バイトコードVM
+式を命令列にコンパイルし、明示的なスタックで実行する
+末尾位置の関数呼び出しはフレームを積まずに置き換える
+-----------------------------
+*/
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hasher},
+    rc::Rc,
+    sync::Arc,
+};
+
+use super::{
+    bind_closure, eval_apply, eval_at, eval_get, eval_op1, eval_op2, infix, list_free_var,
+    lookup_var, val_as_bool, ClosureCall, EvalContext, EvalError, EvalMode, EvalResult, Expr,
+    ExprOp1, ExprOp2, Param, Scope, STEP_LIMIT,
+};
+
+// コンパイル済みのクロージャ本体をいくつまで覚えておくか
+const CACHE_LIMIT: usize = 1024;
+
+// 名前で呼ばれたときだけ引数を評価せずに扱う組み込み関数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Special {
+    If,
+    Lazy,
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Push(EvalResult),
+    // trueなら読んだ値が遅延評価のとき強制する
+    Load(String, bool),
+    // 呼び出されたクロージャの引数を位置で読む
+    Local(usize, bool),
+    List(usize),
+    Object(Vec<String>),
+    Get(String),
+    At,
+    Op1(ExprOp1),
+    Op2(ExprOp2),
+    // 右辺が定数の二項演算
+    ConstOp2(ExprOp2, EvalResult),
+    Swap,
+    // スタックの先頭が遅延評価なら評価する
+    Force,
+    // 自由変数ごとに、今のフレームの引数ならその位置を持つ
+    Closure(Arc<[Param]>, Arc<Expr>, Vec<(String, Option<usize>)>),
+    Lazy(Box<Expr>),
+    Call(usize),
+    TailCall(usize),
+    Jump(usize),
+    JumpUnless(usize),
+    // 関数がSpecialの組み込み関数なら続きの命令で処理する
+    // 別の値に束縛されていれば引数を評価して普通に呼び出し、指定位置へ飛ぶ
+    Special(Special, Vec<Expr>, usize),
+    // 関数がifやlazyなら引数を評価せずに処理して指定位置へ飛ぶ
+    Guard(usize),
+    Return,
+}
+
+// 命令列と、エラー表示用の各命令に対応する式
+#[derive(Debug, Default)]
+pub struct Chunk {
+    ops: Vec<Op>,
+    srcs: Vec<Expr>,
+    // クロージャの本体なら引数の名前
+    params: Vec<String>,
+}
+
+impl Chunk {
+    fn emit(&mut self, op: Op, src: &Expr) -> usize {
+        self.ops.push(op);
+        self.srcs.push(src.clone());
+        self.ops.len() - 1
+    }
+
+    // 引数の名前なら位置を返す。同じ名前が並んでいれば後のものが見える
+    fn slot(&self, name: &str) -> Option<usize> {
+        self.params.iter().rposition(|p| p == name)
+    }
+
+    // 遅延評価になりえない式の後では強制を省く。変数は読む命令で強制する
+    // 演算子の結果は、多重定義された関数を呼んだときだけ戻るところで強制する
+    fn emit_force(&mut self, expr: &Expr) {
+        if let Expr::Const(_) = expr {
+            if let Some(Op::Load(_, force) | Op::Local(_, force)) = self.ops.last_mut() {
+                *force = true;
+                return;
+            }
+        }
+        if !matches!(
+            expr,
+            Expr::IVal(_)
+                | Expr::FVal(_)
+                | Expr::BVal(_)
+                | Expr::SVal(_)
+                | Expr::Duration(_)
+                | Expr::DateTime(_)
+                | Expr::List(_)
+                | Expr::Object(_)
+                | Expr::Lambda(..)
+                | Expr::Op1(..)
+        ) && !matches!(expr, Expr::Op2(op, _, _) if *op != ExprOp2::Pipe)
+        {
+            self.emit(Op::Force, expr);
+        }
+    }
+
+    fn patch(&mut self, at: usize, target: usize) {
+        match &mut self.ops[at] {
+            Op::Jump(t) | Op::JumpUnless(t) | Op::Special(_, _, t) | Op::Guard(t) => *t = target,
+            _ => unreachable!("patching non-jump op"),
+        }
+    }
+}
+
+const fn is_literal(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::IVal(_) | Expr::FVal(_) | Expr::BVal(_) | Expr::SVal(_)
+    )
+}
+
+fn literal_of(val: EvalResult) -> Option<Expr> {
+    match val {
+        EvalResult::IVal(i) => Some(Expr::IVal(i)),
+        EvalResult::FVal(f) => Some(Expr::FVal(f)),
+        EvalResult::BVal(b) => Some(Expr::BVal(b)),
+        EvalResult::SVal(s) => Some(Expr::SVal(s)),
+        _ => None,
+    }
+}
+
+fn value_of(expr: &Expr) -> EvalResult {
+    match expr {
+        Expr::IVal(i) => EvalResult::IVal(*i),
+        Expr::FVal(f) => EvalResult::FVal(*f),
+        Expr::BVal(b) => EvalResult::BVal(*b),
+        Expr::SVal(s) => EvalResult::SVal(s.clone()),
+        _ => unreachable!("not a literal"),
+    }
+}
+
+// 定数同士の演算を前もって計算しておく
+// ダイスのように毎回結果の変わるものや、エラーになるものはそのまま残す
+pub fn fold_constants(expr: &Expr) -> Expr {
+    let fold = |e: &Expr| Box::new(fold_constants(e));
+    match expr {
+        Expr::List(l) => Expr::List(l.iter().map(fold_constants).collect()),
+        Expr::Object(o) => Expr::Object(o.iter().map(|(k, v)| (k.clone(), fold(v))).collect()),
+        Expr::Get(e, k) => Expr::Get(fold(e), k.clone()),
+        Expr::At(e1, e2) => Expr::At(fold(e1), fold(e2)),
+        Expr::Op1(op, e) => {
+            let e = fold_constants(e);
+            if *op != ExprOp1::OneDice && is_literal(&e) {
+                if let Some(lit) = eval_op1(expr, 0, *op, value_of(&e))
+                    .ok()
+                    .and_then(|(v, _)| literal_of(v))
+                {
+                    return lit;
+                }
+            }
+            Expr::Op1(*op, Box::new(e))
+        }
+        Expr::Op2(op, e1, e2) => {
+            let (e1, e2) = (fold_constants(e1), fold_constants(e2));
+            let foldable = !matches!(op, ExprOp2::Dice | ExprOp2::Pipe | ExprOp2::Compose);
+            if foldable && is_literal(&e1) && is_literal(&e2) {
+                if let Some(lit) = eval_op2(expr, 0, *op, &value_of(&e1), &value_of(&e2))
+                    .ok()
+                    .and_then(|(v, _)| literal_of(v))
+                {
+                    return lit;
+                }
+            }
+            Expr::Op2(*op, Box::new(e1), Box::new(e2))
+        }
+        Expr::Apply(f, args) => Expr::Apply(fold(f), args.iter().map(fold_constants).collect()),
+        Expr::Lambda(params, body) => Expr::Lambda(params.clone(), fold(body)),
+        Expr::Infix(symbol, e1, e2) => Expr::Infix(symbol.clone(), fold(e1), fold(e2)),
+        _ => expr.clone(),
+    }
+}
+
+fn compile_expr(chunk: &mut Chunk, expr: &Expr, tail: bool) {
+    match expr {
+        Expr::IVal(_) | Expr::FVal(_) | Expr::BVal(_) | Expr::SVal(_) => {
+            chunk.emit(Op::Push(value_of(expr)), expr);
+        }
+        Expr::Duration(ms) => {
+            chunk.emit(Op::Push(EvalResult::Duration(*ms)), expr);
+        }
+        Expr::DateTime(dt) => {
+            chunk.emit(Op::Push(EvalResult::DateTime(*dt)), expr);
+        }
+        Expr::List(l) => {
+            for e in l {
+                compile_expr(chunk, e, false);
+            }
+            chunk.emit(Op::List(l.len()), expr);
+        }
+        Expr::Object(o) => {
+            let mut keys = Vec::new();
+            for (k, v) in o {
+                compile_expr(chunk, v, false);
+                keys.push(k.clone());
+            }
+            chunk.emit(Op::Object(keys), expr);
+        }
+        Expr::Get(e, k) => {
+            compile_expr(chunk, e, false);
+            chunk.emit_force(e);
+            chunk.emit(Op::Get(k.clone()), expr);
+        }
+        Expr::At(e1, e2) => {
+            compile_expr(chunk, e1, false);
+            chunk.emit_force(e1);
+            compile_expr(chunk, e2, false);
+            chunk.emit_force(e2);
+            chunk.emit(Op::At, expr);
+        }
+        Expr::Const(s) => {
+            let op = chunk
+                .slot(s)
+                .map_or_else(|| Op::Load(s.clone(), false), |slot| Op::Local(slot, false));
+            chunk.emit(op, expr);
+        }
+        Expr::Op1(op, e) => {
+            compile_expr(chunk, e, false);
+            chunk.emit_force(e);
+            chunk.emit(Op::Op1(*op), expr);
+        }
+        // x |> f は f(x) として呼び出す
+        Expr::Op2(ExprOp2::Pipe, e1, e2) => {
+            compile_expr(chunk, e1, false);
+            chunk.emit_force(e1);
+            compile_expr(chunk, e2, false);
+            chunk.emit_force(e2);
+            chunk.emit(Op::Swap, expr);
+            chunk.emit(if tail { Op::TailCall(1) } else { Op::Call(1) }, expr);
+        }
+        Expr::Op2(op, e1, e2) if is_literal(e2) => {
+            compile_expr(chunk, e1, false);
+            chunk.emit_force(e1);
+            chunk.emit(Op::ConstOp2(*op, value_of(e2)), expr);
+        }
+        Expr::Op2(op, e1, e2) => {
+            compile_expr(chunk, e1, false);
+            chunk.emit_force(e1);
+            compile_expr(chunk, e2, false);
+            chunk.emit_force(e2);
+            chunk.emit(Op::Op2(*op), expr);
+        }
+        Expr::Apply(f, args) => match (f.as_ref(), args.as_slice()) {
+            (Expr::Const(name), [cond, then, otherwise]) if name == "if" => {
+                chunk.emit(Op::Load(name.clone(), false), f);
+                let special = chunk.emit(Op::Special(Special::If, args.clone(), 0), expr);
+                compile_expr(chunk, cond, false);
+                chunk.emit_force(cond);
+                let jump_else = chunk.emit(Op::JumpUnless(0), expr);
+                compile_expr(chunk, then, tail);
+                let jump_end = chunk.emit(Op::Jump(0), expr);
+                chunk.patch(jump_else, chunk.ops.len());
+                compile_expr(chunk, otherwise, tail);
+                chunk.patch(jump_end, chunk.ops.len());
+                chunk.patch(special, chunk.ops.len());
+            }
+            (Expr::Const(name), [body]) if name == "lazy" => {
+                chunk.emit(Op::Load(name.clone(), false), f);
+                let special = chunk.emit(Op::Special(Special::Lazy, args.clone(), 0), expr);
+                chunk.emit(Op::Lazy(Box::new(body.clone())), expr);
+                chunk.patch(special, chunk.ops.len());
+            }
+            _ => {
+                compile_expr(chunk, f, false);
+                chunk.emit_force(f);
+                let guard = chunk.emit(Op::Guard(0), expr);
+                for e in args {
+                    compile_expr(chunk, e, false);
+                }
+                let call = if tail {
+                    Op::TailCall(args.len())
+                } else {
+                    Op::Call(args.len())
+                };
+                chunk.emit(call, expr);
+                chunk.patch(guard, chunk.ops.len());
+            }
+        },
+        Expr::Lambda(params, body) => {
+            let captures = list_free_var(body)
+                .into_iter()
+                .map(|name| {
+                    let slot = chunk.slot(&name);
+                    (name, slot)
+                })
+                .collect();
+            chunk.emit(
+                Op::Closure(
+                    Arc::from(params.as_slice()),
+                    Arc::new(body.as_ref().clone()),
+                    captures,
+                ),
+                expr,
+            );
+        }
+        Expr::Infix(symbol, e1, e2) => {
+            compile_expr(chunk, &infix::desugar(symbol, e1, e2), tail);
+        }
+    }
+}
+
+// force: 結果が遅延評価なら評価してから返す
+pub fn compile(expr: &Expr, force: bool) -> Chunk {
+    compile_with(expr, force, Vec::new())
+}
+
+fn compile_with(expr: &Expr, force: bool, params: Vec<String>) -> Chunk {
+    let expr = fold_constants(expr);
+    let mut chunk = Chunk {
+        params,
+        ..Chunk::default()
+    };
+    compile_expr(&mut chunk, &expr, !force);
+    if force {
+        chunk.emit_force(&expr);
+    }
+    chunk.emit(Op::Return, &expr);
+    chunk
+}
+
+type BodyKey = (*const Expr, usize);
+
+// 本体の場所を混ぜるだけのハッシュ。呼び出しのたびに引くのでSipHashでは重い
+#[derive(Default)]
+struct BodyHasher(u64);
+
+impl Hasher for BodyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.write_u64(u64::from(*b));
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+}
+
+type BodyCache = HashMap<BodyKey, (Arc<Expr>, Rc<Chunk>), BuildHasherDefault<BodyHasher>>;
+
+thread_local! {
+    static BODY_CACHE: RefCell<BodyCache> =
+        const { RefCell::new(HashMap::with_hasher(BuildHasherDefault::new())) };
+}
+
+// クロージャの本体は呼ばれるたびにコンパイルせず使い回す
+// 同じラムダ式から作られたクロージャは本体を共有するので、その場所で引く
+// 部分適用されたクロージャは残りの引数の数で区別する
+// 表が本体を持っている間は同じ場所に別の式が置かれることはない
+fn compile_body(body: &Arc<Expr>, params: &[Param]) -> Rc<Chunk> {
+    let key = (Arc::as_ptr(body), params.len());
+    BODY_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if let Some((_, chunk)) = cache.get(&key) {
+            return Rc::clone(chunk);
+        }
+        if cache.len() >= CACHE_LIMIT {
+            cache.clear();
+        }
+        let names = params.iter().map(|p| p.name.clone()).collect();
+        let chunk = Rc::new(compile_with(body, false, names));
+        cache.insert(key, (Arc::clone(body), Rc::clone(&chunk)));
+        chunk
+    })
+}
+
+struct Frame {
+    chunk: Rc<Chunk>,
+    pc: usize,
+    env: Scope,
+    // クロージャの引数。名前はchunk.paramsにある
+    locals: Vec<EvalResult>,
+    // 引数を加えた環境。必要になったときに作って覚えておく
+    scope: Option<Scope>,
+    // このフレームの値スタックの底
+    base: usize,
+    // 戻り値を呼び出し元で強制する
+    force: bool,
+}
+
+pub struct Vm<'a> {
+    global_context: &'a EvalContext,
+    stack: Vec<EvalResult>,
+    frames: Vec<Frame>,
+    step: usize,
+}
+
+type VmResult<T> = Result<T, (EvalError, Expr)>;
+
+impl<'a> Vm<'a> {
+    const fn new(global_context: &'a EvalContext, step: usize) -> Self {
+        Self {
+            global_context,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            step,
+        }
+    }
+
+    fn pop(&mut self, src: &Expr) -> VmResult<EvalResult> {
+        self.stack
+            .pop()
+            .ok_or_else(|| (EvalError::StackUnderflow, src.clone()))
+    }
+
+    // 先頭からn個を積んだ順に取り出す
+    fn pop_n(&mut self, n: usize, src: &Expr) -> VmResult<Vec<EvalResult>> {
+        let Some(at) = self.stack.len().checked_sub(n) else {
+            return Err((EvalError::StackUnderflow, src.clone()));
+        };
+        Ok(self.stack.split_off(at))
+    }
+
+    fn push_frame(&mut self, chunk: Rc<Chunk>, env: Scope, locals: Vec<EvalResult>) {
+        self.frames.push(Frame {
+            chunk,
+            pc: 0,
+            env,
+            locals,
+            scope: None,
+            base: self.stack.len(),
+            force: false,
+        });
+    }
+
+    // 式をこの場の環境で評価する（遅延評価の強制など）
+    fn eval_nested(&mut self, expr: &Expr, force: bool, env: Scope) -> VmResult<EvalResult> {
+        let depth = self.frames.len();
+        self.push_frame(Rc::new(compile(expr, force)), env, Vec::new());
+        self.run(depth)
+    }
+
+    fn current_env(&mut self) -> Scope {
+        let Some(frame) = self.frames.last_mut() else {
+            return Scope::new();
+        };
+        frame
+            .scope
+            .get_or_insert_with(|| {
+                let names = frame.chunk.params.iter().cloned();
+                frame.env.extend(names.zip(frame.locals.iter().cloned()))
+            })
+            .clone()
+    }
+
+    fn force(&mut self, src: &Expr) -> VmResult<()> {
+        while let Some(EvalResult::Lazy(e)) = self.stack.last() {
+            let e = e.clone();
+            self.pop(src)?;
+            self.step += 1;
+            let env = self.current_env();
+            let val = self.eval_nested(&e, false, env)?;
+            if self.step > STEP_LIMIT {
+                return Err((EvalError::StepLimitExceeded, src.clone()));
+            }
+            self.stack.push(val);
+        }
+        Ok(())
+    }
+
+    // 引数を評価する前のifとlazyの適用
+    fn short_circuit(
+        &mut self,
+        src: &Expr,
+        func: &EvalResult,
+        args: &[Expr],
+    ) -> VmResult<EvalResult> {
+        self.step += 1;
+        let env = self.current_env();
+        match (func, args) {
+            (EvalResult::FuncIf, [cond, then, otherwise]) => {
+                let cond = self.eval_nested(cond, true, env.clone())?;
+                let Some(b) = val_as_bool(&cond) else {
+                    return Err((EvalError::NotANumber(cond), src.clone()));
+                };
+                self.eval_nested(if b { then } else { otherwise }, false, env)
+            }
+            (EvalResult::FuncIf, _) => {
+                Err((EvalError::ArgCountMismatch(args.len(), 3), src.clone()))
+            }
+            (EvalResult::FuncLazy, [body]) => Ok(EvalResult::Lazy(Box::new(body.clone()))),
+            _ => Err((EvalError::ArgCountMismatch(args.len(), 1), src.clone())),
+        }
+    }
+
+    // 関数を呼び出す。クロージャならフレームを積み、それ以外は結果を積む
+    fn call(
+        &mut self,
+        src: &Expr,
+        func: EvalResult,
+        args: Vec<EvalResult>,
+        tail: bool,
+    ) -> VmResult<()> {
+        self.step += 1;
+        match func {
+            EvalResult::Closure(params, body, ctx) => {
+                let (params, body, env, locals) = match bind_closure(src, params, body, ctx, args)?
+                {
+                    ClosureCall::Partial(val) => {
+                        self.stack.push(val);
+                        return Ok(());
+                    }
+                    ClosureCall::Exact(params, body, env, locals) => (params, body, env, locals),
+                };
+                let chunk = compile_body(&body, &params);
+                // 末尾呼び出しなら今のフレームを捨てる
+                let force = tail && {
+                    let frame = self.frames.pop().expect("tail call without frame");
+                    self.stack.truncate(frame.base);
+                    frame.force
+                };
+                self.push_frame(chunk, env, locals);
+                self.frames.last_mut().expect("no frame").force = force;
+                Ok(())
+            }
+            // 別名で呼ばれたifやlazyは、引数がすでに評価済み
+            EvalResult::FuncIf => {
+                let [cond, then, otherwise] = <[EvalResult; 3]>::try_from(args)
+                    .map_err(|a| (EvalError::ArgCountMismatch(a.len(), 3), src.clone()))?;
+                let Some(b) = val_as_bool(&cond) else {
+                    return Err((EvalError::NotANumber(cond), src.clone()));
+                };
+                self.stack.push(if b { then } else { otherwise });
+                Ok(())
+            }
+            EvalResult::FuncLazy => {
+                let [val] = <[EvalResult; 1]>::try_from(args)
+                    .map_err(|a| (EvalError::ArgCountMismatch(a.len(), 1), src.clone()))?;
+                self.stack.push(val);
+                Ok(())
+            }
+            EvalResult::Lazy(body) => {
+                let env = self.current_env();
+                let func = self.eval_nested(&body, true, env)?;
+                self.call(src, func, args, tail)
+            }
+            _ => {
+                let env = self.current_env();
+                let global_context = self.global_context;
+                let (val, step) = eval_apply(
+                    src,
+                    self.step,
+                    &mut EvalMode::Vm(self),
+                    global_context,
+                    &env,
+                    func,
+                    args,
+                )?;
+                self.step = step;
+                self.stack.push(val);
+                Ok(())
+            }
+        }
+    }
+
+    // stdlibから関数を呼び出す。実行中のフレームの上に積み、戻ってくるまで実行する
+    pub fn apply(
+        &mut self,
+        src: &Expr,
+        step: usize,
+        func: EvalResult,
+        args: Vec<EvalResult>,
+    ) -> VmResult<(EvalResult, usize)> {
+        let (depth, base) = (self.frames.len(), self.stack.len());
+        self.step = step;
+        let result = self.call(src, func, args, false).and_then(|()| {
+            if self.frames.len() > depth {
+                self.run(depth)
+            } else {
+                self.pop(src)
+            }
+        });
+        // エラーで抜けたときは積んだままのフレームを片付ける
+        if result.is_err() {
+            self.frames.truncate(depth);
+            self.stack.truncate(base);
+        }
+        result.map(|val| (val, self.step))
+    }
+
+    // フレームの数がdepthに戻るまで実行し、最後の戻り値を返す
+    fn run(&mut self, depth: usize) -> VmResult<EvalResult> {
+        loop {
+            let (chunk, pc) = {
+                let frame = self.frames.last_mut().expect("no frame to run");
+                frame.pc += 1;
+                (Rc::clone(&frame.chunk), frame.pc - 1)
+            };
+            let src = &chunk.srcs[pc];
+            if self.step > STEP_LIMIT {
+                return Err((EvalError::StepLimitExceeded, src.clone()));
+            }
+
+            match &chunk.ops[pc] {
+                Op::Call(n) | Op::TailCall(n) => {
+                    let args = self.pop_n(*n, src)?;
+                    let func = self.pop(src)?;
+                    let tail = matches!(chunk.ops[pc], Op::TailCall(_));
+                    self.call(src, func, args, tail)?;
+                }
+                Op::Op2(op) => {
+                    let val2 = self.pop(src)?;
+                    self.op2(*op, val2, src)?;
+                }
+                Op::ConstOp2(op, val2) => self.op2(*op, val2.clone(), src)?,
+                Op::Force => self.force(src)?,
+                Op::Local(slot, force) => {
+                    let frame = self.frames.last().expect("no frame");
+                    let Some(val) = frame.locals.get(*slot) else {
+                        return Err((EvalError::StackUnderflow, src.clone()));
+                    };
+                    self.stack.push(val.clone());
+                    if *force {
+                        self.force(src)?;
+                    }
+                }
+                Op::Load(_, force) => {
+                    self.exec(&chunk.ops[pc], src)?;
+                    if *force {
+                        self.force(src)?;
+                    }
+                }
+                Op::Special(special, args, end) => self.special(*special, args, *end, src)?,
+                Op::Guard(end) => self.guard(*end, src)?,
+                Op::Return => {
+                    let val = self.pop(src)?;
+                    let frame = self.frames.pop().expect("no frame to return from");
+                    self.stack.truncate(frame.base);
+                    if self.frames.len() == depth {
+                        return Ok(val);
+                    }
+                    self.stack.push(val);
+                    if frame.force {
+                        self.force(src)?;
+                    }
+                }
+                op => self.exec(op, src)?,
+            }
+        }
+    }
+
+    fn op2(&mut self, op: ExprOp2, val2: EvalResult, src: &Expr) -> VmResult<()> {
+        let val1 = self.pop(src)?;
+        if let Some(method) = infix::overload(op, &val1, &val2) {
+            let depth = self.frames.len();
+            self.call(src, method, vec![val1, val2], false)?;
+            if self.frames.len() > depth {
+                self.frames.last_mut().expect("no frame").force = true;
+                return Ok(());
+            }
+            return self.force(src);
+        }
+        let (val, step) = eval_op2(src, self.step, op, &val1, &val2)?;
+        self.step = step;
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn special(&mut self, special: Special, args: &[Expr], end: usize, src: &Expr) -> VmResult<()> {
+        let func = self.pop(src)?;
+        let expected = match special {
+            Special::If => matches!(func, EvalResult::FuncIf),
+            Special::Lazy => matches!(func, EvalResult::FuncLazy),
+        };
+        if !expected {
+            // ifやlazyが別の値に束縛されている
+            let mut vargs = Vec::new();
+            for e in args {
+                let env = self.current_env();
+                vargs.push(self.eval_nested(e, false, env)?);
+            }
+            self.frames.last_mut().expect("no frame").pc = end;
+            self.call(src, func, vargs, false)?;
+        }
+        Ok(())
+    }
+
+    fn guard(&mut self, end: usize, src: &Expr) -> VmResult<()> {
+        if !matches!(
+            self.stack.last(),
+            Some(EvalResult::FuncIf | EvalResult::FuncLazy)
+        ) {
+            return Ok(());
+        }
+        let func = self.pop(src)?;
+        let Expr::Apply(_, args) = src else {
+            unreachable!("guard without application");
+        };
+        let val = self.short_circuit(src, &func, args)?;
+        self.stack.push(val);
+        self.frames.last_mut().expect("no frame").pc = end;
+        Ok(())
+    }
+
+    // 関数を呼び出さない命令。stdlibのコールバックはrunに入り直すので、runのフレームを小さく保つ
+    fn exec(&mut self, op: &Op, src: &Expr) -> VmResult<()> {
+        match op {
+            Op::Push(val) => self.stack.push(val.clone()),
+            Op::Load(name, _) => {
+                let env = &self.frames.last().expect("no frame").env;
+                let (val, _) = lookup_var(src, 0, name, self.global_context, env)?;
+                self.stack.push(val);
+            }
+            Op::List(n) => {
+                let l = self.pop_n(*n, src)?;
+                self.step += 1;
+                self.stack.push(EvalResult::List(l));
+            }
+            Op::Object(keys) => {
+                let vals = self.pop_n(keys.len(), src)?;
+                let o = keys.iter().cloned().zip(vals.into_iter().map(Box::new));
+                self.step += 1;
+                self.stack.push(EvalResult::Object(o.collect()));
+            }
+            Op::Get(k) => {
+                let val = self.pop(src)?;
+                let (val, step) = eval_get(src, self.step, val, k)?;
+                self.step = step;
+                self.stack.push(val);
+            }
+            Op::At => {
+                let val2 = self.pop(src)?;
+                let val1 = self.pop(src)?;
+                let (val, step) = eval_at(src, self.step, val1, val2)?;
+                self.step = step;
+                self.stack.push(val);
+            }
+            Op::Op1(op) => {
+                let val = self.pop(src)?;
+                let (val, step) = eval_op1(src, self.step, *op, val)?;
+                self.step = step;
+                self.stack.push(val);
+            }
+            Op::Swap => {
+                let val2 = self.pop(src)?;
+                let val1 = self.pop(src)?;
+                self.stack.push(val2);
+                self.stack.push(val1);
+            }
+            Op::Closure(params, body, captures) => {
+                let frame = self.frames.last().expect("no frame");
+                let captured = Scope::new().extend(captures.iter().filter_map(|(name, slot)| {
+                    let val = slot.map_or_else(|| frame.env.get(name), |i| frame.locals.get(i));
+                    val.map(|val| (name.clone(), val.clone()))
+                }));
+                self.stack.push(EvalResult::Closure(
+                    Arc::clone(params),
+                    Arc::clone(body),
+                    captured,
+                ));
+            }
+            Op::Lazy(body) => self.stack.push(EvalResult::Lazy(body.clone())),
+            Op::Jump(target) => self.frames.last_mut().expect("no frame").pc = *target,
+            Op::JumpUnless(target) => {
+                let cond = self.pop(src)?;
+                let Some(b) = val_as_bool(&cond) else {
+                    return Err((EvalError::NotANumber(cond), src.clone()));
+                };
+                if !b {
+                    self.frames.last_mut().expect("no frame").pc = *target;
+                }
+            }
+            _ => unreachable!("control op in exec"),
+        }
+        Ok(())
+    }
+}
+
+// 式を評価する。結果が遅延評価なら強制する
+pub fn eval(
+    expr: &Expr,
+    global_context: &EvalContext,
+    scope: &Scope,
+) -> Result<(EvalResult, usize), (EvalError, Expr)> {
+    let mut vm = Vm::new(global_context, 0);
+    vm.push_frame(Rc::new(compile(expr, true)), scope.clone(), Vec::new());
+    let val = vm.run(0)?;
+    Ok((val, vm.step))
+}
+
+#[cfg(test)]
+mod tests_vm {
+    use super::*;
+    use crate::calculator::parse_expr;
+
+    #[test]
+    fn test_fold_constants() {
+        let expr = parse_expr("x => x * (2 + 3) - 10 / 4").unwrap().1;
+        assert_eq!(
+            fold_constants(&expr),
+            parse_expr("x => x * 5 - 2.5").unwrap().1
+        );
+        // ダイスは畳み込まない
+        let expr = parse_expr("3d6 + 1").unwrap().1;
+        assert_eq!(fold_constants(&expr), expr);
+    }
+
+    #[test]
+    fn test_tail_call() {
+        // 末尾再帰はフレームを積まないので深くてもスタックを溢れさせない
+        let global = super::super::generate_context(&EvalContext::new());
+        let expr =
+            parse_expr("fix(loop => (n, acc) => if(n == 0, acc, loop(n - 1, acc + n)))(1000, 0)")
+                .unwrap()
+                .1;
+        let (val, _) = eval(&expr, &global, &Scope::new()).unwrap();
+        assert_eq!(val, EvalResult::IVal(500_500));
+    }
+
+    #[test]
+    fn test_step_per_application() {
+        // 適用1回につき1ステップなので、長いリストのmapも上限に届かない
+        let global = EvalContext::new();
+        for (src, expected) in [
+            (
+                "sum(map(x => x*x, range(3000)))",
+                EvalResult::FVal(8_995_500_500.0),
+            ),
+            ("length(map(x => x+1, range(2500)))", EvalResult::IVal(2500)),
+        ] {
+            let expr = parse_expr(src).unwrap().1;
+            let val = super::super::eval_expr(&expr, &global).unwrap();
+            assert_eq!(val, expected, "{src}");
+        }
+    }
+
+    #[test]
+    fn test_agrees_with_tree() {
+        let global = EvalContext::new();
+        for src in [
+            "foldl((acc, x) => acc + x * x, 0, range(1, 100))",
+            "map(x => x * 2, [1, 2, 3]) |> (l => l[1])",
+            "(g => g(true, 1, 1 / 0))(if)",
+            "((a, b) => a - b)(10)(3)",
+            "(x => y => x * y)(6)(7)",
+            "{a: [1, 2.5], b: \"s\"}.a",
+            "take(5, filter(x => x % 3 == 0, naturals()))",
+            "lazy(1 + 2) * 3",
+            "undefined_var + 1",
+        ] {
+            let expr = parse_expr(src).unwrap().1;
+            let vm = super::super::eval_expr(&expr, &global).map_err(|(e, _)| e.to_string());
+            let tree = super::super::eval_expr_tree(&expr, &global).map_err(|(e, _)| e.to_string());
+            assert_eq!(vm, tree, "{src}");
+        }
+    }
+    #[test]
+    fn test_tree_stays_off_vm() {
+        // 木の評価ではstdlibに渡したクロージャもvmを通らない
+        let global = EvalContext::new();
+        BODY_CACHE.with(|cache| cache.borrow_mut().clear());
+        let expr = parse_expr("foldl((acc, x) => acc + x, 0, map(x => x * 2, [1, 2, 3]))")
+            .unwrap()
+            .1;
+        let val = super::super::eval_expr_tree(&expr, &global).unwrap();
+        assert_eq!(val, EvalResult::IVal(12));
+        assert!(BODY_CACHE.with(|cache| cache.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_body_cache_key() {
+        // 0.0と-0.0の本体は別々にコンパイルされる
+        let global = EvalContext::new();
+        for (src, expected) in [
+            ("1 / (x => 0.0)(1)", f64::INFINITY),
+            ("1 / (x => -0.0)(1)", f64::NEG_INFINITY),
+        ] {
+            let expr = parse_expr(src).unwrap().1;
+            let val = super::super::eval_expr(&expr, &global).unwrap();
+            assert_eq!(val, EvalResult::FVal(expected), "{src}");
+        }
+    }
+}
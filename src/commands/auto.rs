@@ -1,12 +1,15 @@
-use crate::ai::GeminiModel;
+use crate::ai::{ChatModel, Persona, ReplyToAll};
 use serenity::{
     builder::{CreateCommand, CreateCommandOption},
     model::application::{CommandOptionType, ResolvedValue},
 };
 use std::time::Duration;
 
-use crate::{commands::ManamiSlashCommand, Bot};
-use serenity::model::application::ResolvedOption;
+use crate::{
+    commands::{model_option, ManamiSlashCommand},
+    Bot,
+};
+use serenity::model::{application::ResolvedOption, id::ChannelId};
 
 const COMMAND_NAME: &str = "auto";
 
@@ -16,23 +19,16 @@ pub const SLASH_AUTO_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     description: "呼びかけられなくてもお返事するよ！",
     register,
     run: |option, ctx| {
-        let opts = parse_options(option, ctx.bot);
-        Box::pin(async move { run_body(opts, ctx.bot).await })
+        let opts = parse_options(option, ctx.bot, ctx.channel_id);
+        Box::pin(async move { run_body(opts, ctx.bot, ctx.channel_id).await.into() })
     },
     is_local_command: true,
 };
 
-pub fn register() -> CreateCommand {
+pub fn register(bot: &Bot) -> CreateCommand {
     CreateCommand::new(COMMAND_NAME)
         .description("[sec]秒以内の連続した会話に対して、[model]を使って必ず返信するよ")
-        .add_option(
-            CreateCommandOption::new(CommandOptionType::String, "model", "モデル")
-                .required(false)
-                .add_string_choice("Gemini 2.0 Flash Lite", "gemini-2.0-flash-lite")
-                .add_string_choice("Gemini 2.0 Flash", "gemini-2.0-flash")
-                .add_string_choice("Gemini 2.5 Flash Preview", "gemini-2.5-flash-preview-04-17")
-                .add_string_choice("Gemini 2.5 Pro Experimental", "gemini-2.5-pro-exp-03-25"),
-        )
+        .add_option(model_option(bot))
         .add_option(
             CreateCommandOption::new(CommandOptionType::Integer, "sec", "秒数")
                 .required(false)
@@ -41,18 +37,22 @@ pub fn register() -> CreateCommand {
         )
 }
 
-pub async fn run(option: Vec<ResolvedOption<'_>>, bot: &Bot) -> String {
-    run_body(parse_options(option, bot), bot).await
+pub async fn run(option: Vec<ResolvedOption<'_>>, bot: &Bot, channel_id: ChannelId) -> String {
+    run_body(parse_options(option, bot, channel_id), bot, channel_id).await
 }
 
-fn parse_options(option: Vec<ResolvedOption<'_>>, bot: &Bot) -> (GeminiModel, Duration) {
+fn parse_options(
+    option: Vec<ResolvedOption<'_>>,
+    bot: &Bot,
+    channel_id: ChannelId,
+) -> (anyhow::Result<ChatModel>, Duration) {
     let model = option
         .iter()
         .fold(None, |model, option| match (option.name, &option.value) {
-            ("model", ResolvedValue::String(s)) => Some(GeminiModel::from(*s)),
+            ("model", ResolvedValue::String(s)) => Some(bot.ai.models().find(s)),
             _ => model,
         })
-        .unwrap_or_else(|| bot.gemini.get_model());
+        .unwrap_or_else(|| Ok(bot.ai.model_for(channel_id)));
 
     let sec = Duration::from_secs(
         option
@@ -67,10 +67,34 @@ fn parse_options(option: Vec<ResolvedOption<'_>>, bot: &Bot) -> (GeminiModel, Du
     (model, sec)
 }
 
-async fn run_body((model, sec): (GeminiModel, Duration), bot: &Bot) -> String {
+async fn run_body(
+    (model, sec): (anyhow::Result<ChatModel>, Duration),
+    bot: &Bot,
+    channel_id: ChannelId,
+) -> String {
+    let model = match model {
+        Ok(model) => model,
+        Err(e) => return e.to_string(),
+    };
+    if !bot.is_ai_channel(channel_id) {
+        return "このチャンネルではおしゃべりしてないよ。`/persona`で設定してね".to_owned();
+    }
+
+    // 再起動しても続くように、チャンネルの設定に保存する
+    let persona = Persona {
+        reply_to_all: Some(ReplyToAll {
+            secs: sec.as_secs(),
+            model: model.name.clone(),
+        }),
+        ..bot.ai.persona(channel_id).unwrap_or_default()
+    };
+    if let Err(e) = bot.database.upsert_persona(&channel_id, &persona).await {
+        return format!("設定を保存できなかったよ: {e}");
+    }
+    bot.ai.set_persona(channel_id, persona);
     bot.reply_to_all_mode
-        .lock()
-        .unwrap()
+        .entry(channel_id)
+        .or_default()
         .set(model.clone(), sec);
 
     let msg = format!(
@@ -11,10 +11,10 @@ pub const SLASH_BF_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     name: "bf",
     usage: "/bf <code> [input]",
     description: "まなみはいんたぷりた？　なんだよ！",
-    register,
+    register: |_| register(),
     run: |options, _| {
         let result = run(options);
-        Box::pin(async move { result })
+        Box::pin(async move { result.into() })
     },
     is_local_command: false,
 };
@@ -64,7 +64,7 @@ pub fn run(option: Vec<ResolvedOption>) -> String {
     run_body(parse_options(option))
 }
 
-fn parse_options(option: Vec<ResolvedOption>) -> Result<(Vec<BrainfuckCommand>, &str), &str> {
+fn parse_options(option: Vec<ResolvedOption<'_>>) -> Result<(Vec<BrainfuckCommand>, &str), &str> {
     let (code, input) = option.iter().fold((None, None), |(code, input), option| {
         match (option.name, &option.value) {
             ("code", ResolvedValue::String(s)) => (Some(*s), input),
@@ -0,0 +1,131 @@
+use serenity::{
+    all::{ButtonStyle, ComponentInteraction, ResolvedOption, ResolvedValue},
+    builder::{
+        CreateActionRow, CreateButton, CreateCommand, CreateCommandOption, CreateEmbed,
+        CreateEmbedFooter, CreateInteractionResponse, CreateInteractionResponseMessage,
+    },
+    client::Context,
+    model::application::CommandOptionType,
+};
+use tracing::error;
+
+use crate::calculator::help_pages;
+
+use super::{ManamiSlashCommand, SlashReply};
+
+// ボタンのcustom_idは "calchelp:{ページ}:{検索語}"
+// 状態を持たないように、表示に必要なものは全部custom_idに入れる
+pub const CUSTOM_ID_PREFIX: &str = "calchelp:";
+
+// Discordのcustom_idは100文字まで
+const CUSTOM_ID_MAX_LEN: usize = 100;
+
+pub const SLASH_CALCHELP_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
+    name: "calchelp",
+    usage: "/calchelp [query]",
+    description: "calcの関数の説明を表示するよ！",
+    register: |_| register(),
+    run: |options, _| {
+        let result = run(&options);
+        Box::pin(async move { result })
+    },
+    is_local_command: false,
+};
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("calchelp")
+        .description("calcの関数の説明を表示するよ！")
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::String,
+            "query",
+            "関数名かカテゴリ名（math, list など）",
+        ))
+}
+
+fn run(options: &[ResolvedOption<'_>]) -> SlashReply {
+    let query = options
+        .iter()
+        .find_map(|option| match (option.name, &option.value) {
+            ("query", ResolvedValue::String(query)) => Some(*query),
+            _ => None,
+        })
+        .unwrap_or("");
+    SlashReply::Message(Box::new(page_message(query, 0)))
+}
+
+fn custom_id(page: usize, query: &str) -> String {
+    let mut id = format!("{CUSTOM_ID_PREFIX}{page}:");
+    for c in query.chars() {
+        if id.len() + c.len_utf8() > CUSTOM_ID_MAX_LEN {
+            break;
+        }
+        id.push(c);
+    }
+    id
+}
+
+fn parse_custom_id(id: &str) -> Option<(usize, &str)> {
+    let (page, query) = id.strip_prefix(CUSTOM_ID_PREFIX)?.split_once(':')?;
+    Some((page.parse().ok()?, query))
+}
+
+fn page_message(query: &str, page: usize) -> CreateInteractionResponseMessage {
+    let pages = help_pages(query);
+    if pages.is_empty() {
+        return CreateInteractionResponseMessage::new()
+            .content(format!("`{}` は見つからなかったよ", query.trim()));
+    }
+    let page = page.min(pages.len() - 1);
+    let embed = CreateEmbed::new()
+        .title(&pages[page].title)
+        .description(&pages[page].body)
+        .footer(CreateEmbedFooter::new(format!(
+            "{}/{}",
+            page + 1,
+            pages.len()
+        )));
+    let message = CreateInteractionResponseMessage::new().embed(embed);
+    if pages.len() == 1 {
+        return message;
+    }
+
+    // 端のページでは押せないようにする
+    let prev = CreateButton::new(custom_id(page.saturating_sub(1), query))
+        .label("◀")
+        .style(ButtonStyle::Secondary)
+        .disabled(page == 0);
+    let next = CreateButton::new(custom_id(page + 1, query))
+        .label("▶")
+        .style(ButtonStyle::Secondary)
+        .disabled(page + 1 == pages.len());
+    message.components(vec![CreateActionRow::Buttons(vec![prev, next])])
+}
+
+pub async fn handle_component(ctx: &Context, component: &ComponentInteraction) {
+    let Some((page, query)) = parse_custom_id(&component.data.custom_id) else {
+        return;
+    };
+    let builder = CreateInteractionResponse::UpdateMessage(page_message(query, page));
+    if let Err(why) = component.create_response(&ctx.http, builder).await {
+        error!("Error updating calchelp: {:?}", why);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_id() {
+        let id = custom_id(3, "list");
+        assert_eq!(id, "calchelp:3:list");
+        assert_eq!(parse_custom_id(&id), Some((3, "list")));
+        // 検索語にコロンが入っていても戻せる
+        assert_eq!(parse_custom_id("calchelp:0:a:b"), Some((0, "a:b")));
+        assert_eq!(parse_custom_id("cclemon:0:a"), None);
+
+        let long = custom_id(12, &"あ".repeat(100));
+        assert!(long.len() <= CUSTOM_ID_MAX_LEN);
+        assert!(parse_custom_id(&long).is_some());
+    }
+}
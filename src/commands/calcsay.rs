@@ -1,4 +1,4 @@
-use crate::calculator::{eval_from_str, val_as_str};
+use crate::calculator::{eval_from_str, show_result};
 use crate::commands::CommandContext;
 
 use crate::commands::ManamiPrefixCommand;
@@ -20,7 +20,7 @@ pub async fn run(ctx: CommandContext<'_>) {
     let result = eval_from_str(&expression, &bot.variables);
     if let Ok(result) = result {
         reply
-            .say(&ctx.cache_http(), val_as_str(&result))
+            .say(&ctx.cache_http(), show_result(&result))
             .await
             .unwrap();
     }
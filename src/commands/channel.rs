@@ -6,8 +6,8 @@ pub const SLASH_CHANNEL_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     name: "channel",
     usage: "/channel <channel>",
     description: "代筆先のチャンネルを指定するよ！",
-    register,
-    run: |options, ctx| Box::pin(async move { run(options, ctx).await }),
+    register: |_| register(),
+    run: |options, ctx| Box::pin(async move { run(options, ctx).await.into() }),
     is_local_command: false,
 };
 
@@ -1,4 +1,11 @@
+//
+use std::collections::HashMap;
+
+use serenity::model::id::{MessageId, UserId};
+
+use crate::ai::GeminiContent;
 use crate::commands::CommandContext;
+use crate::db::MessageInfo;
 
 use crate::commands::ManamiPrefixCommand;
 pub const PREFIX_CLEAR_COMMAND: ManamiPrefixCommand = ManamiPrefixCommand {
@@ -17,11 +24,98 @@ pub const PREFIX_CLEAR_COMMAND: ManamiPrefixCommand = ManamiPrefixCommand {
     is_guild_command: true,
 };
 
+// 忘れた印。再起動してログを読み直すときはここより後だけを使う
+pub const CLEAR_MESSAGE: &str = "1……2の……ポカン！";
+
 pub async fn run(ctx: CommandContext<'_>) {
     ctx.channel_id
-        .say(ctx.cache_http(), "1……2の……ポカン！")
+        .say(ctx.cache_http(), CLEAR_MESSAGE)
         .await
         .unwrap();
 
-    ctx.bot.gemini.clear();
+    ctx.bot.ai.clear(ctx.channel_id);
+}
+
+// 保存されたログのうち、最後に忘れた後のものを会話にする
+// 会話に入れたときに印をつけたもの（ai_log）だけを使うので、ほかのボットやコマンドの結果は入らない
+pub fn remembered_log(
+    messages: &[MessageInfo],
+    ai_log: &HashMap<MessageId, Option<String>>,
+    my_userid: &UserId,
+) -> Vec<GeminiContent> {
+    let start = messages
+        .iter()
+        .rposition(|m| m.user_id == *my_userid && m.content == CLEAR_MESSAGE)
+        .map_or(0, |i| i + 1);
+    messages[start..]
+        .iter()
+        .filter_map(|m| {
+            let reply = ai_log.get(&m.message_id)?;
+            // 分けて送った返事は、最初のメッセージに全文を覚えている
+            Some(reply.as_ref().map_or_else(
+                || m.gemini_content(my_userid),
+                |reply| GeminiContent::model(reply),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn message(id: u64, user_id: u64, user_name: &str, content: &str) -> MessageInfo {
+        MessageInfo {
+            message_id: MessageId::new(id),
+            user_id: UserId::new(user_id),
+            user_name: user_name.to_owned(),
+            timestamp: Utc::now(),
+            content: content.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_remembered_log() {
+        let manami = UserId::new(100);
+        let messages = [
+            message(1, 1, "宇田", "秘密の話"),
+            message(2, 1, "宇田", "!clear"),
+            message(3, 100, "まなみ", CLEAR_MESSAGE),
+            message(4, 1, "宇田", "ただいま"),
+            message(5, 100, "まなみ", "おかえり！"),
+            message(6, 2, "ほかのボット", "ピコーン"),
+            message(7, 1, "宇田", "!calc 1 + 1"),
+            message(8, 100, "まなみ", "2"),
+            message(9, 1, "宇田", "長い話して"),
+            message(10, 100, "まなみ", "前半"),
+            message(11, 100, "まなみ", "後半"),
+        ];
+        let ai_log = [
+            (1, None),
+            (2, None),
+            (4, None),
+            (5, Some("おかえり！")),
+            (7, None),
+            (9, None),
+            (10, Some("前半後半")),
+        ]
+        .into_iter()
+        .map(|(id, reply)| (MessageId::new(id), reply.map(str::to_owned)))
+        .collect::<HashMap<_, _>>();
+        assert_eq!(
+            remembered_log(&messages, &ai_log, &manami),
+            vec![
+                GeminiContent::user("宇田", "ただいま"),
+                GeminiContent::model("おかえり！"),
+                GeminiContent::user("宇田", "!calc 1 + 1"),
+                GeminiContent::user("宇田", "長い話して"),
+                GeminiContent::model("前半後半"),
+            ]
+        );
+        // 忘れていなければ全部使う
+        assert_eq!(remembered_log(&messages[3..5], &ai_log, &manami).len(), 2);
+        assert_eq!(remembered_log(&messages[..2], &ai_log, &manami).len(), 2);
+    }
 }
@@ -27,10 +27,10 @@ pub const SLASH_DICE_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     name: "dice",
     usage: "/dice <operation>",
     description: "サイコロを振るよ！　ex. 2d6 <= 9",
-    register,
+    register: |_| register(),
     run: |options, _| {
         let result = run(options);
-        Box::pin(async move { result })
+        Box::pin(async move { result.into() })
     },
     is_local_command: false,
 };
@@ -74,7 +74,14 @@ pub fn run(options: Vec<ResolvedOption>) -> String {
     run_body(parse_options(options))
 }
 
-fn parse_options(options: Vec<ResolvedOption>) -> Result<Dice, &str> {
+// 2d6>=7 のような文字列から振る。Discordを通さずに使う
+pub fn run_literal(literal: &str) -> String {
+    run_body(describe_error(
+        parse_dice(literal).finish().map(|(_, parsed)| parsed),
+    ))
+}
+
+fn parse_options(options: Vec<ResolvedOption<'_>>) -> Result<Dice, &str> {
     // parse options
     let (literal, num, dice, operator, operand) = options.iter().fold(
         (None, None, None, None, None),
@@ -104,7 +111,10 @@ fn parse_options(options: Vec<ResolvedOption>) -> Result<Dice, &str> {
         },
         |s| parse_dice(s).finish().map(|(_, parsed)| parsed),
     );
+    describe_error(dice)
+}
 
+const fn describe_error(dice: Result<Dice, Error<&str>>) -> Result<Dice, &'static str> {
     match dice {
         Ok(dice) => Ok(dice),
         Err(Error {
@@ -1,6 +1,6 @@
-use serenity::builder::CreateCommand;
+use serenity::{builder::CreateCommand, model::id::ChannelId};
 
-use crate::{commands::ManamiSlashCommand, Bot};
+use crate::{ai::Persona, commands::ManamiSlashCommand, Bot};
 
 const COMMAND_NAME: &str = "endauto";
 
@@ -8,8 +8,8 @@ pub const SLASH_ENDAUTO_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     name: COMMAND_NAME,
     usage: "/endauto",
     description: "自動返信を止めるよ！",
-    register,
-    run: |_, ctx| Box::pin(run(ctx.bot)),
+    register: |_| register(),
+    run: |_, ctx| Box::pin(async move { run(ctx.bot, ctx.channel_id).await.into() }),
     is_local_command: true,
 };
 
@@ -17,8 +17,23 @@ pub fn register() -> CreateCommand {
     CreateCommand::new("endauto").description("自動返信を終了するよ")
 }
 
-pub async fn run(bot: &Bot) -> String {
-    bot.reply_to_all_mode.lock().unwrap().end();
+pub async fn run(bot: &Bot, channel_id: ChannelId) -> String {
+    if let Some(mut mode) = bot.reply_to_all_mode.get_mut(&channel_id) {
+        mode.end();
+    }
+
+    if let Some(persona) = bot.ai.persona(channel_id) {
+        if persona.reply_to_all.is_some() {
+            let persona = Persona {
+                reply_to_all: None,
+                ..persona
+            };
+            if let Err(e) = bot.database.upsert_persona(&channel_id, &persona).await {
+                return format!("設定を保存できなかったよ: {e}");
+            }
+            bot.ai.set_persona(channel_id, persona);
+        }
+    }
 
     "（全レス終了）".to_owned()
 }
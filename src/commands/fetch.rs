@@ -19,10 +19,10 @@ pub const SLASH_FETCH_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     name: "fetch",
     usage: "/fetch [count]",
     description: "このチャンネルに投稿された、覚えているのより古いメッセージを取得するよ！",
-    register,
+    register: |_| register(),
     run: |option, ctx| {
         let opts = parse_options(option);
-        Box::pin(async move { run_body(opts, &ctx).await })
+        Box::pin(async move { run_body(opts, &ctx).await.into() })
     },
     is_local_command: true,
 };
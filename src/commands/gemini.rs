@@ -1,12 +1,13 @@
-use serenity::{
-    builder::{CreateCommand, CreateCommandOption},
-    model::application::{CommandOptionType, ResolvedValue},
-};
+use serenity::{builder::CreateCommand, model::application::ResolvedValue};
+use tracing::error;
 
-use crate::ai::GeminiModel;
+use crate::ai::{self, ChatModel};
 
-use crate::{commands::ManamiSlashCommand, Bot};
-use serenity::model::application::ResolvedOption;
+use crate::{
+    commands::{model_option, ManamiSlashCommand},
+    Bot,
+};
+use serenity::model::{application::ResolvedOption, id::ChannelId};
 pub const SLASH_GEMINI_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     name: "gemini",
     usage: "/gemini <model>",
@@ -14,47 +15,49 @@ pub const SLASH_GEMINI_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     register,
     run: |option, ctx| {
         let opts = parse(option, ctx.bot);
-        Box::pin(async move { run_body(opts, ctx.bot).await })
+        Box::pin(async move { run_body(opts, ctx.bot, ctx.channel_id).await.into() })
     },
     is_local_command: true,
 };
 
-pub fn register() -> CreateCommand {
+pub fn register(bot: &Bot) -> CreateCommand {
     CreateCommand::new("gemini")
         .description("Geminiの設定を変更するよ")
-        .add_option(
-            CreateCommandOption::new(CommandOptionType::String, "model", "モデル")
-                .required(false)
-                .add_string_choice("Gemini 2.0 Flash Lite", "gemini-2.0-flash-lite")
-                .add_string_choice("Gemini 2.0 Flash", "gemini-2.0-flash")
-                .add_string_choice("Gemini 2.5 Flash Preview", "gemini-2.5-flash-preview-05-20")
-                .add_string_choice("Gemini 2.5 Pro Preview", "gemini-2.5-pro-preview-05-06"),
-        )
+        .add_option(model_option(bot))
 }
 
-pub async fn run(option: Vec<ResolvedOption<'_>>, bot: &Bot) -> String {
-    run_body(parse(option, bot), bot).await
+pub async fn run(option: Vec<ResolvedOption<'_>>, bot: &Bot, channel_id: ChannelId) -> String {
+    run_body(parse(option, bot), bot, channel_id).await
 }
 
-fn parse(option: Vec<ResolvedOption<'_>>, _: &Bot) -> Option<GeminiModel> {
+fn parse(option: Vec<ResolvedOption<'_>>, bot: &Bot) -> Option<anyhow::Result<ChatModel>> {
     option
         .iter()
         .fold(None, |model, option| match (option.name, &option.value) {
-            ("model", ResolvedValue::String(s)) => Some(GeminiModel::from(*s)),
+            ("model", ResolvedValue::String(s)) => Some(bot.ai.models().find(s)),
             _ => model,
         })
 }
 
-async fn run_body(model: Option<GeminiModel>, bot: &Bot) -> String {
+async fn run_body(
+    model: Option<anyhow::Result<ChatModel>>,
+    bot: &Bot,
+    channel_id: ChannelId,
+) -> String {
     if let Some(model) = model {
+        let model = match model {
+            Ok(model) => model,
+            Err(e) => return e.to_string(),
+        };
         let msg = format!("モデルを{model}に変更したよ");
-        bot.gemini.set_model(model);
+        bot.ai.set_model(model);
         msg
     } else {
-        match bot.gemini.generate().await {
+        match bot.ai.generate(channel_id).await {
             Ok(content) => content.replace("うだまなみ: ", ""),
             Err(e) => {
-                format!("Error sending message: {e:?}")
+                error!("Error generating AI reply: {e:?}");
+                ai::user_message(&e).to_owned()
             }
         }
     }
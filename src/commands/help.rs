@@ -17,10 +17,10 @@ pub const SLASH_HELP_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     name: "help",
     usage: "/help",
     description: "ヘルプを表示するよ！",
-    register,
+    register: |_| register(),
     run: |_, ctx| {
         let result = run(ctx.bot);
-        Box::pin(async move { result })
+        Box::pin(async move { result.into() })
     },
     is_local_command: false,
 };
@@ -1,6 +1,7 @@
 use crate::ManamiPrefixCommand;
 
 use chrono::TimeDelta;
+use tracing::error;
 
 use super::CommandContext;
 
@@ -69,12 +70,12 @@ pub async fn run_body(time: Option<u32>, ctx: &CommandContext<'_>) {
 
     let result = ctx
         .bot
-        .gemini
-        .generate_matome(gemini_contents)
+        .ai
+        .generate_matome(channel_id, gemini_contents)
         .await
         .unwrap_or_else(|e| {
-            println!("Failed to generate matome: {e}");
-            "".to_owned()
+            error!("Failed to generate matome: {e:?}");
+            crate::ai::user_message(&e).to_owned()
         });
 
     ctx.channel_id.say(ctx.cache_http(), result).await.ok();
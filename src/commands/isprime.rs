@@ -6,6 +6,10 @@ use crate::commands::ManamiSlashCommand;
 
 use serenity::model::application::ResolvedValue;
 
+// AIの道具と電卓で判定する数の上限。スラッシュコマンドの整数と同じ2^53まで
+// コマンドで直接頼まれたときは上限を設けない
+pub const MAX_NUMBER: u64 = 1 << 53;
+
 pub const PREFIX_ISPRIME_COMMAND: ManamiPrefixCommand = ManamiPrefixCommand {
     name: "isprime",
     alias: &[],
@@ -35,11 +39,8 @@ async fn run_old(ctx: CommandContext<'_>) {
         return;
     };
 
-    let (is_prime, factor) = check_is_prime(num);
-
-    let is_prime = message(num, is_prime, factor);
     ctx.channel_id
-        .say(ctx.cache_http(), is_prime)
+        .say(ctx.cache_http(), run_number(num))
         .await
         .unwrap();
 }
@@ -48,8 +49,8 @@ pub const SLASH_ISPRIME_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     name: "isprime",
     usage: "/isprime <n>",
     description: "nが素数かどうかを判定するよ！",
-    register,
-    run: |options, _| Box::pin(async move { run(options) }),
+    register: |_| register(),
+    run: |options, _| Box::pin(async move { run(options).into() }),
     is_local_command: false,
 };
 
@@ -71,13 +72,20 @@ fn run(options: Vec<serenity::model::application::ResolvedOption<'_>>) -> String
     let ResolvedValue::Integer(num) = options[0].value else {
         return "わかんないよ".to_owned();
     };
-    let num = num as u64;
+    run_number(num as u64)
+}
 
+// Discordを通さずに判定する
+pub fn run_number(num: u64) -> String {
     let (is_prime, factor) = check_is_prime(num);
-
     message(num, is_prime, factor)
 }
 
+// 電卓のisprimeから使う
+pub fn is_prime(num: u64) -> bool {
+    check_is_prime(num).0
+}
+
 fn check_is_prime(num: u64) -> (bool, Vec<u64>) {
     match num {
         0 | 1 => (false, vec![]),
@@ -86,15 +94,16 @@ fn check_is_prime(num: u64) -> (bool, Vec<u64>) {
             let mut num = num;
             let mut factor = vec![];
 
-            while num % 2 == 0 {
+            while num.is_multiple_of(2) {
                 num /= 2;
                 factor.push(2);
             }
 
             let mut i = 3;
 
-            while i * i <= num {
-                if num % i == 0 {
+            // i * iだとあふれるので割り算で比べる
+            while i <= num / i {
+                if num.is_multiple_of(i) {
                     num /= i;
                     factor.push(i);
                 } else {
@@ -128,3 +137,28 @@ fn message(num: u64, is_prime: bool, factor: Vec<u64>) -> String {
         }
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_is_prime() {
+        assert_eq!(check_is_prime(1), (false, vec![]));
+        assert_eq!(check_is_prime(57), (false, vec![3, 19]));
+        // 2^53より小さい最大の素数
+        assert_eq!(
+            check_is_prime(MAX_NUMBER - 111),
+            (true, vec![MAX_NUMBER - 111])
+        );
+        // i * iがあふれる大きさでも止まる
+        assert_eq!(
+            check_is_prime(u64::MAX),
+            (false, vec![3, 5, 17, 257, 641, 65537, 6_700_417])
+        );
+        assert_eq!(
+            run_number(MAX_NUMBER + 1),
+            "9007199254740993は素数じゃないよ。素因数は[3, 107, 28059810762433]だよ。"
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::calculator;
+use crate::calculator::{self, EvalResult};
 use crate::commands::unjail;
 use crate::commands::CommandContext;
 use crate::Bot;
@@ -144,20 +144,25 @@ pub async fn run_old(ctx: CommandContext<'_>) {
 
             let expression = args.join(" ");
 
-            let Ok(jailtermsec) = calculator::eval_from_str(&expression, &bot.variables) else {
+            let Ok(jailterm) = calculator::eval_from_str(&expression, &bot.variables) else {
                 reply.say(&ctx.http, "刑期がおかしいよ").await.unwrap();
                 return;
             };
-            let Some(jailtermsec) = calculator::val_as_int(&jailtermsec) else {
+            // 90s や 2h30m のような時間はそのまま、数値は秒数として扱う
+            let jailtermms = match jailterm {
+                EvalResult::Duration(ms) => Some(ms),
+                _ => calculator::val_as_int(&jailterm).and_then(|sec| sec.checked_mul(1000)),
+            };
+            let Some(jailtermms) = jailtermms else {
                 reply.say(&ctx.http, "刑期がおかしいよ").await.unwrap();
                 return;
             };
-            let Ok(jailtermsec) = u64::try_from(jailtermsec) else {
+            let Ok(jailtermms) = u64::try_from(jailtermms) else {
                 reply.say(&ctx.http, "刑期が負だよ").await.unwrap();
                 return;
             };
 
-            let jailterm = if jailtermsec > JAIL_TERM_MAX.as_secs() {
+            let jailterm = if u128::from(jailtermms) > JAIL_TERM_MAX.as_millis() {
                 reply
                     .say(
                         &ctx.http,
@@ -170,13 +175,16 @@ pub async fn run_old(ctx: CommandContext<'_>) {
                     .unwrap();
                 JAIL_TERM_MAX
             } else {
-                Duration::from_secs(jailtermsec)
+                Duration::from_millis(jailtermms)
             };
             (user, jailterm)
         }
         _ => {
             reply
-                .say(&ctx.http, "使い方: `!jail <user> [刑期（秒）]`")
+                .say(
+                    &ctx.http,
+                    "使い方: `!jail <user> [刑期（秒、または 90s や 1h30m など）]`",
+                )
                 .await
                 .unwrap();
             return;
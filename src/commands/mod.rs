@@ -5,14 +5,16 @@ use std::pin::Pin;
 
 use std::time::Instant;
 
-use crate::ai::GeminiModel;
+use crate::ai::ChatModel;
 use std::time::Duration;
 
-use serenity::all::ResolvedOption;
+use serenity::all::{CommandOptionType, ResolvedOption};
+use serenity::builder::{CreateCommandOption, CreateInteractionResponseMessage};
 
 pub mod auto;
 pub mod bf;
 pub mod calc;
+pub mod calchelp;
 pub mod calcsay;
 pub mod cclemon;
 pub mod channel;
@@ -27,7 +29,10 @@ pub mod imakita;
 pub mod isprime;
 pub mod jail;
 pub mod listvar;
+pub mod persona;
 pub mod ping;
+pub mod relation;
+pub mod repl;
 pub mod unjail;
 pub mod var;
 pub mod varbulk;
@@ -36,7 +41,8 @@ pub mod varbulk;
 #[derive(Clone)]
 pub struct ReplyToAllModeData {
     pub until: Option<Instant>,
-    pub model: GeminiModel,
+    // Noneなら普段のモデルを使う
+    pub model: Option<ChatModel>,
     pub duration: Duration,
 }
 
@@ -50,14 +56,14 @@ impl ReplyToAllModeData {
     pub const fn blank() -> Self {
         Self {
             until: None,
-            model: GeminiModel::Gemini20FlashLite,
+            model: None,
             duration: Duration::from_secs(0),
         }
     }
 
-    pub fn set(&mut self, model: GeminiModel, duration: Duration) {
+    pub fn set(&mut self, model: ChatModel, duration: Duration) {
         self.until = Instant::now().checked_add(duration);
-        self.model = model;
+        self.model = Some(model);
         self.duration = duration;
     }
 
@@ -144,15 +150,44 @@ pub struct ManamiPrefixCommand {
     pub is_guild_command: bool,
 }
 
+// スラッシュコマンドの返信。埋め込みやボタンを付けるときはMessageで返す
+pub enum SlashReply {
+    Text(String),
+    Message(Box<CreateInteractionResponseMessage>),
+}
+
+impl From<String> for SlashReply {
+    fn from(content: String) -> Self {
+        Self::Text(content)
+    }
+}
+
+impl SlashReply {
+    pub fn into_message(self) -> CreateInteractionResponseMessage {
+        match self {
+            Self::Text(content) => CreateInteractionResponseMessage::new().content(content),
+            Self::Message(message) => *message,
+        }
+    }
+}
+
 pub struct ManamiSlashCommand {
     pub name: &'static str,
     pub usage: &'static str,
     pub description: &'static str,
-    pub register: fn() -> serenity::builder::CreateCommand,
-    pub run: for<'a> fn(Vec<ResolvedOption<'a>>, CommandContext<'a>) -> BoxedFuture<'a, String>,
+    pub register: fn(&crate::Bot) -> serenity::builder::CreateCommand,
+    pub run: for<'a> fn(Vec<ResolvedOption<'a>>, CommandContext<'a>) -> BoxedFuture<'a, SlashReply>,
     pub is_local_command: bool,
 }
 
+// 設定されたモデルから選ぶオプション
+pub fn model_option(bot: &crate::Bot) -> CreateCommandOption {
+    bot.ai.models().models().iter().fold(
+        CreateCommandOption::new(CommandOptionType::String, "model", "モデル").required(false),
+        |option, model| option.add_string_choice(&model.label, &model.name),
+    )
+}
+
 pub fn slash_commands(disabled_commands: &[&str]) -> Vec<ManamiSlashCommand> {
     [
         help::SLASH_HELP_COMMAND,
@@ -160,11 +195,14 @@ pub fn slash_commands(disabled_commands: &[&str]) -> Vec<ManamiSlashCommand> {
         auto::SLASH_AUTO_COMMAND,
         endauto::SLASH_ENDAUTO_COMMAND,
         bf::SLASH_BF_COMMAND,
+        calchelp::SLASH_CALCHELP_COMMAND,
         channel::SLASH_CHANNEL_COMMAND,
         dice::SLASH_DICE_COMMAND,
         fetch::SLASH_FETCH_COMMAND,
         gemini::SLASH_GEMINI_COMMAND,
         isprime::SLASH_ISPRIME_COMMAND,
+        persona::SLASH_PERSONA_COMMAND,
+        relation::SLASH_RELATION_COMMAND,
     ]
     .into_iter()
     .filter(|command| !disabled_commands.contains(&command.name))
@@ -186,6 +224,7 @@ pub fn prefix_commands(disabled_commands: &[&str]) -> Vec<ManamiPrefixCommand> {
         cclemon::PREFIX_CCLEMON_COMMAND,
         calc::PREFIX_CALC_COMMAND,
         calcsay::PREFIX_CALCSAY_COMMAND,
+        repl::PREFIX_REPL_COMMAND,
         var::PREFIX_VAR_COMMAND,
         varbulk::PREFIX_VARBULK_COMMAND,
         fetch::PREFIX_FETCH_COMMAND,
@@ -0,0 +1,247 @@
+use serenity::{
+    builder::{CreateCommand, CreateCommandOption},
+    model::{
+        application::{CommandOptionType, ResolvedOption, ResolvedValue},
+        id::ChannelId,
+        Permissions,
+    },
+};
+
+use crate::{
+    ai::Persona,
+    commands::{model_option, ManamiSlashCommand},
+    Bot,
+};
+
+const COMMAND_NAME: &str = "persona";
+
+// 返信に載せる指示の長さ。Discordのメッセージは2000文字まで
+const PROMPT_PREVIEW_LEN: usize = 1500;
+
+pub const SLASH_PERSONA_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
+    name: COMMAND_NAME,
+    usage: "/persona [prompt] [model] [rate] [reset] [off]",
+    description: "このチャンネルでのまなみを設定するよ！",
+    register,
+    run: |option, ctx| {
+        let opts = parse_options(option);
+        Box::pin(async move { run_body(opts, ctx.bot, ctx.channel_id).await.into() })
+    },
+    is_local_command: true,
+};
+
+pub fn register(bot: &Bot) -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .description("このチャンネルでのまなみの指示・モデル・返事をする確率を設定するよ")
+        .default_member_permissions(Permissions::MANAGE_CHANNELS)
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                "prompt",
+                "まなみへの指示。{{relationships}}と書くと/relationの一覧が入るよ",
+            )
+            .required(false)
+            .max_length(6000),
+        )
+        .add_option(model_option(bot))
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Number,
+                "rate",
+                "呼びかけられなくても返事をする確率（0〜1）",
+            )
+            .required(false)
+            .min_number_value(0.0)
+            .max_number_value(1.0),
+        )
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::Boolean, "reset", "設定を元に戻す")
+                .required(false),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Boolean,
+                "off",
+                "このチャンネルでおしゃべりするのをやめる",
+            )
+            .required(false),
+        )
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct PersonaOptions {
+    prompt: Option<String>,
+    model: Option<String>,
+    rate: Option<f64>,
+    reset: bool,
+    off: bool,
+}
+
+impl PersonaOptions {
+    const fn is_empty(&self) -> bool {
+        self.prompt.is_none() && self.model.is_none() && self.rate.is_none() && !self.reset
+    }
+
+    // 今の設定に変更を当てる
+    // 全レスモードは/autoと/endautoで切り替えるので、resetしてもそのまま
+    fn apply(self, current: Option<Persona>) -> Persona {
+        let reply_to_all = current.as_ref().and_then(|p| p.reply_to_all.clone());
+        let current = if self.reset {
+            Persona::default()
+        } else {
+            current.unwrap_or_default()
+        };
+        Persona {
+            prompt: self.prompt.or(current.prompt),
+            model: self.model.or(current.model),
+            reply_rate: self.rate.unwrap_or(current.reply_rate),
+            reply_to_all,
+        }
+    }
+}
+
+fn parse_options(option: Vec<ResolvedOption<'_>>) -> PersonaOptions {
+    option
+        .iter()
+        .fold(PersonaOptions::default(), |opts, option| {
+            match (option.name, &option.value) {
+                ("prompt", ResolvedValue::String(s)) => PersonaOptions {
+                    prompt: Some((*s).to_owned()),
+                    ..opts
+                },
+                ("model", ResolvedValue::String(s)) => PersonaOptions {
+                    model: Some((*s).to_owned()),
+                    ..opts
+                },
+                ("rate", ResolvedValue::Number(n)) => PersonaOptions {
+                    rate: Some(n.clamp(0.0, 1.0)),
+                    ..opts
+                },
+                ("reset", ResolvedValue::Boolean(b)) => PersonaOptions { reset: *b, ..opts },
+                ("off", ResolvedValue::Boolean(b)) => PersonaOptions { off: *b, ..opts },
+                _ => opts,
+            }
+        })
+}
+
+fn show(persona: &Persona) -> String {
+    let persona = Persona {
+        prompt: persona.prompt.as_ref().map(|prompt| {
+            if prompt.chars().count() > PROMPT_PREVIEW_LEN {
+                format!(
+                    "{}…",
+                    prompt.chars().take(PROMPT_PREVIEW_LEN).collect::<String>()
+                )
+            } else {
+                prompt.clone()
+            }
+        }),
+        ..persona.clone()
+    };
+    persona.to_string()
+}
+
+async fn run_body(opts: PersonaOptions, bot: &Bot, channel_id: ChannelId) -> String {
+    if opts.off {
+        if let Err(e) = bot.database.delete_persona(&channel_id).await {
+            return format!("設定を消せなかったよ: {e}");
+        }
+        bot.ai.remove_persona(channel_id);
+        if let Some(mut mode) = bot.reply_to_all_mode.get_mut(&channel_id) {
+            mode.end();
+        }
+        return if bot.is_ai_channel(channel_id) {
+            "設定を消して、いつものまなみに戻ったよ".to_owned()
+        } else {
+            "このチャンネルではもうおしゃべりしないよ".to_owned()
+        };
+    }
+
+    let current = bot.ai.persona(channel_id);
+    if opts.is_empty() {
+        return match current {
+            Some(persona) => format!("このチャンネルのまなみだよ\n{}", show(&persona)),
+            None if bot.is_ai_channel(channel_id) => {
+                format!("いつものまなみだよ\n{}", show(&Persona::default()))
+            }
+            None => "このチャンネルではおしゃべりしてないよ。`/persona`で設定してね".to_owned(),
+        };
+    }
+
+    if let Some(model) = &opts.model {
+        if let Err(e) = bot.ai.models().find(model) {
+            return e.to_string();
+        }
+    }
+
+    let persona = opts.apply(current);
+    if let Err(e) = bot.database.upsert_persona(&channel_id, &persona).await {
+        return format!("設定を保存できなかったよ: {e}");
+    }
+    let msg = format!("このチャンネルのまなみを設定したよ\n{}", show(&persona));
+    bot.ai.set_persona(channel_id, persona);
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::ReplyToAll;
+
+    #[test]
+    fn test_apply() {
+        let current = Persona {
+            prompt: Some("あなたは猫です".to_owned()),
+            model: Some("gemini-2.0-flash".to_owned()),
+            reply_rate: 0.5,
+            reply_to_all: Some(ReplyToAll {
+                secs: 300,
+                model: "gemini-2.0-flash".to_owned(),
+            }),
+        };
+
+        // 指定しなかった項目は今の設定のまま
+        let opts = PersonaOptions {
+            rate: Some(1.0),
+            ..PersonaOptions::default()
+        };
+        assert_eq!(
+            opts.apply(Some(current.clone())),
+            Persona {
+                reply_rate: 1.0,
+                ..current.clone()
+            }
+        );
+
+        // resetしてから指定した項目を当てる
+        let opts = PersonaOptions {
+            model: Some("gemini-2.0-flash-lite".to_owned()),
+            reset: true,
+            ..PersonaOptions::default()
+        };
+        assert!(!opts.is_empty());
+        assert_eq!(
+            opts.apply(Some(current.clone())),
+            Persona {
+                model: Some("gemini-2.0-flash-lite".to_owned()),
+                reply_to_all: current.reply_to_all,
+                ..Persona::default()
+            }
+        );
+
+        assert!(PersonaOptions::default().is_empty());
+        assert_eq!(PersonaOptions::default().apply(None), Persona::default());
+    }
+
+    #[test]
+    fn test_show() {
+        let persona = Persona {
+            prompt: Some("あ".repeat(3000)),
+            ..Persona::default()
+        };
+        let shown = show(&persona);
+        assert!(shown.contains("返事をする確率: 30%"));
+        assert!(!shown.contains("全レスモード"));
+        assert!(shown.chars().count() < 2000);
+    }
+}
@@ -6,8 +6,8 @@ pub const SLASH_PING_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
     name: "ping",
     usage: "/ping",
     description: "起きてたらお返事するね！",
-    register,
-    run: |_, _| Box::pin(async { run() }),
+    register: |_| register(),
+    run: |_, _| Box::pin(async { run().into() }),
     is_local_command: false,
 };
 
@@ -0,0 +1,214 @@
+use serenity::{
+    builder::{CreateCommand, CreateCommandOption},
+    model::{
+        application::{CommandOptionType, ResolvedOption, ResolvedValue},
+        id::UserId,
+        Permissions,
+    },
+};
+
+use crate::{ai::Relationship, commands::ManamiSlashCommand, Bot};
+
+const COMMAND_NAME: &str = "relation";
+
+pub const SLASH_RELATION_COMMAND: ManamiSlashCommand = ManamiSlashCommand {
+    name: COMMAND_NAME,
+    usage: "/relation [user] [name] [relation] [call] [remove]",
+    description: "まなみとユーザーの関係を設定するよ！",
+    register: |_| register(),
+    run: |option, ctx| {
+        let opts = parse_options(option);
+        Box::pin(async move { run_body(opts, ctx.bot).await.into() })
+    },
+    is_local_command: true,
+};
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .description("まなみとユーザーの関係を設定するよ。userを省略すると一覧を表示するよ")
+        .default_member_permissions(Permissions::ADMINISTRATOR)
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::User, "user", "ユーザー").required(false),
+        )
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::String, "name", "名前")
+                .required(false)
+                .max_length(100),
+        )
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::String, "relation", "まなみとの関係")
+                .required(false)
+                .max_length(100),
+        )
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::String, "call", "まなみからの呼び方")
+                .required(false)
+                .max_length(100),
+        )
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::Boolean, "remove", "関係を消す")
+                .required(false),
+        )
+}
+
+#[derive(Debug, Default)]
+struct RelationOptions {
+    // ユーザーIDと、ログに出てくるユーザー名
+    user: Option<(UserId, String)>,
+    nickname: Option<String>,
+    relation: Option<String>,
+    call_name: Option<String>,
+    remove: bool,
+}
+
+impl RelationOptions {
+    const fn has_changes(&self) -> bool {
+        self.nickname.is_some() || self.relation.is_some() || self.call_name.is_some()
+    }
+
+    // 今の関係に変更を当てる
+    fn apply(
+        self,
+        current: Option<Relationship>,
+        user_id: UserId,
+        user_name: String,
+    ) -> Relationship {
+        let current = current.unwrap_or_else(|| Relationship {
+            user_id,
+            user_name: user_name.clone(),
+            nickname: None,
+            relation: None,
+            call_name: None,
+        });
+        if self.remove {
+            return Relationship {
+                user_name,
+                nickname: None,
+                relation: None,
+                call_name: None,
+                ..current
+            };
+        }
+        Relationship {
+            user_name,
+            nickname: self.nickname.or(current.nickname),
+            relation: self.relation.or(current.relation),
+            call_name: self.call_name.or(current.call_name),
+            ..current
+        }
+    }
+}
+
+fn parse_options(option: Vec<ResolvedOption<'_>>) -> RelationOptions {
+    option
+        .iter()
+        .fold(RelationOptions::default(), |opts, option| {
+            match (option.name, &option.value) {
+                ("user", ResolvedValue::User(user, member)) => {
+                    let user_name = member
+                        .and_then(|member| member.nick.clone())
+                        .unwrap_or_else(|| user.display_name().to_owned());
+                    RelationOptions {
+                        user: Some((user.id, user_name)),
+                        ..opts
+                    }
+                }
+                ("name", ResolvedValue::String(s)) => RelationOptions {
+                    nickname: Some((*s).to_owned()),
+                    ..opts
+                },
+                ("relation", ResolvedValue::String(s)) => RelationOptions {
+                    relation: Some((*s).to_owned()),
+                    ..opts
+                },
+                ("call", ResolvedValue::String(s)) => RelationOptions {
+                    call_name: Some((*s).to_owned()),
+                    ..opts
+                },
+                ("remove", ResolvedValue::Boolean(b)) => RelationOptions { remove: *b, ..opts },
+                _ => opts,
+            }
+        })
+}
+
+fn list(relationships: &[Relationship]) -> String {
+    if relationships.is_empty() {
+        return "特別な関係のユーザーはまだいないよ".to_owned();
+    }
+    let list = relationships
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("まなみの大切な人たちだよ\n```\n{list}\n```")
+}
+
+async fn run_body(mut opts: RelationOptions, bot: &Bot) -> String {
+    let relationships = bot.ai.relationships();
+    let Some((user_id, user_name)) = opts.user.take() else {
+        return list(&relationships);
+    };
+    let current = relationships.into_iter().find(|r| r.user_id == user_id);
+    if !opts.has_changes() && !opts.remove {
+        return current.map_or_else(
+            || format!("{user_name}さんとは特別な関係じゃないよ"),
+            |r| format!("```\n{r}\n```"),
+        );
+    }
+
+    let relationship = opts.apply(current, user_id, user_name);
+    if let Err(e) = bot.database.set_relationship(&relationship).await {
+        return format!("関係を保存できなかったよ: {e}");
+    }
+    // 指示に書き込む一覧を読み直す
+    match bot.database.fetch_relationships().await {
+        Ok(relationships) => bot.ai.set_relationships(relationships),
+        Err(e) => return format!("関係を読み直せなかったよ: {e}"),
+    }
+    if relationship.nickname.is_none()
+        && relationship.relation.is_none()
+        && relationship.call_name.is_none()
+    {
+        format!("{}さんとの関係を消したよ", relationship.user_name)
+    } else {
+        format!("関係を設定したよ\n```\n{relationship}\n```")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let user_id = UserId::new(1);
+        let opts = RelationOptions {
+            relation: Some("兄".to_owned()),
+            call_name: Some("お兄ちゃん".to_owned()),
+            ..RelationOptions::default()
+        };
+        assert!(opts.has_changes());
+        let relationship = opts.apply(None, user_id, "宇田".to_owned());
+        assert_eq!(
+            relationship.to_string(),
+            "- ユーザー名: 宇田\n    - 関係: 兄\n    - 呼び方: お兄ちゃん"
+        );
+
+        // 指定しなかった項目は残し、ユーザー名は新しくする
+        let opts = RelationOptions {
+            nickname: Some("宇田まなと".to_owned()),
+            ..RelationOptions::default()
+        };
+        let updated = opts.apply(Some(relationship), user_id, "宇田さん".to_owned());
+        assert_eq!(updated.user_name, "宇田さん");
+        assert_eq!(updated.nickname.as_deref(), Some("宇田まなと"));
+        assert_eq!(updated.call_name.as_deref(), Some("お兄ちゃん"));
+
+        let opts = RelationOptions {
+            remove: true,
+            ..RelationOptions::default()
+        };
+        let removed = opts.apply(Some(updated), user_id, "宇田".to_owned());
+        assert_eq!(removed.to_string(), "- ユーザー名: 宇田");
+    }
+}
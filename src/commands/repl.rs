@@ -0,0 +1,349 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use regex::Regex;
+use serenity::{
+    all::{AutoArchiveDuration, ChannelType},
+    builder::CreateThread,
+    http::Http,
+    model::{
+        channel::Message,
+        id::{ChannelId, UserId},
+    },
+    prelude::Mentionable,
+};
+use tokio::{spawn, time::sleep};
+use tracing::error;
+
+use crate::calculator::{self, show_result, EvalContext, EvalResult, Scope};
+use crate::commands::CommandContext;
+use crate::reply::{split_message, MESSAGE_LIMIT};
+use crate::Bot;
+
+use super::ManamiPrefixCommand;
+
+pub const PREFIX_REPL_COMMAND: ManamiPrefixCommand = ManamiPrefixCommand {
+    name: "repl",
+    alias: &[],
+    usage: "!repl",
+    description: "calcの対話セッションを開くよ！",
+    run: |ctx| Box::pin(run(ctx)),
+    is_dm_command: true,
+    is_guild_command: true,
+};
+
+// 最後の入力からこれだけ経つとセッションを閉じる
+const REPL_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// 直前の結果を入れる変数
+const REPL_LAST: &str = "_";
+
+const REPL_USAGE: &str = "式を送ると計算するよ！\n\
+    `let x = 式` で変数を作れるよ（このセッションの中だけ）\n\
+    `:vars` 変数の一覧　`:reset` 変数を全部消す　`:save x` 変数をずっと使えるように保存\n\
+    `:quit` セッションを閉じる\n\
+    コードブロックで囲むと複数行まとめて送れるよ\n\
+    セッション中は`!`で始まらないメッセージを全部式として読むよ。`!`のコマンドはそのまま使えるし、10分なにも送らなければ自動で閉じるよ";
+
+pub type ReplSessions = Arc<DashMap<ChannelId, ReplSession>>;
+
+pub struct ReplSession {
+    pub owner: UserId,
+    // セッションの識別用。同じチャンネルで開き直したときに古いタイマーが閉じないように
+    pub started: Instant,
+    pub last_active: Instant,
+    pub scope: Scope,
+}
+
+#[derive(Debug)]
+pub enum ReplOutput {
+    Value(EvalResult),
+    Error(String),
+    Reply(String),
+    Save(String, EvalResult),
+    Close,
+}
+
+impl ReplSession {
+    pub fn new(owner: UserId) -> Self {
+        let now = Instant::now();
+        Self {
+            owner,
+            started: now,
+            last_active: now,
+            scope: Scope::new(),
+        }
+    }
+
+    // 1回分の入力を処理する
+    pub fn eval_input(&mut self, input: &str, global_context: &EvalContext) -> ReplOutput {
+        self.last_active = Instant::now();
+        let input = input.trim();
+        let mut words = input.split_whitespace();
+        match words.next() {
+            Some(":quit" | ":exit") => return ReplOutput::Close,
+            Some(":help") => return ReplOutput::Reply(REPL_USAGE.to_owned()),
+            Some(":reset") => {
+                self.scope = Scope::new();
+                return ReplOutput::Reply("変数を全部消したよ！".to_owned());
+            }
+            Some(":vars") => return ReplOutput::Reply(self.show_vars()),
+            Some(":save") => {
+                let Some(name) = words.next() else {
+                    return ReplOutput::Reply("使い方: `:save <変数名>`".to_owned());
+                };
+                return match self.scope.get(name) {
+                    Some(val) if name != REPL_LAST => {
+                        ReplOutput::Save(name.to_owned(), val.clone())
+                    }
+                    _ => ReplOutput::Reply(format!("変数 `{name}` はこのセッションにないよ")),
+                };
+            }
+            Some(cmd) if cmd.starts_with(':') => {
+                return ReplOutput::Reply(format!("知らないコマンドだよ: `{cmd}`"))
+            }
+            _ => {}
+        }
+
+        let mut last = None;
+        for statement in split_statements(strip_code_fence(input), global_context) {
+            // 演算子の定義は全体に保存する
+            if let Some(declared) =
+                calculator::eval_declaration(&statement, global_context, &self.scope)
+            {
+                return match declared {
+                    Ok((name, val)) => ReplOutput::Save(name, val),
+                    Err(e) => ReplOutput::Error(e),
+                };
+            }
+            let (name, expression) = split_let(&statement);
+            match calculator::eval_from_str_in_scope(expression, global_context, &self.scope) {
+                Ok(val) => {
+                    let mut bindings = vec![(REPL_LAST.to_owned(), val.clone())];
+                    if let Some(name) = name {
+                        bindings.push((name.to_owned(), val.clone()));
+                    }
+                    self.scope = self.scope.extend(bindings);
+                    last = Some(val);
+                }
+                Err(e) => return ReplOutput::Error(e),
+            }
+        }
+        last.map_or_else(
+            || ReplOutput::Reply("何か入力してね".to_owned()),
+            ReplOutput::Value,
+        )
+    }
+
+    fn show_vars(&self) -> String {
+        let vars = self
+            .scope
+            .bindings()
+            .into_iter()
+            .filter(|(name, _)| *name != REPL_LAST)
+            .map(|(name, val)| format!("{name} = {val:#}"))
+            .collect::<Vec<_>>();
+        if vars.is_empty() {
+            "変数はないよ！".to_owned()
+        } else {
+            format!("```\n{}\n```", vars.join("\n"))
+        }
+    }
+}
+
+// ```calc ... ``` のようなコードブロックの中身を取り出す
+fn strip_code_fence(input: &str) -> &str {
+    let Some(body) = input
+        .strip_prefix("```")
+        .and_then(|s| s.strip_suffix("```"))
+    else {
+        return input;
+    };
+    // 1行目は言語名なので捨てる
+    body.split_once('\n').map_or(body, |(_, rest)| rest)
+}
+
+// :から始まるコマンドか、最後まで読める式ならtrue
+pub fn is_complete_statement(statement: &str, global_context: &EvalContext) -> bool {
+    if statement.trim_start().starts_with(':') {
+        return true;
+    }
+    let expression = match calculator::declaration_body(statement) {
+        Some(Ok(body)) => body,
+        // 定義の誤りはすぐに知らせる
+        Some(Err(_)) => return true,
+        None => split_let(statement).1,
+    };
+    matches!(calculator::parse_expr_in(expression, global_context), Ok((rest, _)) if rest.trim().is_empty())
+}
+
+// 行ごとに区切る。式が閉じていなければ次の行とつなげる
+pub fn split_statements(input: &str, global_context: &EvalContext) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut buffer = String::new();
+    for line in input.lines() {
+        if line.trim().is_empty() && buffer.is_empty() {
+            continue;
+        }
+        if !buffer.is_empty() {
+            buffer.push('\n');
+        }
+        buffer.push_str(line);
+        if is_complete_statement(&buffer, global_context) {
+            statements.push(std::mem::take(&mut buffer));
+        }
+    }
+    if !buffer.trim().is_empty() {
+        statements.push(buffer);
+    }
+    statements
+}
+
+// let x = expr を (Some("x"), "expr") に分ける
+fn split_let(statement: &str) -> (Option<&str>, &str) {
+    let let_pattern = Regex::new(r"(?s)^\s*let\s+([A-Za-z_][A-Za-z0-9_]*)\s*=(.*)$").unwrap();
+    let_pattern
+        .captures(statement)
+        .map_or((None, statement), |caps| {
+            (
+                caps.get(1).map(|m| m.as_str()),
+                caps.get(2).map_or("", |m| m.as_str()),
+            )
+        })
+}
+
+pub async fn run(ctx: CommandContext<'_>) {
+    let bot = ctx.bot;
+    let http = ctx.cache_http();
+
+    // サーバーではスレッドを作ってそこで対話する
+    let channel = if ctx.guild_id.is_some() {
+        let thread = CreateThread::new("まなみのcalc")
+            .kind(ChannelType::PublicThread)
+            .auto_archive_duration(AutoArchiveDuration::OneHour);
+        match ctx.channel_id.create_thread(http, thread).await {
+            Ok(thread) => thread.id,
+            Err(_) => ctx.channel_id,
+        }
+    } else {
+        ctx.channel_id
+    };
+
+    let session = ReplSession::new(ctx.author_id);
+    let started = session.started;
+    bot.repl_sessions.insert(channel, session);
+    channel
+        .say(
+            http,
+            format!(
+                "{}専用の計算セッションだよ！\n{REPL_USAGE}",
+                ctx.author_id.mention()
+            ),
+        )
+        .await
+        .unwrap();
+
+    // 放置されたセッションを閉じる
+    let sessions = bot.repl_sessions.clone();
+    let http = ctx.ctx.http.clone();
+    spawn(async move {
+        let mut wait = REPL_IDLE_TIMEOUT;
+        loop {
+            sleep(wait).await;
+            let idle = match sessions.get(&channel) {
+                Some(session) if session.started == started => session.last_active.elapsed(),
+                _ => return,
+            };
+            if idle < REPL_IDLE_TIMEOUT {
+                wait = REPL_IDLE_TIMEOUT - idle;
+                continue;
+            }
+            sessions.remove(&channel);
+            if let Err(e) = channel
+                .say(&http, "しばらく入力がなかったからセッションを閉じたよ")
+                .await
+            {
+                error!("Error sending repl timeout: {e:?}");
+            }
+            return;
+        }
+    });
+}
+
+// セッション中のチャンネルで、開いた本人が送ったメッセージならtrue
+// DMでは転送されるはずのメッセージもこちらで受け取るので、閉じるまではコマンドだけが素通りする
+pub fn is_session_message(bot: &Bot, msg: &Message) -> bool {
+    !msg.content.starts_with('!')
+        && bot
+            .repl_sessions
+            .get(&msg.channel_id)
+            .is_some_and(|session| session.owner == msg.author.id)
+}
+
+pub async fn handle_message(bot: &Bot, http: &Http, msg: &Message) {
+    let output = match bot.repl_sessions.get_mut(&msg.channel_id) {
+        Some(mut session) => session.eval_input(&msg.content, &bot.variables),
+        None => return,
+    };
+    let reply = match output {
+        ReplOutput::Value(val) => show_result(&val),
+        ReplOutput::Error(e) => format!("{e} ……だってさ。"),
+        ReplOutput::Reply(content) => content,
+        ReplOutput::Save(name, val) => {
+            bot.database
+                .upsert_var(&name, val.clone(), msg.author.id)
+                .await
+                .ok();
+            bot.variables.insert(name.clone(), val);
+            format!("変数 `{name}` を保存したよ！")
+        }
+        ReplOutput::Close => {
+            bot.repl_sessions.remove(&msg.channel_id);
+            "セッションを閉じたよ！".to_owned()
+        }
+    };
+    // 長い結果は2000文字ごとに分けて送る
+    for chunk in split_message(&reply, MESSAGE_LIMIT) {
+        if let Err(e) = msg.channel_id.say(http, chunk).await {
+            error!("Error sending repl reply: {e:?}");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(output: ReplOutput) -> String {
+        match output {
+            ReplOutput::Value(val) => show_result(&val),
+            ReplOutput::Error(s) | ReplOutput::Reply(s) => s,
+            other => panic!("unexpected output: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_repl_session() {
+        let global = EvalContext::new();
+        let mut session = ReplSession::new(UserId::new(1));
+        assert_eq!(reply(session.eval_input("let x = 3", &global)), "3");
+        assert_eq!(reply(session.eval_input("x * _", &global)), "9");
+        let fenced = "```calc\nlet sq = y =>\n  y * y\nsq(x) + 1\n```";
+        assert_eq!(reply(session.eval_input(fenced, &global)), "10");
+        assert!(reply(session.eval_input(":vars", &global)).contains("sq = "));
+        assert!(matches!(
+            session.eval_input(":save x", &global),
+            ReplOutput::Save(name, EvalResult::IVal(3)) if name == "x"
+        ));
+        session.eval_input(":reset", &global);
+        assert!(reply(session.eval_input("x", &global)).contains("Undefined variable"));
+        assert!(matches!(
+            session.eval_input(":quit", &global),
+            ReplOutput::Close
+        ));
+    }
+}
@@ -4,7 +4,7 @@ use serenity::all::UserId;
 use serenity::http::Http;
 use serenity::model::id::ChannelId;
 
-use crate::calculator::{self, val_as_str};
+use crate::calculator::{self, show_result, Scope};
 use crate::commands::CommandContext;
 
 use super::ManamiPrefixCommand;
@@ -29,6 +29,23 @@ pub async fn run(ctx: CommandContext<'_>) {
 }
 
 pub async fn var(reply: ChannelId, cache_http: &Http, input: String, bot: &Bot, author_id: UserId) {
+    // infixl 6 <+> = 式 は演算子の定義
+    if let Some(declared) = calculator::eval_declaration(&input, &bot.variables, &Scope::new()) {
+        let message = match declared {
+            Ok((var, result)) => {
+                bot.database
+                    .upsert_var(&var, result.clone(), author_id)
+                    .await
+                    .ok();
+                bot.variables.insert(var.clone(), result);
+                format!("`{var}` を定義したよ！")
+            }
+            Err(e) => format!("{e} ……だってさ。"),
+        };
+        reply.say(&cache_http, message).await.unwrap();
+        return;
+    }
+
     let var_pattern = Regex::new(r"([a-zA-Z0-9]+)\s*=\s*(.*)").unwrap();
 
     let (var, expression) = match var_pattern.captures(&input) {
@@ -65,7 +82,7 @@ pub async fn var_main(
                     .ok();
             }
             bot.variables.insert(var, result.clone());
-            reply.say(&cache_http, val_as_str(&result)).await.unwrap();
+            reply.say(&cache_http, show_result(&result)).await.unwrap();
         }
         Err(e) => {
             reply
@@ -2,7 +2,9 @@
 
 pub mod prelude;
 
+pub mod ai_log;
 pub mod calc_var;
 pub mod channel;
 pub mod message;
+pub mod persona;
 pub mod user;
@@ -1,6 +1,8 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.1.10
 
+pub use super::ai_log::Entity as AiLog;
 pub use super::calc_var::Entity as CalcVar;
 pub use super::channel::Entity as Channel;
 pub use super::message::Entity as Message;
+pub use super::persona::Entity as Persona;
 pub use super::user::Entity as User;
@@ -10,6 +10,9 @@ pub struct Model {
     pub user_id: i64,
     pub username: String,
     pub room_pointer: Option<i64>,
+    pub nickname: Option<String>,
+    pub relation: Option<String>,
+    pub call_name: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
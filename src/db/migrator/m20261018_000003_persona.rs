@@ -0,0 +1,47 @@
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261018_000003_persona"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Persona::Table)
+                    .col(
+                        ColumnDef::new(Persona::ChannelId)
+                            .big_integer()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Persona::Prompt).text())
+                    .col(ColumnDef::new(Persona::Model).string())
+                    .col(ColumnDef::new(Persona::ReplyRate).double().not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Persona::Table).to_owned())
+            .await
+    }
+}
+
+#[allow(clippy::enum_variant_names, dead_code)]
+#[derive(Iden)]
+pub enum Persona {
+    Table,
+    ChannelId,
+    Prompt,
+    Model,
+    ReplyRate,
+}
@@ -0,0 +1,80 @@
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261018_000004_relationship"
+    }
+}
+
+// 以前は指示に直接書いていた、まなみと関係のあるユーザー
+// (ユーザー名, 名前, 関係, 呼び方)
+const RELATIONSHIPS: [(&str, &str, &str, &str); 4] = [
+    ("宇田", "宇田まなと", "兄", "お兄ちゃん"),
+    ("うさみむ", "うさみむ", "義姉", "おねえちゃん"),
+    ("響", "響", "みむの弟", "響くん"),
+    ("メタぺん", "メタぺん", "友人", "メタぺんさん"),
+];
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLiteは1回のALTER TABLEで1列しか追加できない
+        for column in [User::Nickname, User::Relation, User::CallName] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .add_column(ColumnDef::new(column).string().to_owned())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        // ユーザーIDは分からないので、ユーザー名が一致する人だけ書き込む
+        // 見つからない人は、指示を作るときにai::prompt::DEFAULT_RELATIONSHIPSで補う
+        for (username, nickname, relation, call_name) in RELATIONSHIPS {
+            manager
+                .exec_stmt(
+                    Query::update()
+                        .table(User::Table)
+                        .values([
+                            (User::Nickname, nickname.into()),
+                            (User::Relation, relation.into()),
+                            (User::CallName, call_name.into()),
+                        ])
+                        .and_where(Expr::col(User::Username).eq(username))
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [User::Nickname, User::Relation, User::CallName] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::enum_variant_names, dead_code)]
+#[derive(Iden)]
+pub enum User {
+    Table,
+    UserId,
+    Username,
+    RoomPointer,
+    Nickname,
+    Relation,
+    CallName,
+}
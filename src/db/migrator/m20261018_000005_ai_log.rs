@@ -0,0 +1,45 @@
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261018_000005_ai_log"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AiLog::Table)
+                    .col(
+                        ColumnDef::new(AiLog::MessageId)
+                            .big_integer()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AiLog::ChannelId).big_integer().not_null())
+                    .col(ColumnDef::new(AiLog::Reply).text())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AiLog::Table).to_owned())
+            .await
+    }
+}
+
+#[allow(clippy::enum_variant_names, dead_code)]
+#[derive(Iden)]
+pub enum AiLog {
+    Table,
+    MessageId,
+    ChannelId,
+    Reply,
+}
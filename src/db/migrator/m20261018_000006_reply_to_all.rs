@@ -0,0 +1,54 @@
+use sea_orm_migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261018_000006_reply_to_all"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLiteは1回のALTER TABLEで1列しか追加できない
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Persona::Table)
+                    .add_column(ColumnDef::new(Persona::ReplyToAllSecs).big_integer())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Persona::Table)
+                    .add_column(ColumnDef::new(Persona::ReplyToAllModel).string())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [Persona::ReplyToAllSecs, Persona::ReplyToAllModel] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Persona::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::enum_variant_names, dead_code)]
+#[derive(Iden)]
+pub enum Persona {
+    Table,
+    ReplyToAllSecs,
+    ReplyToAllModel,
+}
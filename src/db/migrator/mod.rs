@@ -2,6 +2,10 @@ use sea_orm_migration::prelude::*;
 
 mod m20250429_000001_create_tables;
 mod m20250526_000002_room_pointer;
+mod m20261018_000003_persona;
+mod m20261018_000004_relationship;
+mod m20261018_000005_ai_log;
+mod m20261018_000006_reply_to_all;
 
 pub struct Migrator;
 
@@ -11,6 +15,10 @@ impl MigratorTrait for Migrator {
         vec![
             Box::new(m20250429_000001_create_tables::Migration),
             Box::new(m20250526_000002_room_pointer::Migration),
+            Box::new(m20261018_000003_persona::Migration),
+            Box::new(m20261018_000004_relationship::Migration),
+            Box::new(m20261018_000005_ai_log::Migration),
+            Box::new(m20261018_000006_reply_to_all::Migration),
         ]
     }
 }
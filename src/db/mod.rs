@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::ai::GeminiContent;
+use crate::ai::{GeminiContent, Persona, Relationship, ReplyToAll};
 use crate::calculator::EvalContext;
 use crate::db::migrator::Migrator;
 use chrono::DateTime;
@@ -144,6 +144,7 @@ impl BotDatabase {
             user_id: ActiveValue::Set(user_id.get() as i64),
             room_pointer: ActiveValue::Set(room_pointer.map(|c| c.get() as i64)),
             username: ActiveValue::Set(username.to_owned()),
+            ..Default::default()
         };
 
         user::Entity::insert(user_model)
@@ -196,6 +197,161 @@ impl BotDatabase {
         Ok(())
     }
 
+    // 名前・関係・呼び方のどれかが設定されているユーザー
+    pub async fn fetch_relationships(&self) -> anyhow::Result<Vec<Relationship>> {
+        let users = user::Entity::find()
+            .filter(
+                user::Column::Nickname
+                    .is_not_null()
+                    .or(user::Column::Relation.is_not_null())
+                    .or(user::Column::CallName.is_not_null()),
+            )
+            .order_by_asc(user::Column::UserId)
+            .all(&self.db)
+            .await?;
+        Ok(users
+            .into_iter()
+            .map(|u| Relationship {
+                user_id: UserId::from(u.user_id as u64),
+                user_name: u.username,
+                nickname: u.nickname,
+                relation: u.relation,
+                call_name: u.call_name,
+            })
+            .collect())
+    }
+
+    pub async fn set_relationship(&self, relationship: &Relationship) -> anyhow::Result<()> {
+        let user_model = user::ActiveModel {
+            user_id: ActiveValue::Set(relationship.user_id.get() as i64),
+            username: ActiveValue::Set(relationship.user_name.clone()),
+            nickname: ActiveValue::Set(relationship.nickname.clone()),
+            relation: ActiveValue::Set(relationship.relation.clone()),
+            call_name: ActiveValue::Set(relationship.call_name.clone()),
+            ..Default::default()
+        };
+
+        user::Entity::insert(user_model)
+            .on_conflict(
+                OnConflict::columns([user::Column::UserId])
+                    .update_columns([
+                        user::Column::Username,
+                        user::Column::Nickname,
+                        user::Column::Relation,
+                        user::Column::CallName,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn fetch_personas(&self) -> anyhow::Result<Vec<(ChannelId, Persona)>> {
+        let personas = persona::Entity::find().all(&self.db).await?;
+        Ok(personas
+            .into_iter()
+            .map(|p| {
+                (
+                    ChannelId::from(p.channel_id as u64),
+                    Persona {
+                        prompt: p.prompt,
+                        model: p.model,
+                        reply_rate: p.reply_rate,
+                        reply_to_all: p.reply_to_all_secs.zip(p.reply_to_all_model).map(
+                            |(secs, model)| ReplyToAll {
+                                secs: secs as u64,
+                                model,
+                            },
+                        ),
+                    },
+                )
+            })
+            .collect())
+    }
+
+    pub async fn upsert_persona(
+        &self,
+        channel_id: &ChannelId,
+        persona: &Persona,
+    ) -> anyhow::Result<()> {
+        let persona_model = persona::ActiveModel {
+            channel_id: ActiveValue::Set(channel_id.get() as i64),
+            prompt: ActiveValue::Set(persona.prompt.clone()),
+            model: ActiveValue::Set(persona.model.clone()),
+            reply_rate: ActiveValue::Set(persona.reply_rate),
+            reply_to_all_secs: ActiveValue::Set(
+                persona.reply_to_all.as_ref().map(|auto| auto.secs as i64),
+            ),
+            reply_to_all_model: ActiveValue::Set(
+                persona.reply_to_all.as_ref().map(|auto| auto.model.clone()),
+            ),
+        };
+
+        persona::Entity::insert(persona_model)
+            .on_conflict(
+                OnConflict::columns([persona::Column::ChannelId])
+                    .update_columns([
+                        persona::Column::Prompt,
+                        persona::Column::Model,
+                        persona::Column::ReplyRate,
+                        persona::Column::ReplyToAllSecs,
+                        persona::Column::ReplyToAllModel,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_persona(&self, channel_id: &ChannelId) -> anyhow::Result<()> {
+        persona::Entity::delete_by_id(channel_id.get() as i64)
+            .exec(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    // AIの会話に入れたメッセージを覚えておく。replyはまなみの返事の全文（分けて送っても1つ）
+    pub async fn insert_ai_log(
+        &self,
+        message_id: &MessageId,
+        channel_id: &ChannelId,
+        reply: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let log_model = ai_log::ActiveModel {
+            message_id: ActiveValue::Set(message_id.get() as i64),
+            channel_id: ActiveValue::Set(channel_id.get() as i64),
+            reply: ActiveValue::Set(reply.map(str::to_owned)),
+        };
+
+        ai_log::Entity::insert(log_model)
+            .on_conflict(
+                OnConflict::columns([ai_log::Column::MessageId])
+                    .update_columns([ai_log::Column::Reply])
+                    .to_owned(),
+            )
+            .exec(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    // メッセージのうちAIの会話に入れたもの。値はまなみの返事の全文
+    pub async fn fetch_ai_log(
+        &self,
+        messages: &[MessageInfo],
+    ) -> anyhow::Result<HashMap<MessageId, Option<String>>> {
+        let ids = messages.iter().map(|m| m.message_id.get() as i64);
+        let logs = ai_log::Entity::find()
+            .filter(ai_log::Column::MessageId.is_in(ids))
+            .all(&self.db)
+            .await?;
+        Ok(logs
+            .into_iter()
+            .map(|log| (MessageId::from(log.message_id as u64), log.reply))
+            .collect())
+    }
+
     pub async fn fetch_oldest_message(
         &self,
         channel_id: &ChannelId,
@@ -235,6 +391,25 @@ impl BotDatabase {
             .collect())
     }
 
+    // 本文に文字列を含むメッセージを新しい順に探す
+    pub async fn search_log(
+        &self,
+        channel_id: &ChannelId,
+        query: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<MessageInfo>> {
+        let messages: Vec<(message::Model, Option<user::Model>)> = message::Entity::find()
+            .filter(message::Column::ChannelId.eq(channel_id.get() as i64))
+            .filter(message::Column::Content.contains(query))
+            .order_by_desc(message::Column::Timestamp)
+            .limit(limit as u64)
+            .find_also_related(user::Entity)
+            .all(&self.db)
+            .await?;
+
+        Ok(Self::query_result_to_message(messages))
+    }
+
     pub async fn fetch_log_by_duration(
         &self,
         channel_id: &ChannelId,
@@ -305,7 +480,7 @@ impl BotDatabase {
                 let user_id = UserId::from(user.as_ref().map_or(0, |u| u.user_id) as u64);
                 let user_name = user
                     .as_ref()
-                    .map_or("Unknown".to_owned(), |u| u.username.clone());
+                    .map_or_else(|| "Unknown".to_owned(), |u| u.username.clone());
                 MessageInfo {
                     message_id,
                     user_id,
@@ -344,17 +519,20 @@ impl BotDatabase {
     }
 
     pub async fn retrieve_eval_context(&self) -> EvalContext {
-        (calc_var::Entity::find().all(&self.db).await).map_or(EvalContext::new(), |models| {
-            EvalContext::from_dashmap({
-                let dashmap = DashMap::new();
-                models.into_iter().for_each(|model| {
-                    if let Ok(value) = serde_json::from_str::<EvalResult>(&model.var_value) {
-                        dashmap.insert(model.var_name, value);
-                    }
-                });
-                dashmap
-            })
-        })
+        (calc_var::Entity::find().all(&self.db).await).map_or_else(
+            |_| EvalContext::new(),
+            |models| {
+                EvalContext::from_dashmap({
+                    let dashmap = DashMap::new();
+                    models.into_iter().for_each(|model| {
+                        if let Ok(value) = serde_json::from_str::<EvalResult>(&model.var_value) {
+                            dashmap.insert(model.var_name, value);
+                        }
+                    });
+                    dashmap
+                })
+            },
+        )
     }
 
     pub async fn delete_var(&self, varname: &str) -> anyhow::Result<()> {
@@ -379,7 +557,7 @@ impl BotDatabase {
             .map(|(var, user)| {
                 (
                     var.var_name,
-                    user.map_or("[不明]".to_owned(), |u| u.username),
+                    user.map_or_else(|| "[不明]".to_owned(), |u| u.username),
                 )
             })
             .collect())
@@ -2,7 +2,7 @@
 /// メッセージ・コマンドのハンドリングを担当
 use std::{
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use rand::rng;
@@ -15,7 +15,7 @@ use serenity::all::{MessageId, MessageUpdateEvent};
 use serenity::{
     all::{ActivityData, Command},
     async_trait,
-    builder::{CreateInteractionResponse, CreateInteractionResponseMessage},
+    builder::CreateInteractionResponse,
     model::{
         application::Interaction,
         channel::Message,
@@ -37,6 +37,8 @@ pub mod calculator;
 pub mod cclemon;
 pub mod db;
 pub mod parser;
+pub mod reply;
+pub mod tools;
 
 pub struct Bot {
     // Discordサーバーの情報
@@ -53,14 +55,14 @@ pub struct Bot {
     pub commit_date: Option<String>,
 
     // まなみの雑談用のAI
-    pub gemini: ai::GeminiAI,
+    pub ai: ai::ChatAI,
 
     // コマンド用のデータ
     // ログなどを保存するDB
     pub database: BotDatabase,
 
-    // 全レスモードのデータ
-    pub reply_to_all_mode: Arc<Mutex<ReplyToAllModeData>>,
+    // チャンネルごとの全レスモードのデータ
+    pub reply_to_all_mode: Arc<DashMap<ChannelId, ReplyToAllModeData>>,
 
     // 有効なコマンドのデータ
     pub slash_commands: Vec<ManamiSlashCommand>,
@@ -75,6 +77,9 @@ pub struct Bot {
 
     // var, calcコマンドのデータ
     pub variables: EvalContext,
+
+    // replコマンドのセッション
+    pub repl_sessions: repl::ReplSessions,
 }
 
 impl Bot {
@@ -87,7 +92,7 @@ impl Bot {
         jail_mark_role_id: RoleId,
         jail_main_role_id: RoleId,
 
-        gemini: ai::GeminiAI,
+        ai: ai::ChatAI,
 
         commit_hash: Option<String>,
         commit_date: Option<String>,
@@ -99,8 +104,31 @@ impl Bot {
         let variables = database.retrieve_eval_context().await;
         let jail_process = Arc::new(DashMap::new());
         let jail_id = Arc::new(Mutex::new(0));
-        let reply_to_all_mode: Arc<Mutex<ReplyToAllModeData>> =
-            Arc::new(Mutex::new(ReplyToAllModeData::blank()));
+        let repl_sessions = Arc::new(DashMap::new());
+        let reply_to_all_mode = Arc::new(DashMap::new());
+        match database.fetch_personas().await {
+            Ok(personas) => {
+                for (channel_id, persona) in personas {
+                    let auto = persona.reply_to_all.clone();
+                    ai.set_persona(channel_id, persona);
+                    // 保存された全レスモードを続ける。モデルが一覧から消えていればチャンネルのモデルにする
+                    if let Some(auto) = auto {
+                        let model = ai
+                            .models()
+                            .find(&auto.model)
+                            .unwrap_or_else(|_| ai.model_for(channel_id));
+                        let mut mode = ReplyToAllModeData::blank();
+                        mode.set(model, Duration::from_secs(auto.secs));
+                        reply_to_all_mode.insert(channel_id, mode);
+                    }
+                }
+            }
+            Err(e) => error!("Error loading personas: {e:?}"),
+        }
+        match database.fetch_relationships().await {
+            Ok(relationships) => ai.set_relationships(relationships),
+            Err(e) => error!("Error loading relationships: {e:?}"),
+        }
         let prefix_commands = prefix_commands(disabled_commands);
         let slash_commands = slash_commands(disabled_commands);
 
@@ -115,14 +143,57 @@ impl Bot {
             commit_hash,
             commit_date,
             variables,
+            repl_sessions,
             reply_to_all_mode,
-            gemini,
+            ai,
             prefix_commands,
             slash_commands,
             database,
         }
     }
 
+    // まなみが会話するチャンネル。デバッグ用のチャンネルと/personaで設定したチャンネル
+    pub fn is_ai_channel(&self, channel_id: ChannelId) -> bool {
+        channel_id == self.debug_channel_id || self.ai.persona(channel_id).is_some()
+    }
+
+    pub fn ai_channels(&self) -> Vec<ChannelId> {
+        let mut channels = self.ai.persona_channels();
+        if !channels.contains(&self.debug_channel_id) {
+            channels.push(self.debug_channel_id);
+        }
+        channels
+    }
+
+    // 呼びかけられていないメッセージに返事をする確率
+    pub fn reply_rate(&self, channel_id: ChannelId) -> f64 {
+        self.ai
+            .persona(channel_id)
+            .map_or(ai::DEFAULT_REPLY_RATE, |persona| persona.reply_rate)
+    }
+
+    pub async fn restore_ai_log(&self, channel_id: ChannelId, my_userid: &UserId) {
+        match self
+            .database
+            .fetch_log_by_count(&channel_id, ai::MAX_LOG_LEN)
+            .await
+        {
+            Ok(messages) => {
+                let ai_log = match self.database.fetch_ai_log(&messages).await {
+                    Ok(ai_log) => ai_log,
+                    Err(e) => {
+                        error!("Error restoring AI log: {e:?}");
+                        return;
+                    }
+                };
+                let contents = clear::remembered_log(&messages, &ai_log, my_userid);
+                info!("Restored {} AI logs for {channel_id}", contents.len());
+                self.ai.restore(channel_id, contents);
+            }
+            Err(e) => error!("Error restoring AI log: {e:?}"),
+        }
+    }
+
     pub async fn get_user_room_pointer(&self, user_id: &UserId) -> ChannelId {
         let default_channel_id = self.channel_ids[0];
         self.database
@@ -177,6 +248,11 @@ impl EventHandler for Bot {
             error!("Error sending message: {:?}", why);
         };
 
+        // 再起動しても会話を覚えているように、保存されたログから作り直す
+        for channel_id in self.ai_channels() {
+            self.restore_ai_log(channel_id, &ready.user.id).await;
+        }
+
         // ローカルコマンドの登録
         let _ = self
             .guild_id
@@ -185,7 +261,7 @@ impl EventHandler for Bot {
                 self.slash_commands
                     .iter()
                     .filter(|cmd| cmd.is_local_command)
-                    .map(|cmd| (cmd.register)())
+                    .map(|cmd| (cmd.register)(self))
                     .collect::<Vec<_>>(),
             )
             .await;
@@ -196,7 +272,7 @@ impl EventHandler for Bot {
             .iter()
             .filter(|cmd| !cmd.is_local_command)
         {
-            let _ = Command::create_global_command(&ctx.http, (command.register)()).await;
+            let _ = Command::create_global_command(&ctx.http, (command.register)(self)).await;
         }
 
         // roles のいずれかが付いているユーザーを恩赦
@@ -262,6 +338,17 @@ impl EventHandler for Bot {
     }
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        if let Interaction::Component(component) = &interaction {
+            // ボタンはcustom_idの接頭辞で持ち主のコマンドを見分ける
+            if component
+                .data
+                .custom_id
+                .starts_with(calchelp::CUSTOM_ID_PREFIX)
+            {
+                calchelp::handle_component(&ctx, component).await;
+            }
+            return;
+        }
         if let Interaction::Command(command) = interaction {
             #[allow(unused_variables)]
             let command_context = CommandContext::new_from_command_interaction(
@@ -270,17 +357,16 @@ impl EventHandler for Bot {
                 &command,
                 &command.data.name,
             );
-            let content = match self
+            let reply = match self
                 .slash_commands
                 .iter()
                 .find(|cmd| cmd.name == command.data.name)
             {
                 Some(cmd) => (cmd.run)(command.data.options(), command_context).await,
-                None => "知らないコマンドだよ！".to_owned(),
+                None => "知らないコマンドだよ！".to_owned().into(),
             };
 
-            let data = CreateInteractionResponseMessage::new().content(content);
-            let builder = CreateInteractionResponse::Message(data);
+            let builder = CreateInteractionResponse::Message(reply.into_message());
             if let Err(why) = command.create_response(&ctx.http, builder).await {
                 error!("Error sending message: {:?}", why);
             }
@@ -294,6 +380,12 @@ async fn direct_message(bot: &Bot, ctx: &Context, msg: &Message) {
         return;
     }
 
+    // 計算セッション中なら式として扱う
+    if repl::is_session_message(bot, msg) {
+        repl::handle_message(bot, &ctx.http, msg).await;
+        return;
+    }
+
     // if message is not command, forward to the room
     if !msg.content.starts_with('!') {
         // 代筆先のチャンネルにメッセージを転送する
@@ -344,8 +436,15 @@ async fn save_guild_message(bot: &Bot, ctx: &Context, msg: &Message) {
     }
 
     // AIのためにメッセージを保存する
-    if msg.channel_id.get() == bot.debug_channel_id.get() && !msg.author.bot {
-        bot.gemini.add_user_log(user_name, &msg.content);
+    if bot.is_ai_channel(msg.channel_id) && !msg.author.bot {
+        bot.ai.add_user_log(msg.channel_id, user_name, &msg.content);
+        if let Err(e) = bot
+            .database
+            .insert_ai_log(&msg.id, &msg.channel_id, None)
+            .await
+        {
+            error!("Error adding AI log: {e:?}");
+        }
     }
 }
 
@@ -357,9 +456,22 @@ async fn guild_message(bot: &Bot, ctx: &Context, msg: &Message) {
         return;
     }
 
+    // 計算セッション中のスレッドなら式として扱う
+    if repl::is_session_message(bot, msg) {
+        repl::handle_message(bot, &ctx.http, msg).await;
+        return;
+    }
+
     // 全レスモード中？
-    let response_to_all = bot.reply_to_all_mode.lock().unwrap().is_active();
-    let response_to_all_model = bot.reply_to_all_mode.lock().unwrap().model.clone();
+    let response_to_all = bot
+        .reply_to_all_mode
+        .get(&msg.channel_id)
+        .is_some_and(|mode| mode.is_active());
+    let response_to_all_model = bot
+        .reply_to_all_mode
+        .get(&msg.channel_id)
+        .and_then(|mode| mode.model.clone())
+        .unwrap_or_else(|| bot.ai.model_for(msg.channel_id));
 
     // if message does not contains any command, ignore
     let command_pattern =
@@ -370,20 +482,13 @@ async fn guild_message(bot: &Bot, ctx: &Context, msg: &Message) {
             caps.get(2).unwrap().as_str().to_owned(),
         ),
         None => {
-            // 全レスモードの場合は必ず返答、そうでないときは3割の確率で返答
+            // 全レスモードの場合は必ず返答、そうでないときはチャンネルの確率で返答
 
-            if msg.channel_id.get() == bot.debug_channel_id.get()
-                && (response_to_all || rng().random::<f32>() < 0.3)
+            if bot.is_ai_channel(msg.channel_id)
+                && (response_to_all || rng().random::<f64>() < bot.reply_rate(msg.channel_id))
             {
-                bot.reply_to_all_mode.lock().unwrap().renew(); // 期限更新
-                let content = bot.gemini.generate_with_model(response_to_all_model).await;
-                let content = match content {
-                    Ok(content) => content.replace("うだまなみ: ", ""),
-                    Err(e) => {
-                        format!("Error sending message: {e:?}")
-                    }
-                };
-                let _ = &msg.channel_id.say(&ctx.http, content).await;
+                renew_reply_to_all(bot, msg.channel_id); // 期限更新
+                reply_with_ai(bot, ctx, msg.channel_id, response_to_all_model).await;
             }
             return;
         }
@@ -413,27 +518,71 @@ async fn guild_message(bot: &Bot, ctx: &Context, msg: &Message) {
                 }
             }
 
-            if msg.channel_id.get() == bot.debug_channel_id.get() {
+            if bot.is_ai_channel(msg.channel_id) {
                 // まなみが自由に応答するコーナー
-                let content = if response_to_all {
-                    bot.reply_to_all_mode.lock().unwrap().renew(); // 期限更新
-                                                                   // ↓全レスモードなら全レス用のモデルを使用
-                    bot.gemini.generate_with_model(response_to_all_model).await
+                let model = if response_to_all {
+                    renew_reply_to_all(bot, msg.channel_id); // 期限更新
+                                                             // ↓全レスモードなら全レス用のモデルを使用
+                    response_to_all_model
                 } else {
-                    bot.gemini.generate().await
-                };
-                let content = match content {
-                    Ok(content) => content.replace("うだまなみ: ", ""),
-                    Err(e) => {
-                        format!("Error sending message: {e:?}")
-                    }
+                    bot.ai.model_for(msg.channel_id)
                 };
-                let _ = &msg.channel_id.say(&ctx.http, content).await;
+                reply_with_ai(bot, ctx, msg.channel_id, model).await;
             }
         }
     }
 }
 
+// 生成しながら返事を編集していく
+async fn reply_with_ai(bot: &Bot, ctx: &Context, channel_id: ChannelId, model: ai::ChatModel) {
+    let mut reply = match reply::StreamingReply::start(&ctx.http, channel_id).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            error!("Error sending message: {e:?}");
+            return;
+        }
+    };
+
+    let (progress, mut received) = tokio::sync::mpsc::unbounded_channel();
+    let tools = tools::BotTools::new(bot);
+    let generation = bot
+        .ai
+        .generate_streaming(channel_id, model, &tools, progress);
+    let display = async {
+        while let Some(text) = received.recv().await {
+            reply.update(&ctx.http, &text).await;
+        }
+    };
+    let (content, ()) = tokio::join!(generation, display);
+
+    let content = match content {
+        Ok(content) => {
+            // 会話に入った返事として、全文を最初のメッセージに覚えておく
+            if let Err(e) = bot
+                .database
+                .insert_ai_log(&reply.message_id(), &channel_id, Some(&content))
+                .await
+            {
+                error!("Error adding AI log: {e:?}");
+            }
+            content
+        }
+        Err(e) => {
+            error!("Error generating AI reply: {e:?}");
+            ai::user_message(&e).to_owned()
+        }
+    };
+    reply.finish(&ctx.http, &content).await;
+}
+
+fn renew_reply_to_all(bot: &Bot, channel_id: ChannelId) {
+    if let Some(mut mode) = bot.reply_to_all_mode.get_mut(&channel_id) {
+        if mode.is_active() {
+            mode.renew();
+        }
+    }
+}
+
 async fn has_privilege(bot: &Bot, ctx: &Context, msg: &Message) -> bool {
     if msg.author.bot {
         return false;
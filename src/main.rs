@@ -90,7 +90,32 @@ async fn serenity(
 
     let commit_date = secrets.get("COMMIT_DATE");
 
-    let gemini = ai::GeminiAI::manami(&secrets.get("GEMINI_API_KEY").unwrap());
+    // AIのバックエンド。AI_CHANNEL_BACKENDSで "チャンネルID:バックエンド名" ごとに変えられる
+    let backend_config = ai::BackendConfig {
+        gemini_api_key: secrets.get("GEMINI_API_KEY"),
+        openai_base_url: secrets.get("OPENAI_BASE_URL"),
+        openai_api_key: secrets.get("OPENAI_API_KEY"),
+        openai_models: secrets
+            .get("OPENAI_MODELS")
+            .map(|models| {
+                models
+                    .split(',')
+                    .map(|model| model.trim().to_owned())
+                    .filter(|model| !model.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    let backends = backend_config.backends(
+        secrets.get("AI_BACKEND").as_deref().unwrap_or("gemini"),
+        &secrets.get("AI_CHANNEL_BACKENDS").unwrap_or_default(),
+    )?;
+    // AI_MODELSは "モデル名=表示名@トークンの予算" をカンマで区切ったもの。先頭が既定のモデル
+    let models = match secrets.get("AI_MODELS") {
+        Some(models) => ai::ModelCatalog::parse(&models)?,
+        None => ai::ModelCatalog::default(),
+    };
+    let ai = ai::ChatAI::manami(backends, models);
 
     let database = BotDatabase::new("./db.sqlite").await?;
 
@@ -100,7 +125,7 @@ async fn serenity(
         guild_id,
         jail_mark_role_id,
         jail_main_role_id,
-        gemini,
+        ai,
         commit_hash,
         commit_date,
         &disabled_commands,
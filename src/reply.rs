@@ -0,0 +1,348 @@
+/*
+-----------------------------
+AIの返事をDiscordに送る
+先に仮のメッセージを送り、生成中は途中までの文章で編集し続ける
+2000文字を超えたら段落やコードブロックの切れ目で続きのメッセージに分け、
+長すぎる返事は先頭だけ載せて全文を.mdファイルで添付する
+-----------------------------
+*/
+
+use std::time::{Duration, Instant};
+
+use serenity::{
+    builder::{CreateAllowedMentions, CreateAttachment, CreateMessage, EditMessage},
+    http::Http,
+    model::{
+        channel::Message,
+        id::{ChannelId, MessageId},
+    },
+};
+use tracing::error;
+
+// Discordのメッセージの文字数の上限
+pub const MESSAGE_LIMIT: usize = 2000;
+
+// これより多くのメッセージに分かれるなら、ファイルで添付する
+const MAX_MESSAGES: usize = 3;
+
+// 編集しすぎるとレート制限にかかるので、間隔をあける
+const EDIT_INTERVAL: Duration = Duration::from_millis(1500);
+
+// 生成が始まるまで表示しておくメッセージ
+const PLACEHOLDER: &str = "……";
+
+const ATTACHMENT_NOTICE: &str = "（長くなっちゃったから、全部はファイルにしたよ）";
+const ATTACHMENT_NAME: &str = "manami.md";
+
+const FENCE: &str = "```";
+
+// AIが自分の名前を付けて返事をすることがあるので外す
+pub fn clean(text: &str) -> String {
+    text.replace("うだまなみ: ", "")
+}
+
+// @everyone や @here、ロールへのメンションで通知が飛ばないようにする
+pub fn allowed_mentions() -> CreateAllowedMentions {
+    CreateAllowedMentions::new()
+        .everyone(false)
+        .all_roles(false)
+        .all_users(true)
+        .replied_user(false)
+}
+
+struct Line<'a> {
+    text: &'a str,
+    // この行の後もコードブロックの中なら、開いた行（```rust など）
+    fence_after: Option<&'a str>,
+}
+
+// 長すぎる行は上限で切り、行ごとにコードブロックの中かどうかを調べる
+fn lines(text: &str, width: usize) -> Vec<Line<'_>> {
+    let mut lines = vec![];
+    let mut fence: Option<&str> = None;
+    for line in text.split('\n') {
+        let trimmed = line.trim();
+        let fence_after = if trimmed.starts_with(FENCE) {
+            match fence {
+                Some(_) => None,
+                None => Some(trimmed),
+            }
+        } else {
+            fence
+        };
+        let mut rest = line;
+        while rest.chars().count() > width {
+            let end = rest
+                .char_indices()
+                .nth(width)
+                .map_or(rest.len(), |(i, _)| i);
+            lines.push(Line {
+                text: &rest[..end],
+                fence_after: fence,
+            });
+            rest = &rest[end..];
+        }
+        lines.push(Line {
+            text: rest,
+            fence_after,
+        });
+        fence = fence_after;
+    }
+    lines
+}
+
+// 上限を超えないように分ける
+// なるべく段落（空行）やコードブロックの切れ目で区切り、
+// コードブロックの途中で区切ったら閉じて、次のメッセージで開き直す
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    // 開き直す行と閉じる行の分をあけておく
+    let reserve = text
+        .split('\n')
+        .filter(|line| line.trim().starts_with(FENCE))
+        .map(|line| line.trim().chars().count() + 1)
+        .max()
+        .map_or(0, |opener| opener + FENCE.len() + 1);
+    let lines = lines(text, limit.saturating_sub(reserve).max(1));
+    let fence_before = |i: usize| {
+        if i == 0 {
+            None
+        } else {
+            lines[i - 1].fence_after
+        }
+    };
+    let render_len = |start: usize, end: usize| {
+        let body = lines[start..end]
+            .iter()
+            .map(|line| line.text.chars().count() + 1)
+            .sum::<usize>()
+            - 1;
+        let opener = fence_before(start).map_or(0, |opener| opener.chars().count() + 1);
+        let closer = lines[end - 1].fence_after.map_or(0, |_| FENCE.len() + 1);
+        opener + body + closer
+    };
+    // 前の行が段落の終わりか、コードブロックの外の境目
+    let is_break = |i: usize| {
+        let prev = &lines[i - 1];
+        prev.fence_after.is_none()
+            && (prev.text.trim().is_empty()
+                || prev.text.trim().starts_with(FENCE)
+                || lines[i].text.trim().starts_with(FENCE))
+    };
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < lines.len() {
+        let mut end = start + 1;
+        while end < lines.len() && render_len(start, end + 1) <= limit {
+            end += 1;
+        }
+        // 短くなりすぎない範囲で、段落の切れ目まで戻る
+        if end < lines.len() {
+            if let Some(cut) = (start + 1..=end)
+                .rev()
+                .find(|&i| is_break(i) && render_len(start, i) >= limit / 2)
+            {
+                end = cut;
+            }
+        }
+
+        let mut chunk = String::new();
+        if let Some(opener) = fence_before(start) {
+            chunk.push_str(opener);
+            chunk.push('\n');
+        }
+        chunk.push_str(
+            &lines[start..end]
+                .iter()
+                .map(|line| line.text)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+        if lines[end - 1].fence_after.is_some() {
+            chunk.push('\n');
+            chunk.push_str(FENCE);
+        }
+        let chunk = chunk.trim_matches('\n');
+        if !chunk.trim().is_empty() {
+            chunks.push(chunk.to_owned());
+        }
+        start = end;
+    }
+    chunks
+}
+
+// 送るメッセージの中身
+#[derive(Debug, PartialEq, Eq)]
+pub struct Rendered {
+    pub chunks: Vec<String>,
+    // 全文をファイルで添付するか
+    pub attachment: bool,
+}
+
+pub fn render(text: &str) -> Rendered {
+    let chunks = split_message(text, MESSAGE_LIMIT);
+    if chunks.len() <= MAX_MESSAGES {
+        return Rendered {
+            chunks,
+            attachment: false,
+        };
+    }
+    let limit = MESSAGE_LIMIT - ATTACHMENT_NOTICE.chars().count() - 1;
+    let head = split_message(text, limit).swap_remove(0);
+    Rendered {
+        chunks: vec![format!("{head}\n{ATTACHMENT_NOTICE}")],
+        attachment: true,
+    }
+}
+
+pub struct StreamingReply {
+    channel_id: ChannelId,
+    // 送ったメッセージ。2000文字ごとに1つ
+    messages: Vec<Message>,
+    last_edit: Instant,
+}
+
+impl StreamingReply {
+    // 仮のメッセージを送る
+    pub async fn start(http: &Http, channel_id: ChannelId) -> serenity::Result<Self> {
+        let placeholder = channel_id.say(http, PLACEHOLDER).await?;
+        Ok(Self {
+            channel_id,
+            messages: vec![placeholder],
+            last_edit: Instant::now(),
+        })
+    }
+
+    // 最初に送ったメッセージ。分けて送っても消えずに残る
+    pub fn message_id(&self) -> MessageId {
+        self.messages[0].id
+    }
+
+    // 途中までの文章で編集する。前の編集から間もなければ何もしない
+    pub async fn update(&mut self, http: &Http, text: &str) {
+        if self.last_edit.elapsed() < EDIT_INTERVAL {
+            return;
+        }
+        self.render(http, text, false).await;
+    }
+
+    // 最後の文章で必ず編集する。長すぎれば全文を添付する
+    pub async fn finish(&mut self, http: &Http, text: &str) {
+        self.render(http, text, true).await;
+    }
+
+    async fn render(&mut self, http: &Http, text: &str, finished: bool) {
+        let text = clean(text);
+        let Rendered {
+            mut chunks,
+            attachment,
+        } = render(&text);
+        if chunks.is_empty() {
+            chunks.push(PLACEHOLDER.to_owned());
+        }
+        let attach = attachment && finished;
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            match self.messages.get_mut(i) {
+                Some(message) if message.content == *chunk && !(attach && i == 0) => {}
+                Some(message) => {
+                    let mut edit = EditMessage::new()
+                        .content(chunk)
+                        .allowed_mentions(allowed_mentions());
+                    if attach && i == 0 {
+                        edit = edit.new_attachment(CreateAttachment::bytes(
+                            text.as_bytes().to_vec(),
+                            ATTACHMENT_NAME,
+                        ));
+                    }
+                    if let Err(e) = message.edit(http, edit).await {
+                        error!("Error editing message: {e:?}");
+                    }
+                }
+                None => {
+                    let message = CreateMessage::new()
+                        .content(chunk)
+                        .allowed_mentions(allowed_mentions());
+                    match self.channel_id.send_message(http, message).await {
+                        Ok(message) => self.messages.push(message),
+                        Err(e) => error!("Error sending message: {e:?}"),
+                    }
+                }
+            }
+        }
+
+        // 生成し直したり添付に切り替えたりして減ったら、余ったメッセージを消す
+        while self.messages.len() > chunks.len() {
+            let message = self.messages.pop().unwrap();
+            if let Err(e) = message.delete(http).await {
+                error!("Error deleting message: {e:?}");
+            }
+        }
+        self.last_edit = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_message() {
+        assert!(split_message("", 10).is_empty());
+        assert_eq!(split_message("おはよう", 10), ["おはよう"]);
+
+        // 改行があればそこで区切る
+        assert_eq!(
+            split_message("あいうえお\nかきくけこさしす", 10),
+            ["あいうえお", "かきくけこさしす"]
+        );
+        // なければ上限で区切る
+        assert_eq!(
+            split_message(&"あ".repeat(25), 10),
+            ["あ".repeat(10), "あ".repeat(10), "あ".repeat(5)]
+        );
+        // 段落の切れ目を優先する
+        assert_eq!(
+            split_message("あいう\nえお\n\nかきく\nけこ\nさし", 14),
+            ["あいう\nえお", "かきく\nけこ\nさし"]
+        );
+
+        let text = "まなみ\n".repeat(1000);
+        assert!(split_message(&text, MESSAGE_LIMIT)
+            .iter()
+            .all(|chunk| chunk.chars().count() <= MESSAGE_LIMIT));
+    }
+
+    #[test]
+    fn test_split_code_block() {
+        let code = (0..8).map(|i| format!("x{i}")).collect::<Vec<_>>();
+        let text = format!("コードだよ\n```rust\n{}\n```\nおしまい", code.join("\n"));
+        let chunks = split_message(&text, 30);
+        // 途中で閉じて、次で開き直す
+        assert_eq!(
+            chunks,
+            [
+                "コードだよ\n```rust\nx0\nx1\nx2\nx3\n```",
+                "```rust\nx4\nx5\nx6\nx7\n```\nおしまい",
+            ]
+        );
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 30);
+            assert_eq!(chunk.matches(FENCE).count() % 2, 0);
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let short = render("おはよう！");
+        assert_eq!(short.chunks, ["おはよう！"]);
+        assert!(!short.attachment);
+
+        let text = "まなみだよ\n".repeat(1000);
+        let long = render(&text);
+        assert!(long.attachment);
+        assert_eq!(long.chunks.len(), 1);
+        assert!(long.chunks[0].ends_with(ATTACHMENT_NOTICE));
+        assert!(long.chunks[0].chars().count() <= MESSAGE_LIMIT);
+    }
+}
@@ -0,0 +1,197 @@
+/*
+-----------------------------
+まなみがAIに使わせる道具
+計算・サイコロ・素数判定はコマンドと同じ処理で答え、
+ログの検索は話しているチャンネルの中だけを探す
+-----------------------------
+*/
+
+use chrono::FixedOffset;
+use serde_json::json;
+use serenity::{async_trait, model::id::ChannelId};
+
+use crate::{
+    ai::{ToolBox, ToolCall, ToolDeclaration},
+    calculator::{self, show_result},
+    commands::{dice, isprime},
+    Bot,
+};
+
+// ログ検索で返すメッセージの数
+const DEFAULT_SEARCH_LIMIT: u64 = 10;
+const MAX_SEARCH_LIMIT: u64 = 30;
+
+// 検索結果に載せる1メッセージの長さ
+const SEARCH_PREVIEW_LEN: usize = 200;
+
+pub struct BotTools<'a> {
+    bot: &'a Bot,
+}
+
+impl<'a> BotTools<'a> {
+    pub const fn new(bot: &'a Bot) -> Self {
+        Self { bot }
+    }
+
+    fn calculate(&self, call: &ToolCall) -> String {
+        let Some(expression) = call.str_arg("expression") else {
+            return "expressionがないよ".to_owned();
+        };
+        match calculator::eval_from_str(expression, &self.bot.variables) {
+            Ok(result) => show_result(&result),
+            Err(e) => format!("計算できなかったよ: {e}"),
+        }
+    }
+
+    async fn search_log(&self, channel_id: ChannelId, call: &ToolCall) -> String {
+        let Some(query) = call.str_arg("query").filter(|q| !q.trim().is_empty()) else {
+            return "queryがないよ".to_owned();
+        };
+        let limit = call
+            .u64_arg("limit")
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .clamp(1, MAX_SEARCH_LIMIT);
+        let messages = match self
+            .bot
+            .database
+            .search_log(&channel_id, query.trim(), limit as usize)
+            .await
+        {
+            Ok(messages) => messages,
+            Err(e) => return format!("ログを探せなかったよ: {e}"),
+        };
+        if messages.is_empty() {
+            return format!("「{query}」を含むメッセージは見つからなかったよ");
+        }
+        let jst = FixedOffset::east_opt(9 * 3600).unwrap();
+        messages
+            .iter()
+            .map(|message| {
+                let content = message
+                    .content
+                    .chars()
+                    .take(SEARCH_PREVIEW_LEN)
+                    .collect::<String>();
+                format!(
+                    "[{}] {}: {content}",
+                    message
+                        .timestamp
+                        .with_timezone(&jst)
+                        .format("%Y-%m-%d %H:%M"),
+                    message.user_name
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[async_trait]
+impl ToolBox for BotTools<'_> {
+    fn declarations(&self) -> Vec<ToolDeclaration> {
+        declarations()
+    }
+
+    async fn call(&self, channel_id: ChannelId, call: &ToolCall) -> String {
+        match call.name.as_str() {
+            "calculate" => self.calculate(call),
+            "roll_dice" => call.str_arg("dice").map_or_else(
+                || "diceがないよ".to_owned(),
+                |literal| dice::run_literal(literal.trim()),
+            ),
+            "is_prime" => match call.u64_arg("n") {
+                // コマンドと違い、AIはいくらでも大きな数を渡してくるので上限を設ける
+                Some(n) if n > isprime::MAX_NUMBER => {
+                    format!("大きすぎるよ。{}までにしてね", isprime::MAX_NUMBER)
+                }
+                // 大きな数の素因数分解は時間がかかるので、非同期のスレッドを止めないようにする
+                Some(n) => tokio::task::spawn_blocking(move || isprime::run_number(n))
+                    .await
+                    .unwrap_or_else(|e| format!("判定できなかったよ: {e}")),
+                None => "nがないよ".to_owned(),
+            },
+            "search_log" => self.search_log(channel_id, call).await,
+            name => format!("{name}という道具はないよ"),
+        }
+    }
+}
+
+fn declarations() -> Vec<ToolDeclaration> {
+    vec![
+        ToolDeclaration {
+            name: "calculate".to_owned(),
+            description: "数式を計算する。四則演算、関数、リスト、日付などが使える。\
+                計算を頼まれたら暗算せずに必ずこれを使う"
+                .to_owned(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "expression": { "type": "string", "description": "数式。例: (1 + 2) * 3" },
+                },
+                "required": ["expression"],
+            }),
+        },
+        ToolDeclaration {
+            name: "roll_dice".to_owned(),
+            description: "サイコロを振る。結果は自分で決めずに必ずこれを使う".to_owned(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "dice": { "type": "string", "description": "振り方。例: 1d6, 2d6 >= 7" },
+                },
+                "required": ["dice"],
+            }),
+        },
+        ToolDeclaration {
+            name: "is_prime".to_owned(),
+            description: "整数が素数かどうかを判定し、素因数分解する".to_owned(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "n": {
+                        "type": "integer",
+                        "description": format!("0以上{}以下の整数", isprime::MAX_NUMBER),
+                    },
+                },
+                "required": ["n"],
+            }),
+        },
+        ToolDeclaration {
+            name: "search_log".to_owned(),
+            description: "このチャンネルの過去のメッセージから、文字列を含むものを新しい順に探す"
+                .to_owned(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "description": "探す文字列" },
+                    "limit": {
+                        "type": "integer",
+                        "description": format!("返す数（最大{MAX_SEARCH_LIMIT}）"),
+                    },
+                },
+                "required": ["query"],
+            }),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_declarations() {
+        let declarations = declarations();
+        for declaration in &declarations {
+            // 必須の引数は引数の一覧にある
+            let properties = &declaration.parameters["properties"];
+            for required in declaration.parameters["required"].as_array().unwrap() {
+                assert!(properties.get(required.as_str().unwrap()).is_some());
+            }
+        }
+        let mut names = declarations.iter().map(|d| &d.name).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 4);
+    }
+}